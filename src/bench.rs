@@ -0,0 +1,159 @@
+use crate::{
+    completions::{CompletionConfig, MyCompleter},
+    parse::{ParsedCommand, process_tokens},
+    prompt::{PromptConfig, PromptSystem},
+};
+use reedline::{Completer, Prompt};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io,
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+const GLOB_ENTRIES: usize = 50_000;
+const PATH_COMMANDS: usize = 10_000;
+const PROMPT_RENDERS: u32 = 1_000;
+
+// Scratch directory for the fixtures, removed when the benchmark is done
+struct Fixture(PathBuf);
+
+impl Fixture {
+    fn new(name: &str) -> io::Result<Self> {
+        let dir = env::temp_dir().join(format!("shesh-bench-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        Ok(Self(dir))
+    }
+
+    fn fill(&self, count: usize, prefix: &str, mode: u32) -> io::Result<()> {
+        for i in 0..count {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(mode)
+                .open(self.0.join(format!("{prefix}{i:05}")))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn report(name: &str, detail: &str, took: Duration) {
+    println!(
+        "{name:<12} {detail:<36} {:>10.3} ms",
+        took.as_secs_f64() * 1000.0
+    );
+}
+
+fn time<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let value = f();
+    (value, start.elapsed())
+}
+
+// `dir/*` over a synthetic directory, through the same expansion commands use
+fn bench_globs() -> io::Result<()> {
+    let fixture = Fixture::new("globs")?;
+    fixture.fill(GLOB_ENTRIES, "file", 0o644)?;
+
+    let pattern = format!("{}/*", fixture.0.display());
+    let (expanded, took) = time(|| process_tokens(ParsedCommand::Single(vec![pattern])));
+    let expanded = expanded?;
+    report("globs", &format!("expand {} entries", expanded.len()), took);
+    Ok(())
+}
+
+// Loading and completing commands from a PATH with many executables
+fn bench_completion() -> io::Result<()> {
+    let fixture = Fixture::new("path")?;
+    fixture.fill(PATH_COMMANDS, "cmd", 0o755)?;
+
+    let saved_path = env::var_os("PATH");
+    unsafe {
+        env::set_var("PATH", &fixture.0);
+    }
+    let mut completer = MyCompleter::new(CompletionConfig::default(), crate::jobs::table());
+    // PATH is read at the first Tab
+    let (first, load) = time(|| completer.complete("cmd0000", 7));
+    report(
+        "completion",
+        &format!("first Tab, {PATH_COMMANDS} PATH commands ({})", first.len()),
+        load,
+    );
+    let (all, took) = time(|| completer.complete("c", 1));
+    report(
+        "completion",
+        &format!("complete 'c' ({} hits)", all.len()),
+        took,
+    );
+    let (some, took) = time(|| completer.complete("cmd099", 6));
+    // The next Tab would read PATH again
+    unsafe {
+        match &saved_path {
+            Some(path) => env::set_var("PATH", path),
+            None => env::remove_var("PATH"),
+        }
+    }
+    report(
+        "completion",
+        &format!("complete 'cmd099' ({} hits)", some.len()),
+        took,
+    );
+    Ok(())
+}
+
+// Setting up the completer, the first prompt render in a fresh
+// PromptSystem, then the steady-state average
+fn bench_startup() -> io::Result<()> {
+    let (_, setup) = time(|| MyCompleter::new(CompletionConfig::default(), crate::jobs::table()));
+    report("startup", "completer setup", setup);
+
+    let (_, cold) = time(|| {
+        PromptSystem::new(PromptConfig::default())
+            .render_prompt_left()
+            .into_owned()
+    });
+    report("startup", "cold prompt render", cold);
+
+    let prompt = PromptSystem::new(PromptConfig::default());
+    let (_, warm) = time(|| {
+        for _ in 0..PROMPT_RENDERS {
+            prompt.render_prompt_left();
+        }
+    });
+    report(
+        "startup",
+        &format!("prompt render (avg of {PROMPT_RENDERS})"),
+        warm / PROMPT_RENDERS,
+    );
+    Ok(())
+}
+
+/// `24! bench [globs|completion|startup]`, all of them when none is given
+pub fn run(args: &[&str]) -> io::Result<()> {
+    let all = ["globs", "completion", "startup"];
+    let selected = if args.is_empty() { &all[..] } else { args };
+
+    for name in selected {
+        match *name {
+            "globs" => bench_globs()?,
+            "completion" => bench_completion()?,
+            "startup" => bench_startup()?,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("24! bench: unknown benchmark '{other}' (globs, completion, startup)"),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,13 +1,333 @@
-use libc::{dup2, execvp, fork, waitpid};
+use libc::{dup2, execvp, fork};
 use std::{
     collections::HashMap,
     env,
     ffi::CString,
-    io, ptr,
-    sync::{Arc, Mutex, OnceLock},
+    fs,
+    io::{self, Read, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use crate::utils::expand_tilde;
+use crate::{
+    hash::{self, handle_hash},
+    jobs::{self, handle_bg, handle_disown, handle_fg, handle_jobs},
+    shell::{self, set_last_status},
+    utils::{self, expand_tilde},
+};
+
+/// A command run inside the shell. `shell::run` dispatches through this,
+/// `help` prints it and the completer offers the names, so they agree.
+pub struct Builtin {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub help: &'static str,
+    pub run: fn(&[&str]) -> io::Result<()>,
+    // The handler records the exit status itself instead of 0/1 from its result
+    pub sets_status: bool,
+}
+
+pub static BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: ".",
+        usage: ". file [args...]",
+        summary: "Run a file in the current shell, same as source",
+        help: "See `help source`.",
+        run: handle_source,
+        sets_status: false,
+    },
+    Builtin {
+        name: "24!",
+        usage: "24! [vim_keys | keys | theme [name] | stats [N] | completions ... | bench ...]",
+        summary: "shesh's own settings and tools",
+        help: "Without arguments, lists the 24! commands.
+  vim_keys   Toggle between Emacs and Vi keybindings
+  keys       List the active edit mode and the keys shesh binds
+  theme      Show the color theme, or switch to dark, light or one defined
+             in the config file; the choice is saved there
+  stats [--since 7d] [N]  The most used commands in the history, only
+                          those entered in the last 7 days with --since, and
+                          which commands failed in this session
+  completions list               Cached subcommands per command, with their age
+  completions refresh [cmd]      Run `cmd --help` again, for every cached
+                                 command when none is given
+  completions rescan             Read every PATH directory again at the next
+                                 Tab; changed ones are read by themselves
+  completions disable|enable cmd Stop or resume reading `cmd --help`, saved
+                                 to the config
+  history dedupe          Remove repeated entries, keeping the latest of each
+  history prune pattern   Remove entries matching a * and ? pattern, e.g.
+                          24! history prune 'export TOKEN=*'
+  history prune --older-than 90d
+                          Remove entries entered more than 90 days ago
+  history size            Entry count and size of the history file
+  prompt [style] [--save] Switch between the path, full, minimal and
+                          two-line prompts; --save writes the choice to the
+                          config
+  prompt preview format   Render a custom prompt once, e.g.
+                          24! prompt preview 'at $HOSTNAME> '
+  doctor                  Check that history and cache directories are
+                          writable, the config parses, PATH and TERM look
+                          right and no children are left as zombies
+  reload                  Read the config file again and apply its aliases,
+                          [env] variables, themes, hints, suggestions,
+                          completion settings and [history] ignore
+  env [allow|deny] [dir]  Show the .shesh-env that applies here (the
+                          nearest one above the directory), allow it to be
+                          loaded on cd, or take that back. Editing the file
+                          needs a new allow
+  dirs top [N]            The directories cd has visited, ranked by how
+                          often and how recently; `cd word` goes to the
+                          best one matching when there is no ./word
+  bench      Time glob expansion, completion loading and prompt rendering;
+             give one or more of globs, completion, startup to pick some",
+        run: handle_24_command,
+        sets_status: false,
+    },
+    Builtin {
+        name: "alias",
+        usage: "alias [name=value]",
+        summary: "Define an alias, or list them all",
+        help: "Replaces `name` with `value` when it is the first word of a line.
+`alias name value` works too. Without arguments, prints every alias.
+
+Example:
+  alias ll='ls -la'",
+        run: |args| handle_alias(&args.join(" ")),
+        sets_status: false,
+    },
+    Builtin {
+        name: "bg",
+        usage: "bg [%n]",
+        summary: "Resume a stopped job in the background",
+        help: "Continues job n (the newest stopped job by default) without giving
+it the terminal. Job specs: %n, n, %+ or %% for the current job, %- for the
+previous one.",
+        run: handle_bg,
+        sets_status: false,
+    },
+    Builtin {
+        name: "cd",
+        usage: "cd [dir]",
+        summary: "Change directory",
+        help: "Changes the working directory to dir, or to $HOME without one.
+A leading ~ is expanded.
+
+Example:
+  cd ~/projects",
+        run: cd,
+        sets_status: false,
+    },
+    Builtin {
+        name: "disown",
+        usage: "disown [-h] [%n...]",
+        summary: "Remove a job from the job table so it outlives the shell",
+        help: "Forgets the given jobs (the current job by default): they aren't
+listed, waited on or sent SIGHUP when the shell exits. A stopped job is
+continued first so it doesn't get hung up by the kernel. With -h the jobs
+stay in the table and are only spared the SIGHUP.",
+        run: handle_disown,
+        sets_status: false,
+    },
+    Builtin {
+        name: "exit",
+        usage: "exit [n]",
+        summary: "Exit the shell",
+        help: "Exits with status n, wrapped to 0-255, or with the status of the last
+command. With jobs still running, the first exit lists them and stays; a
+second exit goes through and hangs them up unless `hup_on_exit = false`.",
+        run: handle_exit,
+        sets_status: false,
+    },
+    Builtin {
+        name: "export",
+        usage: "export [name=value...]",
+        summary: "Set environment variables, or list them all",
+        help: "Sets each name=value in the environment of the shell and of the
+commands it runs. Without arguments, prints every variable.
+
+Example:
+  export EDITOR=nvim",
+        run: |args| handle_export_cmd(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>()),
+        sets_status: false,
+    },
+    Builtin {
+        name: "fc",
+        usage: "fc [first [last]] | -l [N]",
+        summary: "Edit earlier commands in $EDITOR and run them",
+        help: "Opens the previous command, entry first, or entries first to last as a
+script in $FCEDIT or $EDITOR (vi when neither is set). When the editor
+exits successfully the saved lines run one by one and go into history.
+  -l [N]   List the last N entries (16 by default) instead",
+        run: handle_fc,
+        sets_status: true,
+    },
+    Builtin {
+        name: "fg",
+        usage: "fg [%n]",
+        summary: "Bring a job to the foreground",
+        help: "Gives job n (the current job by default) the terminal, continues it
+if it was stopped and waits for it. Ctrl-Z stops it again.",
+        run: handle_fg,
+        sets_status: false,
+    },
+    Builtin {
+        name: "hash",
+        usage: "hash [-r] [name...]",
+        summary: "List cached command paths, clear them, or look names up",
+        help: "Without arguments, lists where each command run so far was found
+on PATH and how often it was used. `hash -r` empties the table, which is
+needed after installing a binary that shadows a cached one; exporting PATH
+does it automatically. `hash name` looks name up again.",
+        run: handle_hash,
+        sets_status: false,
+    },
+    Builtin {
+        name: "help",
+        usage: "help [builtin]",
+        summary: "List builtins, or show help for one",
+        help: "Without arguments, lists every builtin with a one-line summary.
+
+Example:
+  help cd",
+        run: handle_help,
+        sets_status: false,
+    },
+    Builtin {
+        name: "history",
+        usage: "history [-t] [N] | -c | -d N | --compact",
+        summary: "List, clear or edit the command history",
+        help: "Lists the history with entry numbers, or just the last N entries.
+  -t          Show when each entry was entered
+  -c          Clear the history, on disk too
+  -d N        Delete entry N
+  --compact   Drop blank lines and repeated entries from the history file",
+        run: handle_history,
+        sets_status: false,
+    },
+    Builtin {
+        name: "jobs",
+        usage: "jobs",
+        summary: "List background and stopped jobs",
+        help: "Prints each job with its number, state and command line. The current
+job is marked with +. Finished jobs are listed once, then forgotten.",
+        run: |_| handle_jobs(),
+        sets_status: false,
+    },
+    Builtin {
+        name: "popd",
+        usage: "popd",
+        summary: "Return to the directory pushd left",
+        help: "Takes the top directory off the stack pushd keeps and changes to it,
+then prints the stack.",
+        run: popd,
+        sets_status: false,
+    },
+    Builtin {
+        name: "pushd",
+        usage: "pushd [dir]",
+        summary: "Change directory, remembering the current one",
+        help: "Changes to dir like cd and puts the directory it left on a stack that
+popd goes back through. Without dir it swaps the current directory with
+the top of the stack. Prints the stack, the current directory first.
+
+Example:
+  pushd /etc; popd",
+        run: pushd,
+        sets_status: false,
+    },
+    Builtin {
+        name: "read",
+        usage: "read [-r] [-p prompt] [name...]",
+        summary: "Read a line from stdin into variables",
+        help: "Splits the line on $IFS: each name gets one field, the last name the
+rest of the line. Without names the line goes to REPLY. The status is 1 at
+end of input.
+  -r          Keep backslashes instead of treating them as escapes
+  -p prompt   Print prompt on stderr first
+
+Example:
+  read -p 'name: ' first last",
+        run: handle_read,
+        sets_status: true,
+    },
+    Builtin {
+        name: "set",
+        usage: "set [-eux | +eux] | [-o | +o] [option]",
+        summary: "Turn shell options on or off",
+        help: "-o option turns it on, +o option off, for this session; the config
+file sets the history defaults. Without an option, -o lists them and +o
+prints the set commands that restore them. -e, -u and -x are short for
+the first three, + turns them off.
+  errexit           Stop a script or startup file at a failing command,
+                    unless it's the left side of && or ||
+  nounset           Make an unset variable an error instead of nothing
+  xtrace            Print each command after expansion, after `+ `
+  histignoredups    Skip a line that repeats the one before it
+  histignorespace   Keep lines starting with a space out of history
+  histerasedups     Drop earlier copies of a line when it's entered again
+  histexpand        Expand !!, !N, !prefix, !$ and !* in typed lines",
+        run: handle_set,
+        sets_status: false,
+    },
+    Builtin {
+        name: "shift",
+        usage: "shift [n]",
+        summary: "Drop the first n positional parameters",
+        help: "$n+1 becomes $1 and $# goes down by n, 1 when omitted, for the rest of
+the script or sourced file; the caller's parameters come back when it
+returns. Fails, changing nothing, when there are fewer than n.",
+        run: handle_shift,
+        sets_status: false,
+    },
+    Builtin {
+        name: "source",
+        usage: "source file [args...]",
+        summary: "Run a file in the current shell",
+        help: "Runs each line of file as if typed, so aliases, exports and cd stick.
+Extra arguments are $1..$n while it runs. A failing line is reported with
+its line number and the rest still runs.",
+        run: handle_source,
+        sets_status: false,
+    },
+    Builtin {
+        name: "time",
+        usage: "time command",
+        summary: "Run a command, then print real/user/sys times",
+        help: "Times the whole pipeline that follows and prints the times on stderr.
+The status is the command's own.
+
+Example:
+  time cargo build",
+        // A keyword: shell::run strips it before dispatch, so this only sees `time` alone
+        run: |_| Ok(()),
+        sets_status: false,
+    },
+    Builtin {
+        name: "which",
+        usage: "which [-a] name...",
+        summary: "Show the alias, builtin or executable a name runs",
+        help: "Checks aliases first, then builtins, then PATH, and prints the first
+match. -a prints every match.",
+        run: handle_which,
+        sets_status: false,
+    },
+];
+
+pub fn find_builtin(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|b| b.name == name)
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    find_builtin(name).is_some()
+}
 
 // Alias storage
 static ALIASES: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
@@ -17,8 +337,13 @@ pub static ENV_VARS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
 
 static VIM_MODE: OnceLock<Arc<Mutex<bool>>> = OnceLock::new();
 
-pub fn init_vim_mode() {
-    VIM_MODE.get_or_init(|| Arc::new(Mutex::new(false)));
+// How deep `source` calls are nested, to stop files that source themselves
+static SOURCE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+const MAX_SOURCE_DEPTH: usize = 64;
+
+// Starts in Vi mode when the config asks for it
+pub fn init_vim_mode(enabled: bool) {
+    VIM_MODE.get_or_init(|| Arc::new(Mutex::new(enabled)));
 }
 
 pub fn toggle_vim_mode() -> bool {
@@ -28,19 +353,95 @@ pub fn toggle_vim_mode() -> bool {
     *enabled
 }
 
-// pub fn get_vim_mode() -> bool {
-//     let mode = VIM_MODE.get_or_init(|| Arc::new(Mutex::new(false)));
-//     *mode.lock().unwrap()
-// }
+pub fn get_vim_mode() -> bool {
+    let mode = VIM_MODE.get_or_init(|| Arc::new(Mutex::new(false)));
+    *mode.lock().unwrap()
+}
+
+/// The `24!` commands: name, arguments and what it does. Listed by `24!`
+/// and offered by completion.
+pub const META_COMMANDS: &[(&str, &str, &str)] = &[
+    ("vim_keys", "", "Toggle Vim keybindings"),
+    ("keys", "", "List the active edit mode and keybindings"),
+    (
+        "theme",
+        " [show|dark|light|name]",
+        "Show or switch the color theme",
+    ),
+    (
+        "stats",
+        " [--since age] [N]",
+        "Most used commands and this session's failures",
+    ),
+    (
+        "completions",
+        " [list|refresh [cmd]|rescan|disable cmd|enable cmd]",
+        "Manage the completion cache",
+    ),
+    (
+        "history",
+        " [dedupe|prune pattern|prune --older-than age|size]",
+        "Clean up the history file",
+    ),
+    (
+        "prompt",
+        " [path|full|minimal|two-line] [--save] | preview format",
+        "Switch or try prompts",
+    ),
+    (
+        "doctor",
+        "",
+        "Check directories, config, PATH and terminal for problems",
+    ),
+    (
+        "reload",
+        "",
+        "Re-read the config file and apply aliases, variables and themes",
+    ),
+    (
+        "env",
+        " [allow|deny] [dir]",
+        "Load this directory's .shesh-env on cd, or stop",
+    ),
+    (
+        "dirs",
+        " top [N]",
+        "The directories `cd` can jump to, best first",
+    ),
+    (
+        "bench",
+        " [globs|completion|startup]",
+        "Time expansion, completion and prompt rendering",
+    ),
+    (
+        "reset-term",
+        "",
+        "Put back the terminal modes, colors and cursor a program broke",
+    ),
+];
 
 pub fn handle_24_command(args: &[&str]) -> io::Result<()> {
     if args.is_empty() {
         println!("24! commands:");
-        println!("  vim_keys - Toggle Vim keybindings");
+        for (name, arguments, summary) in META_COMMANDS {
+            println!("  {name}{arguments} - {summary}");
+        }
         return Ok(());
     }
 
     match args[0] {
+        "bench" => crate::bench::run(&args[1..]),
+        "keys" => crate::keys::show(get_vim_mode()),
+        "theme" => crate::theme::handle_theme(&args[1..]),
+        "stats" => crate::stats::run(&args[1..]),
+        "completions" => crate::completions::handle_completions(&args[1..]),
+        "history" => crate::history::handle_maintenance(&args[1..]),
+        "prompt" => crate::prompt::handle_prompt(&args[1..]),
+        "doctor" => crate::doctor::run(),
+        "reload" => crate::config::reload(),
+        "reset-term" => crate::term::reset(),
+        "env" => crate::direnv::handle_env(&args[1..]),
+        "dirs" => crate::dirs::handle_dirs(&args[1..]),
         "vim_keys" => {
             let enabled = toggle_vim_mode();
             println!("Vim keys {}", if enabled { "enabled" } else { "disabled" });
@@ -73,7 +474,11 @@ pub fn handle_alias(input: &str) -> io::Result<()> {
 
     match parts.as_slice() {
         [name, value] => {
-            aliases.insert(name.trim().to_string(), value.trim().to_string());
+            let value = value.trim();
+            if crate::utils::resolves_to_self(value) {
+                crate::output::warn(&format!("alias '{}' launches shesh itself", name.trim()));
+            }
+            aliases.insert(name.trim().to_string(), value.to_string());
             Ok(())
         }
         _ => Err(io::Error::new(
@@ -83,6 +488,38 @@ pub fn handle_alias(input: &str) -> io::Result<()> {
     }
 }
 
+// Aliases from the config's [aliases] section
+pub fn set_alias(name: &str, value: &str) {
+    get_aliases()
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), value.to_string());
+}
+
+// Names and expansions for completion, read each time so new aliases show
+// up at once
+pub fn alias_list() -> Vec<(String, String)> {
+    get_aliases()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, cmd)| (name.clone(), cmd.clone()))
+        .collect()
+}
+
+// The environment plus variables set in the shell, sorted, for completion
+pub fn var_names() -> Vec<String> {
+    let mut names: Vec<String> = env::vars_os()
+        .filter_map(|(k, _)| k.into_string().ok())
+        .collect();
+    if let Some(env_vars) = ENV_VARS.get() {
+        names.extend(env_vars.lock().unwrap().keys().cloned());
+    }
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
 pub fn expand_aliases(input: &str) -> String {
     let Some(first_word) = input.split_whitespace().next() else {
         return input.to_string();
@@ -99,28 +536,615 @@ pub fn expand_aliases(input: &str) -> String {
         .unwrap_or_else(|| input.to_string())
 }
 
+/// An alias, a builtin or a program that can be run. A command that exits
+/// with 127 itself isn't mistaken for a typo this way.
+pub fn is_known_command(name: &str) -> bool {
+    if name.contains('/') {
+        return utils::is_executable(&expand_tilde(name));
+    }
+    get_aliases().lock().unwrap().contains_key(name)
+        || is_builtin(name)
+        || utils::find_in_path(name).is_some()
+}
+
+// which [-a] NAME...: aliases first, then builtins, then executables on PATH
+pub fn handle_which(args: &[&str]) -> io::Result<()> {
+    let all = args.contains(&"-a");
+    let names: Vec<&str> = args.iter().copied().filter(|a| *a != "-a").collect();
+    if names.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: which [-a] name...",
+        ));
+    }
+
+    let mut missing = Vec::new();
+    for name in names {
+        let mut found = Vec::new();
+        if let Some(expansion) = get_aliases().lock().unwrap().get(name) {
+            found.push(format!("{name}: aliased to {expansion}"));
+        }
+        if is_builtin(name) {
+            found.push(format!("{name}: shell builtin"));
+        }
+        found.extend(
+            utils::find_all_in_path(name)
+                .iter()
+                .map(|path| path.display().to_string()),
+        );
+
+        if found.is_empty() {
+            missing.push(name);
+        }
+        for line in found.iter().take(if all { usize::MAX } else { 1 }) {
+            println!("{line}");
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "which: no {} in aliases, builtins or PATH",
+                missing.join(", ")
+            ),
+        ))
+    }
+}
+
+// shift [n]
+pub fn handle_shift(args: &[&str]) -> io::Result<()> {
+    let n = match args {
+        [] => 1,
+        [n] => n.parse::<usize>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("shift: {n}: numeric argument required"),
+            )
+        })?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Usage: shift [n]",
+            ));
+        }
+    };
+    if shell::shift_positional(n) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("shift: {n}: shift count out of range"),
+        ))
+    }
+}
+
+// exit [N]: N wraps to 0-255 like other shells, the last status when omitted.
+// Returns only when exiting was refused.
+pub fn handle_exit(args: &[&str]) -> io::Result<()> {
+    let code = match args {
+        [] => shell::last_status(),
+        [n] => n
+            .parse::<i64>()
+            .map(|n| n.rem_euclid(256) as i32)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("exit: {n}: numeric argument required"),
+                )
+            })?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "exit: too many arguments",
+            ));
+        }
+    };
+
+    if jobs::confirm_exit() {
+        shell::shutdown(code);
+    }
+    Ok(())
+}
+
 pub fn cd(args: &[&str]) -> io::Result<()> {
     let dir = args.first().unwrap_or(&"~");
     let path = expand_tilde(dir);
 
-    env::set_current_dir(&path).map_err(|e| {
-        let msg = format!("cd: '{}': {e}", path.display());
-        io::Error::other(msg)
-    })
+    if let Err(e) = env::set_current_dir(&path) {
+        // `cd foo` with no foo here goes to the best ranked visited directory
+        // matching it; a path that says where it starts is taken as it is
+        let target = (e.kind() == io::ErrorKind::NotFound && !dir.starts_with(['/', '.', '~']))
+            .then(|| crate::dirs::jump_target(args))
+            .flatten();
+        let Some(target) = target else {
+            return Err(io::Error::other(format!("cd: '{}': {e}", path.display())));
+        };
+        env::set_current_dir(&target)
+            .map_err(|e| io::Error::other(format!("cd: '{}': {e}", target.display())))?;
+        println!("{}", target.display());
+    }
+    entered_dir();
+    Ok(())
 }
 
-pub fn help() -> String {
-    "
-    Available builtins:
-    - cd [dir] : Change directory
-    - exit     : Exit the shell
-    - help     : Show this help"
-        .to_string()
+// What follows the working directory: the visit count and the env file
+fn entered_dir() {
+    crate::dirs::record_visit();
+    crate::direnv::changed_dir();
+}
+
+// The directories pushd left, the most recent last
+static DIR_STACK: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+// The current directory, then the stack from its top
+fn print_dir_stack() -> io::Result<()> {
+    let here = env::current_dir()?;
+    let stack = DIR_STACK.lock().unwrap();
+    let dirs: Vec<String> = std::iter::once(&here)
+        .chain(stack.iter().rev())
+        .map(|dir| dir.display().to_string())
+        .collect();
+    println!("{}", dirs.join(" "));
+    Ok(())
+}
+
+// pushd [dir]: cd to dir and stack the old directory; alone, swap with the top
+pub fn pushd(args: &[&str]) -> io::Result<()> {
+    let here = env::current_dir()?;
+    match args {
+        [] => {
+            let mut stack = DIR_STACK.lock().unwrap();
+            let top = stack
+                .last()
+                .cloned()
+                .ok_or_else(|| io::Error::other("pushd: no other directory"))?;
+            env::set_current_dir(&top)
+                .map_err(|e| io::Error::other(format!("pushd: '{}': {e}", top.display())))?;
+            *stack.last_mut().unwrap() = here;
+            drop(stack);
+            entered_dir();
+        }
+        [dir] => {
+            cd(&[dir])?;
+            DIR_STACK.lock().unwrap().push(here);
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Usage: pushd [dir]",
+            ));
+        }
+    }
+    print_dir_stack()
+}
+
+// popd: back to the directory on top of the stack
+pub fn popd(args: &[&str]) -> io::Result<()> {
+    if !args.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Usage: popd"));
+    }
+    let mut stack = DIR_STACK.lock().unwrap();
+    let top = stack
+        .last()
+        .ok_or_else(|| io::Error::other("popd: directory stack empty"))?;
+    env::set_current_dir(top)
+        .map_err(|e| io::Error::other(format!("popd: '{}': {e}", top.display())))?;
+    stack.pop();
+    drop(stack);
+    entered_dir();
+    print_dir_stack()
+}
+
+// set [-eux | +eux] | [-o | +o] [NAME]: shell options and history settings
+pub fn handle_set(args: &[&str]) -> io::Result<()> {
+    let settings = || {
+        shell::settings()
+            .into_iter()
+            .chain(crate::history::settings())
+    };
+    match args {
+        [] | ["-o"] => {
+            for (name, on) in settings() {
+                println!("{name:<18}{}", if on { "on" } else { "off" });
+            }
+            Ok(())
+        }
+        ["+o"] => {
+            for (name, on) in settings() {
+                println!("set {}o {name}", if on { '-' } else { '+' });
+            }
+            Ok(())
+        }
+        [flag @ ("-o" | "+o"), name] => {
+            let on = *flag == "-o";
+            if shell::set_option(name, on) || crate::history::set(name, on) {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("set: {name}: invalid option name"),
+                ))
+            }
+        }
+        // -e, +x, -eux: one letter per option
+        [flags] if flags.len() > 1 && flags.starts_with(['-', '+']) => {
+            let on = flags.starts_with('-');
+            for letter in flags[1..].chars() {
+                if !shell::set_option(&letter.to_string(), on) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("set: -{letter}: invalid option"),
+                    ));
+                }
+            }
+            Ok(())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: set [-eux | +eux] | [-o | +o] [option]",
+        )),
+    }
+}
+
+// history [N] | -c | -d N | --compact; numbers are the ones `!N` refers to
+pub fn handle_history(args: &[&str]) -> io::Result<()> {
+    let number = |arg: &str| {
+        arg.parse::<usize>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("history: {arg}: numeric argument required"),
+            )
+        })
+    };
+
+    match args {
+        [] => print_history(None),
+        ["-c"] => crate::history::clear(),
+        ["-d"] => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "history: -d: option requires an argument",
+        )),
+        ["-d", n] => crate::history::delete(number(n)?),
+        ["--compact"] => {
+            let (before, after) = crate::history::compact_shared()?;
+            println!("history: compacted {before} lines to {after}");
+            Ok(())
+        }
+        [n] if !n.starts_with('-') => print_history(Some(number(n)?)),
+        ["-t"] => print_timed_history(None),
+        ["-t", n] => print_timed_history(Some(number(n)?)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: history [-t] [N] | -c | -d N | --compact",
+        )),
+    }
+}
+
+fn print_history(limit: Option<usize>) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    for (n, entry) in crate::history::recent(limit) {
+        writeln!(out, "{n:>5}  {entry}")?;
+    }
+    Ok(())
+}
+
+pub fn handle_fc(args: &[&str]) -> io::Result<()> {
+    let result = fc(args);
+    if result.is_err() {
+        set_last_status(1);
+    }
+    result
+}
+
+fn fc(args: &[&str]) -> io::Result<()> {
+    let number = |arg: &str| {
+        arg.parse::<usize>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fc: {arg}: numeric argument required"),
+            )
+        })
+    };
+    let (first, last) = match args {
+        ["-l"] => return list_before_fc(16),
+        ["-l", n] => return list_before_fc(number(n)?),
+        [] => (None, None),
+        [first] => (Some(number(first)?), None),
+        [first, last] => (Some(number(first)?), Some(number(last)?)),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Usage: fc [first [last]] | -l [N]",
+            ));
+        }
+    };
+    let limit = if first.is_some() { None } else { Some(2) };
+    let lines = fc_selection(before_fc(crate::history::recent(limit)), first, last)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "fc: no such history entry"))?;
+    edit_and_run(&lines.join("\n"))
+}
+
+// The history without the `fc` line that is running, the newest entry
+// unless it was kept out
+fn before_fc(mut entries: Vec<(usize, String)>) -> Vec<(usize, String)> {
+    if entries
+        .last()
+        .is_some_and(|(_, entry)| entry.split_whitespace().next() == Some("fc"))
+    {
+        entries.pop();
+    }
+    entries
+}
+
+fn list_before_fc(limit: usize) -> io::Result<()> {
+    let entries = before_fc(crate::history::recent(Some(limit + 1)));
+    let skip = entries.len().saturating_sub(limit);
+    let mut out = io::stdout().lock();
+    for (n, entry) in &entries[skip..] {
+        writeln!(out, "{n:>5}  {entry}")?;
+    }
+    Ok(())
+}
+
+// The newest entry, entry `first`, or `first` to `last` in either order
+fn fc_selection(
+    entries: Vec<(usize, String)>,
+    first: Option<usize>,
+    last: Option<usize>,
+) -> Option<Vec<String>> {
+    let Some(first) = first else {
+        return entries.last().map(|(_, entry)| vec![entry.clone()]);
+    };
+    let last = last.unwrap_or(first);
+    let range = first.min(last)..=first.max(last);
+    let picked: Vec<String> = entries
+        .into_iter()
+        .filter(|(n, _)| range.contains(n))
+        .map(|(_, entry)| entry)
+        .collect();
+    (!picked.is_empty()).then_some(picked)
+}
+
+// A new directory only this user can enter: nobody else can put a symlink
+// where the file will be or swap what the editor saved
+fn private_temp_dir(prefix: &str) -> io::Result<PathBuf> {
+    let mut attempts = 0;
+    loop {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let dir = env::temp_dir().join(format!("{prefix}-{}-{nanos}", std::process::id()));
+        match fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < 10 => attempts += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Writes `text` to a temp file, runs the editor on it with the terminal,
+// then runs what was saved. The editor may leave the terminal in its own
+// modes when it dies, so they are put back first.
+fn edit_and_run(text: &str) -> io::Result<()> {
+    let dir = private_temp_dir("shesh-fc")?;
+    let path = dir.join("command.sh");
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(format!("{text}\n").as_bytes()));
+    if let Err(e) = written {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(e);
+    }
+    let editor = ["FCEDIT", "EDITOR"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    let tty = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } == 0;
+    let ran = shell::exec(&format!("{editor} '{}'", path.display()));
+    if tty {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &saved);
+        }
+    }
+    // Editors often save to a new file and rename it over the old one, so
+    // it's read by name, which only this user can change
+    let edited = fs::read_to_string(&path);
+    let _ = fs::remove_dir_all(&dir);
+    ran?;
+    let status = shell::last_status();
+    if status != 0 {
+        return Err(io::Error::other(format!(
+            "fc: {editor} exited with status {status}, nothing was run"
+        )));
+    }
+
+    // Each line is shown, recorded and run as if typed
+    for line in edited?.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        println!("{line}");
+        if let Err(e) = crate::history::add(line) {
+            crate::output::fail(&format!("Failed to write to history file: {e}"));
+        }
+        if let Err(e) = shell::exec(line) {
+            crate::output::error(&e.to_string());
+        }
+    }
+    Ok(())
+}
+
+// Entries from before times were recorded get a blank column
+fn print_timed_history(limit: Option<usize>) -> io::Result<()> {
+    let entries = crate::history::recent_timed(limit)?;
+    let mut out = io::stdout().lock();
+    for (n, time, entry) in entries {
+        let time = time.map(crate::history::format_time).unwrap_or_default();
+        writeln!(out, "{n:>5}  {time:<16}  {entry}")?;
+    }
+    Ok(())
+}
+
+// Lines are joined until they make a whole command (`parse::is_complete`):
+// past a trailing `\`, an open quote or `$(`. Each command comes with the
+// number of the line it starts on. Blank lines and comments, a `#!` line
+// among them, are left out.
+fn logical_lines<'a>(lines: impl IntoIterator<Item = &'a str>) -> Vec<(usize, String)> {
+    let mut commands = Vec::new();
+    let mut pending: Option<(usize, String)> = None;
+    for (n, line) in lines.into_iter().enumerate() {
+        let (start, mut joined) = match pending.take() {
+            Some((start, mut joined)) => {
+                joined.push('\n');
+                (start, joined)
+            }
+            None if line.trim().is_empty() || line.trim_start().starts_with('#') => continue,
+            None => (n + 1, String::new()),
+        };
+        joined.push_str(line);
+        if crate::parse::is_complete(&joined) {
+            commands.push((start, joined.trim().to_string()));
+        } else {
+            pending = Some((start, joined));
+        }
+    }
+    // Still open at the end of the file: run what there is, the parser
+    // reports what's missing
+    if let Some((start, joined)) = pending {
+        let joined = joined.strip_suffix('\\').unwrap_or(&joined);
+        commands.push((start, joined.trim().to_string()));
+    }
+    commands
+}
+
+/// Runs each line in the current shell so aliases, exports and cd stick.
+/// Blank lines and comments are skipped, a trailing `\` joins a line to the
+/// next; a failing line is reported with its line number and the rest still
+/// runs.
+pub fn source_lines<'a>(lines: impl IntoIterator<Item = &'a str>, origin: &str) {
+    for (n, line) in logical_lines(lines) {
+        if let Err(e) = shell::exec(&line) {
+            eprintln!("{origin}: line {n}: {e}");
+        }
+        // `set -e` ends the file at the first failing line
+        if shell::errexit_failed() {
+            return;
+        }
+    }
+}
+
+/// Runs a file line by line in the current shell, like `source` does.
+/// Failing lines are reported with the file name and line number.
+pub fn source_file(path: &Path) -> io::Result<()> {
+    let name = path.display().to_string();
+    let content = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("source: {name}: {e}")))?;
+
+    if SOURCE_DEPTH.fetch_add(1, Ordering::Relaxed) >= MAX_SOURCE_DEPTH {
+        SOURCE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+        return Err(io::Error::other(format!(
+            "source: {name}: nested more than {MAX_SOURCE_DEPTH} levels deep"
+        )));
+    }
+    source_lines(content.lines(), &name);
+    SOURCE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// `shesh script [args...]`: runs the file with its path as $0 and `args`
+/// as $1..$n, and returns the status of its last command.
+pub fn run_script(path: &Path, args: Vec<String>) -> io::Result<i32> {
+    let name = path.display().to_string();
+    let content =
+        fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{name}: {e}")))?;
+    shell::set_script_name(name.clone());
+    shell::with_positional(args, || source_lines(content.lines(), &name));
+    Ok(shell::last_status())
+}
+
+/// `shesh -c line [name [args...]]`: runs the line with `name` as $0 and
+/// `args` as $1..$n, and returns its status.
+pub fn run_line(line: &str, name: Option<String>, args: Vec<String>) -> i32 {
+    if let Some(name) = name {
+        shell::set_script_name(name);
+    }
+    shell::with_positional(args, || source_lines(line.lines(), "shesh -c"));
+    shell::last_status()
+}
+
+/// Commands piped into a shesh that isn't at a terminal, read to the end
+/// and run like a script; returns the status of the last one.
+pub fn run_stdin() -> io::Result<i32> {
+    let mut content = String::new();
+    io::stdin().read_to_string(&mut content)?;
+    source_lines(content.lines(), "stdin");
+    Ok(shell::last_status())
+}
+
+// source FILE [ARGS...], extra arguments are $1..$n while the file runs
+pub fn handle_source(args: &[&str]) -> io::Result<()> {
+    let Some(file) = args.first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "source: filename argument required",
+        ));
+    };
+    let path = expand_tilde(file);
+    if args.len() > 1 {
+        shell::with_positional(args[1..].iter().map(|s| s.to_string()).collect(), || {
+            source_file(&path)
+        })
+    } else {
+        source_file(&path)
+    }
+}
+
+// help [builtin]: one line per builtin, or the full text for one
+pub fn handle_help(args: &[&str]) -> io::Result<()> {
+    match args {
+        [] => {
+            println!("Available builtins (help <name> for details):");
+            let width = BUILTINS.iter().map(|b| b.name.len()).max().unwrap_or(0);
+            for builtin in BUILTINS {
+                println!("  {:<width$}  {}", builtin.name, builtin.summary);
+            }
+            Ok(())
+        }
+        [name] => {
+            let builtin = find_builtin(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("help: no help topics match '{name}'"),
+                )
+            })?;
+            println!(
+                "usage: {}\n\n{}\n\n{}",
+                builtin.usage, builtin.summary, builtin.help
+            );
+            Ok(())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: help [builtin]",
+        )),
+    }
 }
 
 pub fn execute_external(command: &str, args: &[&str]) -> io::Result<()> {
-    // Prepare command and args as C strings
-    let cmd_cstr = CString::new(command)?;
+    // Anything failing before the command runs counts as a plain failure
+    set_last_status(1);
+
+    // Prepare command and args as C strings, the program through the hash table
+    let cmd_cstr = hash::exec_path(command)?;
     let all_args = std::iter::once(command).chain(args.iter().copied());
 
     // Convert all arguments to CStrings
@@ -145,9 +1169,8 @@ pub fn execute_external(command: &str, args: &[&str]) -> io::Result<()> {
     unsafe {
         match fork() {
             0 => {
-                // Child process
-                libc::signal(libc::SIGINT, libc::SIG_DFL);
-                libc::signal(libc::SIGQUIT, libc::SIG_DFL);
+                // Child process: own process group, default signal handling
+                jobs::setup_child(0);
 
                 // Redirect stderr to stdout to capture command's own error messages
                 dup2(libc::STDOUT_FILENO, libc::STDERR_FILENO);
@@ -159,8 +1182,16 @@ pub fn execute_external(command: &str, args: &[&str]) -> io::Result<()> {
             -1 => Err(io::Error::last_os_error()), // Fork failed
             pid => {
                 // Parent process
-                let mut status = 0;
-                waitpid(pid, &mut status, 0);
+                jobs::set_child_group(pid, pid);
+                let line = std::iter::once(command)
+                    .chain(args.iter().copied())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let waited = jobs::wait_foreground(pid, &[pid], &line, None);
+                set_last_status(waited.exit_code());
+                let jobs::Waited::Finished(status) = waited else {
+                    return Ok(());
+                };
 
                 if libc::WIFEXITED(status) {
                     match libc::WEXITSTATUS(status) {
@@ -221,16 +1252,326 @@ pub fn handle_export_cmd(args: &[String]) -> io::Result<()> {
     } else {
         args.iter()
             .filter_map(|a| a.split_once('='))
-            .for_each(|(k, v)| {
-                ENV_VARS
-                    .get_or_init(|| Mutex::new(HashMap::new()))
-                    .lock()
-                    .unwrap()
-                    .insert(k.into(), v.into());
-                unsafe {
-                    env::set_var(k, v);
-                }
-            });
+            .for_each(|(k, v)| set_var(k, v));
+    }
+    Ok(())
+}
+
+// Variables live in the environment, so `$name` and child processes see them.
+// A new PATH makes every cached command location stale.
+pub fn set_var(name: &str, value: &str) {
+    if name == "PATH" {
+        hash::forget_all();
+    }
+    ENV_VARS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap()
+        .insert(name.into(), value.into());
+    unsafe {
+        env::set_var(name, value);
+    }
+}
+
+pub fn unset_var(name: &str) {
+    if name == "PATH" {
+        hash::forget_all();
+    }
+    if let Some(env_vars) = ENV_VARS.get() {
+        env_vars.lock().unwrap().remove(name);
+    }
+    unsafe {
+        env::remove_var(name);
+    }
+}
+
+// One line from stdin, read a byte at a time so nothing past the newline is
+// consumed. Without `raw`, a backslash escapes the next character (marked
+// true) and backslash-newline continues the line. None at EOF with no input.
+fn read_line_from_stdin(raw: bool) -> io::Result<Option<Vec<(char, bool)>>> {
+    let mut bytes = Vec::new();
+    let mut escaped = Vec::new();
+    let mut pending_escape = false;
+    let mut got_any = false;
+
+    loop {
+        let mut byte = 0u8;
+        let n = unsafe { libc::read(libc::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if n == 0 {
+            break;
+        }
+        got_any = true;
+        match byte {
+            b'\\' if !raw && !pending_escape => pending_escape = true,
+            b'\n' if pending_escape => pending_escape = false,
+            b'\n' => return Ok(Some(decode_marked(&bytes, &escaped))),
+            _ => {
+                bytes.push(byte);
+                escaped.push(pending_escape);
+                pending_escape = false;
+            }
+        }
+    }
+    Ok(got_any.then(|| decode_marked(&bytes, &escaped)))
+}
+
+fn decode_marked(bytes: &[u8], escaped: &[bool]) -> Vec<(char, bool)> {
+    // Escape marks are per byte, a char takes the mark of its first byte
+    let mut out = Vec::new();
+    let mut offset = 0;
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            out.push((c, escaped[offset]));
+            offset += c.len_utf8();
+        }
+        if !chunk.invalid().is_empty() {
+            out.push((char::REPLACEMENT_CHARACTER, escaped[offset]));
+            offset += chunk.invalid().len();
+        }
+    }
+    out
+}
+
+// IFS-style split into `count` fields: separators are unescaped IFS chars,
+// runs of them count once, and the last field keeps the rest of the line
+fn split_fields(line: &[(char, bool)], count: usize, ifs: &str) -> Vec<String> {
+    let is_sep = |&(c, escaped): &(char, bool)| !escaped && ifs.contains(c);
+    let mut fields = Vec::new();
+    let mut rest = line;
+
+    while fields.len() + 1 < count {
+        let start = rest.iter().position(|c| !is_sep(c)).unwrap_or(rest.len());
+        rest = &rest[start..];
+        if rest.is_empty() {
+            break;
+        }
+        let end = rest.iter().position(is_sep).unwrap_or(rest.len());
+        fields.push(rest[..end].iter().map(|&(c, _)| c).collect());
+        rest = &rest[end..];
+    }
+
+    let start = rest.iter().position(|c| !is_sep(c)).unwrap_or(rest.len());
+    let end = rest
+        .iter()
+        .rposition(|c| !is_sep(c))
+        .map_or(start, |i| i + 1);
+    fields.push(
+        rest[start..end.max(start)]
+            .iter()
+            .map(|&(c, _)| c)
+            .collect(),
+    );
+    fields.resize(count, String::new());
+    fields
+}
+
+// read [-r] [-p prompt] [name...]: reads a line from stdin into variables,
+// REPLY when no name is given. Records its own status: 1 at end of input.
+pub fn handle_read(args: &[&str]) -> io::Result<()> {
+    set_last_status(2);
+    let mut raw = false;
+    let mut prompt = None;
+    let mut names = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(&arg) = args.next() {
+        match arg {
+            "-r" => raw = true,
+            "-p" => {
+                prompt = Some(args.next().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "read: -p: option requires an argument",
+                    )
+                })?)
+            }
+            _ => names.push(arg),
+        }
+    }
+    if names.is_empty() {
+        names.push("REPLY");
+    }
+
+    if let Some(prompt) = prompt {
+        eprint!("{prompt}");
+        io::stderr().flush()?;
     }
+
+    let line = read_line_from_stdin(raw)?;
+    let ifs = env::var("IFS").unwrap_or_else(|_| " \t\n".to_string());
+    let fields = split_fields(line.as_deref().unwrap_or_default(), names.len(), &ifs);
+    for (name, value) in names.iter().zip(fields) {
+        set_var(name, &value);
+    }
+
+    set_last_status(if line.is_some() { 0 } else { 1 });
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_builtin_registry() {
+        let names: Vec<&str> = BUILTINS.iter().map(|b| b.name).collect();
+        let mut sorted = names.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(names, sorted, "keep BUILTINS sorted and unique");
+
+        for builtin in BUILTINS {
+            assert!(builtin.usage.starts_with(builtin.name), "{}", builtin.name);
+            assert!(!builtin.summary.is_empty() && !builtin.help.is_empty());
+        }
+        assert!(handle_help(&["cd"]).is_ok());
+        assert!(handle_help(&["no-such-builtin"]).is_err());
+
+        assert!(is_known_command("cd"));
+        assert!(is_known_command("sh"));
+        assert!(is_known_command("/bin/sh"));
+        assert!(!is_known_command("gti-no-such-command"));
+    }
+
+    #[test]
+    fn test_fc_selection() {
+        let entries = |lines: &[&str]| -> Vec<(usize, String)> {
+            lines
+                .iter()
+                .enumerate()
+                .map(|(i, line)| (i + 10, line.to_string()))
+                .collect()
+        };
+        let typed = before_fc(entries(&["ls", "make", "fc"]));
+        assert_eq!(typed.len(), 2);
+        assert_eq!(
+            fc_selection(typed.clone(), None, None),
+            Some(vec!["make".into()])
+        );
+        assert_eq!(
+            fc_selection(typed.clone(), Some(10), None),
+            Some(vec!["ls".into()])
+        );
+        assert_eq!(
+            fc_selection(typed.clone(), Some(11), Some(10)),
+            Some(vec!["ls".into(), "make".into()])
+        );
+        assert_eq!(fc_selection(typed, Some(3), None), None);
+        // A line kept out of history leaves the previous one last
+        assert_eq!(before_fc(entries(&["ls", "make"])).len(), 2);
+    }
+
+    #[test]
+    fn test_read_fields() {
+        let plain = |s: &str| s.chars().map(|c| (c, false)).collect::<Vec<_>>();
+        let ifs = " \t\n";
+
+        assert_eq!(
+            split_fields(&plain("  one  two three  "), 2, ifs),
+            vec!["one", "two three"]
+        );
+        assert_eq!(split_fields(&plain("one"), 3, ifs), vec!["one", "", ""]);
+        assert_eq!(split_fields(&plain("  a b  "), 1, ifs), vec!["a b"]);
+        assert_eq!(split_fields(&plain("a:b:c"), 2, ":"), vec!["a", "b:c"]);
+
+        // An escaped separator doesn't split
+        let escaped = decode_marked(b"a b c", &[false, true, false, false, false]);
+        assert_eq!(split_fields(&escaped, 2, ifs), vec!["a b", "c"]);
+    }
+
+    #[test]
+    fn test_source() {
+        let dir = TempDir::new("source");
+        let script = dir.join("script");
+        let out = dir.join("out");
+        fs::write(
+            &script,
+            format!(
+                "# comment\n\nalias sourced_test=true\necho $2 $# > {}\n",
+                out.display()
+            ),
+        )
+        .unwrap();
+
+        handle_source(&[script.to_str().unwrap(), "a", "b"]).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "b 2\n");
+
+        // "$@" keeps each argument one word; shift is undone on return
+        fs::write(
+            &script,
+            format!(
+                "printf '[%s]' \"$@\" $# > {out}\nshift\nprintf '[%s]' $@ $1 $10 >> {out}\n",
+                out = out.display()
+            ),
+        )
+        .unwrap();
+        let args = [script.to_str().unwrap(), "a b", "c", "", "d"];
+        handle_source(&args).unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "[a b][c][][d][4][c][][d][c][c0]"
+        );
+        assert_eq!(shell::positional_count(), 0);
+        assert!(get_aliases().lock().unwrap().contains_key("sourced_test"));
+
+        // "$*" is one word; a nested source gets its own parameters and
+        // the outer ones are back once it returns
+        let inner = dir.join("inner");
+        fs::write(
+            &inner,
+            format!("printf '[%s]' \"$*\" >> {}\n", out.display()),
+        )
+        .unwrap();
+        fs::write(
+            &script,
+            format!(
+                "printf '[%s]' \"$*\" $* > {out}\nsource {inner} x y\nprintf '[%s]' $# \"$1\" >> {out}\n",
+                out = out.display(),
+                inner = inner.display()
+            ),
+        )
+        .unwrap();
+        handle_source(&[script.to_str().unwrap(), "a b", "c"]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "[a b c][a b][c][x y][2][a b]"
+        );
+        assert_eq!(shell::positional_count(), 0);
+
+        let missing = handle_source(&["/nonexistent/shesh-source"]).unwrap_err();
+        assert!(
+            missing
+                .to_string()
+                .starts_with("source: /nonexistent/shesh-source:")
+        );
+    }
+
+    #[test]
+    fn test_logical_lines() {
+        let script = "#!/usr/bin/env shesh\necho one \\\n  two\n\n# skipped \\\n\
+                      echo 'a\\\\'\nprintf '%s\n\n# kept\n' \"$(echo \"x\"\n)\"\necho end \\";
+        assert_eq!(
+            logical_lines(script.lines()),
+            [
+                (2, "echo one \\\n  two".to_string()),
+                (6, "echo 'a\\\\'".to_string()),
+                (7, "printf '%s\n\n# kept\n' \"$(echo \"x\"\n)\"".to_string()),
+                (12, "echo end".to_string()),
+            ]
+        );
+        // Joined lines run as one command
+        let first = &logical_lines(script.lines())[0].1;
+        let crate::parse::ParsedCommand::Single(args) = crate::parse::parse_syntax(first) else {
+            panic!("a continued line is one command");
+        };
+        assert_eq!(args, ["echo", "one", "two"]);
+    }
+}
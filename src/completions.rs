@@ -1,55 +1,338 @@
-use crate::utils::expand_tilde;
+use crate::{
+    builtins::{BUILTINS, META_COMMANDS, alias_list, expand_aliases, var_names},
+    history::arguments,
+    hosts,
+    jobs::{Job, signal_name},
+    providers,
+    specs::{self, Spec, run_dynamic},
+    utils::{expand_tilde, find_in_path, is_executable},
+};
 use reedline::{Completer, Span, Suggestion};
 use std::{
     collections::{HashMap, HashSet},
     env,
-    fs::{self, OpenOptions},
-    io::{BufRead, BufReader, BufWriter, Write},
-    path::PathBuf,
-    process::Command,
+    ffi::OsString,
+    fs,
+    io::{self, Read},
+    os::unix::{fs::MetadataExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, SystemTime},
 };
 
+/// Which hidden files file completion offers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HiddenFiles {
+    Always,
+    Never,
+    Smart, // only once the word starts with a dot
+}
+
+/// What file completion offers for a command's arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArgFilter {
+    // Only directories, for cd and friends
+    Dirs,
+    // Files with one of these extensions first, then everything else
+    Extensions(Vec<String>),
+    // Executable files and directories, for a path in command position
+    Executables,
+}
+
+// The built-in table; `[completion.filters]` adds to or replaces entries
+fn default_filters() -> Vec<(String, ArgFilter)> {
+    let extensions =
+        |list: &[&str]| ArgFilter::Extensions(list.iter().map(|e| e.to_string()).collect());
+    vec![
+        ("cd".into(), ArgFilter::Dirs),
+        ("pushd".into(), ArgFilter::Dirs),
+        ("popd".into(), ArgFilter::Dirs),
+        ("rmdir".into(), ArgFilter::Dirs),
+        ("unzip".into(), extensions(&["zip"])),
+        (
+            "tar".into(),
+            extensions(&["tar", "gz", "tgz", "bz2", "xz", "zst"]),
+        ),
+        ("gunzip".into(), extensions(&["gz"])),
+    ]
+}
+
+/// The config's `[completion]` section.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletionConfig {
+    pub hidden: HiddenFiles,
+    pub ignore_case: bool,
+    // Subsequence matching, `dcs` finds `docs-site`; prefix matches still
+    // win when there are any
+    pub fuzzy: bool,
+    // Shown next to each entry in the menu, which then has one column
+    pub descriptions: bool,
+    // Run `cmd --help` to find subcommands; off means only cached ones are used
+    pub run_help: bool,
+    // Cached subcommands older than this are read from `--help` again, in
+    // the background while the old ones are still offered
+    pub cache_ttl: Option<Duration>,
+    pub column_width: usize,
+    pub disabled: Vec<String>,
+    pub cache_dir: Option<PathBuf>,
+    pub filters: Vec<(String, ArgFilter)>,
+    // Commands that run the command after them, so completion looks past
+    // them, their flags and `VAR=value` words
+    pub wrappers: Vec<String>,
+    // Commands from PATH and `./` paths in command position must have an
+    // execute bit; off for filesystems that don't keep one
+    pub executables_only: bool,
+    // File completion stops after this many matches
+    pub max_files: usize,
+    // A program printing `value<TAB>description` lines for the line in
+    // COMP_LINE with the cursor at COMP_POINT, like `carapace _carapace`;
+    // given up on after external_timeout
+    pub external: Option<String>,
+    // Its candidates replace the built-in ones whenever it has any, rather
+    // than coming first
+    pub external_override: bool,
+    pub external_timeout: Duration,
+}
+
+impl CompletionConfig {
+    /// Adds a rule for `command`, replacing the one it had.
+    pub fn set_filter(&mut self, command: &str, filter: ArgFilter) {
+        self.filters.retain(|(name, _)| name != command);
+        self.filters.push((command.to_string(), filter));
+    }
+
+    fn filter(&self, command: &str) -> Option<&ArgFilter> {
+        self.filters
+            .iter()
+            .find(|(name, _)| name == command)
+            .map(|(_, filter)| filter)
+    }
+}
+
+impl Default for CompletionConfig {
+    fn default() -> Self {
+        Self {
+            hidden: HiddenFiles::Smart,
+            ignore_case: false,
+            fuzzy: false,
+            descriptions: true,
+            run_help: true,
+            cache_ttl: Some(DEFAULT_CACHE_TTL),
+            column_width: 20,
+            disabled: NEVER_HELP.map(String::from).to_vec(),
+            cache_dir: None,
+            filters: default_filters(),
+            wrappers: ["sudo", "doas", "env", "nohup", "time", "nice", "xargs"]
+                .map(String::from)
+                .to_vec(),
+            executables_only: true,
+            max_files: 500,
+            external: None,
+            external_override: false,
+            external_timeout: Duration::from_millis(200),
+        }
+    }
+}
+
+// Commands whose `--help` is never run unless `[completion] disabled` says
+// otherwise: they're interactive, may wait on the network, or are ones
+// nobody should run by accident if `--help` isn't understood
+const NEVER_HELP: [&str; 16] = [
+    "ssh", "scp", "sftp", "telnet", "ftp", "su", "passwd", "rm", "dd", "mkfs", "shutdown",
+    "reboot", "poweroff", "halt", "kill", "killall",
+];
+
+// A `--help` still running after this is killed
+const HELP_TIMEOUT: Duration = Duration::from_secs(2);
+
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// "90s", "30m", "12h", "7d"; "never" or "0" for no expiry
+pub fn parse_ttl(value: &str) -> Option<Option<Duration>> {
+    let value = value.trim();
+    if value == "never" || value == "0" {
+        return Some(None);
+    }
+    let unit = match value.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let n: u64 = value[..value.len() - 1].parse().ok()?;
+    Some(Some(Duration::from_secs(n * unit)))
+}
+
+// Commands whose `--help` is never run for completions, from
+// `[completion] disabled` in the config and `24! completions disable`
+static DISABLED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+fn disabled() -> &'static Mutex<HashSet<String>> {
+    DISABLED.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+pub fn set_disabled(commands: &[String]) {
+    *disabled().lock().unwrap() = commands.iter().cloned().collect();
+}
+
+fn is_disabled(cmd: &str) -> bool {
+    disabled().lock().unwrap().contains(cmd)
+}
+
+// `[completion] cache_dir` from the config, set once at startup
+static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_cache_dir(dir: PathBuf) {
+    let _ = CACHE_DIR.set(dir);
+}
+
+pub fn cache_dir() -> PathBuf {
+    CACHE_DIR
+        .get()
+        .cloned()
+        .unwrap_or_else(|| crate::config::cache_dir().join("completions"))
+}
+
+// Set by `24! completions rescan`: every PATH directory is read again at
+// the next Tab, whatever its mtime says
+static RESCAN: AtomicBool = AtomicBool::new(false);
+
+// The commands in each PATH directory, with the directory's mtime when it
+// was read. Adding or removing a file changes that mtime, so only those
+// directories are read again.
+#[derive(Default)]
+struct PathScan {
+    var: Option<OsString>,
+    dirs: Vec<(PathBuf, Option<SystemTime>, Vec<String>)>,
+}
+
+impl PathScan {
+    // Catches up with `var`, the value of PATH; true when the command set
+    // changed. With nothing new it costs a stat per directory.
+    fn update(&mut self, var: Option<OsString>, executables_only: bool) -> bool {
+        if RESCAN.swap(false, Ordering::Relaxed) {
+            self.dirs.clear();
+        } else if var == self.var
+            && self
+                .dirs
+                .iter()
+                .all(|(dir, mtime, _)| modified(dir) == *mtime)
+        {
+            return false;
+        }
+        let mut old = std::mem::take(&mut self.dirs);
+        for dir in var.iter().flat_map(env::split_paths) {
+            let mtime = modified(&dir);
+            let scanned = match old.iter().position(|(d, m, _)| *d == dir && *m == mtime) {
+                Some(i) => old.swap_remove(i),
+                None => (dir.clone(), mtime, read_commands(&dir, executables_only)),
+            };
+            self.dirs.push(scanned);
+        }
+        self.var = var;
+        true
+    }
+
+    // Each command with its description: "builtin" or the PATH directory it
+    // runs from
+    fn commands(&self) -> HashMap<String, String> {
+        let mut commands = HashMap::new();
+        for (dir, _, names) in &self.dirs {
+            for name in names {
+                commands
+                    .entry(name.clone())
+                    .or_insert_with(|| dir.display().to_string());
+            }
+        }
+        for b in BUILTINS {
+            commands.insert(b.name.to_string(), "builtin".to_string());
+        }
+        commands
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read_commands(dir: &Path, executables_only: bool) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| {
+            let path = entry.path();
+            is_executable(&path) || !executables_only && path.is_file()
+        })
+        .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+        .collect()
+}
+
 /// Main completer struct that handles command completions
 pub struct MyCompleter {
-    commands: HashSet<String>,
+    commands: HashMap<String, String>,
+    // None keeps `commands` as they are
+    path: Option<PathScan>,
     cache_dir: PathBuf,
-    subcommand_cache: HashMap<String, Vec<String>>,
+    config: CompletionConfig,
+    jobs: &'static Mutex<Vec<Job>>,
 }
 
 impl MyCompleter {
-    pub fn new() -> Self {
-        let cache_dir = PathBuf::from(env::var("HOME").unwrap()).join(".cache/shesh/completions");
-
-        fs::create_dir_all(&cache_dir).expect("Failed to create cache directory");
+    pub fn new(config: CompletionConfig, jobs: &'static Mutex<Vec<Job>>) -> Self {
+        // Created when the first entry is saved; a read-only home only means
+        // nothing gets cached
+        let cache_dir = cache_dir();
 
+        // PATH is read at the first Tab on a command, not before the first
+        // prompt; until then only the builtins are known
+        let path = PathScan::default();
         Self {
-            commands: Self::load_commands(),
+            commands: path.commands(),
+            path: Some(path),
             cache_dir,
-            subcommand_cache: HashMap::new(),
+            config,
+            jobs,
         }
     }
 
-    pub fn load_commands() -> HashSet<String> {
-        let mut commands = HashSet::new();
+    fn matches(&self, candidate: &str, word: &str) -> bool {
+        if self.config.ignore_case {
+            candidate.to_lowercase().starts_with(&word.to_lowercase())
+        } else {
+            candidate.starts_with(word)
+        }
+    }
 
-        if let Some(path_var) = env::var_os("PATH") {
-            env::split_paths(&path_var)
-                .flat_map(|dir| fs::read_dir(dir).ok().into_iter().flatten())
-                .filter_map(|entry| {
-                    entry
-                        .ok()
-                        .and_then(|e| e.file_name().to_str().map(str::to_string))
-                })
-                .for_each(|cmd| {
-                    commands.insert(cmd);
-                });
+    // None for no match; a prefix match scores PREFIX_SCORE, above any
+    // fuzzy one
+    fn score(&self, candidate: &str, word: &str) -> Option<i64> {
+        if self.matches(candidate, word) {
+            Some(PREFIX_SCORE)
+        } else if self.config.fuzzy {
+            fuzzy_score(candidate, word, self.config.ignore_case)
+        } else {
+            None
         }
+    }
 
-        let builtins = ["alias", "cd", "exit", "help"];
-        for b in builtins {
-            commands.insert(b.to_string());
+    // Best first; fuzzy matches only show when nothing matched the prefix
+    fn rank(&self, mut scored: Vec<(i64, Suggestion)>) -> Vec<Suggestion> {
+        if self.config.fuzzy {
+            if scored.iter().any(|(score, _)| *score == PREFIX_SCORE) {
+                scored.retain(|(score, _)| *score == PREFIX_SCORE);
+            }
+            scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.value.cmp(&b.1.value)));
         }
-        commands
+        scored.into_iter().map(|(_, s)| s).collect()
     }
 
     fn get_cache_path(&self, cmd: &str) -> PathBuf {
@@ -57,196 +340,849 @@ impl MyCompleter {
             .join(format!("{}.24", sanitize_filename(cmd)))
     }
 
-    fn get_subcommands(&mut self, cmd: &str) -> Vec<String> {
-        if let Some(cached) = self.load_from_cache(cmd) {
-            return cached;
+    // Only commands found on PATH get `--help` run; `./script` could be
+    // anything
+    fn get_help(&mut self, cmd: &str) -> HelpEntries {
+        // shesh's own commands are known without asking
+        if cmd == "24!" {
+            return HelpEntries::meta_commands();
         }
-
-        let subcommands = self.extract_subcommands(cmd);
-        if !subcommands.is_empty() {
-            let _ = self.save_to_cache(cmd, &subcommands);
-            self.subcommand_cache
-                .insert(cmd.to_string(), subcommands.clone());
+        // A hand-written spec is used instead of `--help`
+        if let Some(spec) = specs::get(cmd) {
+            return HelpEntries::from_spec(spec);
         }
-
-        subcommands
-    }
-
-    fn save_to_cache(&self, cmd: &str, subcommands: &[String]) -> Result<(), std::io::Error> {
-        let path = self.get_cache_path(cmd);
-
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
+        if is_disabled(cmd) {
+            return HelpEntries::default();
         }
-
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?;
-
-        let mut writer = BufWriter::new(file);
-        for sub in subcommands {
-            writeln!(writer, "{sub}")?;
+        let run_help = self.config.run_help && !cmd.contains('/');
+        if let Some((cached, stale)) = self.load_from_cache(cmd) {
+            if stale && run_help {
+                refresh_in_background(self.get_cache_path(cmd), cmd);
+            }
+            return cached;
         }
-
-        Ok(())
+        // Nothing this time; `--help` runs on a worker thread and fills the
+        // cache for the next Tab
+        if run_help {
+            refresh_in_background(self.get_cache_path(cmd), cmd);
+        }
+        HelpEntries::default()
     }
 
-    fn load_from_cache(&self, cmd: &str) -> Option<Vec<String>> {
+    // The cached entries, and whether they're due to be read again
+    fn load_from_cache(&self, cmd: &str) -> Option<(HelpEntries, bool)> {
         let cache_file = self.get_cache_path(cmd);
         if !cache_file.exists() {
             return None;
         }
+        let stale = is_stale(
+            &cache_file,
+            find_in_path(cmd).as_deref(),
+            self.config.cache_ttl,
+        );
 
-        let file = OpenOptions::new().read(true).open(&cache_file).ok()?;
-        let reader = BufReader::new(file);
-
-        let subcommands: Vec<String> = reader
-            .lines()
-            .map_while(Result::ok)
-            .filter(|line| !line.trim().is_empty())
-            .collect();
-
-        if subcommands.is_empty() {
+        let entries = HelpEntries::parse_cache(&fs::read_to_string(&cache_file).ok()?);
+        if entries.is_empty() {
             None
         } else {
-            Some(subcommands)
+            Some((entries, stale))
         }
     }
 
-    fn extract_subcommands(&self, cmd: &str) -> Vec<String> {
-        let output = match Command::new(cmd).arg("--help").output().ok() {
-            Some(output) => output,
-            None => return Vec::new(),
+    // Hosts from the ssh files after ssh and sftp, and after scp and rsync
+    // as `host:` next to local files; a remote path is left as typed. None
+    // for options and for other commands.
+    fn complete_remote(
+        &self,
+        cmd: &str,
+        word: &str,
+        span: Span,
+        hosts: impl FnOnce() -> Vec<String>,
+    ) -> Option<Vec<Suggestion>> {
+        let remote_path = match cmd {
+            "ssh" | "sftp" => false,
+            "scp" | "rsync" => true,
+            _ => return None,
         };
-        let help = String::from_utf8_lossy(&output.stdout);
+        if word.starts_with('-') {
+            return None;
+        }
+        if remote_path && word.contains(':') {
+            return Some(Vec::new());
+        }
+        // A local path
+        if remote_path && (word.contains('/') || word.starts_with(['.', '~'])) {
+            return None;
+        }
+        // `user@` stays, the host after it is completed
+        let at = word.find('@').map_or(0, |i| i + 1);
+        let host_span = Span::new(span.start + at, span.end);
+        let mut suggestions: Vec<Suggestion> = hosts()
+            .into_iter()
+            .filter(|host| self.matches(host, &word[at..]))
+            .map(|host| Suggestion {
+                value: if remote_path {
+                    format!("{host}:")
+                } else {
+                    host
+                },
+                description: Some("host".into()),
+                span: host_span,
+                append_whitespace: !remote_path,
+                ..Default::default()
+            })
+            .collect();
+        if remote_path && at == 0 {
+            suggestions.extend(self.complete_files(word, span, None));
+        }
+        Some(suggestions)
+    }
 
-        let mut subs = Vec::new();
+    // `%N` for the jobs after fg, bg, disown, wait and kill; kill also takes
+    // the user's pids and, after a dash, signal names. None for other
+    // commands.
+    fn complete_process(
+        &self,
+        cmd: &str,
+        word: &str,
+        span: Span,
+        processes: impl FnOnce() -> Vec<(i32, String)>,
+    ) -> Option<Vec<Suggestion>> {
+        let kill = match cmd {
+            "fg" | "bg" | "disown" | "wait" => false,
+            "kill" => true,
+            _ => return None,
+        };
+        let suggestion = |value: String, description: String| Suggestion {
+            value,
+            description: Some(description),
+            span,
+            append_whitespace: true,
+            ..Default::default()
+        };
+        if kill && word.starts_with('-') {
+            return Some(
+                (1..32)
+                    .filter_map(|n| signal_name(128 + n))
+                    .map(|name| format!("-{name}"))
+                    .filter(|flag| self.matches(flag, word))
+                    .map(|flag| suggestion(flag, "signal".into()))
+                    .collect(),
+            );
+        }
+        let mut suggestions: Vec<Suggestion> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|job| (format!("%{}", job.id), job.command.clone()))
+            .filter(|(spec, _)| self.matches(spec, word))
+            .map(|(spec, command)| suggestion(spec, command))
+            .collect();
+        if kill && !word.starts_with('%') {
+            suggestions.extend(
+                processes()
+                    .into_iter()
+                    .map(|(pid, comm)| (pid.to_string(), comm))
+                    .filter(|(pid, _)| pid.starts_with(word))
+                    .map(|(pid, comm)| suggestion(pid, comm)),
+            );
+        }
+        Some(suggestions)
+    }
 
-        for line in help.lines() {
-            if line.starts_with("  ") {
-                if let Some(token) = line.split_whitespace().next() {
-                    if token.len() > 1 && !token.contains(['<', '"', '[', '(']) {
-                        subs.push(token.trim_end_matches(',').to_string());
-                    }
-                }
+    // Arguments `cmd` was used with before, after the matches found some
+    // other way and without repeating them
+    fn with_history(
+        &self,
+        cmd: &str,
+        word: &str,
+        span: Span,
+        mut suggestions: Vec<Suggestion>,
+        used: impl FnOnce(&str) -> Vec<String>,
+    ) -> Vec<Suggestion> {
+        for arg in used(cmd) {
+            if self.matches(&arg, word) && !suggestions.iter().any(|s| s.value == arg) {
+                suggestions.push(Suggestion {
+                    value: arg,
+                    description: Some("history".into()),
+                    span,
+                    append_whitespace: true,
+                    ..Default::default()
+                });
             }
         }
-        subs.sort();
-        subs.dedup();
-        subs
+        suggestions
     }
 
     /// Handle file/directory completions
-    fn complete_files(&self, current: &str, span: Span) -> Vec<Suggestion> {
-        let last_slash = current.rfind('/').map_or(0, |i| i + 1);
-        let (base, partial) = current.split_at(last_slash);
+    fn complete_files(
+        &self,
+        current: &str,
+        span: Span,
+        filter: Option<&ArgFilter>,
+    ) -> Vec<Suggestion> {
+        // Inside an open quote names go in as they are; otherwise what was
+        // typed is unescaped and the names get escaped
+        let quote = current.chars().next().filter(|c| *c == '"' || *c == '\'');
+        let body = &current[quote.map_or(0, |_| 1)..];
+        let last_slash = body.rfind('/').map_or(0, |i| i + 1);
+        let (base, partial) = body.split_at(last_slash);
+        let partial = unescape(partial, quote);
 
         let expanded_base = if base.is_empty() {
             PathBuf::from(".")
+        } else if quote.is_some() {
+            PathBuf::from(unescape(base, quote))
         } else {
-            expand_tilde(base)
+            expand_tilde(&unescape(base, None))
         };
 
         if !expanded_base.is_dir() {
             return Vec::new();
         }
 
-        let partial_span = Span::new(span.start + last_slash, span.end);
-
-        let reader = match fs::read_dir(&expanded_base) {
-            Ok(rd) => rd,
-            Err(_) => return Vec::new(),
-        };
+        let partial_span = Span::new(span.end - (body.len() - last_slash), span.end);
 
-        reader
-            .flatten()
-            .filter_map(|entry| {
-                let file_name = entry.file_name();
-                let name = file_name.to_str()?;
-
-                // Skip hidden files unless explicitly requested
-                if !partial.starts_with('.') && name.starts_with('.') {
+        let limit = self.config.max_files;
+        let mut suggestions: Vec<(u8, i64, Suggestion)> =
+            list_dir(&expanded_base, limit, |name, is_dir| {
+                let show_hidden = match self.config.hidden {
+                    HiddenFiles::Always => true,
+                    HiddenFiles::Never => false,
+                    HiddenFiles::Smart => partial.starts_with('.'),
+                };
+                if name.starts_with('.') && !show_hidden {
                     return None;
                 }
 
-                if !name.starts_with(partial) {
-                    return None;
-                }
+                let score = self.score(name, &partial)?;
 
-                // Escape spaces by adding backslash before them
-                let escaped_name = name.replace(' ', "\\ ");
+                let path = expanded_base.join(name);
+                // Matching files sort before directories, the rest after;
+                // with no filter directories come first
+                let rank = match filter {
+                    Some(ArgFilter::Dirs) if !is_dir => return None,
+                    Some(ArgFilter::Executables) if !is_dir && !is_executable(&path) => {
+                        return None;
+                    }
+                    Some(ArgFilter::Extensions(extensions)) => {
+                        let extension = name.rsplit_once('.').map(|(_, e)| e);
+                        if is_dir {
+                            1
+                        } else if extension.is_some_and(|e| extensions.iter().any(|x| x == e)) {
+                            0
+                        } else {
+                            2
+                        }
+                    }
+                    Some(_) => 0,
+                    None => u8::from(!is_dir),
+                };
 
-                let value = if entry.path().is_dir() {
-                    format!("{escaped_name}/")
+                // A directory leaves the quote open for the next part of the
+                // path, a file closes it
+                let value = match (quote, is_dir) {
+                    (None, true) => format!("{}/", escape(name, None)),
+                    (None, false) => escape(name, None),
+                    (Some(q), true) => format!("{}/", escape(name, Some(q))),
+                    (Some(q), false) => format!("{}{q}", escape(name, Some(q))),
+                };
+
+                let description = if is_dir {
+                    "dir".to_string()
                 } else {
-                    escaped_name.to_string()
+                    fs::metadata(&path).map_or_else(|_| String::new(), |m| format_size(m.len()))
                 };
 
-                Some(Suggestion {
-                    value,
-                    span: partial_span,
-                    ..Default::default()
-                })
+                Some((
+                    rank,
+                    score,
+                    Suggestion {
+                        value,
+                        description: Some(description),
+                        span: partial_span,
+                        ..Default::default()
+                    },
+                ))
+            });
+        if self.config.fuzzy && suggestions.iter().any(|s| s.1 == PREFIX_SCORE) {
+            suggestions.retain(|s| s.1 == PREFIX_SCORE);
+        }
+        // By rank, then the best score, then name
+        suggestions.sort_by(|a, b| {
+            (a.0.cmp(&b.0))
+                .then(b.1.cmp(&a.1))
+                .then_with(|| a.2.value.cmp(&b.2.value))
+        });
+        suggestions.into_iter().map(|(_, _, s)| s).collect()
+    }
+}
+
+// The last directory read to the end, by its mtime: narrowing a prefix with
+// more Tabs doesn't read it again. Kept directories first, then by name.
+struct Listing {
+    dir: PathBuf,
+    mtime: Option<SystemTime>,
+    entries: Vec<(String, bool)>,
+}
+
+static LISTING: OnceLock<Mutex<Option<Listing>>> = OnceLock::new();
+
+// What `keep` makes of the names in `dir` (and whether each is a
+// directory), in directory order. Reading stops at `limit` kept entries, so
+// a short prefix in a huge directory doesn't go through all of it.
+fn list_dir<T>(dir: &Path, limit: usize, mut keep: impl FnMut(&str, bool) -> Option<T>) -> Vec<T> {
+    let mtime = modified(dir);
+    let mut cached = LISTING.get_or_init(|| Mutex::new(None)).lock().unwrap();
+    if let Some(listing) = cached.as_ref()
+        && listing.dir == dir
+        && listing.mtime == mtime
+        && mtime.is_some()
+    {
+        return listing
+            .entries
+            .iter()
+            .filter_map(|(name, is_dir)| keep(name, *is_dir))
+            .take(limit)
+            .collect();
+    }
+
+    let Ok(reader) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut entries = Vec::new();
+    let mut kept = Vec::new();
+    for entry in reader.flatten() {
+        if kept.len() == limit {
+            return kept;
+        }
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        // The type comes with the entry; only a symlink needs a stat
+        let is_dir = match entry.file_type() {
+            Ok(t) if t.is_symlink() => entry.path().is_dir(),
+            Ok(t) => t.is_dir(),
+            Err(_) => false,
+        };
+        kept.extend(keep(&name, is_dir));
+        entries.push((name, is_dir));
+    }
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    *cached = Some(Listing {
+        dir: dir.to_path_buf(),
+        mtime,
+        entries,
+    });
+    kept
+}
+
+const PREFIX_SCORE: i64 = i64::MAX;
+
+// `word`'s characters in order somewhere in `candidate`, scored higher for
+// runs of consecutive characters and for matches at the start of a word
+// (`ds` in `docs-site`); each skipped character costs a point. The best
+// placement is kept for each matched character, so an early stray match
+// doesn't hide a better one later.
+fn fuzzy_score(candidate: &str, word: &str, ignore_case: bool) -> Option<i64> {
+    let fold = |c: char| {
+        if ignore_case {
+            c.to_ascii_lowercase()
+        } else {
+            c
+        }
+    };
+    let chars: Vec<char> = candidate.chars().collect();
+    let wanted: Vec<char> = word.chars().map(fold).collect();
+    if wanted.is_empty() {
+        return Some(0);
+    }
+    let bonus = |i: usize| {
+        let boundary = i == 0 || {
+            let p = chars[i - 1];
+            "-_. /".contains(p) || (p.is_lowercase() && chars[i].is_uppercase())
+        };
+        1 + if boundary { 8 } else { 0 }
+    };
+
+    // best[i]: the score with the current character of `word` at `i`
+    let mut best: Vec<Option<i64>> = (0..chars.len())
+        .map(|i| (fold(chars[i]) == wanted[0]).then(|| bonus(i) - i as i64))
+        .collect();
+    for &w in &wanted[1..] {
+        best = (0..chars.len())
+            .map(|i| {
+                if fold(chars[i]) != w {
+                    return None;
+                }
+                (0..i)
+                    .filter_map(|k| {
+                        let run = if k + 1 == i { 5 } else { 0 };
+                        Some(best[k]? - (i - k - 1) as i64 + run)
+                    })
+                    .max()
+                    .map(|score| score + bonus(i))
             })
-            .collect()
+            .collect();
+    }
+    best.into_iter().flatten().max()
+}
+
+// Characters that need a backslash in a completed name, outside quotes and
+// inside double quotes; single quotes take everything as it is
+const SPECIAL: &[char] = &[
+    ' ', '\t', '$', '&', ';', '(', ')', '\'', '"', '\\', '|', '<', '>', '`', '*', '?',
+];
+const SPECIAL_IN_DOUBLE: &[char] = &['"', '$', '`', '\\'];
+
+fn escape(name: &str, quote: Option<char>) -> String {
+    let special = match quote {
+        None => SPECIAL,
+        Some('"') => SPECIAL_IN_DOUBLE,
+        Some(_) => &[],
+    };
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// What typed text names: `My\ Doc` is `My Doc`
+fn unescape(text: &str, quote: Option<char>) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let escapes = match quote {
+            None => chars.peek().is_some(),
+            Some('"') => chars.peek().is_some_and(|n| SPECIAL_IN_DOUBLE.contains(n)),
+            Some(_) => false,
+        };
+        if c == '\\' && escapes {
+            unescaped.extend(chars.next());
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+// `512B`, `4.2K`, `17M`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 || size >= 10.0 {
+        format!("{size:.0}{}", UNITS[unit])
+    } else {
+        format!("{size:.1}{}", UNITS[unit])
+    }
+}
+
+// `$NA` or `${NA` at the end of `word`: where the `$` is, the partial name
+// and whether it's braced
+fn variable_at_end(word: &str) -> Option<(usize, &str, bool)> {
+    let dollar = word.rfind('$')?;
+    let rest = &word[dollar + 1..];
+    let (name, braced) = match rest.strip_prefix('{') {
+        Some(name) => (name, true),
+        None => (rest, false),
+    };
+    name.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        .then_some((dollar, name, braced))
+}
+
+/// Where the word under the cursor sits in the line.
+#[derive(Debug, PartialEq)]
+struct Context {
+    // Start of the command the word belongs to, after the last `|`, `&&`,
+    // `||`, `;` or `&` outside quotes
+    command: usize,
+    // Start of the word itself
+    word: usize,
+    // The word follows `>`, `>>` or `<`
+    redirect: bool,
+    // The quote still open at the end
+    quote: Option<char>,
+}
+
+// Where the real command starts in `words`: past wrappers like `sudo`, the
+// flags given to them and variable assignments
+fn command_index(words: &[&str], wrappers: &[String]) -> usize {
+    // Wrapper flags whose value is the next word: `sudo -u root`,
+    // `nice -n 5`, `xargs -I {}`, `env -u NAME`
+    const TAKES_VALUE: &[&str] = &["-u", "-g", "-n", "-C", "-D", "-I"];
+
+    let is_assignment = |word: &str| {
+        word.split_once('=').is_some_and(|(name, _)| {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+    };
+    let mut wrapped = false;
+    let mut value_next = false;
+    let mut index = 0;
+    for word in words {
+        if value_next {
+            value_next = false;
+        } else if wrappers.iter().any(|w| w == word) {
+            wrapped = true;
+        } else if wrapped && TAKES_VALUE.contains(word) {
+            value_next = true;
+        } else if !(is_assignment(word) || wrapped && word.starts_with('-')) {
+            break;
+        }
+        index += 1;
+    }
+    index
+}
+
+// Operators, and spaces between words, count only outside quotes and when
+// not escaped
+fn context(line: &str) -> Context {
+    let mut ctx = Context {
+        command: 0,
+        word: 0,
+        redirect: false,
+        quote: None,
+    };
+    let mut quote = None;
+    let mut escaped = false;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            } else if c == '\\' && q == '"' {
+                escaped = true;
+            }
+        } else if c == ' ' {
+            if i > ctx.word {
+                ctx.redirect = false;
+            }
+            ctx.word = i + 1;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        } else if c == '>'
+            || c == '<'
+            || (c == '&' && (prev == '>' || line[i + 1..].starts_with('>')))
+            || (c == '|' && prev == '>')
+        {
+            // `2>&1`, `&>` and `>|` are redirections, not a background `&`
+            // or a pipe
+            ctx.word = i + 1;
+            ctx.redirect = c != '&' || prev != '>';
+        } else if matches!(c, '|' | '&' | ';') {
+            ctx.command = i + 1;
+            ctx.word = i + 1;
+            ctx.redirect = false;
+        }
+        prev = c;
+    }
+    ctx.quote = quote;
+    ctx
+}
+
+// Length of the rest of the word the cursor is in: `rest` is the line after
+// the cursor and `quote` the quote open there. A closing quote belongs to
+// the word.
+fn word_end(rest: &str, mut quote: Option<char>) -> usize {
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            } else if c == '\\' && q == '"' {
+                escaped = true;
+            }
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        } else if c.is_whitespace() || matches!(c, '|' | '&' | ';' | '<' | '>') {
+            return i;
+        }
     }
+    rest.len()
 }
 
 impl Completer for MyCompleter {
     fn complete(&mut self, line: &str, pos: usize) -> Vec<Suggestion> {
+        let mut suggestions = self.suggestions(line, pos);
+        if let Some(cmd) = &self.config.external {
+            let external = run_external(cmd, line, pos, self.config.external_timeout);
+            if self.config.external_override && !external.is_empty() {
+                suggestions = external;
+            } else {
+                suggestions.retain(|s| !external.iter().any(|e| e.value == s.value));
+                suggestions.splice(0..0, external);
+            }
+        }
+        // Matching only looks at what's before the cursor; the rest of the
+        // word is replaced too
+        let end = pos + word_end(&line[pos..], context(&line[..pos]).quote);
+        for suggestion in &mut suggestions {
+            if suggestion.span.end == pos {
+                suggestion.span.end = end;
+            }
+            if !self.config.descriptions {
+                suggestion.description = None;
+            }
+        }
+        suggestions
+    }
+}
+
+impl MyCompleter {
+    fn suggestions(&mut self, line: &str, pos: usize) -> Vec<Suggestion> {
         let line = &line[..pos];
-        let parts: Vec<&str> = line.split_whitespace().collect();
+        let ctx = context(line);
+        let span = Span::new(ctx.word, pos);
+        let current_word = &line[ctx.word..pos];
 
-        let last_space = line.rfind(' ').map(|i| i + 1).unwrap_or(0);
-        let span = Span::new(last_space, pos);
-        let current_word = &line[last_space..pos];
+        // Arguments complete as if an alias in front had been typed out.
+        // `parts` is the command and the words typed after it.
+        let expanded = expand_aliases(line[ctx.command..ctx.word].trim_start());
+        let words: Vec<&str> = expanded.split_whitespace().collect();
+        let parts = &words[command_index(&words, &self.config.wrappers)..];
 
-        // Complete commands at beginning
-        if parts.is_empty() || (parts.len() == 1 && last_space == 0) {
-            return self
-                .commands
-                .iter()
-                .filter(|cmd| cmd.starts_with(current_word))
-                .map(|cmd| Suggestion {
-                    value: cmd.to_string(),
+        // Variable names, replacing only the `$NAME` part of the word; names
+        // are case-sensitive whatever `ignore_case` says
+        if let Some((dollar, partial, braced)) = variable_at_end(current_word) {
+            let span = Span::new(ctx.word + dollar, pos);
+            return var_names()
+                .into_iter()
+                .filter(|name| name.starts_with(partial))
+                .map(|name| Suggestion {
+                    value: if braced {
+                        format!("${{{name}}}")
+                    } else {
+                        format!("${name}")
+                    },
                     span,
-                    append_whitespace: true,
                     ..Default::default()
                 })
                 .collect();
         }
 
+        // A redirection's target is a file wherever it appears
+        if ctx.redirect {
+            return self.complete_files(current_word, span, None);
+        }
+
+        // A command given by its path: only what can be run, and directories
+        // on the way to it
+        if parts.is_empty() && (current_word.contains('/') || current_word.starts_with('~')) {
+            let filter = self
+                .config
+                .executables_only
+                .then_some(&ArgFilter::Executables);
+            return self.complete_files(current_word, span, filter);
+        }
+
+        // Complete commands and aliases at the start of each command
+        if parts.is_empty() {
+            if let Some(path) = &mut self.path
+                && path.update(env::var_os("PATH"), self.config.executables_only)
+            {
+                self.commands = path.commands();
+            }
+            // An alias hides the command of the same name
+            let aliases: Vec<(String, String)> = alias_list()
+                .into_iter()
+                .map(|(name, cmd)| (name, format!("alias → {cmd}")))
+                .collect();
+            let scored = aliases
+                .iter()
+                .map(|(name, description)| (name, description))
+                .chain(
+                    self.commands
+                        .iter()
+                        .filter(|(name, _)| !aliases.iter().any(|(a, _)| a == *name)),
+                )
+                .filter_map(|(cmd, description)| {
+                    let suggestion = Suggestion {
+                        value: cmd.to_string(),
+                        description: Some(description.clone()),
+                        span,
+                        append_whitespace: true,
+                        ..Default::default()
+                    };
+                    Some((self.score(cmd, current_word)?, suggestion))
+                })
+                .collect();
+            return self.rank(scored);
+        }
+
+        if let Some(suggestions) = self.complete_remote(parts[0], current_word, span, hosts::hosts)
+        {
+            return self.with_history(parts[0], current_word, span, suggestions, arguments);
+        }
+        if let Some(suggestions) =
+            self.complete_process(parts[0], current_word, span, user_processes)
+        {
+            return suggestions;
+        }
+
+        let filter = parts
+            .first()
+            .and_then(|cmd| self.config.filter(cmd))
+            .cloned();
+
+        // Options from the cached `--help`; one that takes a value gets no
+        // space after its `=`
+        if current_word.starts_with('-') && !current_word.contains('=') {
+            let help = self.get_help(parts[0]);
+            let scored: Vec<_> = help
+                .flags
+                .iter()
+                .filter_map(|flag| {
+                    let suggestion = Suggestion {
+                        value: flag.to_string(),
+                        description: help.description(flag),
+                        span,
+                        append_whitespace: !flag.ends_with('='),
+                        ..Default::default()
+                    };
+                    Some((self.score(flag, current_word)?, suggestion))
+                })
+                .collect();
+            if !scored.is_empty() {
+                return self.rank(scored);
+            }
+        }
+
+        // Built-in providers, such as git's branches and changed files; when
+        // none of theirs fit the word the usual completion takes over
+        if !current_word.starts_with('-')
+            && let Some(candidates) = providers::complete(parts[0], &parts[1..])
+        {
+            let scored: Vec<_> = candidates
+                .into_iter()
+                .filter_map(|(value, description)| {
+                    let score = self.score(&value, current_word)?;
+                    let suggestion = Suggestion {
+                        value: escape(&value, None),
+                        description: Some(description),
+                        span,
+                        append_whitespace: true,
+                        ..Default::default()
+                    };
+                    Some((score, suggestion))
+                })
+                .collect();
+            if !scored.is_empty() {
+                return self.rank(scored);
+            }
+        }
+
         // Complete files for paths
         if current_word.contains('/') || current_word.starts_with('~') {
-            return self.complete_files(current_word, span);
+            return self.complete_files(current_word, span, filter.as_ref());
         }
 
+        // A spec's `dynamic` and `[arguments]` commands run at each Tab
+        let spec = specs::get(parts[0]);
+
         // Complete subcommands for known commands
-        if parts.len() == 1 {
+        if parts.len() == 1 && filter.is_none() {
             let main_cmd = parts[0];
-            let subcommands = self.get_subcommands(main_cmd);
+            let mut help = self.get_help(main_cmd);
+            if let Some(dynamic) = spec.as_ref().and_then(|s| s.dynamic.as_ref()) {
+                help.subcommands.extend(run_dynamic(dynamic));
+            }
 
-            if !subcommands.is_empty() {
-                return subcommands
+            if !help.subcommands.is_empty() {
+                let scored = help
+                    .subcommands
                     .iter()
-                    .filter(|subcmd| subcmd.starts_with(current_word))
-                    .map(|subcmd| Suggestion {
-                        value: subcmd.to_string(),
-                        span,
-                        append_whitespace: true,
-                        ..Default::default()
+                    .filter_map(|subcmd| {
+                        let suggestion = Suggestion {
+                            value: subcmd.to_string(),
+                            description: help.description(subcmd),
+                            span,
+                            append_whitespace: true,
+                            ..Default::default()
+                        };
+                        Some((self.score(subcmd, current_word)?, suggestion))
                     })
                     .collect();
+                let ranked = self.rank(scored);
+                return self.with_history(main_cmd, current_word, span, ranked, arguments);
             }
         }
+        if let [_, sub, ..] = parts
+            && let Some(cmd) = spec.as_ref().and_then(|s| s.arguments.get(*sub))
+        {
+            let scored = run_dynamic(cmd)
+                .into_iter()
+                .filter_map(|candidate| {
+                    let score = self.score(&candidate, current_word)?;
+                    let suggestion = Suggestion {
+                        value: candidate,
+                        span,
+                        append_whitespace: true,
+                        ..Default::default()
+                    };
+                    Some((score, suggestion))
+                })
+                .collect();
+            return self.rank(scored);
+        }
 
-        // Default to file completion
-        self.complete_files(current_word, span)
+        // Default to file completion, then what the command was used with
+        let files = self.complete_files(current_word, span, filter.as_ref());
+        self.with_history(parts[0], current_word, span, files, arguments)
     }
 }
 
+// The candidates of the `[completion] external` program for the word at
+// the cursor. Nothing when it fails, prints nothing or runs too long, so a
+// hung program costs one timeout and typing goes on.
+fn run_external(cmd: &str, line: &str, pos: usize, timeout: Duration) -> Vec<Suggestion> {
+    let vars = [
+        ("COMP_LINE", line.to_string()),
+        ("COMP_POINT", pos.to_string()),
+    ];
+    let Ok(output) = crate::process_exec::capture_output_with(cmd, &vars, timeout) else {
+        return Vec::new();
+    };
+    let ctx = context(&line[..pos]);
+    parse_external(&String::from_utf8_lossy(&output), Span::new(ctx.word, pos))
+}
+
+// `value<TAB>description` lines, the description optional. A value ending
+// in `/` or `=` is still being typed, so no space goes after it.
+fn parse_external(output: &str, span: Span) -> Vec<Suggestion> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (value, description) = line.split_once('\t').unwrap_or((line, ""));
+            (!value.is_empty()).then(|| Suggestion {
+                value: escape(value, None),
+                description: Some(description.trim().to_string()).filter(|d| !d.is_empty()),
+                span,
+                append_whitespace: !value.ends_with(['/', '=']),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
 /// Create sanitized filename for cache
 fn sanitize_filename(name: &str) -> String {
     name.chars()
@@ -260,7 +1196,1054 @@ fn sanitize_filename(name: &str) -> String {
         .collect()
 }
 
-/// Create default completer instance
-pub fn create_default_completer() -> Box<dyn Completer> {
-    Box::new(MyCompleter::new())
+/// What completion learns from a command's `--help`.
+#[derive(Debug, Default, PartialEq)]
+struct HelpEntries {
+    subcommands: Vec<String>,
+    // Long options, ending in `=` when they take a value
+    flags: Vec<String>,
+    // The text after a subcommand or option on its `--help` line
+    descriptions: HashMap<String, String>,
+}
+
+// In a cache file, the subcommands come first and the flags follow this line
+const FLAGS_SECTION: &str = "[flags]";
+
+impl HelpEntries {
+    fn is_empty(&self) -> bool {
+        self.subcommands.is_empty() && self.flags.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.subcommands.len() + self.flags.len()
+    }
+
+    fn extract(cmd: &str) -> Self {
+        run_help(cmd).map_or_else(Self::default, |help| Self::parse_help(&help))
+    }
+
+    fn parse_help(help: &str) -> Self {
+        let mut entries = Self::default();
+        for line in help.lines() {
+            if line.trim_start().starts_with('-') {
+                let spec = line.trim_start();
+                let description = spec.find("  ").map_or("", |end| spec[end..].trim());
+                for flag in long_options(line) {
+                    if !description.is_empty() {
+                        entries
+                            .descriptions
+                            .insert(flag.clone(), description.to_string());
+                    }
+                    entries.flags.push(flag);
+                }
+            } else if line.starts_with("  ")
+                && let Some(token) = line.split_whitespace().next()
+                && token.len() > 1
+                && !token.contains(['<', '"', '[', '('])
+            {
+                let sub = token.trim_end_matches(',').to_string();
+                let description = line.trim_start()[token.len()..].trim();
+                if !description.is_empty() {
+                    entries
+                        .descriptions
+                        .insert(sub.clone(), description.to_string());
+                }
+                entries.subcommands.push(sub);
+            }
+        }
+        for list in [&mut entries.subcommands, &mut entries.flags] {
+            list.sort();
+            list.dedup();
+        }
+        entries
+    }
+
+    // One entry per line, a tab before its description. Files written before
+    // flags were cached have no section line.
+    fn parse_cache(content: &str) -> Self {
+        let mut entries = Self::default();
+        let mut in_flags = false;
+        for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if line == FLAGS_SECTION {
+                in_flags = true;
+                continue;
+            }
+            let (name, description) = line.split_once('\t').unwrap_or((line, ""));
+            if !description.is_empty() {
+                entries
+                    .descriptions
+                    .insert(name.to_string(), description.to_string());
+            }
+            if in_flags {
+                entries.flags.push(name.to_string());
+            } else {
+                entries.subcommands.push(name.to_string());
+            }
+        }
+        entries
+    }
+
+    fn format_cache(&self) -> String {
+        let mut content = String::new();
+        let mut add = |name: &str| match self.descriptions.get(name) {
+            Some(description) => content.push_str(&format!("{name}\t{description}\n")),
+            None => content.push_str(&format!("{name}\n")),
+        };
+        for sub in &self.subcommands {
+            add(sub);
+        }
+        if !self.flags.is_empty() {
+            add(FLAGS_SECTION);
+            for flag in &self.flags {
+                add(flag);
+            }
+        }
+        content
+    }
+
+    fn from_spec(spec: Spec) -> Self {
+        Self {
+            subcommands: spec.subcommands,
+            flags: spec.flags,
+            descriptions: spec.descriptions,
+        }
+    }
+
+    fn meta_commands() -> Self {
+        Self {
+            subcommands: META_COMMANDS
+                .iter()
+                .map(|(name, _, _)| name.to_string())
+                .collect(),
+            flags: Vec::new(),
+            descriptions: META_COMMANDS
+                .iter()
+                .map(|(name, _, summary)| (name.to_string(), summary.to_string()))
+                .collect(),
+        }
+    }
+
+    fn description(&self, name: &str) -> Option<String> {
+        self.descriptions.get(name).cloned()
+    }
+}
+
+// The long options named before the description on a `--help` line:
+// `  -o, --output <FILE>  Write to FILE` gives `--output=`,
+// `      --color[=WHEN]` gives `--color`
+fn long_options(line: &str) -> Vec<String> {
+    let spec = line.trim_start();
+    let spec = spec.find("  ").map_or(spec, |end| &spec[..end]);
+    let tokens: Vec<&str> = spec.split([' ', ',']).filter(|t| !t.is_empty()).collect();
+    let mut options = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let Some(name) = token.strip_prefix("--") else {
+            continue;
+        };
+        let end = name.find(['=', '[']).unwrap_or(name.len());
+        let (name, rest) = name.split_at(end);
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            continue;
+        }
+        let value_next = tokens.get(i + 1).is_some_and(|next| {
+            next.starts_with('<')
+                || (!next.starts_with('-')
+                    && next.chars().all(|c| c.is_ascii_uppercase() || c == '_'))
+        });
+        if rest.starts_with('=') || value_next {
+            options.push(format!("--{name}="));
+        } else {
+            options.push(format!("--{name}"));
+        }
+    }
+    options
+}
+
+// `cmd --help` with nothing from the terminal, killed along with its
+// children when it's still running after HELP_TIMEOUT: some tools wait for
+// input, start a pager or go to the network
+fn run_help(cmd: &str) -> Option<String> {
+    let (mut child, _waiting) = crate::jobs::spawn_waited(
+        Command::new(cmd)
+            .arg("--help")
+            .env("PAGER", "cat")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .process_group(0),
+    )
+    .ok()?;
+    let mut stdout = child.stdout.take()?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stdout.read_to_end(&mut output);
+        let _ = sender.send(output);
+    });
+    let output = receiver.recv_timeout(HELP_TIMEOUT);
+    if output.is_err() {
+        unsafe {
+            libc::kill(-(child.id() as i32), libc::SIGKILL);
+        }
+    }
+    let _ = child.wait();
+    Some(String::from_utf8_lossy(&output.ok()?).into_owned())
+}
+
+// Replaced atomically, so completion reading it meanwhile sees either the
+// old entries or the new ones
+fn write_cache(path: &Path, entries: &HelpEntries) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    crate::utils::atomic_write(path, entries.format_cache().as_bytes())
+}
+
+// Older than `ttl`, or older than the binary, which was probably upgraded
+fn is_stale(cache_file: &Path, binary: Option<&Path>, ttl: Option<Duration>) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let Some(cached_at) = modified(cache_file) else {
+        return false;
+    };
+    let expired = ttl.is_some_and(|ttl| cached_at.elapsed().is_ok_and(|age| age > ttl));
+    expired
+        || binary
+            .and_then(modified)
+            .is_some_and(|built| built > cached_at)
+}
+
+// Re-runs `cmd --help` into `path`, returns the entry count
+fn rewrite(path: &Path, cmd: &str) -> io::Result<usize> {
+    let entries = if is_disabled(cmd) {
+        HelpEntries::default()
+    } else {
+        HelpEntries::extract(cmd)
+    };
+    if entries.is_empty() {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    } else {
+        write_cache(path, &entries)?;
+    }
+    Ok(entries.len())
+}
+
+// Re-runs `cmd --help` and replaces its cache file, returns the entry count
+fn refresh(cmd: &str) -> io::Result<usize> {
+    rewrite(
+        &cache_dir().join(format!("{}.24", sanitize_filename(cmd))),
+        cmd,
+    )
+}
+
+// Commands whose stale cache file is being rewritten
+static REFRESHING: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+// Tab never waits on `--help`: the old entries are offered now and the
+// new ones from the next time
+fn refresh_in_background(path: PathBuf, cmd: &str) {
+    let refreshing = REFRESHING.get_or_init(|| Mutex::new(HashSet::new()));
+    if !refreshing.lock().unwrap().insert(cmd.to_string()) {
+        return;
+    }
+    let cmd = cmd.to_string();
+    thread::spawn(move || {
+        let _ = rewrite(&path, &cmd);
+        refreshing.lock().unwrap().remove(&cmd);
+    });
+}
+
+// Cached commands with their entry count and file age, by name
+fn cached() -> Vec<(String, usize, Option<std::time::Duration>)> {
+    let Ok(entries) = fs::read_dir(cache_dir()) else {
+        return Vec::new();
+    };
+    let mut cached: Vec<_> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let cmd = path.file_name()?.to_str()?.strip_suffix(".24")?.to_string();
+            let count = fs::read_to_string(&path)
+                .map(|s| HelpEntries::parse_cache(&s).len())
+                .unwrap_or(0);
+            let age = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| SystemTime::now().duration_since(t).ok());
+            Some((cmd, count, age))
+        })
+        .collect();
+    cached.sort();
+    cached
+}
+
+fn format_age(age: Option<std::time::Duration>) -> String {
+    let Some(secs) = age.map(|a| a.as_secs()) else {
+        return "?".into();
+    };
+    match secs {
+        s if s < 60 * 60 => format!("{}m", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h", s / (60 * 60)),
+        s => format!("{}d", s / (24 * 60 * 60)),
+    }
+}
+
+/// `24! completions list | refresh [cmd] | rescan | disable cmd | enable cmd`.
+/// The completer reads the cache files and the disabled set on every
+/// completion, so changes apply to the running session right away.
+pub fn handle_completions(args: &[&str]) -> io::Result<()> {
+    match args {
+        [] | ["list"] => {
+            let cached = cached();
+            if cached.is_empty() {
+                println!("completions: nothing cached");
+            }
+            for (cmd, count, age) in cached {
+                println!("  {cmd:<20} {count:>5} entries  {:>4} old", format_age(age));
+            }
+            let mut off: Vec<String> = disabled().lock().unwrap().iter().cloned().collect();
+            if !off.is_empty() {
+                off.sort();
+                println!("disabled: {}", off.join(", "));
+            }
+            Ok(())
+        }
+        ["refresh"] => {
+            let count = specs::load();
+            println!(
+                "completions: read {count} specs from {}",
+                specs::spec_dir().display()
+            );
+            for (cmd, _, _) in cached() {
+                let count = refresh(&cmd)?;
+                println!("completions: refreshed {cmd} ({count} entries)");
+            }
+            Ok(())
+        }
+        ["rescan"] => {
+            RESCAN.store(true, Ordering::Relaxed);
+            println!("completions: PATH is read again at the next Tab");
+            Ok(())
+        }
+        ["refresh", cmd] => {
+            specs::load();
+            let count = refresh(cmd)?;
+            println!("completions: refreshed {cmd} ({count} entries)");
+            Ok(())
+        }
+        [action @ ("disable" | "enable"), cmd] => {
+            let mut off = disabled().lock().unwrap();
+            if *action == "disable" {
+                off.insert(cmd.to_string());
+                let _ = fs::remove_file(cache_dir().join(format!("{}.24", sanitize_filename(cmd))));
+            } else {
+                off.remove(*cmd);
+            }
+            let mut list: Vec<String> = off.iter().cloned().collect();
+            list.sort_unstable();
+            crate::config::set_option("completion", "disabled", crate::config::Value::List(list))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: 24! completions [list | refresh [cmd] | rescan | disable cmd | enable cmd]",
+        )),
+    }
+}
+
+// The current user's processes from /proc with their command names, by pid
+fn user_processes() -> Vec<(i32, String)> {
+    let uid = unsafe { libc::getuid() };
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut found: Vec<(i32, String)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let pid: i32 = entry.file_name().to_str()?.parse().ok()?;
+            if entry.metadata().ok()?.uid() != uid {
+                return None;
+            }
+            let comm = fs::read_to_string(entry.path().join("comm")).ok()?;
+            Some((pid, comm.trim_end().to_string()))
+        })
+        .collect();
+    found.sort();
+    found
+}
+
+/// Create default completer instance; `jobs` is the shell's job table
+pub fn create_default_completer(
+    config: CompletionConfig,
+    jobs: &'static Mutex<Vec<Job>>,
+) -> Box<dyn Completer> {
+    specs::load();
+    Box::new(MyCompleter::new(config, jobs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    // A completer that knows only `commands` and caches help in `cache_dir`
+    fn completer(commands: &[&str], cache_dir: &Path) -> MyCompleter {
+        MyCompleter {
+            commands: commands
+                .iter()
+                .map(|name| (name.to_string(), String::new()))
+                .collect(),
+            path: None,
+            cache_dir: cache_dir.to_path_buf(),
+            config: CompletionConfig::default(),
+            jobs: crate::jobs::table(),
+        }
+    }
+
+    #[test]
+    fn test_alias_completion() {
+        let dir = TempDir::new("complete-alias");
+        fs::write(dir.join("shesh-fake-git.24"), "checkout\nstatus\n").unwrap();
+        crate::builtins::set_alias("shesh-gs", "shesh-fake-git");
+        let mut completer = completer(&["shesh-fake-git"], &dir);
+        let values = |suggestions: Vec<Suggestion>| {
+            let mut values: Vec<String> = suggestions.into_iter().map(|s| s.value).collect();
+            values.sort();
+            values
+        };
+
+        assert_eq!(
+            values(completer.complete("shesh-", 6)),
+            ["shesh-fake-git", "shesh-gs"]
+        );
+        // The alias's command gives the subcommands, spanning what was typed
+        let suggestions = completer.complete("shesh-gs ", 9);
+        assert_eq!(suggestions[0].span, Span::new(9, 9));
+        assert_eq!(values(suggestions), ["checkout", "status"]);
+    }
+
+    #[test]
+    fn test_file_completion_settings() {
+        let dir = TempDir::new("complete");
+        for name in [".hidden", "Notes", "notes.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let base = format!("{}/", dir.display());
+        let names = |config: CompletionConfig, partial: &str| {
+            let completer = MyCompleter {
+                config,
+                ..completer(&[], &dir)
+            };
+            let word = format!("{base}{partial}");
+            let mut names: Vec<String> = completer
+                .complete_files(&word, Span::new(0, word.len()), None)
+                .into_iter()
+                .map(|s| s.value)
+                .collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(
+            names(CompletionConfig::default(), ""),
+            ["Notes", "notes.txt"]
+        );
+        assert_eq!(names(CompletionConfig::default(), "."), [".hidden"]);
+        let always = CompletionConfig {
+            hidden: HiddenFiles::Always,
+            ..CompletionConfig::default()
+        };
+        assert_eq!(names(always, "").len(), 3);
+        let never = CompletionConfig {
+            hidden: HiddenFiles::Never,
+            ..CompletionConfig::default()
+        };
+        assert!(names(never, ".").is_empty());
+        let ignore_case = CompletionConfig {
+            ignore_case: true,
+            ..CompletionConfig::default()
+        };
+        assert_eq!(names(ignore_case, "no"), ["Notes", "notes.txt"]);
+
+        assert_eq!(parse_ttl("7d"), Some(Some(Duration::from_secs(7 * 86400))));
+        assert_eq!(parse_ttl("never"), Some(None));
+        assert_eq!(parse_ttl("soon"), None);
+    }
+
+    #[test]
+    fn test_argument_filters() {
+        let dir = TempDir::new("complete-filter");
+        fs::create_dir_all(dir.join("build")).unwrap();
+        for name in ["a.txt", "b.zip", "c.tar.gz"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let mut config = CompletionConfig::default();
+        config.set_filter(
+            "unzip",
+            ArgFilter::Extensions(vec!["zip".into(), "txt".into()]),
+        );
+        let mut completer = MyCompleter {
+            config,
+            ..completer(&[], &dir)
+        };
+        let mut values = |line: String| -> Vec<String> {
+            let pos = line.len();
+            completer
+                .complete(&line, pos)
+                .into_iter()
+                .map(|s| s.value)
+                .collect()
+        };
+        let base = format!("{}/", dir.display());
+
+        assert_eq!(values(format!("cd {base}")), ["build/"]);
+        assert_eq!(values(format!("rmdir x {base}b")), ["build/"]);
+        assert_eq!(
+            values(format!("tar -xf {base}")),
+            ["c.tar.gz", "build/", "a.txt", "b.zip"]
+        );
+        // A config rule replaces the built-in one
+        assert_eq!(
+            values(format!("unzip {base}")),
+            ["a.txt", "b.zip", "build/", "c.tar.gz"]
+        );
+        // A path in command position only lists what can be run
+        fs::write(dir.join("run.sh"), "").unwrap();
+        fs::set_permissions(dir.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        let mut found = values(base.clone());
+        found.sort();
+        assert_eq!(found, ["build/", "run.sh"]);
+    }
+
+    #[test]
+    fn test_variable_completion() {
+        crate::builtins::set_var("SHESH_TEST_PREFIX", "/usr");
+        let mut completer = completer(&[], &env::temp_dir());
+
+        let line = "echo $SHESH_TEST_P";
+        let suggestions = completer.complete(line, line.len());
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].value, "$SHESH_TEST_PREFIX");
+        assert_eq!(suggestions[0].span, Span::new(5, line.len()));
+
+        // The flag before the variable stays
+        let line = "./configure --prefix=${SHESH_TEST_P";
+        let suggestions = completer.complete(line, line.len());
+        assert_eq!(suggestions[0].value, "${SHESH_TEST_PREFIX}");
+        assert_eq!(suggestions[0].span, Span::new(21, line.len()));
+
+        assert!(completer.complete("echo $shesh_test_p", 18).is_empty());
+        assert_eq!(variable_at_end("a$b/c"), None);
+    }
+
+    #[test]
+    fn test_completion_context() {
+        fn at(line: &str) -> (&str, &str, bool) {
+            let ctx = context(line);
+            (
+                &line[ctx.command..ctx.word],
+                &line[ctx.word..],
+                ctx.redirect,
+            )
+        }
+        assert_eq!(at("ls | gr"), (" ", "gr", false));
+        assert_eq!(at("make && ./run; ec"), (" ", "ec", false));
+        assert_eq!(at("a||b"), ("", "b", false));
+        assert_eq!(at("sleep 1 &vi"), ("", "vi", false));
+        assert_eq!(at("ls -l > fi"), ("ls -l > ", "fi", true));
+        assert_eq!(at("sort <in"), ("sort <", "in", true));
+        assert_eq!(at("cmd >> log ar"), ("cmd >> log ", "ar", false));
+        assert_eq!(at("make 2>&1 | le"), (" ", "le", false));
+        assert_eq!(at("make &>lo"), ("make &>", "lo", true));
+        assert_eq!(at("make &> log ar"), ("make &> log ", "ar", false));
+        assert_eq!(at("echo hi >|ou"), ("echo hi >|", "ou", true));
+        assert_eq!(at("cmd 2>er"), ("cmd 2>", "er", true));
+        // Operators inside quotes or escaped don't start a command
+        assert_eq!(at("echo 'a|b' x"), ("echo 'a|b' ", "x", false));
+        assert_eq!(at("echo a\\;b"), ("echo ", "a\\;b", false));
+
+        let mut completer = completer(&["shesh-grep", "shesh-ls"], &env::temp_dir());
+        let line = "shesh-ls|shesh-gr";
+        let suggestions = completer.complete(line, line.len());
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].value, "shesh-grep");
+        assert_eq!(suggestions[0].span, Span::new(9, line.len()));
+
+        let suggestions = completer.complete("24! comp", 8);
+        assert_eq!(suggestions[0].value, "completions");
+        assert_eq!(
+            suggestions[0].description.as_deref(),
+            Some("Manage the completion cache")
+        );
+    }
+
+    #[test]
+    fn test_wrapper_commands() {
+        let wrappers = CompletionConfig::default().wrappers;
+        let index = |line: &str| {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            command_index(&words, &wrappers)
+        };
+        assert_eq!(index("sudo systemctl"), 1);
+        assert_eq!(index("sudo -u root -E systemctl"), 4);
+        assert_eq!(index("env LANG=C FOO=1 sort -u"), 3);
+        assert_eq!(index("LANG=C nice nohup make"), 3);
+        assert_eq!(index("git -C sudo"), 0);
+        assert_eq!(index("sudo"), 1);
+
+        let dir = TempDir::new("complete-wrap");
+        fs::write(dir.join("shesh-fake-ctl.24"), "restart\nstatus\n").unwrap();
+        let mut completer = completer(&["shesh-fake-ctl"], &dir);
+        let line = "sudo shesh-fake-ctl res";
+        let suggestions = completer.complete(line, line.len());
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].value, "restart");
+        let line = "env A=1 shesh-fake-c";
+        assert_eq!(
+            completer.complete(line, line.len())[0].value,
+            "shesh-fake-ctl"
+        );
+    }
+
+    #[test]
+    fn test_quoted_completion() {
+        let dir = TempDir::new("complete-quote");
+        fs::create_dir_all(dir.join("a&b")).unwrap();
+        fs::write(dir.join("My Documents.txt"), "").unwrap();
+        fs::write(dir.join("a&b/it's"), "").unwrap();
+        let mut completer = completer(&[], &dir);
+        let base = format!("{}/", dir.display());
+        let mut complete = |word: &str| {
+            let line = format!("cat {base}{word}");
+            let suggestions = completer.complete(&line, line.len());
+            assert_eq!(suggestions.len(), 1, "{line}");
+            let suggestion = &suggestions[0];
+            let mut line = line.clone();
+            line.replace_range(
+                suggestion.span.start..suggestion.span.end,
+                &suggestion.value,
+            );
+            line[4 + base.len()..].to_string()
+        };
+
+        assert_eq!(complete("My"), "My\\ Documents.txt");
+        assert_eq!(complete("My\\ Doc"), "My\\ Documents.txt");
+        assert_eq!(complete("a"), "a\\&b/");
+        assert_eq!(complete("a\\&b/it"), "a\\&b/it\\'s");
+
+        let line = format!("cat \"{base}My Doc");
+        let suggestions = completer.complete(&line, line.len());
+        assert_eq!(suggestions[0].value, "My Documents.txt\"");
+        assert_eq!(suggestions[0].span.start, line.len() - "My Doc".len());
+        let line = format!("cat '{base}a&");
+        assert_eq!(completer.complete(&line, line.len())[0].value, "a&b/");
+
+        assert_eq!(unescape("My\\ Doc", None), "My Doc");
+        assert_eq!(unescape("a\\b\\\"", Some('"')), "a\\b\"");
+    }
+
+    #[test]
+    fn test_fuzzy_matching() {
+        assert!(fuzzy_score("docs-site", "dcs", false).is_some());
+        assert_eq!(fuzzy_score("docs-site", "xyz", false), None);
+        assert_eq!(fuzzy_score("Docs", "dc", false), None);
+        assert!(fuzzy_score("Docs", "dc", true).is_some());
+        // A run at word starts beats letters spread through the name
+        assert!(fuzzy_score("docs-site", "ds", false) > fuzzy_score("dashboards", "ds", false));
+
+        let dir = TempDir::new("complete-fuzzy");
+        for name in ["docs-site", "dist", "dcs.txt", "readme"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let completer = MyCompleter {
+            config: CompletionConfig {
+                fuzzy: true,
+                ..CompletionConfig::default()
+            },
+            ..completer(&[], &dir)
+        };
+        let names = |partial: &str| -> Vec<String> {
+            let word = format!("{}/{partial}", dir.display());
+            completer
+                .complete_files(&word, Span::new(0, word.len()), None)
+                .into_iter()
+                .map(|s| s.value)
+                .collect()
+        };
+        // A prefix match hides the fuzzy ones
+        assert_eq!(names("dcs"), ["dcs.txt"]);
+        assert_eq!(names("dst"), ["dcs.txt", "dist", "docs-site"]);
+        assert_eq!(names("rdm"), ["readme"]);
+    }
+
+    #[test]
+    fn test_stale_cache() {
+        let dir = TempDir::new("complete-stale");
+        let cache = dir.join("tool.24");
+        let binary = dir.join("tool");
+        let entries = HelpEntries {
+            subcommands: vec!["build".into(), "run".into()],
+            ..HelpEntries::default()
+        };
+        write_cache(&cache, &entries).unwrap();
+        fs::write(&binary, "").unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let set_age = |path: &Path, age: Duration| {
+            let file = fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(SystemTime::now() - age).unwrap();
+        };
+
+        set_age(&binary, 2 * day);
+        assert!(!is_stale(&cache, Some(&binary), Some(7 * day)));
+        assert!(!is_stale(&cache, None, None));
+        // Cached before the binary was upgraded
+        set_age(&cache, 3 * day);
+        assert!(is_stale(&cache, Some(&binary), None));
+        // Or just old
+        set_age(&cache, 8 * day);
+        assert!(is_stale(&cache, None, Some(7 * day)));
+        assert!(!is_stale(&cache, None, None));
+        assert_eq!(fs::read_to_string(&cache).unwrap(), "build\nrun\n");
+    }
+
+    #[test]
+    fn test_help_flags() {
+        let help = "Usage: tool [OPTIONS] <COMMAND>
+
+Commands:
+  build    Compile
+  run      Run it
+
+Options:
+  -o, --output <FILE>    Write to FILE
+  -q, --quiet            No output
+      --color[=WHEN]     Colorize
+      --jobs=N           Parallel jobs
+      --target TRIPLE    Build for TRIPLE
+  -h, --help             Print help
+";
+        let entries = HelpEntries::parse_help(help);
+        assert_eq!(entries.subcommands, ["build", "run"]);
+        assert_eq!(
+            entries.flags,
+            [
+                "--color",
+                "--help",
+                "--jobs=",
+                "--output=",
+                "--quiet",
+                "--target="
+            ]
+        );
+        assert_eq!(entries.description("build").as_deref(), Some("Compile"));
+        assert_eq!(
+            entries.description("--output=").as_deref(),
+            Some("Write to FILE")
+        );
+        assert_eq!(HelpEntries::parse_cache(&entries.format_cache()), entries);
+        // Caches from before flags were stored
+        assert_eq!(
+            HelpEntries::parse_cache("build\nrun\n").subcommands,
+            ["build", "run"]
+        );
+
+        let dir = TempDir::new("complete-flags");
+        fs::write(dir.join("shesh-fake-tool.24"), entries.format_cache()).unwrap();
+        let mut completer = completer(&[], &dir);
+        let line = "shesh-fake-tool --o";
+        let suggestions = completer.complete(line, line.len());
+        assert_eq!(suggestions[0].value, "--output=");
+        assert_eq!(suggestions[0].description.as_deref(), Some("Write to FILE"));
+        assert!(!suggestions[0].append_whitespace);
+        // Nothing cached and never run: its options complete to nothing. A
+        // command that isn't installed, so no help is cached after the test
+        let line = "shesh-fake-missing -";
+        assert!(completer.complete(line, line.len()).is_empty());
+        let line = "shesh-fake-tool --o";
+        completer.config.descriptions = false;
+        assert_eq!(completer.complete(line, line.len())[0].description, None);
+        assert_eq!(format_size(512), "512B");
+        assert_eq!(format_size(4300), "4.2K");
+        assert_eq!(format_size(17 << 20), "17M");
+    }
+
+    #[test]
+    fn test_remote_completion() {
+        let completer = completer(&[], &env::temp_dir());
+        let complete = |cmd: &str, word: &str| {
+            completer
+                .complete_remote(cmd, word, Span::new(10, 10 + word.len()), || {
+                    vec!["shesh-box".into()]
+                })
+                .map(|list| {
+                    list.into_iter()
+                        .map(|s| (s.value, s.span.start))
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        assert_eq!(
+            complete("ssh", "me@shesh"),
+            Some(vec![("shesh-box".into(), 13)])
+        );
+        assert_eq!(
+            complete("scp", "shesh-b"),
+            Some(vec![("shesh-box:".into(), 10)])
+        );
+        assert_eq!(complete("scp", "shesh-box:/etc/"), Some(vec![]));
+        assert_eq!(complete("rsync", "./"), None);
+        assert_eq!(complete("ssh", "-o"), None);
+        assert_eq!(complete("ls", "shesh"), None);
+    }
+
+    #[test]
+    fn test_process_completion() {
+        let jobs = Box::leak(Box::new(Mutex::new(vec![Job {
+            id: 2,
+            pgid: 400,
+            pids: vec![400],
+            last_pid: 400,
+            command: "sleep 100".into(),
+            state: crate::jobs::JobState::Stopped,
+            exit_code: 0,
+            nohup: false,
+        }])));
+        let completer = MyCompleter {
+            jobs,
+            ..completer(&[], &env::temp_dir())
+        };
+        let complete = |cmd: &str, word: &str| {
+            completer
+                .complete_process(cmd, word, Span::new(5, 5 + word.len()), || {
+                    vec![(41, "vim".into()), (412, "cargo".into())]
+                })
+                .map(|list| {
+                    list.into_iter()
+                        .map(|s| (s.value, s.description.unwrap_or_default()))
+                        .collect::<Vec<_>>()
+                })
+        };
+        let pair = |a: &str, b: &str| (a.to_string(), b.to_string());
+
+        assert_eq!(complete("fg", ""), Some(vec![pair("%2", "sleep 100")]));
+        assert_eq!(
+            complete("kill", "41"),
+            Some(vec![pair("41", "vim"), pair("412", "cargo")])
+        );
+        assert_eq!(complete("kill", "%"), Some(vec![pair("%2", "sleep 100")]));
+        assert_eq!(complete("kill", "-TE"), Some(vec![pair("-TERM", "signal")]));
+        assert_eq!(complete("kill", "-K").unwrap()[0].0, "-KILL");
+        assert_eq!(complete("ls", ""), None);
+        assert!(
+            user_processes()
+                .iter()
+                .any(|(pid, _)| *pid == std::process::id() as i32)
+        );
+    }
+
+    #[test]
+    fn test_history_arguments() {
+        let completer = completer(&[], &env::temp_dir());
+        let found = vec![Suggestion {
+            value: "web".into(),
+            ..Default::default()
+        }];
+        let values: Vec<String> = completer
+            .with_history("ssh", "w", Span::new(4, 5), found, |cmd| {
+                assert_eq!(cmd, "ssh");
+                vec!["web".into(), "build".into(), "www".into()]
+            })
+            .into_iter()
+            .map(|s| s.value)
+            .collect();
+        // Real matches stay first; history only adds what they didn't have
+        assert_eq!(values, ["web", "www"]);
+    }
+
+    #[test]
+    fn test_path_rescan() {
+        let dir = TempDir::new("path");
+        let (one, two) = (dir.join("one"), dir.join("two"));
+        fs::create_dir_all(&one).unwrap();
+        fs::create_dir_all(&two).unwrap();
+        let install = |path: PathBuf| {
+            fs::write(&path, "").unwrap();
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        install(one.join("shesh-a"));
+        fs::write(one.join("notes"), "").unwrap();
+
+        let mut scan = PathScan::default();
+        assert!(scan.update(Some(one.clone().into()), true));
+        assert!(scan.commands().contains_key("shesh-a"));
+        assert!(!scan.commands().contains_key("notes"));
+        assert!(!scan.update(Some(one.clone().into()), true));
+
+        // A new PATH entry
+        install(two.join("shesh-b"));
+        let var = env::join_paths([&one, &two]).unwrap();
+        assert!(scan.update(Some(var.clone()), true));
+        assert_eq!(scan.commands()["shesh-b"], two.display().to_string());
+
+        // A newly installed binary changes the directory's mtime
+        std::thread::sleep(Duration::from_millis(20));
+        install(two.join("shesh-c"));
+        assert!(scan.update(Some(var.clone()), true));
+        assert!(scan.commands().contains_key("shesh-c"));
+
+        // Nothing is read before the first prompt, only at the first Tab
+        let completer = MyCompleter::new(CompletionConfig::default(), crate::jobs::table());
+        assert_eq!(completer.commands.len(), BUILTINS.len());
+        assert!(completer.path.as_ref().is_some_and(|p| p.dirs.is_empty()));
+    }
+
+    #[test]
+    fn test_file_limit() {
+        let dir = TempDir::new("limit");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for i in 0..40 {
+            fs::write(dir.join(format!("f{i:02}")), "").unwrap();
+        }
+        let completer = MyCompleter {
+            config: CompletionConfig {
+                max_files: 5,
+                ..Default::default()
+            },
+            ..completer(&[], &env::temp_dir())
+        };
+        let base = format!("{}/", dir.display());
+        let values = |word: &str| -> Vec<String> {
+            completer
+                .complete_files(word, Span::new(0, word.len()), None)
+                .into_iter()
+                .map(|s| s.value)
+                .collect()
+        };
+
+        assert_eq!(values(&base).len(), 5);
+        let some = values(&format!("{base}f1"));
+        assert_eq!(some.len(), 5);
+        assert!(some.iter().all(|name| name.starts_with("f1")));
+        let everything = list_dir(&dir, usize::MAX, |name, is_dir| {
+            Some((name.to_string(), is_dir))
+        });
+        assert_eq!(everything.len(), 41);
+        assert!(everything.contains(&("sub".to_string(), true)));
+        assert_eq!(values(&format!("{base}f0")).len(), 5);
+
+        // A new file changes the directory's mtime
+        std::thread::sleep(Duration::from_millis(20));
+        fs::write(dir.join("g0"), "").unwrap();
+        assert_eq!(values(&format!("{base}g")), ["g0"]);
+    }
+
+    #[test]
+    fn test_mid_line_completion() {
+        assert_eq!(word_end("bar baz", None), 3);
+        assert_eq!(word_end("ar|wc", None), 2);
+        assert_eq!(word_end("le\\ name x", None), 8);
+        assert_eq!(word_end("b c\" d", Some('"')), 4);
+        assert_eq!(word_end("", None), 0);
+
+        let dir = TempDir::new("midline");
+        fs::write(dir.join("my file.txt"), "").unwrap();
+        fs::write(dir.join("notes.md"), "").unwrap();
+        let mut completer = completer(&[], &env::temp_dir());
+        let base = format!("{}/", dir.display());
+        let mut at = |line: &str, cursor: &str| -> Vec<(String, Span)> {
+            let pos = line.find(cursor).unwrap() + cursor.len();
+            completer
+                .complete(line, pos)
+                .into_iter()
+                .map(|s| (s.value, s.span))
+                .collect()
+        };
+
+        // The cursor in the middle of a word: all of it is replaced
+        let line = format!("cat {base}notx.md -n");
+        let start = 4 + base.len();
+        assert_eq!(
+            at(&line, "no"),
+            [("notes.md".into(), Span::new(start, start + 7))]
+        );
+        // After an escaped space
+        let line = format!("cat {base}my\\ f | wc");
+        assert_eq!(
+            at(&line, "my\\ f"),
+            [("my\\ file.txt".into(), Span::new(start, start + 5))]
+        );
+        // Right after an operator
+        let line = format!("echo hi>{base}no");
+        let start = 8 + base.len();
+        assert_eq!(
+            at(&line, &format!(">{base}no")),
+            [("notes.md".into(), Span::new(start, start + 2))]
+        );
+    }
+
+    #[test]
+    fn test_external_completer() {
+        let span = Span::new(4, 6);
+        let parsed = parse_external("status\tShow the tree\nmy file\nsrc/\t\n\n", span);
+        let values: Vec<_> = parsed
+            .iter()
+            .map(|s| {
+                (
+                    s.value.as_str(),
+                    s.description.as_deref(),
+                    s.append_whitespace,
+                )
+            })
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("status", Some("Show the tree"), true),
+                ("my\\ file", None, true),
+                ("src/", None, false),
+            ]
+        );
+        assert!(parsed.iter().all(|s| s.span == span));
+
+        let dir = TempDir::new("external");
+        fs::write(dir.join("shesh-fake-tool.24"), "build\nstatus\n").unwrap();
+        let mut completer = MyCompleter {
+            config: CompletionConfig {
+                external: Some(
+                    "printf 'status\\tfrom %s\\n' \"$COMP_POINT\"; \
+                     [ \"$COMP_LINE\" = 'shesh-fake-tool ' ] && echo deploy"
+                        .into(),
+                ),
+                ..CompletionConfig::default()
+            },
+            ..completer(&["shesh-fake-tool"], &dir)
+        };
+        let complete = |completer: &mut MyCompleter| -> Vec<(String, Option<String>)> {
+            completer
+                .complete("shesh-fake-tool ", 16)
+                .into_iter()
+                .map(|s| (s.value, s.description))
+                .collect()
+        };
+        // The program's candidates come first and win over the same value
+        assert_eq!(
+            complete(&mut completer),
+            [
+                ("status".into(), Some("from 16".into())),
+                ("deploy".into(), None),
+                ("build".into(), None),
+            ]
+        );
+        completer.config.external_override = true;
+        assert_eq!(complete(&mut completer).len(), 2);
+        // A program that hangs is given up on
+        completer.config.external = Some("sleep 5".into());
+        completer.config.external_timeout = Duration::from_millis(50);
+        let start = std::time::Instant::now();
+        assert_eq!(complete(&mut completer).len(), 2);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
 }
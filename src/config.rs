@@ -1,126 +1,1729 @@
+use crate::{
+    completions::{CompletionConfig, HiddenFiles},
+    hinter::{HinterConfig, Source},
+    keys::Binding,
+    prompt::{PromptConfig, Shorten},
+    theme::Theme,
+    utils::atomic_write,
+};
 use std::{
-    env,
-    fs::{self, OpenOptions, create_dir_all},
-    io::Write,
+    env, fmt,
+    fs::{self, DirBuilder, create_dir_all},
+    io,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
     path::{Path, PathBuf},
-    process::exit,
+    sync::OnceLock,
+    time::Duration,
 };
 
 pub struct Config {
-    pub prompt: Option<String>,
+    pub prompt: PromptConfig,
     pub startup: Vec<String>,
+    pub max_depth: u32,
+    pub hints: bool,
+    pub hinter: HinterConfig,
+    pub quiet_background: bool,
+    // Send SIGHUP to the jobs left running on exit
+    pub hup_on_exit: bool,
+    // `cd foo` jumps to a visited directory matching foo when there's no ./foo
+    pub cd_jump: bool,
+    // Terminal title updates; None decides from $TERM
+    pub title: Option<bool>,
+    // OSC 133 prompt marks and OSC 7 directory reports; None decides from $TERM
+    pub integration: Option<bool>,
+    pub vi_mode: bool,
+    // Ctrl-C empties the line instead of leaving it on screen with ^C
+    pub clear_on_ctrl_c: bool,
+    pub theme: Option<String>,
+    pub themes: Vec<Theme>,
+    pub history: crate::history::Options,
+    pub completion: CompletionConfig,
+    pub aliases: Vec<(String, String)>,
+    // Variables to export, in file order so later ones can use earlier ones
+    pub env: Vec<(String, String)>,
+    pub keybindings: Vec<Binding>,
+    pub warnings: Vec<Warning>,
+    // The config file and the files it includes, in the order they were read
+    pub files: Vec<PathBuf>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            prompt: Some("#shesh> ".to_string()),
+            prompt: PromptConfig::default(),
             startup: vec![],
+            max_depth: 8,
+            hints: true,
+            hinter: HinterConfig::default(),
+            quiet_background: false,
+            hup_on_exit: true,
+            cd_jump: true,
+            title: None,
+            integration: None,
+            vi_mode: false,
+            clear_on_ctrl_c: false,
+            theme: None,
+            themes: vec![],
+            history: crate::history::Options::default(),
+            completion: CompletionConfig::default(),
+            aliases: vec![],
+            env: vec![],
+            keybindings: vec![],
+            warnings: vec![],
+            files: vec![],
         }
     }
 }
 
-pub fn get_home() -> PathBuf {
-    env::var("HOME").map(PathBuf::from).unwrap_or_else(|_| {
-        eprintln!("can't find the home dir");
-        exit(1)
-    })
+/// A config line that was skipped, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub line: usize,
+    pub text: String,
+    pub reason: String,
+    // The included file the line is in; None for the config file itself
+    pub file: Option<PathBuf>,
 }
 
-pub fn get_config() -> PathBuf {
-    env::var_os("XDG_CONFIG_HOME")
+impl Warning {
+    fn new(line: usize, text: &str, reason: impl Into<String>) -> Self {
+        Self {
+            line,
+            text: text.trim().to_string(),
+            reason: reason.into(),
+            file: None,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+        write!(f, "line {}: {} (`{}`)", self.line, self.reason, self.text)
+    }
+}
+
+// An XDG base directory: `$var` when it holds an absolute path, else
+// `~/fallback`, else a per-user directory under /tmp when HOME is unset
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    env::var_os(var)
         .map(PathBuf::from)
-        .unwrap_or_else(|| get_home().join(".config"))
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| {
+            let home = env::var_os("HOME").filter(|home| !home.is_empty())?;
+            Some(PathBuf::from(home).join(fallback))
+        })
+        .unwrap_or_else(temp_home)
+}
+
+static TEMP_HOME: OnceLock<PathBuf> = OnceLock::new();
+
+// /tmp/shesh-<uid>, made 0700. Anyone can create that name first, so a
+// directory there that isn't this user's and private is refused for a
+// numbered one of this process's own
+fn temp_home() -> PathBuf {
+    TEMP_HOME
+        .get_or_init(|| {
+            let uid = unsafe { libc::getuid() };
+            let shared = env::temp_dir().join(format!("shesh-{uid}"));
+            if make_private_dir(&shared, uid) {
+                return shared;
+            }
+            crate::output::warn(&format!(
+                "{} is not a private directory of this user; not using it",
+                shared.display()
+            ));
+            (0..10)
+                .map(|n| env::temp_dir().join(format!("shesh-{uid}-{}-{n}", std::process::id())))
+                .find(|dir| make_private_dir(dir, uid))
+                .unwrap_or_else(|| PathBuf::from("/nonexistent"))
+        })
+        .clone()
+}
+
+// Creates `dir` with mode 0700 unless it exists, then whether it is a real
+// directory owned by `uid` that no one else can get into
+fn make_private_dir(dir: &Path, uid: libc::uid_t) -> bool {
+    let _ = DirBuilder::new().mode(0o700).create(dir);
+    fs::symlink_metadata(dir)
+        .is_ok_and(|meta| meta.is_dir() && meta.uid() == uid && meta.mode() & 0o077 == 0)
+}
+
+pub fn get_config() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
 }
 
-// pub fn get_cache() -> PathBuf {
-//     env::var_os("XDG_CACHE_HOME")
-//         .map(PathBuf::from)
-//         .unwrap_or_else(|| get_home().join(".cache"))
-// }
+pub fn data_dir() -> PathBuf {
+    xdg_dir("XDG_DATA_HOME", ".local/share").join("shesh")
+}
+
+pub fn state_dir() -> PathBuf {
+    xdg_dir("XDG_STATE_HOME", ".local/state").join("shesh")
+}
+
+pub fn cache_dir() -> PathBuf {
+    xdg_dir("XDG_CACHE_HOME", ".cache").join("shesh")
+}
+
+// shesh.toml, or the old shesh.24 while there is no shesh.toml next to it
+fn config_in(dir: &Path) -> PathBuf {
+    let toml = dir.join("shesh.toml");
+    let legacy = dir.join("shesh.24");
+    if !toml.exists() && legacy.exists() {
+        legacy
+    } else {
+        toml
+    }
+}
 
 pub fn config_file_path() -> PathBuf {
-    get_config().join("shesh").join("shesh.24")
+    config_in(&get_config().join("shesh"))
+}
+
+fn is_legacy(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "24")
+}
+
+// History belongs in the state directory, but a file shesh already keeps
+// in the data directory stays in use until it is moved
+fn history_in(data: &Path, state: &Path) -> PathBuf {
+    let old = data.join("history");
+    let new = state.join("history");
+    if !new.exists() && old.exists() {
+        old
+    } else {
+        new
+    }
 }
 
 pub fn history_file_path() -> PathBuf {
-    get_home().join(".local/share/shesh/history")
+    history_in(&data_dir(), &state_dir())
 }
 
+// Written on first run, and again if the config turns out empty
+const DEFAULT_CONFIG: &str = r##"# shesh configuration. Commented lines show the defaults.
+
+# include = ["prompts/fancy.toml"]  # other files, relative to this directory
+
+[prompt]
+# format = "%u@%h %d> " # a fixed prompt, $VARS and % placeholders are expanded
+# style = "path"        # path, full, minimal or two-line (see `24! prompt`)
+# theme = "dark"        # dark, light or one of the [theme.NAME] sections
+# color = "green"       # path color, the theme's when unset
+# separator = "/"       # between directories
+# suffix = "> "         # after the path
+# shorten = "letter"    # ~/p/shesh; or "unique" ~/pro/shesh, "last" …/shesh, "full"
+# keep_full = 1         # trailing directories never shortened
+# home_tilde = true     # show the home directory as ~
+# git = true            # the git branch after the path, like (main)
+# git_dirty = true      # mark changed files with (main*); skipped in huge repos
+# indicators = ["venv", "conda", "node", "rust"]  # (venv) before the path
+# user_host = true      # user@host in front over SSH or as root (red)
+# status = true         # a red suffix after a failed command
+# status_code = true    # followed by its code, ✗2, or signal, ✗INT
+# took_min = "3s"       # show `took 2m14s` after slower commands, or never
+# took_position = "left" # or "right", after the right prompt
+# transient = false     # redraw a prompt as `❯ ` once its command runs
+# search = "search: "   # in front of the line while Ctrl-R searches history
+# command_timeout_ms = 100  # $(...) in format or right is killed after this
+# command_cache_secs = 5    # and its output reused for this long
+# command = "starship prompt --status=$STATUS --cmd-duration=$CMD_DURATION --jobs=$JOBS"
+#                       # a program printing the whole prompt, with STATUS,
+#                       # CMD_DURATION (ms) and JOBS set for it
+# right_command = "starship prompt --right --status=$STATUS"
+# external_timeout_ms = 500 # the built-in prompt is drawn when they take longer
+# right = ""            # right-side prompt, e.g. "[%?] %T"
+# right_min_width = 0   # hide the right prompt in narrower terminals
+
+# [theme.ocean]
+# path = "cyan"         # a color name, "#rrggbb" or 0-255
+# error = "#ff8800"     # also vi_normal, vi_insert and hint
+
+[shell]
+# max_depth = 8         # refuse to start shesh nested deeper than this
+# hints = true          # suggest fixes when a command fails
+# quiet_background = false
+# hup_on_exit = true    # hang up running jobs on exit; `disown -h` spares one
+# cd_jump = true        # `cd foo` goes to the best visited dir matching foo
+# title = "auto"        # set the terminal title; auto: in graphical terminals
+# integration = "auto"  # mark prompts and report the directory to the terminal
+# edit_mode = "emacs"   # or "vi"; `24! vim_keys` still toggles
+# ctrl_c = "abort-line" # leave the line on screen with ^C; "clear" empties it
+
+[hinter]
+# enabled = true        # the grey suggestion from history while typing
+# source = "recent"     # or "frequent": the most used matching line
+# min_chars = 1         # typed characters before suggesting
+# color = "gray"        # the theme's hint color when unset
+# italic = true
+# underline = true
+
+[history]
+# size = 6000           # entries kept in memory
+# file = "~/.local/state/shesh/history"
+# ignore_dups = true    # skip a line that repeats the previous one
+# ignore_space = false  # keep lines starting with a space out of history
+# erase_dups = false    # drop earlier copies of a line entered again
+# ignore = ["ls", "cd *", "export *TOKEN*"]
+#                       # lines never recorded, matching the whole line
+# expansion = true      # !!, !N, !prefix, !$ and !* in typed lines
+# record_failed = true  # also keep lines that exited non-zero
+# ignore_not_found = false  # drop lines whose command wasn't found (typos)
+# share = true          # pick up lines from other running sessions
+# enabled = true        # false: nothing is read from or written to the file
+
+[completion]
+# disabled = ["ssh", "scp", "sftp", "telnet", "ftp", "su", "passwd", "rm", "dd",
+#             "mkfs", "shutdown", "reboot", "poweroff", "halt", "kill", "killall"]
+#                       # commands whose --help is never run
+# cache_dir = "~/.cache/shesh/completions"
+# cache_ttl = "7d"      # or 90s, 30m, 12h, never: when to read --help again
+#                       # (also once the command is upgraded)
+# run_help = true       # false: never run `cmd --help` to find subcommands
+# hidden = "smart"      # dotfiles: "always", "never" or once you type a dot
+# ignore_case = false
+# fuzzy = false         # `dcs` finds docs-site when nothing starts with it
+# column_width = 20     # of the completion menu
+# descriptions = true   # sizes, PATH dirs, --help text; one column when shown
+# wrappers = ["sudo", "doas", "env", "nohup", "time", "nice", "xargs"]
+# executables_only = true  # false: commands and ./paths without an execute bit too
+# max_files = 500       # file matches listed at most, for huge directories
+# external = "carapace _carapace shesh"  # prints value<TAB>description lines
+#                       # for the line in $COMP_LINE, cursor at $COMP_POINT
+# external_mode = "merge"  # its candidates first; "override": only them when
+#                       # it has any
+# external_timeout_ms = 200  # the built-in candidates alone when it's slower
+
+[completion.filters]
+# What arguments complete to; cd, pushd, popd and rmdir already take only
+# directories, unzip, tar and gunzip list their archives first
+# mkcd = "dirs"
+# 7z = ["7z", "zip"]
+
+[aliases]
+# ll = "ls -la"
+
+[env]
+# PATH = "$HOME/bin:$PATH"
+
+[keybindings]
+# ctrl-l = "clear_screen"   # `24! keys` lists what is bound
+# f1 = "history_menu"
+
+[startup]
+commands = ['echo "shesh ready!"']
+"##;
+
 //config file
 pub fn init() -> Config {
     let config_path = config_file_path();
 
-    if let Some(parent) = config_path.parent() {
-        let _ = create_dir_all(parent);
+    if !config_path.exists() {
+        write_default_config(&config_path);
     }
+    let config = load_config(&config_path);
+    apply_definitions(&config);
+    config
+}
 
-    if !config_path.exists() {
-        fs::write(
-            &config_path,
-            "#prompt = \"shesh> \"\n#startup\necho \"shesh ready!\"",
-        )
-        .expect("Unable to creat config file")
+fn write_default_config(path: &Path) {
+    if let Some(parent) = path.parent() {
+        let _ = create_dir_all(parent);
+    }
+    if let Err(e) = atomic_write(path, DEFAULT_CONFIG.as_bytes()) {
+        crate::output::fail(&format!(
+            "Failed to write config file {}: {e}",
+            path.display()
+        ));
     }
-    load_config(&config_path)
 }
 
 pub fn load_config(path: &Path) -> Config {
-    parse_config(&fs::read_to_string(path).expect("Unable to load a config file"))
+    match fs::read_to_string(path) {
+        Ok(content) if !content.trim().is_empty() => {
+            let config = parse_config(path, &content);
+            print_warnings(path, &config.warnings);
+            config
+        }
+        Ok(_) => {
+            // Most likely a write that was cut short, start over from the defaults
+            let fresh = path.with_file_name("shesh.toml");
+            crate::output::warn(&format!(
+                "Config file {} is empty, writing defaults to {}",
+                path.display(),
+                fresh.display()
+            ));
+            write_default_config(&fresh);
+            parse_toml(DEFAULT_CONFIG)
+        }
+        Err(e) => {
+            crate::output::warn(&format!(
+                "Unable to load config file {}: {e}, using defaults",
+                path.display()
+            ));
+            parse_toml(DEFAULT_CONFIG)
+        }
+    }
+}
+
+// In yellow, once per load: at startup and on `24! reload`
+fn print_warnings(path: &Path, warnings: &[Warning]) {
+    for warning in warnings {
+        let msg = match warning.file {
+            Some(_) => warning.to_string(),
+            None => format!("{}: {warning}", path.display()),
+        };
+        crate::output::warn(&crate::output::paint(nu_ansi_term::Color::Yellow, &msg));
+    }
+}
+
+/// Parses the config file at `path` along with every file it includes.
+pub fn parse_config(path: &Path, content: &str) -> Config {
+    let mut config = Config::default();
+    let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    Loader { dir, stack: vec![] }.read(&mut config, path, content);
+    config
+}
+
+// Includes nested deeper than this are skipped
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+// Reads a config file into `config`, and every file it includes at the
+// point of the include, so whatever is read later overrides earlier keys
+struct Loader {
+    // Include paths are relative to the config directory
+    dir: PathBuf,
+    // The files being read, outermost first, to catch cycles
+    stack: Vec<PathBuf>,
+}
+
+type Include<'a> = dyn FnMut(&mut Config, &str) -> Result<(), String> + 'a;
+
+impl Loader {
+    fn read(&mut self, config: &mut Config, path: &Path, content: &str) {
+        self.stack
+            .push(fs::canonicalize(path).unwrap_or(path.to_path_buf()));
+        config.files.push(path.to_path_buf());
+        let mut include = |config: &mut Config, target: &str| self.include(config, target);
+        if is_legacy(path) {
+            read_legacy(config, content, &mut include);
+        } else {
+            read_toml(config, content, &mut include);
+        }
+        self.stack.pop();
+    }
+
+    fn include(&mut self, config: &mut Config, target: &str) -> Result<(), String> {
+        let path = self.dir.join(crate::utils::expand_tilde(target));
+        if self.stack.len() > MAX_INCLUDE_DEPTH {
+            return Err(format!(
+                "include {target}: more than {MAX_INCLUDE_DEPTH} includes deep"
+            ));
+        }
+        let content =
+            fs::read_to_string(&path).map_err(|e| format!("include {}: {e}", path.display()))?;
+        if fs::canonicalize(&path).is_ok_and(|real| self.stack.contains(&real)) {
+            return Err(format!("include {}: already being read", path.display()));
+        }
+
+        let start = config.warnings.len();
+        self.read(config, &path, &content);
+        for warning in &mut config.warnings[start..] {
+            warning.file.get_or_insert_with(|| path.clone());
+        }
+        Ok(())
+    }
+}
+
+/// A value in the TOML subset shesh reads.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<String>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "a string",
+            Value::Int(_) => "an integer",
+            Value::Bool(_) => "true or false",
+            Value::List(_) => "a list of strings",
+        }
+    }
+
+    fn to_toml(&self) -> String {
+        match self {
+            Value::Str(s) => quote(s),
+            Value::Int(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::List(items) => format!(
+                "[{}]",
+                items
+                    .iter()
+                    .map(|s| quote(s))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    // How the same setting reads in a shesh.24 line
+    fn to_legacy(&self) -> String {
+        match self {
+            Value::Str(s) => s.clone(),
+            Value::List(items) => items.join(" "),
+            other => other.to_toml(),
+        }
+    }
+}
+
+fn quote(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    format!("\"{escaped}\"")
+}
+
+// One `key = value` line; `section` is the last [header] above it
+struct Entry {
+    line: usize,
+    text: String,
+    section: String,
+    key: String,
+    value: Value,
+}
+
+// Reads a string starting at the opening quote, returns it and the rest
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    let quote = s.chars().next()?;
+    let mut out = String::new();
+    let mut chars = s[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return Some((out, &s[1 + i + 1..])),
+            '\\' if quote == '"' => match chars.next()?.1 {
+                'n' => out.push('\n'),
+                't' => out.push('\t'),
+                'e' => out.push('\x1b'),
+                other => out.push(other),
+            },
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+fn parse_value(raw: &str) -> Result<Value, String> {
+    let raw = raw.trim();
+    match raw.chars().next() {
+        Some('"' | '\'') => match parse_string(raw) {
+            Some((s, rest)) if rest.trim().is_empty() => Ok(Value::Str(s)),
+            _ => Err(format!("malformed string {raw}")),
+        },
+        Some('[') => {
+            let mut items = Vec::new();
+            let mut rest = raw[1..].trim_start();
+            loop {
+                if let Some(after) = rest.strip_prefix(']') {
+                    if !after.trim().is_empty() {
+                        return Err(format!("unexpected text after list: {after}"));
+                    }
+                    return Ok(Value::List(items));
+                }
+                let (item, after) = parse_string(rest)
+                    .ok_or_else(|| format!("lists may only hold strings: {raw}"))?;
+                items.push(item);
+                rest = after.trim_start();
+                rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+            }
+        }
+        _ => match raw {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => raw
+                .replace('_', "")
+                .parse()
+                .map(Value::Int)
+                .map_err(|_| format!("can't read value {raw} (strings need quotes)")),
+        },
+    }
+}
+
+// Index of a `#` that starts a comment, i.e. one outside quotes
+fn comment_start(line: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote == Some('"') => escaped = true,
+            '"' | '\'' if quote.is_none() => quote = Some(c),
+            _ if quote == Some(c) => quote = None,
+            '#' if quote.is_none() => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+// True while `[` of a list outnumber the `]`, so the list goes on next line
+fn list_is_open(value: &str) -> bool {
+    let mut depth = 0;
+    let mut quote = None;
+    for c in value.chars() {
+        match c {
+            '"' | '\'' if quote.is_none() => quote = Some(c),
+            _ if quote == Some(c) => quote = None,
+            '[' if quote.is_none() => depth += 1,
+            ']' if quote.is_none() => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+// Splits the file into entries; lines that can't be read become problems
+fn parse_entries(content: &str) -> (Vec<Entry>, Vec<Warning>) {
+    let mut entries = Vec::new();
+    let mut problems = Vec::new();
+    let mut section = String::new();
+    let mut lines = content.lines().enumerate();
+
+    while let Some((n, raw)) = lines.next() {
+        let line = raw[..comment_start(raw).unwrap_or(raw.len())].trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            match header.strip_suffix(']') {
+                Some(name) => section = name.trim().to_string(),
+                None => problems.push(Warning::new(n + 1, raw, "malformed section header")),
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            problems.push(Warning::new(n + 1, raw, "expected key = value"));
+            continue;
+        };
+
+        // A dotted key like `history.enabled` reaches into a sub-table
+        let key = key.trim();
+        let (table, key) = match key.chars().next() {
+            Some('"' | '\'') => (
+                section.clone(),
+                parse_string(key).map(|(k, _)| k).unwrap_or_default(),
+            ),
+            _ => match key.rsplit_once('.') {
+                Some((prefix, key)) if section.is_empty() => (prefix.to_string(), key.to_string()),
+                Some((prefix, key)) => (format!("{section}.{prefix}"), key.to_string()),
+                None => (section.clone(), key.to_string()),
+            },
+        };
+        let mut value = value.trim().to_string();
+        while list_is_open(&value) {
+            let Some((_, more)) = lines.next() else {
+                break;
+            };
+            value.push(' ');
+            value.push_str(more[..comment_start(more).unwrap_or(more.len())].trim());
+        }
+
+        match parse_value(&value) {
+            Ok(value) => entries.push(Entry {
+                line: n + 1,
+                text: raw.trim().to_string(),
+                section: table,
+                key,
+                value,
+            }),
+            Err(e) => problems.push(Warning::new(n + 1, raw, e)),
+        }
+    }
+    (entries, problems)
+}
+
+/// The `key = value` lines of another file in the same TOML subset, as
+/// (section, key, value); the first line that can't be read is the error.
+pub fn read_table(content: &str) -> Result<Vec<(String, String, Value)>, String> {
+    let (entries, problems) = parse_entries(content);
+    if let Some(problem) = problems.first() {
+        return Err(problem.to_string());
+    }
+    Ok(entries
+        .into_iter()
+        .map(|entry| (entry.section, entry.key, entry.value))
+        .collect())
+}
+
+// Applies one entry, or says why it doesn't fit
+fn apply(config: &mut Config, entry: Entry) -> Result<(), String> {
+    let Entry {
+        section,
+        key,
+        value,
+        ..
+    } = entry;
+    let wrong_type = |expected: &str, value: &Value| {
+        Err(format!(
+            "{section}.{key} should be {expected}, not {}",
+            value.type_name()
+        ))
+    };
+
+    match (section.as_str(), key.as_str(), value) {
+        ("prompt", "format", Value::Str(s)) => config.prompt.format = Some(s),
+        ("prompt", "style", Value::Str(s)) => config.prompt.style = Some(s),
+        ("prompt", "theme", Value::Str(s)) => config.theme = Some(s),
+        ("prompt", "color", Value::Str(s)) => {
+            config.prompt.color = Some(
+                crate::theme::parse_color(&s)
+                    .ok_or_else(|| format!("prompt.color: unknown color \"{s}\""))?,
+            )
+        }
+        ("prompt", "separator", Value::Str(s)) => config.prompt.separator = s,
+        ("prompt", "right", Value::Str(s)) => config.prompt.right = s,
+        ("prompt", "command", Value::Str(s)) => config.prompt.command = Some(s),
+        ("prompt", "right_command", Value::Str(s)) => config.prompt.right_command = Some(s),
+        ("prompt", "took_min", Value::Str(s)) => {
+            config.prompt.took_min = crate::completions::parse_ttl(&s).ok_or_else(|| {
+                format!("prompt.took_min \"{s}\" should look like 3s, 1m or never")
+            })?
+        }
+        ("prompt", "took_position", Value::Str(s)) => {
+            config.prompt.took_right = match s.as_str() {
+                "left" => false,
+                "right" => true,
+                _ => {
+                    return Err(format!(
+                        "prompt.took_position should be \"left\" or \"right\", not \"{s}\""
+                    ));
+                }
+            }
+        }
+        ("prompt", "suffix", Value::Str(s)) => config.prompt.suffix = s,
+        ("prompt", "shorten", Value::Bool(b)) => {
+            config.prompt.shorten = if b { Shorten::Letter } else { Shorten::Full }
+        }
+        ("prompt", "shorten", Value::Str(s)) => {
+            config.prompt.shorten = Shorten::parse(&s).ok_or_else(|| {
+                format!(
+                    "prompt.shorten should be \"full\", \"last\", \"letter\" or \"unique\", not \"{s}\""
+                )
+            })?
+        }
+        ("prompt", "shorten", v) => return wrong_type("a string or true or false", &v),
+        ("prompt", "home_tilde", Value::Bool(b)) => config.prompt.home_tilde = b,
+        ("prompt", "git", Value::Bool(b)) => config.prompt.git = b,
+        ("prompt", "git_dirty", Value::Bool(b)) => config.prompt.git_dirty = b,
+        ("prompt", "status", Value::Bool(b)) => config.prompt.status = b,
+        ("prompt", "status_code", Value::Bool(b)) => config.prompt.status_code = b,
+        ("prompt", "user_host", Value::Bool(b)) => config.prompt.user_host = b,
+        ("prompt", "transient", Value::Bool(b)) => config.prompt.transient = b,
+        ("prompt", "search", Value::Str(s)) => config.prompt.search = s,
+        ("prompt", "command_timeout_ms", Value::Int(n)) => {
+            config.prompt.command_timeout = Duration::from_millis(
+                u64::try_from(n)
+                    .map_err(|_| format!("prompt.command_timeout_ms {n} is out of range"))?,
+            )
+        }
+        ("prompt", "command_cache_secs", Value::Int(n)) => {
+            config.prompt.command_cache = Duration::from_secs(
+                u64::try_from(n)
+                    .map_err(|_| format!("prompt.command_cache_secs {n} is out of range"))?,
+            )
+        }
+        ("prompt", "external_timeout_ms", Value::Int(n)) => {
+            config.prompt.external_timeout = Duration::from_millis(
+                u64::try_from(n)
+                    .map_err(|_| format!("prompt.external_timeout_ms {n} is out of range"))?,
+            )
+        }
+        ("prompt", "command_timeout_ms" | "command_cache_secs" | "external_timeout_ms", v) => {
+            return wrong_type("an integer", &v);
+        }
+        ("prompt", "indicators", Value::List(names)) => {
+            if let Some(name) = names
+                .iter()
+                .find(|name| !crate::prompt::INDICATORS.contains(&name.as_str()))
+            {
+                return Err(format!(
+                    "prompt.indicators: unknown indicator \"{name}\" ({})",
+                    crate::prompt::INDICATORS.join(", ")
+                ));
+            }
+            config.prompt.indicators = names
+        }
+        ("prompt", "indicators", v) => return wrong_type("a list of strings", &v),
+        ("prompt", "keep_full", Value::Int(n)) => {
+            config.prompt.keep_full =
+                usize::try_from(n).map_err(|_| format!("prompt.keep_full {n} is out of range"))?
+        }
+        ("prompt", "right_min_width", Value::Int(n)) => {
+            config.prompt.right_min_width = usize::try_from(n)
+                .map_err(|_| format!("prompt.right_min_width {n} is out of range"))?
+        }
+        (
+            "prompt",
+            "format" | "style" | "theme" | "color" | "separator" | "suffix" | "right" | "took_min"
+            | "took_position" | "command" | "right_command",
+            v,
+        ) => {
+            return wrong_type("a string", &v);
+        }
+        (
+            "prompt",
+            "home_tilde" | "git" | "git_dirty" | "status" | "status_code" | "user_host" | "transient",
+            v,
+        ) => {
+            return wrong_type("true or false", &v);
+        }
+        ("prompt", "keep_full" | "right_min_width", v) => return wrong_type("an integer", &v),
+
+        ("shell", "max_depth", Value::Int(n)) => {
+            config.max_depth =
+                u32::try_from(n).map_err(|_| format!("max_depth {n} is out of range"))?
+        }
+        ("shell", "hints", Value::Bool(b)) => config.hints = b,
+        ("shell", "quiet_background", Value::Bool(b)) => config.quiet_background = b,
+        ("shell", "hup_on_exit", Value::Bool(b)) => config.hup_on_exit = b,
+        ("shell", "cd_jump", Value::Bool(b)) => config.cd_jump = b,
+        ("shell", "title", Value::Bool(b)) => config.title = Some(b),
+        ("shell", "title", Value::Str(s)) if s == "auto" => config.title = None,
+        ("shell", "title", v) => return wrong_type("true, false or \"auto\"", &v),
+        ("shell", "integration", Value::Bool(b)) => config.integration = Some(b),
+        ("shell", "integration", Value::Str(s)) if s == "auto" => config.integration = None,
+        ("shell", "integration", v) => return wrong_type("true, false or \"auto\"", &v),
+        ("shell", "edit_mode", Value::Str(mode)) => {
+            config.vi_mode = parse_edit_mode(&mode).ok_or_else(|| {
+                format!("shell.edit_mode should be \"vi\" or \"emacs\", not \"{mode}\"")
+            })?
+        }
+        ("shell", "edit_mode", v) => return wrong_type("a string", &v),
+        ("shell", "ctrl_c", Value::Str(mode)) => {
+            config.clear_on_ctrl_c = match mode.as_str() {
+                "abort-line" => false,
+                "clear" => true,
+                _ => {
+                    return Err(format!(
+                        "shell.ctrl_c should be \"abort-line\" or \"clear\", not \"{mode}\""
+                    ));
+                }
+            }
+        }
+        ("shell", "ctrl_c", v) => return wrong_type("a string", &v),
+        ("shell", "max_depth", v) => return wrong_type("an integer", &v),
+        ("shell", "hints" | "quiet_background" | "hup_on_exit" | "cd_jump", v) => {
+            return wrong_type("true or false", &v);
+        }
+
+        ("hinter", "enabled", Value::Bool(b)) => config.hinter.enabled = b,
+        ("hinter", "italic", Value::Bool(b)) => config.hinter.italic = b,
+        ("hinter", "underline", Value::Bool(b)) => config.hinter.underline = b,
+        ("hinter", "color", Value::Str(s)) => {
+            config.hinter.color = Some(
+                crate::theme::parse_color(&s)
+                    .ok_or_else(|| format!("hinter.color: unknown color \"{s}\""))?,
+            )
+        }
+        ("hinter", "min_chars", Value::Int(n)) => {
+            config.hinter.min_chars =
+                usize::try_from(n).map_err(|_| format!("hinter.min_chars {n} is out of range"))?
+        }
+        ("hinter", "source", Value::Str(s)) => {
+            config.hinter.source = match s.as_str() {
+                "recent" => Source::Recent,
+                "frequent" => Source::Frequent,
+                _ => {
+                    return Err(format!(
+                        "hinter.source should be \"recent\" or \"frequent\", not \"{s}\""
+                    ));
+                }
+            }
+        }
+        ("hinter", "enabled" | "italic" | "underline", v) => {
+            return wrong_type("true or false", &v);
+        }
+        ("hinter", "color" | "source", v) => return wrong_type("a string", &v),
+        ("hinter", "min_chars", v) => return wrong_type("an integer", &v),
+
+        ("history", "size", Value::Int(n)) => {
+            config.history.size =
+                usize::try_from(n).map_err(|_| format!("history size {n} is out of range"))?
+        }
+        ("history", "file", Value::Str(s)) => {
+            config.history.file = Some(crate::utils::expand_tilde(&s))
+        }
+        ("history", "ignore_dups", Value::Bool(b)) => config.history.ignore_dups = b,
+        ("history", "ignore_space", Value::Bool(b)) => config.history.ignore_space = b,
+        ("history", "erase_dups", Value::Bool(b)) => config.history.erase_dups = b,
+        ("history", "expansion", Value::Bool(b)) => config.history.expansion = b,
+        ("history", "record_failed", Value::Bool(b)) => config.history.record_failed = b,
+        ("history", "share", Value::Bool(b)) => config.history.share = b,
+        ("history", "ignore_not_found", Value::Bool(b)) => config.history.ignore_not_found = b,
+        ("history", "enabled", Value::Bool(b)) => config.history.enabled = b,
+        ("history", "ignore", Value::List(items)) => config.history.ignore = items,
+        ("history", "ignore", v) => return wrong_type("a list of strings", &v),
+        ("history", "size", v) => return wrong_type("an integer", &v),
+        ("history", "file", v) => return wrong_type("a string", &v),
+        (
+            "history",
+            "ignore_dups" | "ignore_space" | "erase_dups" | "expansion" | "record_failed"
+            | "ignore_not_found" | "share" | "enabled",
+            v,
+        ) => {
+            return wrong_type("true or false", &v);
+        }
+
+        ("completion", "disabled", Value::List(items)) => config.completion.disabled = items,
+        ("completion", "wrappers", Value::List(items)) => config.completion.wrappers = items,
+        ("completion", "cache_dir", Value::Str(s)) => {
+            config.completion.cache_dir = Some(crate::utils::expand_tilde(&s))
+        }
+        ("completion", "hidden", Value::Str(s)) => {
+            config.completion.hidden = match s.as_str() {
+                "always" => HiddenFiles::Always,
+                "never" => HiddenFiles::Never,
+                "smart" => HiddenFiles::Smart,
+                _ => {
+                    return Err(format!(
+                        "completion.hidden should be \"always\", \"never\" or \"smart\", not \"{s}\""
+                    ));
+                }
+            }
+        }
+        ("completion", "ignore_case", Value::Bool(b)) => config.completion.ignore_case = b,
+        ("completion", "fuzzy", Value::Bool(b)) => config.completion.fuzzy = b,
+        ("completion", "descriptions", Value::Bool(b)) => config.completion.descriptions = b,
+        ("completion", "run_help", Value::Bool(b)) => config.completion.run_help = b,
+        ("completion", "executables_only", Value::Bool(b)) => {
+            config.completion.executables_only = b
+        }
+        ("completion", "cache_ttl", Value::Str(s)) => {
+            config.completion.cache_ttl = crate::completions::parse_ttl(&s).ok_or_else(|| {
+                format!("completion.cache_ttl \"{s}\" should look like 90s, 30m, 12h, 7d or never")
+            })?
+        }
+        ("completion", "column_width", Value::Int(n)) => {
+            config.completion.column_width = usize::try_from(n)
+                .ok()
+                .filter(|w| *w > 0)
+                .ok_or_else(|| format!("completion.column_width {n} is out of range"))?
+        }
+        ("completion", "disabled" | "wrappers", v) => {
+            return wrong_type("a list of strings", &v);
+        }
+        ("completion", "cache_dir" | "hidden" | "cache_ttl", v) => {
+            return wrong_type("a string", &v);
+        }
+        (
+            "completion",
+            "ignore_case" | "fuzzy" | "descriptions" | "run_help" | "executables_only",
+            v,
+        ) => {
+            return wrong_type("true or false", &v);
+        }
+        ("completion", "max_files", Value::Int(n)) => {
+            config.completion.max_files = usize::try_from(n)
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("completion.max_files {n} is out of range"))?
+        }
+        ("completion", "external", Value::Str(s)) => config.completion.external = Some(s),
+        ("completion", "external_mode", Value::Str(s)) => {
+            config.completion.external_override = match s.as_str() {
+                "merge" => false,
+                "override" => true,
+                _ => {
+                    return Err(format!(
+                        "completion.external_mode should be \"merge\" or \"override\", not \"{s}\""
+                    ));
+                }
+            }
+        }
+        ("completion", "external_timeout_ms", Value::Int(n)) => {
+            config.completion.external_timeout = Duration::from_millis(
+                u64::try_from(n)
+                    .map_err(|_| format!("completion.external_timeout_ms {n} is out of range"))?,
+            )
+        }
+        ("completion", "external" | "external_mode", v) => return wrong_type("a string", &v),
+        ("completion", "column_width" | "max_files" | "external_timeout_ms", v) => {
+            return wrong_type("an integer", &v);
+        }
+
+        ("completion.filters", command, Value::Str(s)) if s == "dirs" => config
+            .completion
+            .set_filter(command, crate::completions::ArgFilter::Dirs),
+        ("completion.filters", command, Value::List(items)) => {
+            let extensions = items
+                .iter()
+                .map(|e| e.trim_start_matches('.').to_string())
+                .collect();
+            config
+                .completion
+                .set_filter(command, crate::completions::ArgFilter::Extensions(extensions))
+        }
+        ("completion.filters", _, v) => {
+            return wrong_type("\"dirs\" or a list of extensions", &v);
+        }
+
+        ("aliases", name, Value::Str(s)) => {
+            if name.is_empty() || name.contains(|c: char| c.is_whitespace() || "=/$".contains(c)) {
+                return Err(format!("aliases.{name}: not a valid alias name"));
+            }
+            config.aliases.push((name.to_string(), s))
+        }
+        ("aliases", _, v) => return wrong_type("a string", &v),
+
+        ("env", name, Value::Str(s)) => {
+            if !is_var_name(name) {
+                return Err(format!("env.{name}: not a valid variable name"));
+            }
+            config.env.push((name.to_string(), s))
+        }
+        ("env", _, v) => return wrong_type("a string", &v),
+
+        ("startup", "commands", Value::List(items)) => config.startup = items,
+        ("startup", "commands", v) => return wrong_type("a list of strings", &v),
+
+        ("keybindings", chord, Value::Str(action)) => config
+            .keybindings
+            .push(crate::keys::parse_binding(chord, &action)?),
+        ("keybindings", _, v) => return wrong_type("an action name", &v),
+
+        (theme, field, value) if theme.starts_with("theme.") => {
+            let name = &theme["theme.".len()..];
+            let Value::Str(color) = value else {
+                return wrong_type("a color string", &value);
+            };
+            let index = match config.themes.iter().position(|t| t.name == name) {
+                Some(i) => i,
+                None => {
+                    config.themes.push(Theme::custom(name));
+                    config.themes.len() - 1
+                }
+            };
+            if !config.themes[index].set_field(field, &color) {
+                return Err(format!("unknown theme field or color: {field} = {color}"));
+            }
+        }
+
+        // `prompt = "..."` and `startup = [...]` work outside a section too
+        ("", "prompt", Value::Str(s)) => config.prompt.format = Some(s),
+        ("", "startup", Value::List(items)) => config.startup = items,
+        ("", "prompt", v) => return wrong_type("a string", &v),
+        ("", "startup", v) => return wrong_type("a list of strings", &v),
+        ("", key, _) => return Err(format!("{key} needs to be inside a [section]")),
+        (section, key, _) => return Err(format!("unknown key {key} in [{section}]")),
+    }
+    Ok(())
+}
+
+// true for vi, false for emacs
+fn parse_edit_mode(mode: &str) -> Option<bool> {
+    match mode.trim().to_ascii_lowercase().as_str() {
+        "vi" | "vim" => Some(true),
+        "emacs" => Some(false),
+        _ => None,
+    }
+}
+
+fn is_var_name(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+}
+
+// Text that is not a config file has nothing to include from
+fn no_include(_: &mut Config, target: &str) -> Result<(), String> {
+    Err(format!(
+        "include {target}: only a config file can include others"
+    ))
 }
 
-fn parse_config(content: &str) -> Config {
+/// Reads shesh.toml. Anything not understood becomes a warning and leaves
+/// the default in place.
+pub fn parse_toml(content: &str) -> Config {
     let mut config = Config::default();
+    read_toml(&mut config, content, &mut no_include);
+    config
+}
+
+// A top-level `include = "file"` or `include = ["a", "b"]` reads those
+// files where the line is
+fn read_toml(config: &mut Config, content: &str, include: &mut Include) {
+    let (entries, mut warnings) = parse_entries(content);
+    for entry in entries {
+        let (line, text) = (entry.line, entry.text.clone());
+        if entry.section.is_empty() && entry.key == "include" {
+            let targets = match entry.value {
+                Value::Str(target) => vec![target],
+                Value::List(targets) => targets,
+                v => {
+                    let reason = format!(
+                        "include should be a string or a list of strings, not {}",
+                        v.type_name()
+                    );
+                    warnings.push(Warning::new(line, &text, reason));
+                    continue;
+                }
+            };
+            for target in targets {
+                if let Err(e) = include(config, &target) {
+                    warnings.push(Warning::new(line, &text, e));
+                }
+            }
+            continue;
+        }
+        if let Err(e) = apply(config, entry) {
+            warnings.push(Warning::new(line, &text, e));
+        }
+    }
+    warnings.sort_by_key(|w| w.line);
+    config.warnings.extend(warnings);
+}
+
+#[cfg(test)]
+fn parse_legacy(content: &str) -> Config {
+    let mut config = Config::default();
+    read_legacy(&mut config, content, &mut no_include);
+    config
+}
+
+// The old shesh.24 format: `key = value` lines, then everything after a
+// `#startup` line runs at startup. `#include path` reads another file in
+// place; any other `#` line is a comment.
+fn read_legacy(config: &mut Config, content: &str, include: &mut Include) {
     let mut in_startup = false;
 
-    for linee in content.lines() {
+    for (n, linee) in content.lines().enumerate() {
         let line = linee.trim();
         if !line.is_empty() {
             if let Some(stripped) = line.strip_prefix('#') {
-                match stripped.trim() {
-                    c if c.starts_with("prompt") => config.prompt = None,
-                    c if c.eq_ignore_ascii_case("startup") => in_startup = true,
-                    _ => {}
+                if stripped.trim().eq_ignore_ascii_case("startup") {
+                    in_startup = true;
+                } else if let Some(target) = stripped.strip_prefix("include ")
+                    && let Err(e) = include(config, target.trim().trim_matches('"'))
+                {
+                    config.warnings.push(Warning::new(n + 1, line, e));
                 }
                 continue;
             }
 
             if in_startup {
                 config.startup.push(line.to_string());
-            } else if let Some((key, value)) = line.split_once('=') {
-                if key.trim() == "prompt" {
-                    config.prompt = Some(value.trim().trim_matches('"').to_string());
+                continue;
+            }
+            // `alias name=value` and `export NAME=value`, as typed at the prompt
+            if let Some((kind, definition)) = line.split_once(' ')
+                && matches!(kind, "alias" | "export")
+                && let Some((name, value)) = definition.split_once('=')
+            {
+                let (name, value) = (name.trim(), value.trim());
+                let value = value
+                    .strip_prefix(['"', '\''])
+                    .and_then(|v| v.strip_suffix(['"', '\'']))
+                    .unwrap_or(value)
+                    .to_string();
+                match kind {
+                    "export" if !is_var_name(name) => config.warnings.push(Warning::new(
+                        n + 1,
+                        line,
+                        format!("export {name}: not a valid variable name"),
+                    )),
+                    "export" => config.env.push((name.to_string(), value)),
+                    _ => config.aliases.push((name.to_string(), value)),
                 }
+                continue;
+            }
+            let understood = match line.split_once('=') {
+                Some((key, value)) => match key.trim() {
+                    "prompt" => {
+                        config.prompt.format = Some(value.trim().trim_matches('"').to_string());
+                        true
+                    }
+                    "max_depth" => value
+                        .trim()
+                        .parse()
+                        .map(|depth| config.max_depth = depth)
+                        .is_ok(),
+                    "hints" => {
+                        config.hints = !matches!(value.trim(), "false" | "off");
+                        true
+                    }
+                    "quiet_background" => {
+                        config.quiet_background = matches!(value.trim(), "true" | "on");
+                        true
+                    }
+                    "edit_mode" => parse_edit_mode(value)
+                        .map(|vi| config.vi_mode = vi)
+                        .is_some(),
+                    "prompt_style" => {
+                        config.prompt.style = Some(value.trim().to_string());
+                        true
+                    }
+                    "completions_disabled" => {
+                        config.completion.disabled =
+                            value.split_whitespace().map(str::to_string).collect();
+                        true
+                    }
+                    "theme" => {
+                        config.theme = Some(value.trim().trim_matches('"').to_string());
+                        true
+                    }
+                    // theme.NAME.FIELD = color defines or extends a custom theme
+                    key if key.starts_with("theme.") => {
+                        match key["theme.".len()..].split_once('.') {
+                            Some((name, field)) => {
+                                let index = match config.themes.iter().position(|t| t.name == name)
+                                {
+                                    Some(i) => i,
+                                    None => {
+                                        config.themes.push(Theme::custom(name));
+                                        config.themes.len() - 1
+                                    }
+                                };
+                                config.themes[index].set_field(field, value)
+                            }
+                            None => false,
+                        }
+                    }
+                    _ => false,
+                },
+                None => false,
+            };
+            if !understood {
+                config
+                    .warnings
+                    .push(Warning::new(n + 1, line, "unknown setting or bad value"));
             }
         }
     }
-    config
 }
 
-pub fn run_startup(config: &Config) {
-    for cmd_line in &config.startup {
-        if !cmd_line.trim().is_empty() {
-            if let Err(e) = crate::shell::exec(cmd_line) {
-                eprintln!("[X] Startup failed: {e}");
-            }
+/// Sets `key` in `[section]` of the config file, replacing the line that
+/// sets it or adding one to the section. The old shesh.24 gets the same
+/// setting as a flat line above its startup section.
+pub fn set_option(section: &str, key: &str, value: Value) -> io::Result<()> {
+    let path = config_file_path();
+    let content = fs::read_to_string(&path).unwrap_or_default();
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+
+    if is_legacy(&path) {
+        set_legacy_option(&mut lines, section, key, &value);
+    } else {
+        set_toml_option(&mut lines, section, key, &value);
+    }
+
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    atomic_write(&path, updated.as_bytes())
+}
+
+fn set_toml_option(lines: &mut Vec<String>, section: &str, key: &str, value: &Value) {
+    let setting = format!("{key} = {}", value.to_toml());
+    let is_header = |l: &String| l.trim_start().starts_with('[');
+    let header = format!("[{section}]");
+
+    let Some(start) = lines.iter().position(|l| l.trim() == header) else {
+        if lines.last().is_some_and(|l| !l.trim().is_empty()) {
+            lines.push(String::new());
+        }
+        lines.push(header);
+        lines.push(setting);
+        return;
+    };
+    let end = lines[start + 1..]
+        .iter()
+        .position(is_header)
+        .map_or(lines.len(), |i| start + 1 + i);
+    // A list left open continues on the next lines, which go with the entry
+    let code = |line: &str| line[..comment_start(line).unwrap_or(line.len())].to_string();
+    let mut i = start + 1;
+    while i < end {
+        let line = code(&lines[i]);
+        let Some((k, value)) = line.split_once('=') else {
+            i += 1;
+            continue;
+        };
+        let (k, mut value) = (k.trim().to_string(), value.trim().to_string());
+        let mut last = i;
+        while list_is_open(&value) && last + 1 < end {
+            last += 1;
+            value.push(' ');
+            value.push_str(code(&lines[last]).trim());
         }
+        if k == key {
+            lines.splice(i..=last, [setting]);
+            return;
+        }
+        i = last + 1;
+    }
+    lines.insert(start + 1, setting);
+}
+
+fn set_legacy_option(lines: &mut Vec<String>, section: &str, key: &str, value: &Value) {
+    let key = match (section, key) {
+        ("prompt", "style") => "prompt_style",
+        ("completion", "disabled") => "completions_disabled",
+        (_, key) => key,
+    };
+    let setting = format!("{key} = {}", value.to_legacy());
+
+    let startup = lines
+        .iter()
+        .position(|l| {
+            l.trim()
+                .strip_prefix('#')
+                .is_some_and(|c| c.trim().eq_ignore_ascii_case("startup"))
+        })
+        .unwrap_or(lines.len());
+    let existing = lines[..startup].iter().position(|l| {
+        l.split_once('=')
+            .is_some_and(|(k, _)| !l.trim_start().starts_with('#') && k.trim() == key)
+    });
+    match existing {
+        Some(i) => lines[i] = setting,
+        None => lines.insert(startup, setting),
+    }
+}
+
+// Exports the config's variables and defines its aliases. Values are
+// expanded first, so `PATH = "$HOME/bin:$PATH"` extends the inherited PATH.
+fn apply_definitions(config: &Config) {
+    for (name, value) in &config.env {
+        crate::builtins::set_var(name, &crate::utils::expand_env_vars(value));
+    }
+    for (name, value) in &config.aliases {
+        crate::builtins::set_alias(name, value);
+    }
+}
+
+// Settings that can change while shesh runs, applied at startup and by `24! reload`
+pub fn apply_settings(config: &Config) {
+    crate::hints::set_enabled(config.hints);
+    crate::hinter::configure(config.hinter.clone());
+    crate::completions::set_disabled(&config.completion.disabled);
+    crate::history::set_ignored(&config.history.ignore);
+    crate::theme::init(config.themes.clone(), config.theme.as_deref());
+    crate::process_exec::set_quiet_background(config.quiet_background);
+    crate::jobs::set_hup_on_exit(config.hup_on_exit);
+    crate::dirs::set_jump(config.cd_jump);
+    crate::title::configure(config.title);
+    crate::integration::configure(config.integration);
+}
+
+/// `24! reload`: reads the config file again and re-applies aliases,
+/// variables, themes, hints, suggestions and completion settings.
+pub fn reload() -> io::Result<()> {
+    let path = config_file_path();
+    if !path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("24! reload: {} does not exist", path.display()),
+        ));
     }
+    let config = load_config(&path);
+    apply_definitions(&config);
+    apply_settings(&config);
+    println!(
+        "Reloaded {} ({} aliases, {} variables)",
+        path.display(),
+        config.aliases.len(),
+        config.env.len()
+    );
+    println!("Prompt, keybinding and other history changes apply to new sessions");
+    Ok(())
 }
 
-//history file
-pub fn append_to_history(command: &str) {
-    let path = history_file_path();
+/// A file sourced at startup.
+pub struct StartupFile {
+    pub path: PathBuf,
+    // Only sourced when stdin is a terminal
+    pub interactive_only: bool,
+    // Only sourced by a login shell
+    pub login_only: bool,
+}
+
+// Files sourced around the config's [startup] commands: a login shell's
+// profile and the system-wide rc first, then rc.d/*.24 by name and the
+// interactive rc last
+fn startup_files(dir: &Path) -> (Vec<StartupFile>, Vec<StartupFile>) {
+    let every = |path| StartupFile {
+        path,
+        interactive_only: false,
+        login_only: false,
+    };
+    let before = vec![
+        StartupFile {
+            path: dir.join("profile"),
+            interactive_only: false,
+            login_only: true,
+        },
+        every(PathBuf::from("/etc/shesh/rc")),
+    ];
+
+    let mut rc_d: Vec<PathBuf> = fs::read_dir(dir.join("rc.d"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "24"))
+        .collect();
+    rc_d.sort();
+    let mut after: Vec<StartupFile> = rc_d.into_iter().map(every).collect();
+    after.push(StartupFile {
+        path: dir.join("interactive.24"),
+        interactive_only: true,
+        login_only: false,
+    });
+    (before, after)
+}
 
-    if path.parent().is_some_and(|p| create_dir_all(p).is_err()) {
-        eprintln!("[X] Failed to create history directory");
+// Missing files are fine, everything else is reported and startup goes on
+fn source_startup_file(file: &StartupFile, interactive: bool, login: bool) {
+    if (file.interactive_only && !interactive)
+        || (file.login_only && !login)
+        || !file.path.is_file()
+    {
         return;
     }
+    if let Err(e) = crate::builtins::source_file(&file.path) {
+        crate::output::warn(&e.to_string());
+    }
+}
+
+/// Runs everything shesh sources at startup, in order: for a login shell
+/// ~/.config/shesh/profile, then /etc/shesh/rc, the config's [startup]
+/// commands, ~/.config/shesh/rc.d/*.24 and, for an interactive shell,
+/// ~/.config/shesh/interactive.24.
+pub fn run_startup(config: &Config, interactive: bool, login: bool) {
+    let (before, after) = startup_files(&get_config().join("shesh"));
+    for file in &before {
+        source_startup_file(file, interactive, login);
+    }
 
-    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&path) {
-        if let Err(e) = writeln!(file, "{command}") {
-            eprintln!("[X] Failed to write to history file: {e}");
+    for cmd_line in &config.startup {
+        if crate::utils::resolves_to_self(cmd_line) {
+            crate::output::warn(&format!(
+                "Startup command launches shesh itself: {cmd_line}"
+            ));
         }
-    } else {
-        eprintln!("[X] Failed to open history file");
+    }
+    let origin = format!("{} [startup]", config_file_path().display());
+    crate::builtins::source_lines(config.startup.iter().map(String::as_str), &origin);
+
+    for file in &after {
+        source_startup_file(file, interactive, login);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_partial_file_keeps_defaults() {
+        let config = parse_toml(
+            "[prompt]\nstyle = \"full\" # trailing comment\n\n[aliases]\nll = 'ls -la'\n\"..\" = \"cd ..\"\n[keybindings]\nalt-enter = \"newline\"\n",
+        );
+        assert!(config.warnings.is_empty(), "{:?}", config.warnings);
+        assert_eq!(config.prompt.style.as_deref(), Some("full"));
+        assert_eq!(
+            config.aliases,
+            vec![
+                ("ll".to_string(), "ls -la".to_string()),
+                ("..".to_string(), "cd ..".to_string())
+            ]
+        );
+
+        let defaults = Config::default();
+        assert_eq!(config.keybindings.len(), 1);
+        assert_eq!(config.prompt.format, defaults.prompt.format);
+        assert_eq!(config.max_depth, defaults.max_depth);
+        assert_eq!(config.hints, defaults.hints);
+        assert_eq!(config.history, defaults.history);
+
+        // The generated file parses cleanly
+        let generated = parse_toml(DEFAULT_CONFIG);
+        assert!(generated.warnings.is_empty(), "{:?}", generated.warnings);
+        assert_eq!(generated.startup, vec!["echo \"shesh ready!\""]);
+    }
+
+    #[test]
+    fn test_type_errors_and_unknown_keys() {
+        let config = parse_toml(
+            "[shell]\nmax_depth = \"deep\"\nhints = false\ncolour = true\nedit_mode = \"vi\"\n\n[startup]\ncommands = [\n  \"echo a\", # first\n  'echo b',\n]\n[history]\nsize = 10 20\n[keybindings]\nctrl-q = \"quit\"\n",
+        );
+        assert!(!config.hints);
+        assert!(config.vi_mode);
+        assert_eq!(config.max_depth, Config::default().max_depth);
+        assert_eq!(config.startup, vec!["echo a", "echo b"]);
+
+        let lines: Vec<usize> = config.warnings.iter().map(|w| w.line).collect();
+        assert_eq!(lines, vec![2, 4, 13, 15]);
+        assert!(config.warnings[3].reason.contains("unknown action 'quit'"));
+        assert!(config.warnings[0].reason.contains("should be an integer"));
+        assert!(
+            config.warnings[1]
+                .reason
+                .contains("unknown key colour in [shell]")
+        );
+    }
+
+    #[test]
+    fn test_comments_and_warnings() {
+        // A commented-out prompt is just a comment, in both formats
+        let legacy = parse_legacy("#prompt = \"x> \"\nbogus\n#startup\necho hi\n");
+        assert_eq!(legacy.prompt.format, None);
+        assert_eq!(legacy.startup, vec!["echo hi"]);
+        assert_eq!(
+            legacy.warnings,
+            vec![Warning::new(2, "bogus", "unknown setting or bad value")]
+        );
+
+        let config = parse_toml("prompt = \"x> \" # here\nstartup = ['echo hi']\n[shell\n");
+        assert_eq!(config.prompt.format.as_deref(), Some("x> "));
+        assert_eq!(config.startup, vec!["echo hi"]);
+        assert_eq!(
+            config.warnings[0].to_string(),
+            "line 3: malformed section header (`[shell`)"
+        );
+    }
+
+    #[test]
+    fn test_aliases_and_env() {
+        let config = parse_toml(
+            "[env]\nPATH = \"$HOME/bin:$PATH\"\n\"1X\" = \"bad\"\nEDITOR = 1\n[aliases]\n\"l l\" = \"ls\"\n",
+        );
+        assert_eq!(
+            config.env,
+            vec![("PATH".to_string(), "$HOME/bin:$PATH".to_string())]
+        );
+        let problems: Vec<&str> = config.warnings.iter().map(|w| w.reason.as_str()).collect();
+        assert_eq!(problems.len(), 3, "{problems:?}");
+        assert!(problems[0].starts_with("env.1X"));
+        assert!(problems[1].starts_with("env.EDITOR"));
+        assert!(problems[2].starts_with("aliases.l l"));
+
+        let legacy = parse_legacy("alias ll='ls -la'\nexport EDITOR=vi\n#startup\nalias x=y\n");
+        assert_eq!(
+            legacy.aliases,
+            vec![("ll".to_string(), "ls -la".to_string())]
+        );
+        assert_eq!(legacy.env, vec![("EDITOR".to_string(), "vi".to_string())]);
+        assert_eq!(legacy.startup, vec!["alias x=y"]);
+    }
+
+    #[test]
+    fn test_private_dir() {
+        use std::os::unix::fs::{PermissionsExt, symlink};
+
+        let dir = TempDir::new("config-private");
+        let uid = unsafe { libc::getuid() };
+        let fresh = dir.join("fresh");
+        assert!(make_private_dir(&fresh, uid));
+        assert_eq!(fs::metadata(&fresh).unwrap().mode() & 0o777, 0o700);
+        // Already there and still private
+        assert!(make_private_dir(&fresh, uid));
+        // Someone else's
+        assert!(!make_private_dir(&fresh, uid + 1));
+
+        let open = dir.join("open");
+        fs::create_dir(&open).unwrap();
+        fs::set_permissions(&open, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(!make_private_dir(&open, uid));
+
+        let link = dir.join("link");
+        symlink(&fresh, &link).unwrap();
+        assert!(!make_private_dir(&link, uid));
+    }
+
+    #[test]
+    fn test_history_location() {
+        let dir = TempDir::new("config-xdg");
+        let (data, state) = (dir.join("data"), dir.join("state"));
+        assert_eq!(history_in(&data, &state), state.join("history"));
+
+        // An existing file in the old place is kept
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("history"), "ls\n").unwrap();
+        assert_eq!(history_in(&data, &state), data.join("history"));
+
+        fs::create_dir_all(&state).unwrap();
+        fs::write(state.join("history"), "ls\n").unwrap();
+        assert_eq!(history_in(&data, &state), state.join("history"));
+    }
+
+    #[test]
+    fn test_startup_files() {
+        let dir = TempDir::new("config-rc");
+        fs::create_dir_all(dir.join("rc.d")).unwrap();
+        for name in ["20-b.24", "10-a.24", "notes.txt"] {
+            fs::write(dir.join("rc.d").join(name), "").unwrap();
+        }
+
+        let (before, after) = startup_files(&dir);
+        assert_eq!(before[0].path, dir.join("profile"));
+        assert!(before[0].login_only);
+        assert_eq!(before[1].path, PathBuf::from("/etc/shesh/rc"));
+        assert!(!before[1].login_only);
+        let names: Vec<(String, bool)> = after
+            .iter()
+            .map(|f| {
+                let name = f.path.file_name().unwrap().to_string_lossy().to_string();
+                (name, f.interactive_only)
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("10-a.24".to_string(), false),
+                ("20-b.24".to_string(), false),
+                ("interactive.24".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_history_options() {
+        let config = parse_toml(
+            "history.enabled = false\n[history]\nfile = \"~/h\"\nignore_space = true\nrecord_failed = \"no\"\nignore = [\"ls\", \"export *TOKEN*\"]\nignore_not_found = true\n",
+        );
+        assert!(config.history.ignore_not_found);
+        assert_eq!(config.history.ignore, ["ls", "export *TOKEN*"]);
+        assert!(!config.history.enabled);
+        assert!(config.history.ignore_space);
+        assert!(config.history.record_failed);
+        assert_eq!(config.history.file, Some(crate::utils::expand_tilde("~/h")));
+        assert_eq!(config.warnings.len(), 1);
+        assert!(
+            config.warnings[0]
+                .reason
+                .contains("should be true or false")
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = TempDir::new("config-include");
+        let main = dir.join("shesh.toml");
+        let base = dir.join("base.toml");
+        fs::write(
+            &main,
+            "include = [\"base.toml\", \"missing.toml\"]\n[shell]\nmax_depth = 4\n",
+        )
+        .unwrap();
+        fs::write(
+            &base,
+            "include = \"shesh.toml\"\n[shell]\nmax_depth = 2\nhints = false\n",
+        )
+        .unwrap();
+
+        // The main file's own keys come after the include and win
+        let config = load_config(&main);
+        assert_eq!(config.max_depth, 4);
+        assert!(!config.hints);
+        assert_eq!(config.files, vec![main.clone(), base.clone()]);
+        assert_eq!(config.warnings.len(), 2);
+        assert_eq!(config.warnings[0].file, Some(base.clone()));
+        assert!(config.warnings[0].reason.contains("already being read"));
+        assert_eq!(config.warnings[1].file, None);
+        assert!(config.warnings[1].reason.contains("missing.toml"));
+
+        let legacy = dir.join("shesh.24");
+        fs::write(&legacy, "#include base.toml\nmax_depth = 6\n").unwrap();
+        let config = load_config(&legacy);
+        assert_eq!((config.max_depth, config.hints), (6, false));
+    }
+
+    #[test]
+    fn test_hinter_options() {
+        let config = parse_toml(
+            "[hinter]\nsource = \"frequent\"\nmin_chars = 3\ncolor = \"cyan\"\nitalic = false\nenabled = \"yes\"\n",
+        );
+        assert_eq!(config.hinter.source, Source::Frequent);
+        assert_eq!(config.hinter.min_chars, 3);
+        assert_eq!(config.hinter.color, Some(nu_ansi_term::Color::Cyan));
+        assert!(!config.hinter.italic);
+        assert!(config.hinter.enabled);
+        assert_eq!(config.warnings.len(), 1);
+
+        let config = parse_toml("[hinter]\nsource = \"oldest\"\n");
+        assert!(
+            config.warnings[0]
+                .reason
+                .contains("\"recent\" or \"frequent\"")
+        );
+    }
+
+    #[test]
+    fn test_legacy_fallback() {
+        let dir = TempDir::new("config-legacy");
+        let legacy = dir.join("shesh.24");
+        fs::write(
+            &legacy,
+            "prompt = \"old> \"\nmax_depth = 3\n#startup\necho legacy\n",
+        )
+        .unwrap();
+
+        assert_eq!(config_in(&dir), legacy);
+        let config = load_config(&config_in(&dir));
+        assert_eq!(config.prompt.format.as_deref(), Some("old> "));
+        assert_eq!(config.max_depth, 3);
+        assert_eq!(config.startup, vec!["echo legacy"]);
+
+        // Once shesh.toml exists it wins
+        fs::write(dir.join("shesh.toml"), "[shell]\nmax_depth = 5\n").unwrap();
+        assert_eq!(config_in(&dir), dir.join("shesh.toml"));
+        assert_eq!(load_config(&config_in(&dir)).max_depth, 5);
+    }
+
+    #[test]
+    fn test_set_option() {
+        let mut lines: Vec<String> = "[prompt]\nstyle = \"path\"\n\n[startup]\ncommands = []"
+            .lines()
+            .map(String::from)
+            .collect();
+        set_toml_option(&mut lines, "prompt", "style", &Value::Str("full".into()));
+        set_toml_option(&mut lines, "prompt", "theme", &Value::Str("light".into()));
+        set_toml_option(
+            &mut lines,
+            "completion",
+            "disabled",
+            &Value::List(vec!["git".into()]),
+        );
+        let config = parse_toml(&lines.join("\n"));
+        assert!(config.warnings.is_empty(), "{:?}", config.warnings);
+        assert_eq!(config.prompt.style.as_deref(), Some("full"));
+        assert_eq!(config.theme.as_deref(), Some("light"));
+        assert_eq!(config.completion.disabled, vec!["git"]);
+
+        // A list over several lines is replaced whole, as the default config
+        // writes `disabled` once uncommented
+        let mut lines: Vec<String> = "[completion]\n\
+             disabled = [\"ssh\", \"scp\",  # remote\n\
+             \x20           \"rm\", \"dd\"]\n\
+             fuzzy = true"
+            .lines()
+            .map(String::from)
+            .collect();
+        set_toml_option(
+            &mut lines,
+            "completion",
+            "disabled",
+            &Value::List(vec!["ssh".into(), "x".into()]),
+        );
+        assert_eq!(lines.len(), 3, "{lines:?}");
+        let config = parse_toml(&lines.join("\n"));
+        assert!(config.warnings.is_empty(), "{:?}", config.warnings);
+        assert_eq!(config.completion.disabled, vec!["ssh", "x"]);
+        assert!(config.completion.fuzzy);
     }
 }
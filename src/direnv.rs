@@ -0,0 +1,411 @@
+use crate::{builtins, config, output, utils};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+const FILE_NAME: &str = ".shesh-env";
+
+// Set for the interactive session; scripts that cd pick up nothing
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// The file whose variables are set, and what they were before
+struct Loaded {
+    file: PathBuf,
+    saved: Vec<(String, Option<String>)>,
+}
+
+static LOADED: Mutex<Option<Loaded>> = Mutex::new(None);
+
+// Files already reported as not allowed, so each is mentioned once
+static WARNED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Turns loading on and loads the file for the starting directory.
+pub fn enable() {
+    ACTIVE.store(true, Ordering::Relaxed);
+    changed_dir();
+}
+
+/// After `cd`, `pushd` or `popd`: leaves the variables of a file whose tree was left and sets
+/// those of the nearest `.shesh-env` above the new directory, if allowed.
+pub fn changed_dir() {
+    if ACTIVE.load(Ordering::Relaxed) {
+        update(false);
+    }
+}
+
+// The `.shesh-env` in `dir` or the closest directory above it
+fn nearest(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|d| d.join(FILE_NAME))
+        .find(|file| file.is_file())
+}
+
+// `reload` applies the file again even when it is the one already loaded,
+// after it was allowed or denied
+fn update(reload: bool) {
+    let file = env::current_dir().ok().and_then(|cwd| nearest(&cwd));
+    let mut loaded = LOADED.lock().unwrap();
+    if !reload && loaded.as_ref().map(|l| &l.file) == file.as_ref() {
+        return;
+    }
+    if let Some(old) = loaded.take() {
+        unload(old);
+    }
+    let Some(file) = file else {
+        return;
+    };
+    let Ok(text) = fs::read_to_string(&file) else {
+        return;
+    };
+    if is_allowed(&file, &text) {
+        *loaded = Some(load(file, &text));
+    } else {
+        let mut warned = WARNED.lock().unwrap();
+        if !warned.contains(&file) {
+            output::warn(&format!(
+                "{} is not allowed; `24! env allow` loads it",
+                file.display()
+            ));
+            warned.push(file);
+        }
+    }
+}
+
+fn load(file: PathBuf, text: &str) -> Loaded {
+    let mut saved: Vec<(String, Option<String>)> = Vec::new();
+    for (name, value, literal) in parse(text) {
+        if !saved.iter().any(|(n, _)| *n == name) {
+            saved.push((name.clone(), env::var(&name).ok()));
+        }
+        // Expanded as each line is set, so later lines see earlier ones
+        let value = if literal {
+            value
+        } else {
+            utils::expand_env_vars(&value)
+        };
+        builtins::set_var(&name, &value);
+    }
+    let names: Vec<&str> = saved.iter().map(|(n, _)| n.as_str()).collect();
+    output::info(&format!("{}: set {}", file.display(), names.join(" ")));
+    Loaded { file, saved }
+}
+
+fn unload(loaded: Loaded) {
+    for (name, value) in loaded.saved.into_iter().rev() {
+        match value {
+            Some(value) => builtins::set_var(&name, &value),
+            None => builtins::unset_var(&name),
+        }
+    }
+    output::info(&format!("{}: unset", loaded.file.display()));
+}
+
+// `export NAME=value` and `NAME=value` lines. Blank lines and # comments are
+// skipped, and so is anything else: the file is read, never run. Quotes
+// around a value are removed; the flag marks single-quoted values, which
+// don't get $VAR expanded.
+fn parse(text: &str) -> Vec<(String, String, bool)> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+            let (name, value) = line.split_once('=')?;
+            let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                return None;
+            }
+            let unquoted = |q: char| {
+                value
+                    .strip_prefix(q)
+                    .and_then(|v| v.strip_suffix(q))
+                    .map(str::to_string)
+            };
+            Some(match (unquoted('\''), unquoted('"')) {
+                (Some(v), _) => (name.to_string(), v, true),
+                (_, Some(v)) => (name.to_string(), v, false),
+                _ => (name.to_string(), value.to_string(), false),
+            })
+        })
+        .collect()
+}
+
+// `<sha256 of path and contents> <path>` lines; editing a file changes its
+// hash, so it has to be allowed again
+fn allow_list() -> PathBuf {
+    config::get_config().join("shesh").join("env-allowed")
+}
+
+fn digest(file: &Path, text: &str) -> String {
+    let mut data = file.as_os_str().as_encoded_bytes().to_vec();
+    data.push(0);
+    data.extend_from_slice(text.as_bytes());
+    sha256(&data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn is_allowed(file: &Path, text: &str) -> bool {
+    let digest = digest(file, text);
+    fs::read_to_string(allow_list()).is_ok_and(|list| {
+        list.lines()
+            .any(|line| line.split(' ').next() == Some(&digest))
+    })
+}
+
+// Rewrites the allow list without `file`, then with it again if `digest` is
+// given
+fn set_allowed(file: &Path, digest: Option<String>) -> io::Result<()> {
+    let path = allow_list();
+    let shown = file.display().to_string();
+    let mut lines: Vec<String> = fs::read_to_string(&path)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| line.split_once(' ').is_none_or(|(_, p)| p != shown))
+        .map(str::to_string)
+        .collect();
+    if let Some(digest) = digest {
+        lines.push(format!("{digest} {shown}"));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    utils::atomic_write(&path, (lines.join("\n") + "\n").as_bytes())
+}
+
+/// `24! env [allow|deny] [dir]`: shows the file that applies here, or
+/// allows or forgets it.
+pub fn handle_env(args: &[&str]) -> io::Result<()> {
+    let (action, dir) = match args {
+        [] => ("status", None),
+        [action] => (*action, None),
+        [action, dir] => (*action, Some(fs::canonicalize(utils::expand_tilde(dir))?)),
+        _ => return Err(usage()),
+    };
+    let dir = match dir {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+    let file = nearest(&dir);
+    match (action, file) {
+        ("status", None) => println!("env: no {FILE_NAME} here or above"),
+        ("status", Some(file)) => {
+            let loaded = LOADED.lock().unwrap();
+            match loaded.as_ref().filter(|l| l.file == file) {
+                Some(l) => {
+                    let names: Vec<&str> = l.saved.iter().map(|(n, _)| n.as_str()).collect();
+                    println!("env: {} sets {}", file.display(), names.join(" "));
+                }
+                None => {
+                    let allowed = fs::read_to_string(&file).is_ok_and(|t| is_allowed(&file, &t));
+                    let state = if allowed { "allowed" } else { "not allowed" };
+                    println!("env: {} ({state})", file.display());
+                }
+            }
+        }
+        ("allow" | "deny", None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("env: no {FILE_NAME} in {} or above", dir.display()),
+            ));
+        }
+        ("allow", Some(file)) => {
+            let text = fs::read_to_string(&file)?;
+            set_allowed(&file, Some(digest(&file, &text)))?;
+            WARNED.lock().unwrap().retain(|f| *f != file);
+            update(true);
+        }
+        ("deny", Some(file)) => {
+            set_allowed(&file, None)?;
+            update(true);
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+fn usage() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Usage: 24! env [allow|deny] [dir]",
+    )
+}
+
+// FIPS 180-4 SHA-256; only the allow list needs a hash that a changed file
+// can't be made to match
+fn sha256(data: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut out = [0u8; 32];
+    for (chunk, word) in out.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_env_file() {
+        let text = "# project settings\n\
+                    export RUST_LOG=debug\n\
+                    DATABASE_URL=\"postgres://$USER@localhost\"\n\
+                    PS_LIKE='$HOME stays'\n\
+                    echo not run\n\
+                    1BAD=x\n";
+        assert_eq!(
+            parse(text),
+            vec![
+                ("RUST_LOG".into(), "debug".into(), false),
+                (
+                    "DATABASE_URL".into(),
+                    "postgres://$USER@localhost".into(),
+                    false
+                ),
+                ("PS_LIKE".into(), "$HOME stays".into(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sha256() {
+        let hex =
+            |data: &[u8]| -> String { sha256(data).iter().map(|b| format!("{b:02x}")).collect() };
+        assert_eq!(
+            hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Two blocks after padding
+        assert_eq!(
+            hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        // The 896-bit FIPS 180-4 message, and a million a's
+        assert_eq!(
+            hex(
+                b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmno\
+                  ijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"
+            ),
+            "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1"
+        );
+        assert_eq!(
+            hex(&vec![b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+        // Around the block size: 55 bytes still fit the length in one block,
+        // 56 need a second one
+        for (len, expected) in [
+            (
+                55,
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                56,
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                63,
+                "7d3e74a05d7db15bce4ad9ec0658ea98e3f06eeecf16b4c6fff2da457ddc2f34",
+            ),
+            (
+                64,
+                "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb",
+            ),
+            (
+                65,
+                "635361c48bb9eab14198e76ea8ab7f1a41685d6ad62aa9146d301d4f17eb0ae0",
+            ),
+            (
+                119,
+                "31eba51c313a5c08226adf18d4a359cfdfd8d2e816b13f4af952f7ea6584dcfb",
+            ),
+            (
+                120,
+                "2f3d335432c70b580af0e8e1b3674a7c020d683aa5f73aaaedfdc55af904c21c",
+            ),
+            (
+                128,
+                "6836cf13bac400e9105071cd6af47084dfacad4e5e302c94bfed24e013afb73e",
+            ),
+        ] {
+            assert_eq!(hex(&vec![b'a'; len]), expected, "{len} bytes");
+        }
+        // A changed file has to be allowed again
+        assert_ne!(
+            digest(Path::new("/p/.shesh-env"), "A=1\n"),
+            digest(Path::new("/p/.shesh-env"), "A=2\n")
+        );
+    }
+}
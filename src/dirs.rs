@@ -0,0 +1,277 @@
+use crate::{config, utils};
+use std::{
+    collections::HashMap,
+    env, fs,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Set for the interactive session: a script's cd is neither recorded nor
+// sent somewhere it didn't name
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// `cd_jump = false` keeps recording visits but stops `cd foo` from jumping
+static JUMP: AtomicBool = AtomicBool::new(true);
+
+// Past this size the file of visits is folded into one line per directory
+const COMPACT_BYTES: u64 = 64 * 1024;
+
+// Directories kept by a compaction, the best ranked ones, within half of
+// COMPACT_BYTES: the next compaction is always that many appends away, even
+// when long paths would make 1000 lines bigger than the threshold
+const MAX_DIRS: usize = 1000;
+
+pub fn enable() {
+    ACTIVE.store(true, Ordering::Relaxed);
+}
+
+pub fn set_jump(on: bool) {
+    JUMP.store(on, Ordering::Relaxed);
+}
+
+// `<time>\t<visits>\t<path>` lines; a visit appends one with a count of 1,
+// a compaction rewrites them as one line per directory
+fn db_path() -> PathBuf {
+    config::data_dir().join("dirs")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Visits {
+    count: u64,
+    last: u64,
+}
+
+// How often, weighted by how recently: a directory from the last hour
+// counts four times, one not seen for a week a quarter
+fn score(visits: Visits, now: u64) -> f64 {
+    let age = now.saturating_sub(visits.last);
+    let weight = match age {
+        0..3_600 => 4.0,
+        3_600..86_400 => 2.0,
+        86_400..604_800 => 0.5,
+        _ => 0.25,
+    };
+    visits.count as f64 * weight
+}
+
+fn parse(text: &str) -> HashMap<PathBuf, Visits> {
+    let mut dirs: HashMap<PathBuf, Visits> = HashMap::new();
+    for line in text.lines() {
+        let mut fields = line.splitn(3, '\t');
+        let (Some(Ok(last)), Some(Ok(count)), Some(path)) = (
+            fields.next().map(str::parse::<u64>),
+            fields.next().map(str::parse::<u64>),
+            fields.next(),
+        ) else {
+            continue;
+        };
+        let entry = dirs
+            .entry(PathBuf::from(path))
+            .or_insert(Visits { count: 0, last: 0 });
+        entry.count += count;
+        entry.last = entry.last.max(last);
+    }
+    dirs
+}
+
+fn load() -> HashMap<PathBuf, Visits> {
+    parse(&fs::read_to_string(db_path()).unwrap_or_default())
+}
+
+// Best first, the most recent of equal scores first
+fn ranked(dirs: HashMap<PathBuf, Visits>, now: u64) -> Vec<(PathBuf, Visits)> {
+    let mut ranked: Vec<_> = dirs.into_iter().collect();
+    ranked.sort_by(|a, b| {
+        score(b.1, now)
+            .total_cmp(&score(a.1, now))
+            .then(b.1.last.cmp(&a.1.last))
+    });
+    ranked
+}
+
+/// After a successful `cd`: appends a visit to the current directory. Home
+/// isn't recorded, `cd` alone gets there already.
+pub fn record_visit() {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let Ok(cwd) = env::current_dir() else {
+        return;
+    };
+    if env::var_os("HOME").is_some_and(|home| cwd == Path::new(&home)) {
+        return;
+    }
+    let path = db_path();
+    // One short write with O_APPEND, so sessions appending at once don't mix
+    // their lines
+    let append = || {
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.write_all(format!("{}\t1\t{}\n", now(), cwd.display()).as_bytes())?;
+        file.metadata().map(|meta| meta.len())
+    };
+    let len = match append() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Some(dir) = path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            append()
+        }
+        result => result,
+    };
+    if len.is_ok_and(|len| len > COMPACT_BYTES) {
+        let _ = compact();
+    }
+}
+
+// One line per directory, leaving out ones that are gone. A visit another
+// session appends during the rewrite may be lost, which only costs a count.
+fn compact() -> io::Result<()> {
+    let kept = compacted(
+        ranked(load(), now())
+            .into_iter()
+            .filter(|(path, _)| path.is_dir()),
+    );
+    utils::atomic_write(&db_path(), kept.as_bytes())
+}
+
+// The lines of the best ranked directories that fit MAX_DIRS and half of
+// COMPACT_BYTES
+fn compacted(ranked: impl Iterator<Item = (PathBuf, Visits)>) -> String {
+    let mut text = String::new();
+    for (path, visits) in ranked.take(MAX_DIRS) {
+        let line = format!("{}\t{}\t{}\n", visits.last, visits.count, path.display());
+        if (text.len() + line.len()) as u64 > COMPACT_BYTES / 2 {
+            break;
+        }
+        text.push_str(&line);
+    }
+    text
+}
+
+// Every word appears in the path in order, ignoring case, and the last one
+// in its final component, so `cd src` picks a src directory rather than
+// something inside one
+fn matches(path: &Path, words: &[&str]) -> bool {
+    let full = path.to_string_lossy().to_lowercase();
+    let mut rest = full.as_str();
+    for word in words {
+        let word = word.to_lowercase();
+        match rest.find(&word) {
+            Some(i) => rest = &rest[i + word.len()..],
+            None => return false,
+        }
+    }
+    let last = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    words
+        .last()
+        .is_some_and(|word| last.contains(&word.to_lowercase()))
+}
+
+/// Where `cd words...` jumps when no directory of that name is here: the
+/// best ranked visited directory matching the words, other than this one.
+pub fn jump_target(words: &[&str]) -> Option<PathBuf> {
+    if !ACTIVE.load(Ordering::Relaxed) || !JUMP.load(Ordering::Relaxed) || words.is_empty() {
+        return None;
+    }
+    let cwd = env::current_dir().ok();
+    ranked(load(), now())
+        .into_iter()
+        .map(|(path, _)| path)
+        .find(|path| Some(path) != cwd.as_ref() && matches(path, words) && path.is_dir())
+}
+
+/// `24! dirs top [N]`: the N best ranked directories with their scores.
+pub fn handle_dirs(args: &[&str]) -> io::Result<()> {
+    let limit = match args {
+        ["top"] => 10,
+        ["top", n] => n.parse().map_err(|_| usage())?,
+        _ => return Err(usage()),
+    };
+    let now = now();
+    let home = env::var("HOME").unwrap_or_default();
+    for (path, visits) in ranked(load(), now).into_iter().take(limit) {
+        let shown = path.display().to_string();
+        let shown = match shown.strip_prefix(&home) {
+            Some(rest) if !home.is_empty() && rest.starts_with('/') => format!("~{rest}"),
+            _ => shown,
+        };
+        println!("{:>8.1}  {shown}", score(visits, now));
+    }
+    Ok(())
+}
+
+fn usage() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Usage: 24! dirs top [N]")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dir_ranking() {
+        let now: u64 = 10_000_000;
+        let dirs = parse(&format!(
+            "{old}\t20\t/home/u/old-project\n\
+             {recent}\t3\t/home/u/src/shesh\n\
+             {recent}\t1\t/home/u/src/shesh\n\
+             garbage line\n\
+             {recent}\t2\t/srv/shesh-data\n",
+            old = now - 30 * 86_400,
+            recent = now - 60,
+        ));
+        assert_eq!(
+            dirs[Path::new("/home/u/src/shesh")],
+            Visits {
+                count: 4,
+                last: now - 60
+            }
+        );
+        let order: Vec<PathBuf> = ranked(dirs, now).into_iter().map(|(p, _)| p).collect();
+        // 4 recent visits beat 20 from a month ago
+        assert_eq!(order[0], Path::new("/home/u/src/shesh"));
+        assert_eq!(order[2], Path::new("/home/u/old-project"));
+
+        assert!(matches(Path::new("/home/u/src/shesh"), &["shesh"]));
+        assert!(matches(Path::new("/home/u/src/shesh"), &["SRC", "sh"]));
+        assert!(!matches(Path::new("/home/u/src/shesh"), &["src"]));
+        assert!(!matches(Path::new("/home/u/src/shesh"), &["shesh", "src"]));
+    }
+
+    #[test]
+    fn test_compaction_size() {
+        let now: u64 = 10_000_000;
+        let long = "/home/u/work/clients/some-client/projects/a-long-project-name/src/module";
+        let dirs: HashMap<PathBuf, Visits> = (0..MAX_DIRS as u64)
+            .map(|i| {
+                let visits = Visits {
+                    count: i + 1,
+                    last: now,
+                };
+                (PathBuf::from(format!("{long}/{i:04}")), visits)
+            })
+            .collect();
+        // 1000 lines this long are well past the threshold
+        let text = compacted(ranked(dirs, now).into_iter());
+        assert!(text.len() as u64 <= COMPACT_BYTES / 2);
+        // The most visited ones are the ones kept
+        let kept = parse(&text);
+        assert!(kept.contains_key(Path::new(&format!("{long}/0999"))));
+        assert!(!kept.contains_key(Path::new(&format!("{long}/0000"))));
+
+        let short = compacted([(PathBuf::from("/srv"), Visits { count: 2, last: 5 })].into_iter());
+        assert_eq!(short, "5\t2\t/srv\n");
+    }
+}
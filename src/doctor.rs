@@ -0,0 +1,252 @@
+use crate::{
+    config::{config_file_path, parse_config},
+    jobs,
+    utils::{find_in_path, self_exe},
+};
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+// From starting the process to the first prompt; a warm start is a few ms
+const SLOW_STARTUP: Duration = Duration::from_millis(50);
+
+static STARTUP: OnceLock<Duration> = OnceLock::new();
+
+/// Called once, just before the first prompt is drawn.
+pub fn startup_done(took: Duration) {
+    let _ = STARTUP.set(took);
+}
+
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+fn report(status: Status, what: &str, remedy: Option<&str>) {
+    let label = match status {
+        Status::Ok => "[OK]  ",
+        Status::Warn => "[WARN]",
+        Status::Fail => "[FAIL]",
+    };
+    println!("{label} {what}");
+    if let Some(remedy) = remedy {
+        println!("       fix: {remedy}");
+    }
+}
+
+// Creates the directory if needed and a scratch file in it
+fn writable(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".shesh-doctor-{}", std::process::id()));
+    File::create(&probe)?;
+    fs::remove_file(probe)
+}
+
+fn check_dir(name: &str, dir: &Path) {
+    match writable(dir) {
+        Ok(()) => report(
+            Status::Ok,
+            &format!("{name} directory {} is writable", dir.display()),
+            None,
+        ),
+        Err(e) => report(
+            Status::Fail,
+            &format!("{name} directory {} is not writable: {e}", dir.display()),
+            Some(&format!(
+                "check the owner and permissions of {}",
+                dir.display()
+            )),
+        ),
+    }
+}
+
+fn check_config() {
+    let path = config_file_path();
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            report(
+                Status::Fail,
+                &format!("can't read {}: {e}", path.display()),
+                Some("restart shesh to write a default config"),
+            );
+            return;
+        }
+    };
+
+    let config = parse_config(&path, &content);
+    if config.files.len() > 1 {
+        report(
+            Status::Ok,
+            &format!(
+                "{} includes {} file(s):",
+                path.display(),
+                config.files.len() - 1
+            ),
+            None,
+        );
+        for file in &config.files[1..] {
+            println!("       {}", file.display());
+        }
+    }
+    if config.warnings.is_empty() {
+        report(
+            Status::Ok,
+            &format!("{} parsed cleanly", path.display()),
+            None,
+        );
+        return;
+    }
+    report(
+        Status::Warn,
+        &format!(
+            "{} has {} line(s) that set nothing",
+            path.display(),
+            config.warnings.len()
+        ),
+        Some("fix the key or value on those lines, or turn them into # comments"),
+    );
+    for warning in &config.warnings {
+        println!("       {warning}");
+    }
+}
+
+fn check_login_shell() {
+    let shell = env::var("SHELL").unwrap_or_default();
+    let resolved = find_in_path(&shell).and_then(|p| fs::canonicalize(p).ok());
+    if resolved.is_some() && resolved == self_exe() {
+        report(Status::Ok, &format!("$SHELL is shesh ({shell})"), None);
+    } else {
+        report(
+            Status::Warn,
+            &format!("$SHELL is '{shell}', not this shesh"),
+            Some("chsh -s $(which shesh), if you want shesh as your login shell"),
+        );
+    }
+}
+
+fn check_path() {
+    let Some(path_var) = env::var_os("PATH") else {
+        report(
+            Status::Fail,
+            "PATH is not set",
+            Some("export PATH=/usr/local/bin:/usr/bin:/bin"),
+        );
+        return;
+    };
+
+    let mut seen = HashSet::new();
+    let mut duplicates = Vec::new();
+    let mut missing = Vec::new();
+    for dir in env::split_paths(&path_var) {
+        if !seen.insert(dir.clone()) {
+            duplicates.push(dir);
+        } else if !dir.is_dir() {
+            missing.push(dir);
+        }
+    }
+
+    let show = |dirs: &[PathBuf]| {
+        dirs.iter()
+            .map(|d| d.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if duplicates.is_empty() && missing.is_empty() {
+        report(
+            Status::Ok,
+            "PATH has no duplicate or missing directories",
+            None,
+        );
+    }
+    if !duplicates.is_empty() {
+        report(
+            Status::Warn,
+            &format!("PATH lists these more than once: {}", show(&duplicates)),
+            Some("drop the repeated entries where PATH is exported"),
+        );
+    }
+    if !missing.is_empty() {
+        report(
+            Status::Warn,
+            &format!("PATH has directories that don't exist: {}", show(&missing)),
+            Some("remove them from PATH, or create them"),
+        );
+    }
+}
+
+fn check_terminal() {
+    let term = env::var("TERM").unwrap_or_default();
+    if term.is_empty() || term == "dumb" {
+        report(
+            Status::Warn,
+            &format!("TERM is '{term}', colors and line editing may not work"),
+            Some("export TERM=xterm-256color (or whatever your terminal emulates)"),
+        );
+    } else if env::var_os("NO_COLOR").is_some() {
+        report(
+            Status::Warn,
+            &format!("TERM is {term} but NO_COLOR is set"),
+            Some("unset NO_COLOR to let programs use colors"),
+        );
+    } else {
+        let depth = match env::var("COLORTERM").as_deref() {
+            Ok("truecolor" | "24bit") => "24-bit color",
+            _ if term.contains("256color") => "256 colors",
+            _ => "basic colors",
+        };
+        report(Status::Ok, &format!("TERM is {term} ({depth})"), None);
+    }
+}
+
+fn check_startup() {
+    let Some(&took) = STARTUP.get() else {
+        return;
+    };
+    let ms = took.as_secs_f64() * 1000.0;
+    if took > SLOW_STARTUP {
+        report(
+            Status::Warn,
+            &format!("first prompt took {ms:.1} ms"),
+            Some("compare with `shesh --norc`, then look at startup files and [startup] commands"),
+        );
+    } else {
+        report(Status::Ok, &format!("first prompt took {ms:.1} ms"), None);
+    }
+}
+
+fn check_zombies() {
+    jobs::refresh();
+    let zombies = jobs::zombie_children();
+    if zombies.is_empty() {
+        report(Status::Ok, "no zombie child processes", None);
+    } else {
+        let pids: Vec<String> = zombies.iter().map(i32::to_string).collect();
+        report(
+            Status::Warn,
+            &format!("zombie children not reaped: {}", pids.join(", ")),
+            Some("run `jobs` to collect finished jobs; please report this if they stay"),
+        );
+    }
+}
+
+/// `24! doctor`: checks the environment shesh depends on.
+pub fn run() -> io::Result<()> {
+    let history_dir = crate::history::file_path();
+    check_dir("history", history_dir.parent().unwrap_or(Path::new(".")));
+    check_dir("cache", &crate::completions::cache_dir());
+    check_config();
+    check_login_shell();
+    check_path();
+    check_terminal();
+    check_startup();
+    check_zombies();
+    Ok(())
+}
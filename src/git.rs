@@ -0,0 +1,164 @@
+use crate::utils::find_in_path;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Mutex, OnceLock},
+};
+
+// An index bigger than this (about 100k files) means a repo where
+// `git status` would make every prompt wait, so dirtiness isn't checked
+const DIRTY_MAX_INDEX: u64 = 8 << 20;
+
+/// What the prompt shows about the repository a directory is in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Info {
+    // The branch, or the short commit when HEAD is detached
+    pub head: String,
+    // None when the check was skipped
+    pub dirty: Option<bool>,
+}
+
+impl Info {
+    // `(main*)`, `(main)` or `(1a2b3c4)`
+    pub fn segment(&self) -> String {
+        let star = if self.dirty == Some(true) { "*" } else { "" };
+        format!("({}{star})", self.head)
+    }
+}
+
+// Per directory, for the prompt being shown; reedline renders the prompt
+// again on every repaint
+static CACHE: OnceLock<Mutex<HashMap<PathBuf, Option<Info>>>> = OnceLock::new();
+
+fn cache() -> &'static Mutex<HashMap<PathBuf, Option<Info>>> {
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Called before each prompt, since the last command may have changed things
+pub fn forget() {
+    cache().lock().unwrap().clear();
+}
+
+/// The git directory of the repository `dir` is in. `.git` is usually a
+/// directory, but a worktree or submodule has a file pointing elsewhere.
+pub fn git_dir(dir: &Path) -> Option<PathBuf> {
+    for parent in dir.ancestors() {
+        let dot_git = parent.join(".git");
+        if dot_git.is_dir() {
+            return Some(dot_git);
+        }
+        if let Ok(content) = fs::read_to_string(&dot_git) {
+            let target = content.strip_prefix("gitdir:")?.trim();
+            return Some(parent.join(target));
+        }
+    }
+    None
+}
+
+// `ref: refs/heads/main` or a commit hash
+fn parse_head(head: &str) -> Option<String> {
+    let head = head.trim();
+    match head.strip_prefix("ref:") {
+        Some(reference) => {
+            let reference = reference.trim();
+            Some(
+                reference
+                    .strip_prefix("refs/heads/")
+                    .unwrap_or(reference)
+                    .to_string(),
+            )
+        }
+        None if head.len() >= 7 && head.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Some(head[..7].to_string())
+        }
+        None => None,
+    }
+}
+
+// Tracked files with changes; never spawns anything without a git binary
+fn is_dirty(dir: &Path, git_dir: &Path) -> Option<bool> {
+    let index = fs::metadata(git_dir.join("index")).ok()?;
+    if index.len() > DIRTY_MAX_INDEX {
+        return None;
+    }
+    let git = find_in_path("git")?;
+    let (child, _waiting) = crate::jobs::spawn_waited(
+        Command::new(git)
+            .arg("-C")
+            .arg(dir)
+            .args(["--no-optional-locks", "status", "--porcelain"])
+            .arg("--untracked-files=no")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+    )
+    .ok()?;
+    let output = child.wait_with_output().ok()?;
+    output.status.success().then_some(!output.stdout.is_empty())
+}
+
+fn read_info(dir: &Path, check_dirty: bool) -> Option<Info> {
+    let git_dir = git_dir(dir)?;
+    let head = parse_head(&fs::read_to_string(git_dir.join("HEAD")).ok()?)?;
+    let dirty = if check_dirty {
+        is_dirty(dir, &git_dir)
+    } else {
+        None
+    };
+    Some(Info { head, dirty })
+}
+
+/// The repository `dir` is in, if any; cached until the next prompt.
+pub fn info(dir: &Path, check_dirty: bool) -> Option<Info> {
+    if let Some(info) = cache().lock().unwrap().get(dir) {
+        return info.clone();
+    }
+    let info = read_info(dir, check_dirty);
+    cache()
+        .lock()
+        .unwrap()
+        .insert(dir.to_path_buf(), info.clone());
+    info
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_read_info() {
+        let root = TempDir::new("git");
+        let sub = root.join("src/deep");
+        fs::create_dir_all(&sub).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join(".git/HEAD"), "ref: refs/heads/feature/x\n").unwrap();
+
+        let info = read_info(&sub, false).unwrap();
+        assert_eq!(info.head, "feature/x");
+        assert_eq!(info.dirty, None);
+        assert_eq!(info.segment(), "(feature/x)");
+
+        fs::write(
+            root.join(".git/HEAD"),
+            "1a2b3c4d5e6f7a8b9c0d1a2b3c4d5e6f7a8b9c0d\n",
+        )
+        .unwrap();
+        assert_eq!(read_info(&root, false).unwrap().head, "1a2b3c4");
+
+        // A worktree's .git file points at the real git directory
+        let worktree = root.join("wt");
+        fs::create_dir_all(&worktree).unwrap();
+        fs::write(worktree.join(".git"), "gitdir: ../.git\n").unwrap();
+        assert_eq!(read_info(&worktree, false).unwrap().head, "1a2b3c4");
+
+        assert_eq!(parse_head("garbage"), None);
+        let dirty = Info {
+            head: "main".into(),
+            dirty: Some(true),
+        };
+        assert_eq!(dirty.segment(), "(main*)");
+    }
+}
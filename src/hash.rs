@@ -0,0 +1,123 @@
+use crate::utils::{find_in_path, is_executable};
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    io,
+    os::unix::ffi::OsStringExt,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+struct Entry {
+    path: PathBuf,
+    hits: usize,
+}
+
+// Command name -> where PATH lookup found it, like bash's hash table
+static TABLE: OnceLock<Mutex<HashMap<String, Entry>>> = OnceLock::new();
+
+fn table() -> &'static Mutex<HashMap<String, Entry>> {
+    TABLE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Path of `cmd` for running it, counting a hit. The PATH search only
+/// happens on a miss or once the cached file is gone; names containing
+/// a slash are paths already and never cached.
+pub fn lookup(cmd: &str) -> Option<PathBuf> {
+    if cmd.contains('/') {
+        return None;
+    }
+    let mut table = table().lock().unwrap();
+    if let Some(entry) = table.get_mut(cmd)
+        && is_executable(&entry.path)
+    {
+        entry.hits += 1;
+        return Some(entry.path.clone());
+    }
+
+    let path = find_in_path(cmd)?;
+    table.insert(
+        cmd.to_string(),
+        Entry {
+            path: path.clone(),
+            hits: 1,
+        },
+    );
+    Some(path)
+}
+
+// What to hand to execvp: the cached path when there is one, else the name
+pub fn exec_path(cmd: &str) -> io::Result<CString> {
+    let bytes = match lookup(cmd) {
+        Some(path) => path.into_os_string().into_vec(),
+        None => cmd.into(),
+    };
+    Ok(CString::new(bytes)?)
+}
+
+// Dropped when PATH changes and by `hash -r`
+pub fn forget_all() {
+    table().lock().unwrap().clear();
+}
+
+// hash [-r] [name...]: list the table, clear it, or look names up again
+pub fn handle_hash(args: &[&str]) -> io::Result<()> {
+    match args {
+        [] => {
+            let table = table().lock().unwrap();
+            if table.is_empty() {
+                println!("hash: hash table empty");
+                return Ok(());
+            }
+            let mut entries: Vec<_> = table.iter().collect();
+            entries.sort_by_key(|(name, _)| *name);
+            println!("hits  command");
+            for (name, entry) in entries {
+                println!("{:>4}  {name}\t{}", entry.hits, entry.path.display());
+            }
+            Ok(())
+        }
+        ["-r"] => {
+            forget_all();
+            Ok(())
+        }
+        names => {
+            let mut missing = Vec::new();
+            for name in names {
+                match find_in_path(name).filter(|_| !name.contains('/')) {
+                    Some(path) => {
+                        table()
+                            .lock()
+                            .unwrap()
+                            .insert(name.to_string(), Entry { path, hits: 0 });
+                    }
+                    None => missing.push(*name),
+                }
+            }
+            if missing.is_empty() {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("hash: {}: not found", missing.join(", ")),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup_counts_hits() {
+        let first = lookup("sh").unwrap();
+        assert_eq!(lookup("sh"), Some(first));
+        assert!(table().lock().unwrap()["sh"].hits >= 2);
+
+        assert!(lookup("/bin/sh").is_none());
+        assert!(lookup("shesh-no-such-command").is_none());
+        assert!(handle_hash(&["shesh-no-such-command"]).is_err());
+    }
+}
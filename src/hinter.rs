@@ -0,0 +1,194 @@
+use crate::theme;
+use nu_ansi_term::{Color, Style};
+use reedline::{Hinter, History, SearchQuery};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+// Matching lines looked at when picking the most frequent one
+const FREQUENT_SCAN: i64 = 2000;
+
+/// Which history line the inline suggestion completes to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Recent,
+    Frequent,
+}
+
+/// The config's `[hinter]` section: the grey inline suggestion taken from
+/// history, not the hints printed after a failed command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HinterConfig {
+    pub enabled: bool,
+    // None follows the theme's hint color
+    pub color: Option<Color>,
+    pub italic: bool,
+    pub underline: bool,
+    pub min_chars: usize,
+    pub source: Source,
+}
+
+impl Default for HinterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            color: None,
+            italic: true,
+            underline: true,
+            min_chars: 1,
+            source: Source::Recent,
+        }
+    }
+}
+
+// Read on every keystroke, so `24! reload` and theme switches apply at once
+static SETTINGS: OnceLock<Mutex<HinterConfig>> = OnceLock::new();
+
+fn settings() -> HinterConfig {
+    SETTINGS
+        .get_or_init(|| Mutex::new(HinterConfig::default()))
+        .lock()
+        .unwrap()
+        .clone()
+}
+
+// The line at the last repaint, if the cursor sat at its end; Ctrl-C writes
+// ^C after it only then, so the mark can't land mid-line
+static DRAWN: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn drawn_line() -> Option<String> {
+    DRAWN.lock().unwrap().clone()
+}
+
+pub fn configure(config: HinterConfig) {
+    *SETTINGS
+        .get_or_init(|| Mutex::new(HinterConfig::default()))
+        .lock()
+        .unwrap() = config;
+}
+
+fn style(config: &HinterConfig) -> Style {
+    let mut style = Style::new().fg(config.color.unwrap_or(theme::active().hint));
+    if config.italic {
+        style = style.italic();
+    }
+    if config.underline {
+        style = style.underline();
+    }
+    style
+}
+
+// The most common of `lines`, the most recent one on a tie; `lines` is
+// newest first
+fn most_frequent(lines: &[String]) -> Option<&String> {
+    let mut counts: HashMap<&String, usize> = HashMap::new();
+    for line in lines {
+        *counts.entry(line).or_default() += 1;
+    }
+    let best = counts.values().copied().max()?;
+    lines.iter().find(|line| counts[line] == best)
+}
+
+/// Suggests the rest of a history line that starts with what was typed.
+#[derive(Default)]
+pub struct HistoryHinter {
+    current: String,
+}
+
+impl HistoryHinter {
+    fn find(config: &HinterConfig, line: &str, history: &dyn History) -> Option<String> {
+        let query = SearchQuery::last_with_prefix(line.to_string(), history.session());
+        let found = match config.source {
+            Source::Recent => history.search(query).ok()?.into_iter().next()?.command_line,
+            Source::Frequent => {
+                let query = SearchQuery {
+                    limit: Some(FREQUENT_SCAN),
+                    ..query
+                };
+                let lines: Vec<String> = history
+                    .search(query)
+                    .ok()?
+                    .into_iter()
+                    .map(|item| item.command_line)
+                    .collect();
+                most_frequent(&lines)?.clone()
+            }
+        };
+        found.get(line.len()..).map(str::to_string)
+    }
+}
+
+impl Hinter for HistoryHinter {
+    fn handle(
+        &mut self,
+        line: &str,
+        pos: usize,
+        history: &dyn History,
+        use_ansi_coloring: bool,
+        _cwd: &str,
+    ) -> String {
+        *DRAWN.lock().unwrap() = (pos == line.len()).then(|| line.to_string());
+        let config = settings();
+        self.current = if config.enabled && line.chars().count() >= config.min_chars {
+            Self::find(&config, line, history).unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        if use_ansi_coloring && !self.current.is_empty() {
+            style(&config).paint(&self.current).to_string()
+        } else {
+            self.current.clone()
+        }
+    }
+
+    fn complete_hint(&self) -> String {
+        self.current.clone()
+    }
+
+    // Leading spaces and the word after them, for accepting a hint word by word
+    fn next_hint_token(&self) -> String {
+        let start = self.current.len() - self.current.trim_start().len();
+        let end = self.current[start..]
+            .find(char::is_whitespace)
+            .map_or(self.current.len(), |i| start + i);
+        self.current[..end].to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::history::ShellHistory;
+    use reedline::HistoryItem;
+
+    #[test]
+    fn test_recent_and_frequent() {
+        let mut history = ShellHistory::new(100);
+        for line in ["git status", "git stash", "git status", "git stash pop"] {
+            history.save(HistoryItem::from_command_line(line)).unwrap();
+        }
+
+        let recent = HinterConfig::default();
+        assert_eq!(
+            HistoryHinter::find(&recent, "git st", &history).as_deref(),
+            Some("ash pop")
+        );
+        let frequent = HinterConfig {
+            source: Source::Frequent,
+            ..HinterConfig::default()
+        };
+        assert_eq!(
+            HistoryHinter::find(&frequent, "git st", &history).as_deref(),
+            Some("atus")
+        );
+
+        let mut hinter = HistoryHinter {
+            current: "  stash pop".into(),
+        };
+        assert_eq!(hinter.next_hint_token(), "  stash");
+        hinter.current.clear();
+        assert_eq!(hinter.next_hint_token(), "");
+    }
+}
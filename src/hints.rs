@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Turned off with `hints = false` in the config
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+// PowerShell cmdlets and aliases people tend to paste, with the closest Unix command
+static CMDLETS: &[(&str, &str)] = &[
+    ("get-childitem", "ls -la"),
+    ("gci", "ls -la"),
+    ("where-object", "grep"),
+    ("select-string", "grep"),
+    ("sls", "grep"),
+    ("select-object", "head / cut"),
+    ("sort-object", "sort"),
+    ("measure-object", "wc -l"),
+    ("get-content", "cat"),
+    ("gc", "cat"),
+    ("set-content", "echo ... > file"),
+    ("set-location", "cd"),
+    ("get-location", "pwd"),
+    ("copy-item", "cp"),
+    ("move-item", "mv"),
+    ("remove-item", "rm"),
+    ("new-item", "touch / mkdir"),
+    ("write-output", "echo"),
+    ("write-host", "echo"),
+    ("get-process", "ps aux"),
+    ("stop-process", "kill"),
+    ("clear-host", "clear"),
+    ("cls", "clear"),
+    ("invoke-webrequest", "curl"),
+    ("iwr", "curl"),
+    ("get-help", "man"),
+];
+
+pub fn set_enabled(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+pub fn cmdlet_hint(cmd: &str) -> Option<String> {
+    let lower = cmd.to_ascii_lowercase();
+    CMDLETS
+        .iter()
+        .find(|(cmdlet, _)| *cmdlet == lower)
+        .map(|(_, unix)| format!("hint: '{cmd}' is a PowerShell command, try: {unix}"))
+}
+
+// `C:\...`, `.\...`, `..\...` or a `\\server` share, written without quotes
+fn looks_like_windows_path(word: &str) -> bool {
+    let b = word.as_bytes();
+    (b.len() > 2 && b[0].is_ascii_alphabetic() && b[1] == b':' && b[2] == b'\\')
+        || word.starts_with(".\\")
+        || word.starts_with("..\\")
+        || word.starts_with("\\\\")
+}
+
+pub fn windows_path_hint(word: &str) -> Option<String> {
+    looks_like_windows_path(word).then(|| {
+        format!(
+            "hint: backslashes are escape characters in shesh, quote the path as '{word}' or use forward slashes: {}",
+            word.replace('\\', "/")
+        )
+    })
+}
+
+/// Suggestions for a line that just failed with `status`; only consulted on
+/// the error path. A cmdlet is only pointed out when a command wasn't found
+/// (127) and it's one of the commands that don't exist here.
+pub fn for_failed_line(line: &str, status: i32) -> Vec<String> {
+    if !ENABLED.load(Ordering::Relaxed) || status == 0 {
+        return Vec::new();
+    }
+
+    let commands = line
+        .split([';', '|', '&'])
+        .filter_map(|segment| segment.split_whitespace().next())
+        .filter(|cmd| status == 127 && !crate::builtins::is_known_command(cmd))
+        .filter_map(cmdlet_hint);
+    let paths = line.split_whitespace().filter_map(windows_path_hint);
+
+    let mut hints: Vec<String> = commands.chain(paths).collect();
+    hints.dedup();
+    hints
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hints() {
+        let hints = for_failed_line("Get-ChildItem | Where-Object {$_.Length -gt 1kb}", 127);
+        assert_eq!(
+            hints,
+            vec![
+                "hint: 'Get-ChildItem' is a PowerShell command, try: ls -la",
+                "hint: 'Where-Object' is a PowerShell command, try: grep",
+            ]
+        );
+        assert!(cmdlet_hint("get-content").unwrap().ends_with("try: cat"));
+        assert!(cmdlet_hint("Remove-Item").unwrap().ends_with("try: rm"));
+        assert!(cmdlet_hint("ls").is_none());
+        // Only a command that wasn't found gets one: not a cmdlet name that
+        // ran fine, nor any line failing otherwise
+        crate::builtins::set_alias("iwr", "curl");
+        assert_eq!(
+            for_failed_line("iwr example.com; cls", 127),
+            vec!["hint: 'cls' is a PowerShell command, try: clear"]
+        );
+        assert!(for_failed_line("cls; false", 1).is_empty());
+        assert!(for_failed_line("Get-ChildItem", 0).is_empty());
+
+        assert_eq!(
+            for_failed_line(r"ls C:\Users\me", 2),
+            vec![
+                r"hint: backslashes are escape characters in shesh, quote the path as 'C:\Users\me' or use forward slashes: C:/Users/me"
+            ]
+        );
+        assert!(windows_path_hint(r".\build.ps1").is_some());
+        assert!(windows_path_hint(r"'C:\quoted'").is_none());
+        assert!(windows_path_hint("a\\ b").is_none());
+    }
+}
@@ -0,0 +1,1614 @@
+use crate::{
+    config::history_file_path,
+    utils::{atomic_write_with, glob_match},
+};
+use reedline::{
+    CommandLineSearch, History, HistoryItem, HistoryItemId, HistorySessionId, ReedlineError,
+    ReedlineErrorVariants, SearchDirection, SearchQuery,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, MutexGuard, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Entries kept in memory and read from the end of the file at startup,
+// unless `[history] size` says otherwise
+pub const CAPACITY: usize = 6000;
+
+// Same on-disk escaping as reedline's FileBackedHistory so existing files keep working
+const NEWLINE_ESCAPE: &str = "<\\n>";
+
+// Bytes read per step while scanning the history file backwards
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Append-only history backend for large history files.
+///
+/// Only the last `capacity` lines are read at startup. Older lines are loaded
+/// lazily the first time a search walks past the in-memory tail, and they get
+/// negative ids so ordering by id still matches file order.
+pub struct ShellHistory {
+    capacity: usize,
+    ignore_dups: bool,
+    ignore_space: bool,
+    erase_dups: bool,
+    // `[history] ignore` patterns; a matching line is kept out of memory
+    // and the file alike
+    ignore: Vec<String>,
+    // Pick up lines other sessions append to the file
+    share: bool,
+    entries: VecDeque<String>,
+    // When each of `entries` was entered, None for lines written before
+    // times were recorded
+    times: VecDeque<Option<u64>>,
+    file: Option<PathBuf>,
+    len_on_disk: usize,
+    tail_offset: u64,
+    // How far the file has been read and which file it was, to tell lines
+    // other sessions appended from a file rewritten under us
+    file_len: u64,
+    file_ino: u64,
+    older: OnceLock<Vec<String>>,
+}
+
+fn encode_entry(s: &str) -> String {
+    s.replace('\n', NEWLINE_ESCAPE)
+}
+
+fn decode_entry(s: &str) -> String {
+    split_time(s).1.replace(NEWLINE_ESCAPE, "\n")
+}
+
+// zsh's extended history line, `: <start>:<elapsed>;<command>`, with the
+// elapsed time left at 0
+fn encode_line(time: Option<u64>, entry: &str) -> String {
+    match time {
+        Some(time) => format!(": {time}:0;{}", encode_entry(entry)),
+        None => encode_entry(entry),
+    }
+}
+
+// The time a line was entered and the command after it. Only what
+// `encode_line` writes is taken as a time, so a line always encodes back to
+// the same bytes.
+fn split_time(line: &str) -> (Option<u64>, &str) {
+    let parsed = (|| {
+        let (time, rest) = line.strip_prefix(": ")?.split_once(":0;")?;
+        let secs: u64 = time.parse().ok()?;
+        (secs.to_string() == time).then_some((Some(secs), rest))
+    })();
+    parsed.unwrap_or((None, line))
+}
+
+fn now() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
+
+fn construct_entry(id: i64, command_line: String) -> HistoryItem {
+    HistoryItem {
+        id: Some(HistoryItemId::new(id)),
+        ..HistoryItem::from_command_line(command_line)
+    }
+}
+
+impl ShellHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ignore_dups: true,
+            ignore_space: false,
+            erase_dups: false,
+            ignore: Vec::new(),
+            share: false,
+            entries: VecDeque::new(),
+            times: VecDeque::new(),
+            file: None,
+            len_on_disk: 0,
+            tail_offset: 0,
+            file_len: 0,
+            file_ino: 0,
+            older: OnceLock::new(),
+        }
+    }
+
+    pub fn with_file(capacity: usize, file: PathBuf) -> io::Result<Self> {
+        let mut history = Self::new(capacity);
+        let (lines, tail_offset) = match File::open(&file) {
+            Ok(f) => {
+                // Lines appended after this are another session's, read by `sync`
+                let meta = f.metadata()?;
+                history.file_len = meta.len();
+                history.file_ino = meta.ino();
+                read_tail(f, capacity, meta.len())?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Vec::new(), 0),
+            Err(e) => return Err(e),
+        };
+
+        history.entries = lines.iter().map(|l| decode_entry(l)).collect();
+        history.times = lines.iter().map(|l| split_time(l).0).collect();
+        history.len_on_disk = history.entries.len();
+        history.tail_offset = tail_offset;
+        history.file = Some(file);
+        Ok(history)
+    }
+
+    // Lines of the file that precede the in-memory tail, read on first use
+    fn older(&self) -> &[String] {
+        self.older.get_or_init(|| {
+            let Some(path) = &self.file else {
+                return Vec::new();
+            };
+            let Ok(file) = File::open(path) else {
+                return Vec::new();
+            };
+            let mut head = Vec::new();
+            if file.take(self.tail_offset).read_to_end(&mut head).is_err() {
+                return Vec::new();
+            }
+            String::from_utf8_lossy(&head)
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(decode_entry)
+                .collect()
+        })
+    }
+
+    fn get(&self, id: i64) -> Option<&String> {
+        if id >= 0 {
+            self.entries.get(id as usize)
+        } else {
+            let older = self.older();
+            older.get(older.len().checked_sub(id.unsigned_abs() as usize)?)
+        }
+    }
+
+    /// Every entry with its number, oldest first. Numbers count from the
+    /// start of the file, so they stay the same however much is in memory.
+    pub fn numbered(&self) -> impl Iterator<Item = (usize, &String)> {
+        self.older()
+            .iter()
+            .chain(&self.entries)
+            .enumerate()
+            .map(|(i, entry)| (i + 1, entry))
+    }
+
+    // Item id of the entry shown as `number`
+    fn id_of(&self, number: usize) -> Option<i64> {
+        let id = number.checked_sub(1)? as i64 - self.older().len() as i64;
+        self.get(id).is_some().then_some(id)
+    }
+
+    // Drops one entry and rewrites the file without it
+    fn remove_entry(&mut self, id: i64) -> io::Result<()> {
+        self.sync()?;
+        let Some(path) = self.file.clone() else {
+            if id >= 0 {
+                self.entries.remove(id as usize);
+                self.times.remove(id as usize);
+            } else if let Some(older) = self.older.get_mut() {
+                older.remove(older.len() - id.unsigned_abs() as usize);
+            }
+            return Ok(());
+        };
+
+        // Everything is on disk now, the file has the entries with their times.
+        // Without `share` another session's lines can sit between ours, so
+        // the entry is at its number or further on.
+        let skip = (self.older().len() as i64 + id) as usize;
+        let target = self.get(id).cloned();
+        with_lock(&path, |_| {
+            let mut entries = read_timed(&path)?;
+            let found = (skip..entries.len()).find(|&i| Some(&entries[i].1) == target.as_ref());
+            if let Some(i) = found {
+                entries.remove(i);
+            }
+            write_timed(&path, &entries)
+        })?;
+        self.reopen(path)
+    }
+
+    // Drops every earlier copy of `entry`, rewriting the file if one is in it
+    fn erase(&mut self, entry: &str) -> io::Result<()> {
+        self.sync()?;
+        let Some(path) = self.file.clone() else {
+            (self.times, self.entries) = self
+                .times
+                .drain(..)
+                .zip(self.entries.drain(..))
+                .filter(|(_, e)| e != entry)
+                .unzip();
+            return Ok(());
+        };
+        with_lock(&path, |_| {
+            let mut entries = read_timed(&path)?;
+            entries.retain(|(_, e)| e != entry);
+            write_timed(&path, &entries)
+        })?;
+        self.reopen(path)
+    }
+
+    // Reads the file again after it was rewritten, keeping the settings
+    fn reopen(&mut self, path: PathBuf) -> io::Result<()> {
+        let mut reopened = Self::with_file(self.capacity, path)?;
+        reopened.ignore_dups = self.ignore_dups;
+        reopened.ignore_space = self.ignore_space;
+        reopened.erase_dups = self.erase_dups;
+        reopened.ignore = std::mem::take(&mut self.ignore);
+        reopened.share = self.share;
+        // Everything it had is in the file, dropping it must not sync
+        self.file = None;
+        *self = reopened;
+        Ok(())
+    }
+
+    // Adds an entry at the end, moving the oldest out of memory when full
+    fn push(&mut self, time: Option<u64>, entry: String) {
+        if self.entries.len() == self.capacity
+            && let Some(oldest) = self.entries.pop_front()
+            && let Some(time) = self.times.pop_front()
+            && self.len_on_disk > 0
+        {
+            // It is on disk, so it now counts as one of the older lines
+            self.len_on_disk -= 1;
+            match self.older.get_mut() {
+                Some(older) => older.push(oldest),
+                None => self.tail_offset += encode_line(time, &oldest).len() as u64 + 1,
+            }
+        }
+        self.entries.push_back(entry);
+        self.times.push_back(time);
+    }
+
+    // Entries not written yet, taken out so lines from the file can go
+    // before them
+    fn take_unsaved(&mut self) -> Vec<Timed> {
+        let at = self.len_on_disk.min(self.entries.len());
+        let times = self.times.split_off(at);
+        times.into_iter().zip(self.entries.split_off(at)).collect()
+    }
+
+    // The file grew or was replaced since it was last read
+    fn changed_on_disk(&self, path: &Path) -> bool {
+        match fs::metadata(path) {
+            Ok(meta) => meta.len() != self.file_len || meta.ino() != self.file_ino,
+            Err(_) => self.file_len > 0,
+        }
+    }
+
+    // Brings in what other sessions did to the file: appended lines are
+    // added as they are, a rewritten file is read again. Unsaved entries
+    // stay last.
+    fn merge(&mut self, file: &File, path: &Path) -> io::Result<()> {
+        let meta = file.metadata()?;
+        if meta.len() == self.file_len && meta.ino() == self.file_ino {
+            return Ok(());
+        }
+        let unsaved = self.take_unsaved();
+        if meta.ino() != self.file_ino || meta.len() < self.file_len {
+            self.reopen(path.to_path_buf())?;
+        } else {
+            let mut appended = Vec::new();
+            let mut reader = file;
+            reader.seek(SeekFrom::Start(self.file_len))?;
+            reader
+                .take(meta.len() - self.file_len)
+                .read_to_end(&mut appended)?;
+            for line in String::from_utf8_lossy(&appended).lines() {
+                if !line.trim().is_empty() {
+                    self.push(split_time(line).0, decode_entry(line));
+                    self.len_on_disk += 1;
+                }
+            }
+            self.file_len = meta.len();
+        }
+        for (time, entry) in unsaved {
+            self.push(time, entry);
+        }
+        Ok(())
+    }
+}
+
+// Runs `f` holding an exclusive lock on the history file, so appends from
+// several sessions never interleave and a rewrite doesn't lose one. When
+// another session renamed a rewritten file into place while this one
+// waited, the new file is locked instead.
+fn with_lock<T>(path: &Path, f: impl FnOnce(&File) -> io::Result<T>) -> io::Result<T> {
+    let open = || {
+        OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+    };
+    loop {
+        // The directory is made with the first line written, not at startup
+        let file = match open() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                open()?
+            }
+            opened => opened?,
+        };
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let current = fs::metadata(path).map(|meta| meta.ino()).ok();
+        if current == Some(file.metadata()?.ino()) {
+            // The lock goes with the file when it's closed
+            return f(&file);
+        }
+    }
+}
+
+// Reads the last `capacity` lines by walking the file backwards in chunks.
+// Returns the lines and the byte offset where the first of them starts.
+fn read_tail(mut file: File, capacity: usize, len: u64) -> io::Result<(Vec<String>, u64)> {
+    let mut pos = len;
+    let mut buf: Vec<u8> = Vec::new();
+
+    loop {
+        let newlines = buf.iter().filter(|&&b| b == b'\n').count();
+        // One extra newline marks the end of the line before the tail
+        if pos == 0 || newlines > capacity {
+            break;
+        }
+        let step = CHUNK_SIZE.min(pos);
+        pos -= step;
+        let mut chunk = vec![0; step as usize];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&buf);
+        buf = chunk;
+    }
+
+    let mut start = 0;
+    if pos > 0 {
+        // The first line may be cut off by the chunk boundary, it belongs to the older part
+        start = buf
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(buf.len(), |i| i + 1);
+    }
+
+    let text = String::from_utf8_lossy(&buf[start..]);
+    let mut lines: Vec<&str> = text.lines().collect();
+    let mut offset = pos + start as u64;
+
+    if lines.len() > capacity {
+        let skip = lines.len() - capacity;
+        offset += lines[..skip]
+            .iter()
+            .map(|l| l.len() as u64 + 1)
+            .sum::<u64>();
+        lines.drain(..skip);
+    }
+
+    let lines = lines
+        .into_iter()
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect();
+    Ok((lines, offset))
+}
+
+impl History for ShellHistory {
+    fn save(&mut self, h: HistoryItem) -> reedline::Result<HistoryItem> {
+        let entry = h.command_line;
+        if entry.is_empty()
+            || self.capacity == 0
+            || (self.ignore_space && entry.starts_with(' '))
+            || self
+                .ignore
+                .iter()
+                .any(|pattern| glob_match(pattern, &entry))
+            || (self.ignore_dups && self.entries.back() == Some(&entry))
+        {
+            return Ok(HistoryItem::from_command_line(entry));
+        }
+        if self.erase_dups && self.numbered().any(|(_, e)| *e == entry) {
+            self.erase(&entry)
+                .map_err(|e| ReedlineError(ReedlineErrorVariants::IOError(e)))?;
+        }
+
+        self.push(now(), entry.clone());
+        Ok(construct_entry(self.entries.len() as i64 - 1, entry))
+    }
+
+    fn load(&self, id: HistoryItemId) -> reedline::Result<HistoryItem> {
+        self.get(id.0)
+            .map(|cmd| construct_entry(id.0, cmd.clone()))
+            .ok_or(ReedlineError(ReedlineErrorVariants::OtherHistoryError(
+                "Item does not exist",
+            )))
+    }
+
+    fn count(&self, query: SearchQuery) -> reedline::Result<i64> {
+        Ok(self.search(query)?.len() as i64)
+    }
+
+    fn search(&self, query: SearchQuery) -> reedline::Result<Vec<HistoryItem>> {
+        if query.start_time.is_some() || query.end_time.is_some() {
+            return Err(ReedlineError(
+                ReedlineErrorVariants::HistoryFeatureUnsupported {
+                    history: "ShellHistory",
+                    feature: "filtering by time",
+                },
+            ));
+        }
+
+        let matches = |cmd: &String| match &query.filter.command_line {
+            Some(CommandLineSearch::Prefix(p)) => cmd.starts_with(p),
+            Some(CommandLineSearch::Substring(p)) => cmd.contains(p),
+            Some(CommandLineSearch::Exact(p)) => cmd == p,
+            None => true,
+        };
+
+        let newest = self.entries.len() as i64 - 1;
+        let limit = query.limit.map_or(usize::MAX, |l| l.max(0) as usize);
+
+        let results: Vec<i64> = match query.direction {
+            SearchDirection::Backward => {
+                let from = query.start_id.map_or(newest, |id| id.0 - 1);
+                let to = query.end_id.map_or(i64::MIN, |id| id.0 + 1);
+                // Older entries are only loaded once the in-memory tail is exhausted
+                let older = std::iter::once(()).flat_map(|_| {
+                    let len = self.older().len() as i64;
+                    (-len..=from.min(-1)).rev()
+                });
+                (0..=from.min(newest))
+                    .rev()
+                    .chain(older)
+                    .take_while(|&id| id >= to)
+                    .filter(|&id| self.get(id).is_some_and(matches))
+                    .take(limit)
+                    .collect()
+            }
+            SearchDirection::Forward => {
+                let from = query.start_id.map_or(0, |id| id.0 + 1);
+                let to = query.end_id.map_or(newest, |id| id.0 - 1);
+                let older = std::iter::once(())
+                    .filter(|_| from < 0)
+                    .flat_map(|_| from.max(-(self.older().len() as i64))..=to.min(-1));
+                older
+                    .chain(from.max(0)..=to)
+                    .filter(|&id| self.get(id).is_some_and(matches))
+                    .take(limit)
+                    .collect()
+            }
+        };
+        Ok(results
+            .into_iter()
+            .filter_map(|id| Some(construct_entry(id, self.get(id)?.clone())))
+            .collect())
+    }
+
+    fn update(
+        &mut self,
+        _id: HistoryItemId,
+        _updater: &dyn Fn(HistoryItem) -> HistoryItem,
+    ) -> reedline::Result<()> {
+        Err(ReedlineError(
+            ReedlineErrorVariants::HistoryFeatureUnsupported {
+                history: "ShellHistory",
+                feature: "updating entries",
+            },
+        ))
+    }
+
+    fn clear(&mut self) -> reedline::Result<()> {
+        self.entries.clear();
+        self.times.clear();
+        self.len_on_disk = 0;
+        self.tail_offset = 0;
+        self.older = OnceLock::new();
+        self.file_len = 0;
+
+        if let Some(file) = &self.file {
+            with_lock(file, |_| fs::remove_file(file))
+                .map_err(|e| ReedlineError(ReedlineErrorVariants::IOError(e)))?;
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, h: HistoryItemId) -> reedline::Result<()> {
+        if self.get(h.0).is_none() {
+            return Err(ReedlineError(ReedlineErrorVariants::OtherHistoryError(
+                "Item does not exist",
+            )));
+        }
+        self.remove_entry(h.0)
+            .map_err(|e| ReedlineError(ReedlineErrorVariants::IOError(e)))
+    }
+
+    // Appends entries that aren't on disk yet, the file is never rewritten
+    // here. With `share`, lines other sessions added since the last call are
+    // read in first, so memory keeps the order of the file.
+    fn sync(&mut self) -> io::Result<()> {
+        let Some(path) = self.file.clone() else {
+            return Ok(());
+        };
+        let unsaved = self.len_on_disk < self.entries.len();
+        let changed = self.share && self.changed_on_disk(&path);
+        if !unsaved && !changed {
+            return Ok(());
+        }
+
+        with_lock(&path, |mut file| {
+            if self.share {
+                self.merge(file, &path)?;
+            }
+            let mut pending = String::new();
+            let unsaved = self.len_on_disk..;
+            for (time, entry) in self
+                .times
+                .range(unsaved.clone())
+                .zip(self.entries.range(unsaved))
+            {
+                pending.push_str(&encode_line(*time, entry));
+                pending.push('\n');
+            }
+            file.write_all(pending.as_bytes())?;
+            self.len_on_disk = self.entries.len();
+            let meta = file.metadata()?;
+            self.file_len = meta.len();
+            self.file_ino = meta.ino();
+            Ok(())
+        })
+    }
+
+    fn session(&self) -> Option<HistorySessionId> {
+        None
+    }
+}
+
+impl Drop for ShellHistory {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+// The history reedline edits, shared with the `history` builtin. Loaded on
+// first use, which is the editor's startup unless a builtin gets there first.
+static SHARED: OnceLock<Mutex<ShellHistory>> = OnceLock::new();
+
+/// The config's `[history]` section.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub size: usize,
+    pub file: Option<PathBuf>,
+    // Skip a line that repeats the one before it
+    pub ignore_dups: bool,
+    // Keep lines starting with a space out of the file
+    pub ignore_space: bool,
+    // Drop earlier copies of a line when it's entered again
+    pub erase_dups: bool,
+    // Lines matching one of these `*` and `?` patterns are never recorded
+    pub ignore: Vec<String>,
+    // `!!`, `!N`, `!prefix`, `!$` and `!*` in typed lines
+    pub expansion: bool,
+    pub record_failed: bool,
+    // Drop a line whose command wasn't found, so typos stay out
+    pub ignore_not_found: bool,
+    // Lines entered in other sessions show up here before the next prompt;
+    // off keeps each session to its own lines until it restarts
+    pub share: bool,
+    // Off for an incognito session: recall works, nothing is read or written
+    pub enabled: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            size: CAPACITY,
+            file: None,
+            ignore_dups: true,
+            ignore_space: false,
+            erase_dups: false,
+            ignore: Vec::new(),
+            expansion: true,
+            record_failed: true,
+            ignore_not_found: false,
+            share: true,
+            enabled: true,
+        }
+    }
+}
+
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+
+static EXPANSION: AtomicBool = AtomicBool::new(true);
+
+// `[history] ignore`, kept apart from the other options so `24! reload`
+// can change it
+static IGNORE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Applied at startup and on `24! reload`. Every line is recorded through
+/// `save`, before it is written by `sync` or `command_done`, so checking
+/// there keeps a match out of the file and out of arrow-up recall.
+pub fn set_ignored(patterns: &[String]) {
+    *IGNORE.lock().unwrap() = patterns.to_vec();
+    if let Some(history) = SHARED.get() {
+        history.lock().unwrap().ignore = patterns.to_vec();
+    }
+}
+
+// Set once at startup, before the shared history is opened
+pub fn configure(options: Options) {
+    EXPANSION.store(options.expansion, Ordering::Relaxed);
+    let _ = OPTIONS.set(options);
+}
+
+pub fn options() -> &'static Options {
+    OPTIONS.get_or_init(Options::default)
+}
+
+/// Where history is kept: `[history] file`, or the default location.
+pub fn file_path() -> PathBuf {
+    options().file.clone().unwrap_or_else(history_file_path)
+}
+
+fn shared() -> MutexGuard<'static, ShellHistory> {
+    SHARED
+        .get_or_init(|| {
+            let options = options();
+            let size = options.size.max(1);
+            let mut history = if options.enabled {
+                ShellHistory::with_file(size, file_path())
+                    .unwrap_or_else(|_| ShellHistory::new(size))
+            } else {
+                ShellHistory::new(size)
+            };
+            history.ignore_dups = options.ignore_dups;
+            history.ignore_space = options.ignore_space;
+            history.erase_dups = options.erase_dups;
+            history.ignore = IGNORE.lock().unwrap().clone();
+            history.share = options.share;
+            Mutex::new(history)
+        })
+        .lock()
+        .unwrap()
+}
+
+/// Handle given to reedline; every call goes to the shared history, so the
+/// builtin sees what was just typed and its changes show up in the editor.
+pub struct SharedHistory;
+
+impl SharedHistory {
+    pub fn open() -> Self {
+        drop(shared());
+        SharedHistory
+    }
+}
+
+impl History for SharedHistory {
+    fn save(&mut self, h: HistoryItem) -> reedline::Result<HistoryItem> {
+        shared().save(h)
+    }
+
+    fn load(&self, id: HistoryItemId) -> reedline::Result<HistoryItem> {
+        shared().load(id)
+    }
+
+    fn count(&self, query: SearchQuery) -> reedline::Result<i64> {
+        shared().count(query)
+    }
+
+    fn search(&self, query: SearchQuery) -> reedline::Result<Vec<HistoryItem>> {
+        shared().search(query)
+    }
+
+    fn update(
+        &mut self,
+        id: HistoryItemId,
+        updater: &dyn Fn(HistoryItem) -> HistoryItem,
+    ) -> reedline::Result<()> {
+        shared().update(id, updater)
+    }
+
+    fn clear(&mut self) -> reedline::Result<()> {
+        shared().clear()
+    }
+
+    fn delete(&mut self, h: HistoryItemId) -> reedline::Result<()> {
+        shared().delete(h)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        shared().sync()
+    }
+
+    fn session(&self) -> Option<HistorySessionId> {
+        None
+    }
+}
+
+impl Drop for SharedHistory {
+    fn drop(&mut self) {
+        let _ = shared().sync();
+    }
+}
+
+// Writes the lines entered so far and, with `share`, reads the ones other
+// sessions added; called around every line and before each prompt
+pub fn sync() -> io::Result<()> {
+    shared().sync()
+}
+
+/// Whether a line is only written once it has run, because its status
+/// decides if it is kept.
+pub fn waits_for_status() -> bool {
+    !options().record_failed || options().ignore_not_found
+}
+
+// Called once a line has run. When `waits_for_status` the line is only
+// written now: dropped if it failed without `record_failed`, or if
+// `not_found` (a typo) with `ignore_not_found`.
+pub fn command_done(status: i32, not_found: bool) -> io::Result<()> {
+    let options = options();
+    let discard =
+        (status != 0 && !options.record_failed) || (not_found && options.ignore_not_found);
+    let mut history = shared();
+    if discard && history.len_on_disk < history.entries.len() {
+        history.entries.pop_back();
+        history.times.pop_back();
+    }
+    history.sync()
+}
+
+// The last `limit` entries (all of them when None) with their numbers
+pub fn recent(limit: Option<usize>) -> Vec<(usize, String)> {
+    let history = shared();
+    let all: Vec<(usize, &String)> = history.numbered().collect();
+    let skip = all.len().saturating_sub(limit.unwrap_or(all.len()));
+    all[skip..]
+        .iter()
+        .map(|&(n, entry)| (n, entry.clone()))
+        .collect()
+}
+
+/// Like `recent`, with the time each entry was entered when it is known.
+/// The times are in the file, so this reads it rather than memory.
+pub fn recent_timed(limit: Option<usize>) -> io::Result<Vec<(usize, Option<u64>, String)>> {
+    let mut history = shared();
+    let all = match history.file.clone() {
+        Some(path) => {
+            history.sync()?;
+            read_timed(&path)?
+        }
+        None => history
+            .times
+            .iter()
+            .copied()
+            .zip(history.entries.iter().cloned())
+            .collect(),
+    };
+    let skip = all.len().saturating_sub(limit.unwrap_or(all.len()));
+    Ok(all
+        .into_iter()
+        .enumerate()
+        .skip(skip)
+        .map(|(i, (time, entry))| (i + 1, time, entry))
+        .collect())
+}
+
+/// Seconds since the epoch as local `YYYY-MM-DD HH:MM`.
+pub fn format_time(secs: u64) -> String {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let time = secs as libc::time_t;
+    unsafe {
+        libc::localtime_r(&time, &mut tm);
+    }
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
+
+// Only the newest entries are read for argument suggestions, and a command
+// keeps the arguments it was used with most
+const ARGS_ENTRIES: usize = 20_000;
+const ARGS_PER_COMMAND: usize = 64;
+
+// A use counts like being this many entries more recent
+const ARGS_USE_WEIGHT: usize = 50;
+
+// Arguments each command word was used with, built on the first Tab that
+// needs it and brought up to date with what was entered since
+#[derive(Default)]
+struct ArgIndex {
+    // Number of the last entry read
+    seen: usize,
+    // Argument, times used, number of the entry it was last used in
+    args: HashMap<String, Vec<(String, usize, usize)>>,
+}
+
+impl ArgIndex {
+    // Plain words after each command in `entry`; options, quoting and
+    // expansions aren't worth suggesting again
+    fn add(&mut self, number: usize, entry: &str) {
+        self.seen = number;
+        let mut command: Option<&str> = None;
+        for word in entry.split_whitespace() {
+            if matches!(word, "|" | "||" | "&&" | ";" | "&") {
+                command = None;
+                continue;
+            }
+            let Some(cmd) = command else {
+                if !word.contains('=') {
+                    command = Some(word);
+                }
+                continue;
+            };
+            if word.starts_with(['-', '>', '<'])
+                || word.contains(['"', '\'', '$', '`', '|', ';', '&', '>', '<'])
+            {
+                continue;
+            }
+            let args = self.args.entry(cmd.to_string()).or_default();
+            match args.iter_mut().find(|(arg, _, _)| arg == word) {
+                Some((_, count, last)) => {
+                    *count += 1;
+                    *last = number;
+                }
+                None => {
+                    if args.len() == ARGS_PER_COMMAND {
+                        let (oldest, _) = args
+                            .iter()
+                            .enumerate()
+                            .min_by_key(|(_, arg)| rank(arg))
+                            .unwrap();
+                        args.swap_remove(oldest);
+                    }
+                    args.push((word.to_string(), 1, number));
+                }
+            }
+        }
+    }
+
+    fn ranked(&self, cmd: &str) -> Vec<String> {
+        let mut args = self.args.get(cmd).cloned().unwrap_or_default();
+        args.sort_by_key(|arg| std::cmp::Reverse(rank(arg)));
+        args.into_iter().map(|(arg, _, _)| arg).collect()
+    }
+}
+
+fn rank((_, count, last): &(String, usize, usize)) -> usize {
+    last + count * ARGS_USE_WEIGHT
+}
+
+static ARGS: OnceLock<Mutex<ArgIndex>> = OnceLock::new();
+
+/// Arguments `cmd` was used with, the most used and recent first.
+pub fn arguments(cmd: &str) -> Vec<String> {
+    let history = shared();
+    let mut index = ARGS.get_or_init(Mutex::default).lock().unwrap();
+    let total = history.older().len() + history.entries.len();
+    // Cleared or rewritten since: numbers no longer line up
+    if total < index.seen {
+        *index = ArgIndex::default();
+    }
+    let start = index.seen.max(total.saturating_sub(ARGS_ENTRIES));
+    for (number, entry) in history.numbered().skip(start) {
+        index.add(number, entry);
+    }
+    index.ranked(cmd)
+}
+
+/// The history settings `set -o` knows, with whether each is on now.
+pub fn settings() -> Vec<(&'static str, bool)> {
+    let history = shared();
+    vec![
+        ("histignoredups", history.ignore_dups),
+        ("histignorespace", history.ignore_space),
+        ("histerasedups", history.erase_dups),
+        ("histexpand", EXPANSION.load(Ordering::Relaxed)),
+    ]
+}
+
+/// Turns one of `settings` on or off for this session; false when `name`
+/// isn't one.
+pub fn set(name: &str, on: bool) -> bool {
+    let mut history = shared();
+    let setting = match name {
+        "histignoredups" => &mut history.ignore_dups,
+        "histignorespace" => &mut history.ignore_space,
+        "histerasedups" => &mut history.erase_dups,
+        "histexpand" => {
+            EXPANSION.store(on, Ordering::Relaxed);
+            return true;
+        }
+        _ => return false,
+    };
+    *setting = on;
+    true
+}
+
+// Words of a line for `!$` and `!*`, quotes kept as typed
+fn words(line: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        } else if c.is_whitespace() {
+            if let Some(s) = start.take() {
+                words.push(&line[s..i]);
+            }
+            continue;
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(s) = start {
+        words.push(&line[s..]);
+    }
+    words
+}
+
+// `^old^new` (or `^old^new^`): the previous line with the first `old`
+// replaced by `new`
+fn substitute(spec: &str, previous: Option<&str>) -> Result<String, String> {
+    let (old, new) = spec.split_once('^').unwrap_or((spec, ""));
+    let new = new.strip_suffix('^').unwrap_or(new);
+    let previous = previous.ok_or_else(|| "!!: event not found".to_string())?;
+    if old.is_empty() || !previous.contains(old) {
+        return Err(format!("^{spec}: substitution failed"));
+    }
+    Ok(previous.replacen(old, new, 1))
+}
+
+// `line` with its history references replaced from `entries`, which are
+// numbered oldest first and end with the line before this one. None when
+// there was nothing to expand. Single quotes and a backslash keep a `!`,
+// and so do `$!`, `[!...]`, `!=` and a `!` before a space.
+fn expand_with(line: &str, entries: &[(usize, &str)]) -> Result<Option<String>, String> {
+    let previous = entries.last().map(|(_, entry)| *entry);
+    if let Some(spec) = line.strip_prefix('^') {
+        return substitute(spec, previous).map(Some);
+    }
+    let mut expanded = String::with_capacity(line.len());
+    let mut changed = false;
+    let mut single_quoted = false;
+    let mut escaped = false;
+    let mut prev = ' ';
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let literal = c != '!' || escaped || single_quoted || prev == '$' || prev == '[';
+        escaped = !escaped && c == '\\' && !single_quoted;
+        if c == '\'' && !escaped {
+            single_quoted = !single_quoted;
+        }
+        prev = c;
+        if literal {
+            expanded.push(c);
+            continue;
+        }
+
+        let rest = &line[i + 1..];
+        let not_found = |spec: &str| format!("!{spec}: event not found");
+        let previous_words = || previous.map(words).ok_or_else(|| not_found(&rest[..1]));
+        let (replacement, used) = match rest.chars().next() {
+            Some('!') => (previous.ok_or_else(|| not_found("!"))?.to_string(), 1),
+            Some('$') => {
+                let words = previous_words()?;
+                (words.last().copied().unwrap_or_default().to_string(), 1)
+            }
+            Some('^') => {
+                let words = previous_words()?;
+                (words.get(1).copied().unwrap_or_default().to_string(), 1)
+            }
+            Some('*') => (previous_words()?.get(1..).unwrap_or_default().join(" "), 1),
+            _ => {
+                let spec_len = rest
+                    .find(|c: char| c.is_whitespace() || "!;|&<>()'\"=".contains(c))
+                    .unwrap_or(rest.len());
+                let spec = &rest[..spec_len];
+                let found = if spec.is_empty() {
+                    None
+                } else if let Ok(number) = spec.parse::<usize>() {
+                    entries.iter().find(|(n, _)| *n == number).map(|(_, e)| *e)
+                } else if let Some(back) = spec.strip_prefix('-')
+                    && let Ok(back) = back.parse::<usize>()
+                {
+                    entries
+                        .len()
+                        .checked_sub(back)
+                        .filter(|_| back > 0)
+                        .map(|i| entries[i].1)
+                } else {
+                    entries
+                        .iter()
+                        .rev()
+                        .find(|(_, e)| e.starts_with(spec))
+                        .map(|(_, e)| *e)
+                };
+                match found {
+                    Some(entry) => (entry.to_string(), spec.len()),
+                    None if spec.is_empty() => {
+                        expanded.push(c);
+                        continue;
+                    }
+                    None => return Err(not_found(spec)),
+                }
+            }
+        };
+        expanded.push_str(&replacement);
+        changed = true;
+        for _ in 0..rest[..used].chars().count() {
+            chars.next();
+        }
+        prev = ' ';
+    }
+    Ok(changed.then_some(expanded))
+}
+
+/// History references in a line just entered, expanded from the entries
+/// before it; None when there are none or expansion is off.
+pub fn expand(line: &str) -> Result<Option<String>, String> {
+    if !EXPANSION.load(Ordering::Relaxed) || !(line.contains('!') || line.starts_with('^')) {
+        return Ok(None);
+    }
+    let history = shared();
+    let mut entries: Vec<(usize, &str)> = history
+        .numbered()
+        .map(|(n, entry)| (n, entry.as_str()))
+        .collect();
+    // The editor has already saved the line itself
+    if history.len_on_disk < history.entries.len()
+        && entries.last().is_some_and(|(_, entry)| *entry == line)
+    {
+        entries.pop();
+    }
+    expand_with(line, &entries)
+}
+
+/// Records `expanded` in place of the line it came from, when that line
+/// was recorded; None drops the line, for one that failed to expand.
+pub fn replace_last(line: &str, expanded: Option<&str>) {
+    let mut history = shared();
+    if history.len_on_disk < history.entries.len()
+        && history.entries.back().is_some_and(|entry| entry == line)
+    {
+        history.entries.pop_back();
+        history.times.pop_back();
+        if let Some(expanded) = expanded {
+            let _ = history.save(HistoryItem::from_command_line(expanded));
+        }
+    }
+}
+
+/// Records a line that wasn't typed at the prompt, like the ones `fc` runs,
+/// and writes it out.
+pub fn add(line: &str) -> io::Result<()> {
+    let mut history = shared();
+    history
+        .save(HistoryItem::from_command_line(line))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    history.sync()
+}
+
+pub fn clear() -> io::Result<()> {
+    shared()
+        .clear()
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+// Deletes the entry `history` lists as `number`
+pub fn delete(number: usize) -> io::Result<()> {
+    let mut history = shared();
+    let id = history.id_of(number).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("history: {number}: history position out of range"),
+        )
+    })?;
+    history.remove_entry(id)
+}
+
+fn disabled() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "history: the history file is off in this session ([history] enabled = false)",
+    )
+}
+
+// Compacts the file behind the shared history and reloads it
+pub fn compact_shared() -> io::Result<(usize, usize)> {
+    let mut history = shared();
+    history.sync()?;
+    let path = history.file.clone().ok_or_else(disabled)?;
+    let counts = with_lock(&path, |_| compact(&path))?;
+    history.reopen(path)?;
+    Ok(counts)
+}
+
+/// Rewrites the file behind the shared history with the entries `keep`
+/// returns, then reloads it so the session carries on from the new file.
+/// Returns the entry counts before and after.
+pub fn rewrite_shared(keep: impl FnOnce(Vec<Timed>) -> Vec<Timed>) -> io::Result<(usize, usize)> {
+    let mut history = shared();
+    history.sync()?;
+    let path = history.file.clone().ok_or_else(disabled)?;
+
+    let (before, after) = with_lock(&path, |_| {
+        let entries = read_timed(&path)?;
+        let before = entries.len();
+        let kept = keep(entries);
+        write_timed(&path, &kept)?;
+        Ok((before, kept.len()))
+    })?;
+    history.reopen(path)?;
+    Ok((before, after))
+}
+
+/// An entry with the time it was entered, when the file has one.
+pub type Timed = (Option<u64>, String);
+
+// Every entry of a history file, nothing when there is no file yet
+fn read_timed(path: &Path) -> io::Result<Vec<Timed>> {
+    let mut entries = Vec::new();
+    match for_each_timed(path, |time, entry| entries.push((time, entry.to_string()))) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    Ok(entries)
+}
+
+fn write_timed(path: &Path, entries: &[Timed]) -> io::Result<()> {
+    atomic_write_with(path, |writer| {
+        for (time, entry) in entries {
+            writeln!(writer, "{}", encode_line(*time, entry))?;
+        }
+        Ok(())
+    })
+}
+
+// Drops repeated entries anywhere in the list, keeping the latest of each
+pub fn dedupe(entries: Vec<Timed>) -> Vec<Timed> {
+    let mut seen = std::collections::HashSet::new();
+    let mut kept: Vec<Timed> = entries
+        .into_iter()
+        .rev()
+        .filter(|(_, entry)| seen.insert(entry.clone()))
+        .collect();
+    kept.reverse();
+    kept
+}
+
+/// `24! history dedupe | prune PATTERN | prune --older-than AGE | size`
+pub fn handle_maintenance(args: &[&str]) -> io::Result<()> {
+    match args {
+        ["dedupe"] => {
+            let (before, after) = rewrite_shared(dedupe)?;
+            println!(
+                "history: {before} entries, {} duplicates removed",
+                before - after
+            );
+            Ok(())
+        }
+        ["prune", "--older-than", age] => {
+            let max_age = crate::completions::parse_ttl(age)
+                .flatten()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("history: {age}: not an age like 90d, 12h or 30m"),
+                    )
+                })?;
+            let cutoff = now().unwrap_or(0).saturating_sub(max_age.as_secs());
+            // Entries without a time predate timestamps; nothing says how old
+            let (before, after) = rewrite_shared(|entries| {
+                entries
+                    .into_iter()
+                    .filter(|(time, _)| time.is_none_or(|time| time >= cutoff))
+                    .collect()
+            })?;
+            println!(
+                "history: removed {} entries older than {age}",
+                before - after
+            );
+            Ok(())
+        }
+        ["prune", pattern @ ..] if !pattern.is_empty() => {
+            // Unquoted words are put back together, `prune export TOKEN=*` works
+            let pattern = pattern.join(" ");
+            let (before, after) = rewrite_shared(|entries| {
+                entries
+                    .into_iter()
+                    .filter(|(_, entry)| !glob_match(&pattern, entry))
+                    .collect()
+            })?;
+            println!(
+                "history: removed {} entries matching '{pattern}'",
+                before - after
+            );
+            Ok(())
+        }
+        ["size"] => {
+            sync()?;
+            let path = file_path();
+            let bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            let mut count = 0;
+            match for_each_entry(&path, |_| count += 1) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            println!(
+                "history: {count} entries, {:.1} KiB in {}",
+                bytes as f64 / 1024.0,
+                path.display()
+            );
+            Ok(())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: 24! history [dedupe | prune pattern | prune --older-than age | size]",
+        )),
+    }
+}
+
+/// Calls `f` with every entry of a history file, oldest first, reading it
+/// line by line. Invalid UTF-8 is replaced and blank lines are skipped.
+pub fn for_each_entry(path: &Path, mut f: impl FnMut(&str)) -> io::Result<()> {
+    for_each_timed(path, |_, entry| f(entry))
+}
+
+/// `for_each_entry` with the time each entry was entered, None for lines
+/// from before times were recorded.
+pub fn for_each_timed(path: &Path, mut f: impl FnMut(Option<u64>, &str)) -> io::Result<()> {
+    let reader = BufReader::new(File::open(path)?);
+    for line in reader.split(b'\n') {
+        let line = line?;
+        let line = String::from_utf8_lossy(&line);
+        if !line.trim().is_empty() {
+            f(split_time(&line).0, &decode_entry(&line));
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the history file without blank lines and consecutive duplicates,
+/// which keep the time of the first.
+/// Streams through a temp file so memory stays flat on huge histories.
+pub fn compact(path: &Path) -> io::Result<(usize, usize)> {
+    let reader = BufReader::new(File::open(path)?);
+
+    atomic_write_with(path, |writer| {
+        let (mut before, mut after) = (0, 0);
+        let mut last: Option<String> = None;
+        for line in reader.lines() {
+            let line = line?;
+            before += 1;
+            let command = split_time(&line).1;
+            if line.trim().is_empty() || last.as_deref() == Some(command) {
+                continue;
+            }
+            writeln!(writer, "{line}")?;
+            after += 1;
+            last = Some(command.to_string());
+        }
+        Ok((before, after))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    // A history file of `lines` commands, in a directory gone with the test
+    fn fixture(name: &str, lines: usize) -> (TempDir, PathBuf) {
+        let dir = TempDir::new(&format!("history-{name}"));
+        let path = dir.join("history");
+        let body: String = (0..lines).map(|i| format!("cmd {i}\n")).collect();
+        fs::write(&path, body).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn test_tail_and_lazy_search() {
+        let (_dir, path) = fixture("tail", 200_000);
+        let history = ShellHistory::with_file(6000, path.clone()).unwrap();
+
+        assert_eq!(history.entries.len(), 6000);
+        assert_eq!(history.entries.front().unwrap(), "cmd 194000");
+        assert!(history.older.get().is_none());
+
+        // Searching for an entry outside the tail reaches into the rest of the file
+        let found = history
+            .search(SearchQuery::all_that_contain_rev("cmd 12345".into()))
+            .unwrap();
+        assert_eq!(found[0].command_line, "cmd 123459");
+        assert!(found.iter().any(|h| h.command_line == "cmd 12345"));
+        assert_eq!(history.older().len(), 194_000);
+
+        drop(history);
+    }
+
+    #[test]
+    fn test_dedupe_keeps_latest() {
+        let entries = ["ls", "cd /", "ls", "git st", "cd /"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, e)| (Some(i as u64), e.to_string()))
+            .collect();
+        let kept: Vec<Timed> = dedupe(entries);
+        assert_eq!(
+            kept,
+            [
+                (Some(2), "ls".to_string()),
+                (Some(3), "git st".to_string()),
+                (Some(4), "cd /".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_one_record_per_line() {
+        // A file written by reedline's FileBackedHistory, then this backend
+        let (_dir, path) = fixture("single", 2);
+        let mut history = ShellHistory::with_file(100, path.clone()).unwrap();
+        for line in ["make", "make test"] {
+            history.save(HistoryItem::from_command_line(line)).unwrap();
+            history.sync().unwrap();
+        }
+        // Syncing again, and on drop, writes nothing twice
+        history.sync().unwrap();
+
+        let recalled: Vec<String> = history
+            .search(SearchQuery::everything(SearchDirection::Forward, None))
+            .unwrap()
+            .into_iter()
+            .map(|item| item.command_line)
+            .collect();
+        let listed: Vec<String> = history.numbered().map(|(_, e)| e.clone()).collect();
+        drop(history);
+        let mut on_disk = Vec::new();
+        for_each_entry(&path, |entry| on_disk.push(entry.to_string())).unwrap();
+
+        assert_eq!(on_disk, ["cmd 0", "cmd 1", "make", "make test"]);
+        assert_eq!(recalled, on_disk);
+        assert_eq!(listed, on_disk);
+    }
+
+    #[test]
+    fn test_shared_sessions() {
+        let (_dir, path) = fixture("shared", 0);
+        let open = || {
+            let mut history = ShellHistory::with_file(3, path.clone()).unwrap();
+            history.share = true;
+            history
+        };
+        let (mut a, mut b) = (open(), open());
+        let save = |history: &mut ShellHistory, line: &str| {
+            history.save(HistoryItem::from_command_line(line)).unwrap();
+        };
+        save(&mut a, "from a");
+        a.sync().unwrap();
+        save(&mut b, "from b");
+        b.sync().unwrap();
+        // b read a's line before writing its own, in file order
+        assert_eq!(b.entries, ["from a", "from b"]);
+        a.sync().unwrap();
+        assert_eq!(a.entries, ["from a", "from b"]);
+
+        // Past the capacity the oldest moves to the older part
+        save(&mut b, "b two");
+        save(&mut b, "b three");
+        b.sync().unwrap();
+        a.sync().unwrap();
+        assert_eq!(a.entries, ["from b", "b two", "b three"]);
+        assert_eq!(a.older(), ["from a"]);
+
+        // A rewrite by one session is read again by the other
+        b.erase("from b").unwrap();
+        a.sync().unwrap();
+        let all: Vec<&String> = a.numbered().map(|(_, e)| e).collect();
+        assert_eq!(all, ["from a", "b two", "b three"]);
+
+        // Without share each keeps to its own lines
+        let mut c = ShellHistory::with_file(10, path.clone()).unwrap();
+        save(&mut a, "a again");
+        a.sync().unwrap();
+        c.sync().unwrap();
+        assert_eq!(c.entries.len(), 3);
+        drop((a, b, c));
+    }
+
+    #[test]
+    fn test_ignored_patterns() {
+        let mut history = ShellHistory::new(10);
+        history.ignore = vec!["ls".into(), "export *TOKEN*".into()];
+        for line in ["ls", "ls -l", "export GH_TOKEN=abc", "export PATH=/bin"] {
+            history.save(HistoryItem::from_command_line(line)).unwrap();
+        }
+        assert_eq!(history.entries, ["ls -l", "export PATH=/bin"]);
+    }
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(
+            split_time(": 1700000000:0;ls -l"),
+            (Some(1700000000), "ls -l")
+        );
+        // Anything that wouldn't encode back the same is a plain command
+        for line in [": 0123:0;ls", ": 12:5;ls", ": x:0;ls", "echo :0;"] {
+            assert_eq!(split_time(line), (None, line));
+        }
+        assert_eq!(decode_entry(&encode_line(Some(5), "a\nb")), "a\nb");
+
+        // A file from before times were recorded, then one line with a time
+        let dir = TempDir::new("history-times");
+        let path = dir.join("history");
+        fs::write(&path, "old one\nold two\n").unwrap();
+        let mut history = ShellHistory::with_file(2, path.clone()).unwrap();
+        assert_eq!(history.times, [None, None]);
+        history.save(HistoryItem::from_command_line("new")).unwrap();
+        history.sync().unwrap();
+        // The evicted line's length moved the offset of the older part
+        assert_eq!(history.older(), ["old one"]);
+
+        let timed = read_timed(&path).unwrap();
+        assert_eq!(
+            timed[..2],
+            [(None, "old one".into()), (None, "old two".into())]
+        );
+        assert!(timed[2].0.is_some_and(|t| t > 1_600_000_000));
+        assert_eq!(timed[2].1, "new");
+
+        history.remove_entry(-1).unwrap();
+        let timed = read_timed(&path).unwrap();
+        assert_eq!(timed.len(), 2);
+        assert!(timed[1].0.is_some());
+    }
+
+    #[test]
+    fn test_numbering_and_delete() {
+        let (_dir, path) = fixture("delete", 10);
+        let mut history = ShellHistory::with_file(4, path.clone()).unwrap();
+        history
+            .save(HistoryItem::from_command_line("echo\nmulti"))
+            .unwrap();
+
+        // Numbers cover the whole file, not just the in-memory tail
+        let numbered: Vec<_> = history.numbered().map(|(n, e)| (n, e.clone())).collect();
+        assert_eq!(numbered.len(), 11);
+        assert_eq!(numbered[0], (1, "cmd 0".to_string()));
+        assert_eq!(numbered[10], (11, "echo\nmulti".to_string()));
+
+        let id = history.id_of(2).unwrap();
+        assert!(id < 0);
+        history.delete(HistoryItemId::new(id)).unwrap();
+        let id = history.id_of(10).unwrap();
+        history.delete(HistoryItemId::new(id)).unwrap();
+        assert!(history.id_of(10).is_none());
+
+        let lines: Vec<String> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[1], "cmd 2");
+        assert_eq!(lines[8], "cmd 9");
+
+        drop(history);
+    }
+
+    #[test]
+    fn test_argument_index() {
+        let mut index = ArgIndex::default();
+        let entries = [
+            "ssh build-box",
+            "ssh -p 2222 web",
+            "FOO=1 ssh \"$HOST\" && ssh web | tee log",
+            "ssh old-box",
+        ];
+        for (i, entry) in entries.iter().enumerate() {
+            index.add(i + 1, entry);
+        }
+        assert_eq!(index.seen, 4);
+        assert_eq!(index.ranked("ssh"), ["web", "old-box", "2222", "build-box"]);
+        assert_eq!(index.ranked("tee"), ["log"]);
+        assert!(index.ranked("ls").is_empty());
+
+        for i in 0..ARGS_PER_COMMAND + 10 {
+            index.add(10 + i, &format!("cat file{i}"));
+        }
+        let cat = index.ranked("cat");
+        assert_eq!(cat.len(), ARGS_PER_COMMAND);
+        assert_eq!(cat[0], format!("file{}", ARGS_PER_COMMAND + 9));
+    }
+
+    #[test]
+    fn test_space_and_erased_duplicates() {
+        let (_dir, path) = fixture("erase", 0);
+        let mut history = ShellHistory::with_file(100, path.clone()).unwrap();
+        history.ignore_space = true;
+        history.erase_dups = true;
+        for line in ["ls", "git status", " secret", "make", "ls", "ls"] {
+            history.save(HistoryItem::from_command_line(line)).unwrap();
+        }
+        let entries: Vec<&String> = history.numbered().map(|(_, e)| e).collect();
+        assert_eq!(entries, ["git status", "make", "ls"]);
+        history.sync().unwrap();
+        let mut on_disk = Vec::new();
+        for_each_entry(&path, |entry| on_disk.push(entry.to_string())).unwrap();
+        assert_eq!(on_disk, ["git status", "make", "ls"]);
+
+        // Rewriting the file keeps the settings
+        history
+            .save(HistoryItem::from_command_line(" again"))
+            .unwrap();
+        assert_eq!(history.numbered().count(), 3);
+        drop(history);
+    }
+
+    #[test]
+    fn test_history_expansion() {
+        let entries = [
+            (40, "git status"),
+            (41, "make test"),
+            (42, "cp 'a b.txt' /tmp/out"),
+        ];
+        let expand = |line: &str| expand_with(line, &entries);
+        let expanded = |line: &str| expand(line).unwrap().unwrap();
+
+        assert_eq!(expanded("sudo !!"), "sudo cp 'a b.txt' /tmp/out");
+        assert_eq!(expanded("!41"), "make test");
+        assert_eq!(expanded("!-3"), "git status");
+        assert_eq!(expanded("!git && !ma"), "git status && make test");
+        assert_eq!(expanded("ls !$"), "ls /tmp/out");
+        assert_eq!(expanded("echo !*"), "echo 'a b.txt' /tmp/out");
+        assert_eq!(expanded("echo !^x"), "echo 'a b.txt'x");
+        assert_eq!(expanded("echo \"!!\""), "echo \"cp 'a b.txt' /tmp/out\"");
+
+        // Left alone
+        for line in [
+            "echo '!!'",
+            "echo \\!!",
+            "echo $!",
+            "ls [!a]*",
+            "[ a != b ]",
+            "hi !",
+        ] {
+            assert_eq!(expand(line), Ok(None), "{line}");
+        }
+        assert_eq!(expand("!nope"), Err("!nope: event not found".into()));
+        assert_eq!(expand("!99"), Err("!99: event not found".into()));
+        assert!(expand_with("!!", &[]).is_err());
+
+        assert_eq!(expanded("^cp^mv"), "mv 'a b.txt' /tmp/out");
+        assert_eq!(expanded("^out^in^"), "cp 'a b.txt' /tmp/in");
+        assert_eq!(
+            expand("^nothing^x"),
+            Err("^nothing^x: substitution failed".into())
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::SystemTime,
+};
+
+// Include directives followed before giving up on a loop
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+// The hosts and every file they came from with its mtime when read; the
+// list is read again once any of those changes
+#[derive(Default)]
+struct Cache {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    hosts: Vec<String>,
+}
+
+static CACHE: OnceLock<Mutex<Option<Cache>>> = OnceLock::new();
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// `*` and `?` in an Include's file name; directories are taken as written
+fn glob(pattern: &Path) -> Vec<PathBuf> {
+    let Some(name) = pattern.file_name().and_then(|n| n.to_str()) else {
+        return vec![pattern.to_path_buf()];
+    };
+    if !name.contains(['*', '?']) {
+        return vec![pattern.to_path_buf()];
+    }
+    let dir = pattern.parent().unwrap_or(Path::new("."));
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut found: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|e| e.file_name().to_str().is_some_and(|n| wildcard(name, n)))
+        .map(|e| e.path())
+        .collect();
+    found.sort();
+    found
+}
+
+fn wildcard(pattern: &str, text: &str) -> bool {
+    match pattern.chars().next() {
+        None => text.is_empty(),
+        Some('*') => (0..=text.len())
+            .filter(|&i| text.is_char_boundary(i))
+            .any(|i| wildcard(&pattern[1..], &text[i..])),
+        Some('?') => text
+            .chars()
+            .next()
+            .is_some_and(|c| wildcard(&pattern[1..], &text[c.len_utf8()..])),
+        Some(p) => text
+            .strip_prefix(p)
+            .is_some_and(|rest| wildcard(&pattern[p.len_utf8()..], rest)),
+    }
+}
+
+// `Host` names from an ssh config, skipping patterns, following Include
+// lines (relative ones are under ~/.ssh)
+fn read_config(path: &Path, ssh_dir: &Path, depth: usize, cache: &mut Cache) {
+    cache.files.push((path.to_path_buf(), modified(path)));
+    let Ok(content) = fs::read_to_string(path) else {
+        return;
+    };
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // `Key value`, `Key=value` or `Key = value`
+        let (key, value) = line
+            .split_once(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or((line, ""));
+        let value = value.trim_start_matches(|c: char| c.is_whitespace() || c == '=');
+        let words = value.split('#').next().unwrap_or("").split_whitespace();
+        if key.eq_ignore_ascii_case("host") {
+            for host in words {
+                if !host.contains(['*', '?', '!']) && !cache.hosts.iter().any(|h| h == host) {
+                    cache.hosts.push(host.to_string());
+                }
+            }
+        } else if key.eq_ignore_ascii_case("include") && depth < MAX_INCLUDE_DEPTH {
+            for target in words {
+                let target = crate::utils::expand_tilde(target);
+                let target = if target.is_absolute() {
+                    target
+                } else {
+                    ssh_dir.join(target)
+                };
+                for file in glob(&target) {
+                    read_config(&file, ssh_dir, depth + 1, cache);
+                }
+            }
+        }
+    }
+}
+
+// known_hosts lines: `[@marker] host1,host2 keytype key`; hashed names
+// (`|1|...`) can't be read back, `[host]:port` gives the host
+fn read_known_hosts(path: &Path, cache: &mut Cache) {
+    cache.files.push((path.to_path_buf(), modified(path)));
+    let Ok(content) = fs::read_to_string(path) else {
+        return;
+    };
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(mut names) = fields.next() else {
+            continue;
+        };
+        if names.starts_with('#') {
+            continue;
+        }
+        if names.starts_with('@') {
+            let Some(next) = fields.next() else {
+                continue;
+            };
+            names = next;
+        }
+        for name in names.split(',') {
+            if name.starts_with('|') || name.contains(['*', '?', '!']) {
+                continue;
+            }
+            let host = match name.strip_prefix('[') {
+                Some(rest) => rest.split(']').next().unwrap_or(rest),
+                None => name,
+            };
+            if !host.is_empty() && !cache.hosts.iter().any(|h| h == host) {
+                cache.hosts.push(host.to_string());
+            }
+        }
+    }
+}
+
+fn read_all(ssh_dir: &Path) -> Cache {
+    let mut cache = Cache::default();
+    read_config(&ssh_dir.join("config"), ssh_dir, 0, &mut cache);
+    read_known_hosts(&ssh_dir.join("known_hosts"), &mut cache);
+    cache.hosts.sort();
+    cache
+}
+
+/// Hosts from ~/.ssh/config and ~/.ssh/known_hosts, read again when one of
+/// the files changes.
+pub fn hosts() -> Vec<String> {
+    let Some(home) = env::var_os("HOME") else {
+        return Vec::new();
+    };
+    let ssh_dir = PathBuf::from(home).join(".ssh");
+    let mut cache = CACHE.get_or_init(|| Mutex::new(None)).lock().unwrap();
+    let fresh = cache.as_ref().is_some_and(|c| {
+        c.files
+            .first()
+            .is_some_and(|(path, _)| path.starts_with(&ssh_dir))
+            && c.files.iter().all(|(path, at)| modified(path) == *at)
+    });
+    if !fresh {
+        *cache = Some(read_all(&ssh_dir));
+    }
+    cache.as_ref().map(|c| c.hosts.clone()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_read_hosts() {
+        let dir = TempDir::new("hosts");
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(
+            dir.join("config"),
+            "# comment\nHost web db.internal # two of them\n\
+             Host *.example.com !bastion\n  User me\nInclude conf.d/*.conf\n\
+             Host=staging\n",
+        )
+        .unwrap();
+        fs::write(dir.join("conf.d/work.conf"), "host build-box\n").unwrap();
+        fs::write(
+            dir.join("known_hosts"),
+            "github.com,140.82.112.3 ssh-ed25519 AAAA\n\
+             |1|abc=|def= ssh-rsa AAAA\n\
+             [git.local]:2222 ssh-ed25519 AAAA\n\
+             @cert-authority *.corp ssh-rsa AAAA\n\
+             web ssh-ed25519 AAAA\n",
+        )
+        .unwrap();
+
+        let cache = read_all(&dir);
+        assert_eq!(
+            cache.hosts,
+            [
+                "140.82.112.3",
+                "build-box",
+                "db.internal",
+                "git.local",
+                "github.com",
+                "staging",
+                "web"
+            ]
+        );
+        assert_eq!(cache.files.len(), 3);
+        assert!(wildcard("*.conf", "work.conf"));
+        assert!(!wildcard("*.conf", "work.cfg"));
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    env,
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+// Set from `[shell] integration`; "auto" turns it on for graphical terminals
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// A line was started with OSC 133;C and still needs its D
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+// The directory last reported with OSC 7
+static REPORTED: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// `Some(on)` from the config, None to decide from $TERM. Either way only
+/// a terminal on stdout gets the sequences.
+pub fn configure(setting: Option<bool>) {
+    let on = setting
+        .unwrap_or_else(|| crate::title::looks_graphical(&env::var("TERM").unwrap_or_default()));
+    let tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    ENABLED.store(on && tty, Ordering::Relaxed);
+}
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// OSC 133 marks where prompts, command lines and their output start, so a
+// terminal can jump between prompts or select one command's output
+fn mark(code: &str) -> String {
+    format!("\x1b]133;{code}\x07")
+}
+
+/// Put in front of the prompt the editor draws: the prompt starts here.
+pub fn prompt_start() -> String {
+    if enabled() { mark("A") } else { String::new() }
+}
+
+/// Put after the prompt's last part: the command line starts here.
+pub fn prompt_end() -> String {
+    if enabled() { mark("B") } else { String::new() }
+}
+
+fn emit(sequence: &str) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(sequence.as_bytes());
+    let _ = stdout.flush();
+}
+
+/// After a line was read, before it runs: its output starts here.
+pub fn running() {
+    if enabled() {
+        RUNNING.store(true, Ordering::Relaxed);
+        emit(&mark("C"));
+    }
+}
+
+/// Before each prompt: ends the output of the line that ran, with its
+/// status, and reports the directory with OSC 7 when it changed, so new
+/// tabs open where the shell is.
+pub fn at_prompt(status: i32) {
+    if !enabled() {
+        return;
+    }
+    let mut sequences = String::new();
+    if RUNNING.swap(false, Ordering::Relaxed) {
+        sequences.push_str(&mark(&format!("D;{status}")));
+    }
+    if let Ok(cwd) = env::current_dir() {
+        let mut reported = REPORTED.lock().unwrap();
+        if reported.as_ref() != Some(&cwd) {
+            sequences.push_str(&format!(
+                "\x1b]7;{}\x07",
+                file_url(&crate::prompt::full_host_name(), &cwd)
+            ));
+            *reported = Some(cwd);
+        }
+    }
+    if !sequences.is_empty() {
+        emit(&sequences);
+    }
+}
+
+// `file://host/path`, with everything but unreserved characters and `/`
+// percent-encoded byte by byte
+fn file_url(host: &str, path: &Path) -> String {
+    let mut url = format!("file://{host}");
+    for &b in path.as_os_str().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            url.push(b as char);
+        } else {
+            url.push_str(&format!("%{b:02X}"));
+        }
+    }
+    url
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_file_url() {
+        assert_eq!(
+            file_url("box", Path::new("/home/u/src")),
+            "file://box/home/u/src"
+        );
+        assert_eq!(
+            file_url("box", Path::new("/tmp/my dir/ü")),
+            "file://box/tmp/my%20dir/%C3%BC"
+        );
+        assert_eq!(mark("D;1"), "\x1b]133;D;1\x07");
+    }
+}
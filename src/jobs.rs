@@ -0,0 +1,732 @@
+use libc::{
+    SIG_DFL, SIG_IGN, SIGCONT, SIGHUP, SIGINT, SIGQUIT, SIGTSTP, SIGTTIN, SIGTTOU, STDIN_FILENO,
+    WCONTINUED, WNOHANG, WUNTRACED, getpgrp, isatty, kill, setpgid, signal, tcsetpgrp, waitpid,
+};
+use std::{
+    fmt,
+    io::{self, Write},
+    process::{Child, Command},
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicI32, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    Done(i32), // exit status
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobState::Running => write!(f, "Running"),
+            JobState::Stopped => write!(f, "Stopped"),
+            JobState::Done(0) => write!(f, "Done"),
+            JobState::Done(code) => write!(f, "Exit {code}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: usize,
+    pub pgid: i32,
+    pub pids: Vec<i32>, // processes not reaped yet, in pipeline order
+    pub last_pid: i32,  // its exit status becomes the job's status
+    pub command: String,
+    pub state: JobState,
+    pub exit_code: i32, // of last_pid, once it has finished
+    pub nohup: bool,    // `disown -h`: listed, but not hung up on exit
+}
+
+// Job table, ordered by job id
+static JOBS: OnceLock<Mutex<Vec<Job>>> = OnceLock::new();
+
+// Whether this shell owns the terminal and moves jobs between process groups
+static JOB_CONTROL: AtomicBool = AtomicBool::new(false);
+static SHELL_PGID: AtomicI32 = AtomicI32::new(0);
+
+// Pid of the most recent background job, what `$!` expands to
+static LAST_BACKGROUND: AtomicI32 = AtomicI32::new(0);
+
+// Set by the SIGCHLD handler; children are reaped at the next prompt
+static CHILD_EXITED: AtomicBool = AtomicBool::new(false);
+
+// Children a caller waits for itself, like the `--help` a completion thread
+// runs; reap() leaves their statuses alone
+static WAITED_ELSEWHERE: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+// Set once exit was refused because of active jobs, the next exit goes through
+static EXIT_WARNED: AtomicBool = AtomicBool::new(false);
+
+// `hup_on_exit = false` leaves jobs running when the shell exits
+static HUP_ON_EXIT: AtomicBool = AtomicBool::new(true);
+
+// Write end of the pipe the SIGHUP handler wakes the hangup thread through
+static HANGUP_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+// How long exit waits for hung up jobs to finish before reporting them
+const HANG_UP_WAIT: Duration = Duration::from_millis(200);
+
+/// The shared job table, also read by completion.
+pub fn table() -> &'static Mutex<Vec<Job>> {
+    JOBS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+extern "C" fn on_sigchld(_: libc::c_int) {
+    CHILD_EXITED.store(true, Ordering::Relaxed);
+}
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    let fd = HANGUP_PIPE.load(Ordering::Relaxed);
+    unsafe {
+        libc::write(fd, b"h".as_ptr().cast(), 1);
+    }
+}
+
+// SIGHUP means the terminal was closed. The editor can't notice: reading the
+// dead terminal fails forever inside it. So the handler wakes a thread that
+// shuts the shell down, hanging up the jobs on its way out as bash does.
+fn install_hangup_handler() {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return;
+    }
+    HANGUP_PIPE.store(fds[1], Ordering::Relaxed);
+    thread::spawn(move || {
+        let mut byte = 0u8;
+        while unsafe { libc::read(fds[0], (&mut byte as *mut u8).cast(), 1) } != 1 {}
+        crate::shell::shutdown(128 + SIGHUP);
+    });
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sighup as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(SIGHUP, &action, std::ptr::null_mut());
+    }
+}
+
+pub fn set_hup_on_exit(on: bool) {
+    HUP_ON_EXIT.store(on, Ordering::Relaxed);
+}
+
+// The handler only raises a flag: reaping happens in reap(), where it can't
+// race a foreground wait for the same child
+fn install_reaper() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigchld as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut());
+    }
+}
+
+// Puts an interactive shell in its own process group and takes the terminal
+pub fn init_job_control() {
+    install_reaper();
+    unsafe {
+        if isatty(STDIN_FILENO) == 0 {
+            return;
+        }
+        signal(SIGTSTP, SIG_IGN);
+        signal(SIGTTIN, SIG_IGN);
+        signal(SIGTTOU, SIG_IGN);
+        install_hangup_handler();
+
+        setpgid(0, 0);
+        let pgid = getpgrp();
+        tcsetpgrp(STDIN_FILENO, pgid);
+        SHELL_PGID.store(pgid, Ordering::Relaxed);
+    }
+    JOB_CONTROL.store(true, Ordering::Relaxed);
+}
+
+pub fn job_control() -> bool {
+    JOB_CONTROL.load(Ordering::Relaxed)
+}
+
+// Runs in a freshly forked child: join the job's group (0 starts a new one)
+// and restore the default handlers the shell ignores
+pub fn setup_child(pgid: i32) {
+    unsafe {
+        if job_control() {
+            setpgid(0, pgid);
+        }
+    }
+    default_signals();
+}
+
+/// Puts back the default handlers of the signals the shell ignores, in a
+/// child about to run something else.
+pub fn default_signals() {
+    for sig in [SIGHUP, SIGINT, SIGQUIT, SIGTSTP, SIGTTIN, SIGTTOU] {
+        unsafe {
+            signal(sig, SIG_DFL);
+        }
+    }
+}
+
+// Parent side of setup_child, done in both processes to avoid racing the exec
+pub fn set_child_group(pid: i32, pgid: i32) {
+    if job_control() {
+        unsafe {
+            setpgid(pid, pgid);
+        }
+    }
+}
+
+fn give_terminal(pgid: i32) {
+    if job_control() {
+        unsafe {
+            tcsetpgrp(STDIN_FILENO, pgid);
+        }
+    }
+}
+
+fn reclaim_terminal() {
+    give_terminal(SHELL_PGID.load(Ordering::Relaxed));
+}
+
+// Registers a job and returns its id (lowest free number, like bash)
+pub fn add(pgid: i32, pids: &[i32], command: &str, state: JobState) -> usize {
+    let mut jobs = table().lock().unwrap();
+    let id = (1..).find(|n| jobs.iter().all(|j| j.id != *n)).unwrap_or(1);
+    jobs.push(Job {
+        id,
+        pgid,
+        pids: pids.to_vec(),
+        last_pid: pids.last().copied().unwrap_or(pgid),
+        command: command.to_string(),
+        state,
+        exit_code: 0,
+        nohup: false,
+    });
+    jobs.sort_by_key(|j| j.id);
+    id
+}
+
+pub fn list() -> Vec<Job> {
+    table().lock().unwrap().clone()
+}
+
+// The job `%+` refers to: the newest stopped job, otherwise the newest job
+pub fn current() -> Option<usize> {
+    current_in(&table().lock().unwrap())
+}
+
+fn current_in(jobs: &[Job]) -> Option<usize> {
+    jobs.iter()
+        .rev()
+        .find(|j| j.state == JobState::Stopped)
+        .or_else(|| jobs.last())
+        .map(|j| j.id)
+}
+
+// Resolves `%n`, `n`, `%+`, `%%` and `%-` style job specs
+pub fn resolve(spec: &str) -> io::Result<Job> {
+    let jobs = table().lock().unwrap();
+    let job = match spec.trim_start_matches('%') {
+        "" | "+" | "%" => current_in(&jobs).and_then(|id| jobs.iter().find(|j| j.id == id)),
+        "-" => jobs.iter().rev().nth(1),
+        n => n
+            .parse()
+            .ok()
+            .and_then(|id: usize| jobs.iter().find(|j| j.id == id)),
+    };
+    job.cloned()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{spec}: no such job")))
+}
+
+// Newest stopped job, used when bg/fg get no job spec
+pub fn last_stopped() -> Option<Job> {
+    let jobs = table().lock().unwrap();
+    jobs.iter()
+        .rev()
+        .find(|j| j.state == JobState::Stopped)
+        .cloned()
+}
+
+pub fn set_state(id: usize, state: JobState) {
+    if let Some(job) = table().lock().unwrap().iter_mut().find(|j| j.id == id) {
+        job.state = state;
+    }
+}
+
+fn remove(id: usize) {
+    table().lock().unwrap().retain(|j| j.id != id);
+}
+
+// Polls every job without blocking and records state changes
+pub fn refresh() {
+    let mut jobs = table().lock().unwrap();
+    for job in jobs.iter_mut() {
+        if let JobState::Done(_) = job.state {
+            continue;
+        }
+        for pid in job.pids.clone() {
+            let mut status = 0;
+            match unsafe { waitpid(pid, &mut status, WNOHANG | WUNTRACED | WCONTINUED) } {
+                0 => {}
+                -1 => forget_pid(job, pid), // Already reaped elsewhere
+                _ => update(job, pid, status),
+            }
+        }
+    }
+}
+
+// Applies a wait status of one of the job's processes
+fn update(job: &mut Job, pid: i32, status: i32) {
+    match state_from_status(status) {
+        JobState::Done(code) => {
+            if pid == job.last_pid {
+                job.exit_code = code;
+            }
+            forget_pid(job, pid);
+        }
+        changed => job.state = changed,
+    }
+}
+
+fn forget_pid(job: &mut Job, pid: i32) {
+    job.pids.retain(|&p| p != pid);
+    if job.pids.is_empty() {
+        job.state = JobState::Done(job.exit_code);
+    }
+}
+
+/// Collects the statuses of jobs that changed since the last SIGCHLD, then
+/// of any other child that exited with nobody waiting for it.
+pub fn reap() {
+    if CHILD_EXITED.swap(false, Ordering::Relaxed) {
+        refresh();
+        reap_untracked();
+    }
+}
+
+// waitid with WNOWAIT names the next exited child without collecting it. It
+// can only ever name the first one, so the drain stops at a child a job or
+// another waiter will collect; the next SIGCHLD picks up from there
+fn reap_untracked() {
+    let waited = WAITED_ELSEWHERE.lock().unwrap();
+    let jobs = table().lock().unwrap();
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let options = libc::WEXITED | WNOHANG | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_ALL, 0, &mut info, options) } == -1 {
+            break;
+        }
+        let pid = unsafe { info.si_pid() };
+        if pid == 0 || waited.contains(&pid) || jobs.iter().any(|j| j.pids.contains(&pid)) {
+            break;
+        }
+        let mut status = 0;
+        unsafe {
+            waitpid(pid, &mut status, WNOHANG);
+        }
+    }
+}
+
+/// Keeps reap() away from some children's statuses until dropped, once
+/// they have been waited for.
+pub struct WaitGuard(Vec<i32>);
+
+impl Drop for WaitGuard {
+    fn drop(&mut self) {
+        WAITED_ELSEWHERE
+            .lock()
+            .unwrap()
+            .retain(|pid| !self.0.contains(pid));
+    }
+}
+
+fn waiting_for(pids: &[i32]) -> WaitGuard {
+    WAITED_ELSEWHERE.lock().unwrap().extend_from_slice(pids);
+    WaitGuard(pids.to_vec())
+}
+
+/// Spawns `cmd` for a caller that waits for the child itself. It's listed
+/// before reap() can see it exit; keep the guard until it has been waited for.
+pub fn spawn_waited(cmd: &mut Command) -> io::Result<(Child, WaitGuard)> {
+    let mut waited = WAITED_ELSEWHERE.lock().unwrap();
+    let child = cmd.spawn()?;
+    let pid = child.id() as i32;
+    waited.push(pid);
+    Ok((child, WaitGuard(vec![pid])))
+}
+
+/// Children of the shell that have exited but were never waited for, read
+/// from /proc. Anything listed here escaped reap().
+pub fn zombie_children() -> Vec<i32> {
+    let me = std::process::id().to_string();
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| {
+            // `pid (comm) state ppid ...`, comm may contain spaces
+            std::fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| {
+                let mut fields = stat.rsplit(')').next().unwrap_or("").split_whitespace();
+                fields.next() == Some("Z") && fields.next() == Some(me.as_str())
+            })
+        })
+        .collect()
+}
+
+pub fn set_last_background(pid: i32) {
+    LAST_BACKGROUND.store(pid, Ordering::Relaxed);
+}
+
+// `$!`, empty until something was started in the background
+pub fn last_background() -> Option<i32> {
+    Some(LAST_BACKGROUND.load(Ordering::Relaxed)).filter(|&pid| pid > 0)
+}
+
+pub fn state_from_status(status: i32) -> JobState {
+    if libc::WIFSTOPPED(status) {
+        JobState::Stopped
+    } else if libc::WIFCONTINUED(status) {
+        JobState::Running
+    } else if libc::WIFEXITED(status) {
+        JobState::Done(libc::WEXITSTATUS(status))
+    } else {
+        JobState::Done(128 + libc::WTERMSIG(status))
+    }
+}
+
+// Shell exit code for a wait status: the exit code, or 128 + signal number
+pub fn exit_code(status: i32) -> i32 {
+    match state_from_status(status) {
+        JobState::Done(code) => code,
+        JobState::Stopped => 128 + SIGTSTP,
+        JobState::Running => 0,
+    }
+}
+
+// The signal a 128 + N exit code stands for, `INT` for 130
+pub fn signal_name(code: i32) -> Option<&'static str> {
+    Some(match code.checked_sub(128)? {
+        libc::SIGHUP => "HUP",
+        libc::SIGINT => "INT",
+        libc::SIGQUIT => "QUIT",
+        libc::SIGILL => "ILL",
+        libc::SIGTRAP => "TRAP",
+        libc::SIGABRT => "ABRT",
+        libc::SIGBUS => "BUS",
+        libc::SIGFPE => "FPE",
+        libc::SIGKILL => "KILL",
+        libc::SIGUSR1 => "USR1",
+        libc::SIGSEGV => "SEGV",
+        libc::SIGUSR2 => "USR2",
+        libc::SIGPIPE => "PIPE",
+        libc::SIGALRM => "ALRM",
+        libc::SIGTERM => "TERM",
+        libc::SIGTSTP => "TSTP",
+        libc::SIGTTIN => "TTIN",
+        libc::SIGTTOU => "TTOU",
+        libc::SIGXCPU => "XCPU",
+        libc::SIGXFSZ => "XFSZ",
+        _ => return None,
+    })
+}
+
+/// How a foreground job gave the terminal back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waited {
+    /// Its last process ended with this wait status
+    Finished(i32),
+    /// It was stopped and is in the job table
+    Stopped,
+}
+
+impl Waited {
+    /// What `$?` becomes: the exit code, or 128 + SIGTSTP after a stop as in
+    /// bash. A job that exits 148 itself is still Finished.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Waited::Finished(status) => exit_code(status),
+            Waited::Stopped => 128 + SIGTSTP,
+        }
+    }
+}
+
+/// Hands the terminal to `pgid` and waits for `pids` to finish or stop.
+///
+/// A stopped job is (re)registered as Stopped and reported like bash does.
+pub fn wait_foreground(pgid: i32, pids: &[i32], command: &str, job: Option<usize>) -> Waited {
+    let _waiting = waiting_for(pids);
+    give_terminal(pgid);
+
+    let mut last_status = 0;
+    let mut remaining = pids.to_vec();
+    let mut stopped = false;
+
+    while let Some(&pid) = remaining.first() {
+        let mut status = 0;
+        if unsafe { waitpid(pid, &mut status, WUNTRACED) } == -1 {
+            if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                remaining.remove(0);
+            }
+            continue;
+        }
+        if libc::WIFSTOPPED(status) {
+            stopped = true;
+            break;
+        }
+        if Some(&pid) == pids.last() {
+            last_status = status;
+        }
+        remaining.remove(0);
+    }
+
+    reclaim_terminal();
+
+    if stopped {
+        let id = match job {
+            Some(id) => {
+                if let Some(j) = table().lock().unwrap().iter_mut().find(|j| j.id == id) {
+                    j.pids = remaining;
+                    j.state = JobState::Stopped;
+                }
+                id
+            }
+            None => add(pgid, &remaining, command, JobState::Stopped),
+        };
+        println!();
+        println!("[{id}]+ Stopped  {command}");
+        return Waited::Stopped;
+    }
+
+    if let Some(id) = job {
+        remove(id);
+    }
+    // Start the next prompt on a fresh line after ^C
+    if libc::WIFSIGNALED(last_status) && libc::WTERMSIG(last_status) == SIGINT {
+        println!();
+    }
+    Waited::Finished(last_status)
+}
+
+fn marker(id: usize) -> char {
+    if current() == Some(id) { '+' } else { ' ' }
+}
+
+// Prints the job table and forgets jobs that have finished
+pub fn handle_jobs() -> io::Result<()> {
+    refresh();
+    for job in list() {
+        println!(
+            "[{}]{} {:<8} {}",
+            job.id,
+            marker(job.id),
+            job.state,
+            job.command
+        );
+    }
+    table()
+        .lock()
+        .unwrap()
+        .retain(|j| !matches!(j.state, JobState::Done(_)));
+    Ok(())
+}
+
+// Reaps finished jobs and reports them, called right before a prompt is drawn
+pub fn notify_finished() {
+    reap();
+    let done: Vec<Job> = list()
+        .into_iter()
+        .filter(|j| matches!(j.state, JobState::Done(_)))
+        .collect();
+    for job in done {
+        println!(
+            "[{}]{} {:<8} {}",
+            job.id,
+            marker(job.id),
+            job.state,
+            job.command
+        );
+        remove(job.id);
+    }
+}
+
+// Resumes a stopped job without giving it the terminal
+pub fn handle_bg(args: &[&str]) -> io::Result<()> {
+    refresh();
+    let job = match args.first() {
+        Some(spec) => resolve(spec).map_err(|e| io::Error::new(e.kind(), format!("bg: {e}")))?,
+        None => last_stopped().ok_or_else(|| io::Error::other("bg: no current job"))?,
+    };
+
+    match job.state {
+        JobState::Stopped => {
+            if unsafe { kill(-job.pgid, SIGCONT) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            set_state(job.id, JobState::Running);
+            println!("[{}]{} {} &", job.id, marker(job.id), job.command);
+            Ok(())
+        }
+        JobState::Running => {
+            println!("bg: job {} already in background", job.id);
+            Ok(())
+        }
+        JobState::Done(_) => Err(io::Error::other(format!(
+            "bg: job {} has terminated",
+            job.id
+        ))),
+    }
+}
+
+// Brings a job to the foreground, continuing it if it was stopped
+pub fn handle_fg(args: &[&str]) -> io::Result<()> {
+    refresh();
+    let job = match args.first() {
+        Some(spec) => resolve(spec).map_err(|e| io::Error::new(e.kind(), format!("fg: {e}")))?,
+        None => resolve("%+").map_err(|_| io::Error::other("fg: no current job"))?,
+    };
+
+    if let JobState::Done(_) = job.state {
+        remove(job.id);
+        return Err(io::Error::other(format!(
+            "fg: job {} has terminated",
+            job.id
+        )));
+    }
+
+    println!("{}", job.command);
+    give_terminal(job.pgid);
+    if unsafe { kill(-job.pgid, SIGCONT) } == -1 {
+        reclaim_terminal();
+        return Err(io::Error::last_os_error());
+    }
+    set_state(job.id, JobState::Running);
+    wait_foreground(job.pgid, &job.pids, &job.command, Some(job.id));
+    Ok(())
+}
+
+// Forgets jobs so they aren't waited on or hung up when the shell exits;
+// with -h they stay in the table and are only spared the hangup
+pub fn handle_disown(args: &[&str]) -> io::Result<()> {
+    refresh();
+    let (keep, args) = match args.split_first() {
+        Some((&"-h", rest)) => (true, rest),
+        _ => (false, args),
+    };
+    let jobs = if args.is_empty() {
+        let id = current().ok_or_else(|| io::Error::other("disown: no current job"))?;
+        vec![resolve(&id.to_string())?]
+    } else {
+        args.iter()
+            .map(|spec| resolve(spec).map_err(|e| io::Error::new(e.kind(), format!("disown: {e}"))))
+            .collect::<io::Result<Vec<_>>>()?
+    };
+
+    for job in jobs {
+        if keep {
+            if let Some(j) = table().lock().unwrap().iter_mut().find(|j| j.id == job.id) {
+                j.nohup = true;
+            }
+            continue;
+        }
+        // A stopped job left behind in an orphaned group gets SIGHUP from the
+        // kernel once the shell is gone, so keep it running instead
+        if job.state == JobState::Stopped {
+            unsafe {
+                kill(-job.pgid, SIGCONT);
+            }
+        }
+        remove(job.id);
+    }
+    Ok(())
+}
+
+/// Called before exiting: the first time there are active jobs it lists them
+/// and returns false, a second exit goes through.
+pub fn confirm_exit() -> bool {
+    if !job_control() {
+        return true;
+    }
+    refresh();
+    let active: Vec<Job> = list()
+        .into_iter()
+        .filter(|j| !matches!(j.state, JobState::Done(_)))
+        .collect();
+    if active.is_empty() || EXIT_WARNED.swap(true, Ordering::Relaxed) {
+        return true;
+    }
+
+    println!("There are running jobs.");
+    for job in active {
+        println!(
+            "[{}]{} {:<8} {}",
+            job.id,
+            marker(job.id),
+            job.state,
+            job.command
+        );
+    }
+    false
+}
+
+/// Hangs up the jobs still in the table on exit, as bash does with
+/// huponexit, and reports the ones that ended. Disowned jobs are left alone,
+/// and so are jobs that ignore SIGHUP (started with `nohup`): they are
+/// still running when the wait is over.
+pub fn hang_up() {
+    if !job_control() || !HUP_ON_EXIT.load(Ordering::Relaxed) {
+        return;
+    }
+    refresh();
+    let signaled: Vec<usize> = list()
+        .into_iter()
+        .filter(|job| !job.nohup && !matches!(job.state, JobState::Done(_)))
+        .map(|job| {
+            unsafe {
+                kill(-job.pgid, SIGHUP);
+                if job.state == JobState::Stopped {
+                    kill(-job.pgid, SIGCONT);
+                }
+            }
+            job.id
+        })
+        .collect();
+    if signaled.is_empty() {
+        return;
+    }
+
+    let deadline = Instant::now() + HANG_UP_WAIT;
+    let ended = loop {
+        refresh();
+        let ended: Vec<Job> = list()
+            .into_iter()
+            .filter(|job| signaled.contains(&job.id) && matches!(job.state, JobState::Done(_)))
+            .collect();
+        if ended.len() == signaled.len() || Instant::now() >= deadline {
+            break ended;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    for job in ended {
+        let state = match job.state {
+            JobState::Done(code) if code == 128 + SIGHUP => "Hangup".to_string(),
+            state => state.to_string(),
+        };
+        // With the terminal gone there is no one to tell, and println would panic
+        let _ = writeln!(
+            io::stdout(),
+            "[{}]{} {:<8} {}",
+            job.id,
+            marker(job.id),
+            state,
+            job.command
+        );
+    }
+}
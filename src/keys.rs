@@ -0,0 +1,358 @@
+use reedline::{
+    EditCommand, EditMode, Emacs, KeyCode, KeyModifiers, Keybindings, ReedlineEvent, Vi,
+    default_emacs_keybindings, default_vi_insert_keybindings, default_vi_normal_keybindings,
+};
+use std::{
+    io,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+/// A key shesh binds on top of reedline's defaults.
+#[derive(Clone)]
+pub struct Binding {
+    pub modifiers: KeyModifiers,
+    pub key: KeyCode,
+    pub event: ReedlineEvent,
+    pub action: &'static str,
+}
+
+// shell.ctrl_c = "clear": Ctrl-C empties the line rather than aborting it
+static CTRL_C_CLEARS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_ctrl_c_clears(clears: bool) {
+    CTRL_C_CLEARS.store(clears, Ordering::Relaxed);
+}
+
+// The bindings added to Emacs mode; the editor is built from this list and
+// `24! keys` prints it
+pub fn custom_bindings() -> Vec<Binding> {
+    let ctrl_c = if CTRL_C_CLEARS.load(Ordering::Relaxed) {
+        Binding {
+            modifiers: KeyModifiers::CONTROL,
+            key: KeyCode::Char('c'),
+            event: ReedlineEvent::Edit(vec![EditCommand::Clear]),
+            action: "Clear the line",
+        }
+    } else {
+        Binding {
+            modifiers: KeyModifiers::CONTROL,
+            key: KeyCode::Char('c'),
+            event: ReedlineEvent::CtrlC,
+            action: "Leave the line on screen with ^C and start a new one",
+        }
+    };
+    vec![
+        ctrl_c,
+        Binding {
+            modifiers: KeyModifiers::NONE,
+            key: KeyCode::Tab,
+            event: ReedlineEvent::UntilFound(vec![
+                ReedlineEvent::Menu("completion_menu".into()),
+                ReedlineEvent::MenuNext,
+            ]),
+            action: "Open the completion menu, or select the next item",
+        },
+        Binding {
+            modifiers: KeyModifiers::SHIFT,
+            key: KeyCode::BackTab,
+            event: ReedlineEvent::UntilFound(vec![
+                ReedlineEvent::Menu("completion_menu".into()),
+                ReedlineEvent::MenuPrevious,
+            ]),
+            action: "Open the completion menu, or select the previous item",
+        },
+        Binding {
+            modifiers: KeyModifiers::CONTROL,
+            key: KeyCode::Char('r'),
+            event: ReedlineEvent::UntilFound(vec![
+                ReedlineEvent::Menu("history_menu".into()),
+                ReedlineEvent::MenuNext,
+            ]),
+            action: "Search history for the line, again for older matches",
+        },
+    ]
+}
+
+// Actions a key can be bound to from the config's [keybindings] section
+const ACTIONS: &[(&str, &str)] = &[
+    ("clear_screen", "Clear the screen"),
+    ("clear_line", "Clear the line"),
+    (
+        "abort_line",
+        "Leave the line on screen with ^C and start a new one",
+    ),
+    (
+        "complete",
+        "Open the completion menu, or select the next item",
+    ),
+    (
+        "complete_previous",
+        "Open the completion menu, or select the previous item",
+    ),
+    (
+        "history_menu",
+        "Search history for the line, again for older matches",
+    ),
+    ("search_history", "Search history one match at a time"),
+    ("previous_history", "Previous history entry"),
+    ("next_history", "Next history entry"),
+    ("submit", "Run the line"),
+    ("newline", "Insert a newline"),
+    ("kill_line", "Cut to the end of the line"),
+    ("kill_word", "Cut the word before the cursor"),
+    ("paste", "Paste what was cut last"),
+    ("undo", "Undo"),
+    ("redo", "Redo"),
+    ("none", "Nothing (unbinds the key)"),
+];
+
+fn action_event(name: &str) -> Option<ReedlineEvent> {
+    let menu =
+        |name: &str, next| ReedlineEvent::UntilFound(vec![ReedlineEvent::Menu(name.into()), next]);
+    let edit = |command| ReedlineEvent::Edit(vec![command]);
+    Some(match name {
+        "clear_screen" => ReedlineEvent::ClearScreen,
+        "clear_line" => edit(EditCommand::Clear),
+        "abort_line" => ReedlineEvent::CtrlC,
+        "complete" => menu("completion_menu", ReedlineEvent::MenuNext),
+        "complete_previous" => menu("completion_menu", ReedlineEvent::MenuPrevious),
+        "history_menu" => menu("history_menu", ReedlineEvent::MenuNext),
+        "search_history" => ReedlineEvent::SearchHistory,
+        "previous_history" => ReedlineEvent::PreviousHistory,
+        "next_history" => ReedlineEvent::NextHistory,
+        "submit" => ReedlineEvent::Submit,
+        "newline" => edit(EditCommand::InsertNewline),
+        "kill_line" => edit(EditCommand::CutToEnd),
+        "kill_word" => edit(EditCommand::CutWordLeft),
+        "paste" => edit(EditCommand::PasteCutBufferBefore),
+        "undo" => edit(EditCommand::Undo),
+        "redo" => edit(EditCommand::Redo),
+        "none" => ReedlineEvent::None,
+        _ => return None,
+    })
+}
+
+// "ctrl-l", "alt-enter", "f1", "shift-tab"
+pub fn parse_chord(chord: &str) -> Option<(KeyModifiers, KeyCode)> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut rest = chord.trim();
+    while let Some((prefix, key)) = rest.split_once('-').filter(|(_, key)| !key.is_empty()) {
+        modifiers |= match prefix.to_ascii_lowercase().as_str() {
+            "ctrl" | "control" => KeyModifiers::CONTROL,
+            "alt" | "meta" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => return None,
+        };
+        rest = key;
+    }
+
+    let name = rest.to_ascii_lowercase();
+    let mut chars = rest.chars();
+    let key = match (chars.next(), chars.next()) {
+        (Some(c), None) => KeyCode::Char(c.to_ascii_lowercase()),
+        _ => match name.as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "tab" if modifiers.contains(KeyModifiers::SHIFT) => KeyCode::BackTab,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "esc" | "escape" => KeyCode::Esc,
+            "space" => KeyCode::Char(' '),
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            f => KeyCode::F(
+                f.strip_prefix('f')?
+                    .parse()
+                    .ok()
+                    .filter(|n| (1..=12).contains(n))?,
+            ),
+        },
+    };
+    Some((modifiers, key))
+}
+
+/// Turns a `chord = "action"` line from the config into a binding, or
+/// explains which names are valid.
+pub fn parse_binding(chord: &str, action: &str) -> Result<Binding, String> {
+    let (modifiers, key) = parse_chord(chord).ok_or_else(|| {
+        format!(
+            "unknown key '{chord}' (ctrl-, alt- or shift- followed by a character, \
+             enter, tab, backspace, delete, esc, space, up, down, left, right, home, end, \
+             pageup, pagedown or f1-f12)"
+        )
+    })?;
+    let (event, (_, description)) = action_event(action)
+        .zip(ACTIONS.iter().find(|(name, _)| *name == action))
+        .ok_or_else(|| {
+            let names: Vec<&str> = ACTIONS.iter().map(|(name, _)| *name).collect();
+            format!("unknown action '{action}' ({})", names.join(", "))
+        })?;
+    Ok(Binding {
+        modifiers,
+        key,
+        event,
+        action: description,
+    })
+}
+
+// Bindings from the config, applied after shesh's own so they win
+static USER: OnceLock<Mutex<Vec<Binding>>> = OnceLock::new();
+
+fn user() -> &'static Mutex<Vec<Binding>> {
+    USER.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+pub fn set_user_bindings(bindings: Vec<Binding>) {
+    *user().lock().unwrap() = bindings;
+}
+
+// shesh's bindings and the config's, with a later binding for the same key
+// replacing the earlier one; the flag marks bindings from the config
+fn merged() -> Vec<(Binding, bool)> {
+    let mut merged: Vec<(Binding, bool)> =
+        custom_bindings().into_iter().map(|b| (b, false)).collect();
+    for binding in user().lock().unwrap().iter() {
+        merged.retain(|(b, _)| (b.modifiers, b.key) != (binding.modifiers, binding.key));
+        merged.push((binding.clone(), true));
+    }
+    merged
+}
+
+// reedline's Emacs defaults with shesh's bindings and the config's on top
+pub fn emacs_keybindings() -> Keybindings {
+    let mut keybindings = default_emacs_keybindings();
+    for (binding, _) in merged() {
+        keybindings.add_binding(binding.modifiers, binding.key, binding.event);
+    }
+    keybindings
+}
+
+// reedline's Vi insert defaults with the same bindings on top, so Tab
+// completes in both modes
+pub fn vi_insert_keybindings() -> Keybindings {
+    let mut keybindings = default_vi_insert_keybindings();
+    for (binding, _) in merged() {
+        keybindings.add_binding(binding.modifiers, binding.key, binding.event);
+    }
+    keybindings
+}
+
+/// The editor for the given mode, with shesh's and the config's bindings.
+pub fn edit_mode(vim_mode: bool) -> Box<dyn EditMode> {
+    if vim_mode {
+        Box::new(Vi::new(
+            vi_insert_keybindings(),
+            default_vi_normal_keybindings(),
+        ))
+    } else {
+        Box::new(Emacs::new(emacs_keybindings()))
+    }
+}
+
+// "Ctrl-C", "Shift-Tab", ...
+pub fn key_name(modifiers: KeyModifiers, key: KeyCode) -> String {
+    let mut name = String::new();
+    for (flag, label) in [
+        (KeyModifiers::CONTROL, "Ctrl-"),
+        (KeyModifiers::ALT, "Alt-"),
+        (KeyModifiers::SHIFT, "Shift-"),
+    ] {
+        if modifiers.contains(flag) {
+            name.push_str(label);
+        }
+    }
+    match key {
+        KeyCode::Char(c) => name.push(c.to_ascii_uppercase()),
+        KeyCode::BackTab => name.push_str("Tab"),
+        KeyCode::F(n) => name.push_str(&format!("F{n}")),
+        other => name.push_str(&format!("{other:?}")),
+    }
+    name
+}
+
+/// `24! keys`: the active edit mode and the bindings shesh and the config
+/// add to it.
+pub fn show(vim_mode: bool) -> io::Result<()> {
+    if vim_mode {
+        println!("Edit mode: Vi (reedline's normal mode defaults; insert mode defaults, plus:)");
+    } else {
+        println!("Edit mode: Emacs (reedline's defaults, plus:)");
+    }
+    let rows: Vec<(String, String)> = merged()
+        .into_iter()
+        .map(|(b, from_config)| {
+            let source = if from_config { " (config)" } else { "" };
+            (
+                key_name(b.modifiers, b.key),
+                format!("{}{source}", b.action),
+            )
+        })
+        .collect();
+    let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    for (key, action) in rows {
+        println!("  {key:<width$}  {action}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_names() {
+        assert_eq!(
+            key_name(KeyModifiers::CONTROL, KeyCode::Char('c')),
+            "Ctrl-C"
+        );
+        assert_eq!(key_name(KeyModifiers::SHIFT, KeyCode::BackTab), "Shift-Tab");
+        assert_eq!(key_name(KeyModifiers::NONE, KeyCode::Tab), "Tab");
+    }
+
+    #[test]
+    fn test_parse_binding() {
+        assert_eq!(
+            parse_chord("ctrl-l"),
+            Some((KeyModifiers::CONTROL, KeyCode::Char('l')))
+        );
+        assert_eq!(
+            parse_chord("Alt-Enter"),
+            Some((KeyModifiers::ALT, KeyCode::Enter))
+        );
+        assert_eq!(
+            parse_chord("shift-tab"),
+            Some((KeyModifiers::SHIFT, KeyCode::BackTab))
+        );
+        assert_eq!(parse_chord("f1"), Some((KeyModifiers::NONE, KeyCode::F(1))));
+        assert_eq!(
+            parse_chord("ctrl--"),
+            Some((KeyModifiers::CONTROL, KeyCode::Char('-')))
+        );
+        assert_eq!(parse_chord("hyper-x"), None);
+        assert_eq!(parse_chord("f13"), None);
+
+        // Every listed action maps to an event
+        for (name, _) in ACTIONS {
+            assert!(parse_binding("ctrl-x", name).is_ok(), "{name}");
+        }
+        let err = parse_binding("ctrl-x", "explode").err().unwrap();
+        assert!(err.contains("clear_screen, clear_line"), "{err}");
+    }
+
+    #[test]
+    fn test_ctrl_c_aborts_line() {
+        let ctrl_c = &custom_bindings()[0];
+        assert_eq!(ctrl_c.key, KeyCode::Char('c'));
+        assert!(matches!(ctrl_c.event, ReedlineEvent::CtrlC));
+        assert!(parse_binding("ctrl-", "undo").is_err());
+    }
+}
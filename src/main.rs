@@ -1,115 +1,316 @@
+mod bench;
 mod builtins;
 mod completions;
 mod config;
+mod direnv;
+mod dirs;
+mod doctor;
+mod git;
+mod hash;
+mod hinter;
+mod hints;
+mod history;
+mod hosts;
+mod integration;
+mod jobs;
+mod keys;
+mod output;
 mod parse;
 mod process_exec;
 mod prompt;
+mod providers;
 mod shell;
+mod specs;
+mod stats;
+mod term;
+mod theme;
+mod title;
 mod utils;
 
-use nu_ansi_term::{Color, Style};
-use reedline::{
-    ColumnarMenu, DefaultHinter, EditCommand, Emacs, FileBackedHistory, KeyCode, KeyModifiers,
-    MenuBuilder, Reedline, ReedlineEvent, ReedlineMenu, Signal, Vi, default_emacs_keybindings,
+use nu_ansi_term::Style;
+use reedline::{ColumnarMenu, MenuBuilder, Reedline, ReedlineMenu, Signal};
+use std::{
+    io::{self, Write},
+    time::Instant,
 };
 
-use crate::{completions::create_default_completer, prompt::PromptSystem};
+use crate::{
+    completions::create_default_completer,
+    hinter::HistoryHinter,
+    history::SharedHistory,
+    parse::LineValidator,
+    prompt::{PromptSystem, TransientPrompt},
+};
+
+const HELP: &str = "\
+usage: shesh [options] [-c string [name [args...]] | script [args...]]
+
+Without a script or -c, shesh reads commands at a prompt.
+
+  -c string     Run the string, with name as $0 and args as $1..$n
+  script        Run the file, with its path as $0 and args as $1..$n
+  -l, --login   Login shell: source ~/.config/shesh/profile first
+  --norc        Skip every startup file and the config's [startup] commands
+  -h, --help    Print this help
+  --version     Print the version
+";
+
+// What shesh runs instead of prompting
+enum Script {
+    File(String),
+    Line(String),
+}
 
 fn main() {
-    // Initialize VIM_MODE
-    builtins::init_vim_mode();
+    let started = Instant::now();
+    // [0] --norc skips every startup file and the config's [startup] commands;
+    // `-c string` or the first other argument is what to run, the rest its
+    // arguments. A login shell is started as `-shesh` or with -l.
+    let mut args = std::env::args();
+    let mut login = args.next().is_some_and(|name| name.starts_with('-'));
+    let mut norc = false;
+    let mut script = None;
+    // Not in the help: how shesh starts its own subshells, with the state
+    // shell::subshell_state() passes
+    let mut subshell = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--norc" => norc = true,
+            "--subshell" => {
+                subshell = args.next();
+                norc = true;
+            }
+            "-l" | "--login" => login = true,
+            "--version" => {
+                println!("shesh {}", env!("CARGO_PKG_VERSION"));
+                return;
+            }
+            "-h" | "--help" => {
+                print!("{HELP}");
+                return;
+            }
+            "-c" => {
+                let Some(line) = args.next() else {
+                    eprint!("shesh: -c needs a command string\n{HELP}");
+                    std::process::exit(2);
+                };
+                script = Some(Script::Line(line));
+                break;
+            }
+            other if other.starts_with('-') => {
+                eprint!("shesh: unknown option '{other}'\n{HELP}");
+                std::process::exit(2);
+            }
+            _ => {
+                script = Some(Script::File(arg));
+                break;
+            }
+        }
+    }
+
+    // [1] Load configuration, refuse runaway nesting, and run startup files
+    process_exec::enable_reexec();
+    // A subshell gets its aliases from the parent, and the config's warnings
+    // were shown there already
+    let cfg = if subshell.is_some() {
+        config::Config::default()
+    } else {
+        config::init()
+    };
+    builtins::init_vim_mode(cfg.vi_mode);
+    if let Err(e) = utils::enter_shell_depth(cfg.max_depth) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    config::apply_settings(&cfg);
+    history::configure(cfg.history.clone());
+    if let Some(dir) = &cfg.completion.cache_dir {
+        completions::set_cache_dir(dir.clone());
+    }
+    keys::set_user_bindings(cfg.keybindings.clone());
+    keys::set_ctrl_c_clears(cfg.clear_on_ctrl_c);
+    if let Some(state) = &subshell {
+        shell::restore_subshell_state(state);
+    }
+    let interactive = script.is_none() && unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    // Scripts skip startup files, as `sh script` does, unless they run in a
+    // login shell
+    if !norc && (script.is_none() || login) {
+        config::run_startup(&cfg, interactive, login);
+    }
 
-    // [1] Load configuration and run startup script
-    let cfg = config::init();
-    config::run_startup(&cfg);
+    // A script runs to its end without the line editor, prompt or history,
+    // and so do commands piped in; shesh exits with the last one's status
+    match script {
+        Some(Script::File(path)) => {
+            match builtins::run_script(std::path::Path::new(&path), args.collect()) {
+                Ok(status) => std::process::exit(status),
+                Err(e) => {
+                    eprintln!("shesh: {e}");
+                    std::process::exit(127);
+                }
+            }
+        }
+        // `sh -c` callers pass $0 first, then $1..$n
+        Some(Script::Line(line)) => {
+            std::process::exit(builtins::run_line(&line, args.next(), args.collect()))
+        }
+        None if !interactive => match builtins::run_stdin() {
+            Ok(status) => std::process::exit(status),
+            Err(e) => {
+                eprintln!("shesh: stdin: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => {}
+    }
 
-    // [2] Initialize prompt style
+    // [2] Initialize prompt style and segments from the config
     let prompt = PromptSystem::new(cfg.prompt.clone());
 
-    // [3] Set up command history with file persistence
-    let history = Box::new(
-        FileBackedHistory::with_file(6000, config::history_file_path())
-            .unwrap_or_else(|_| FileBackedHistory::default()),
-    );
+    // [3] Set up command history with file persistence (only the tail is read),
+    // shared with the history builtin
+    let history = Box::new(SharedHistory::open());
 
     // [4] Set up auto-completion
-    let completer = create_default_completer();
+    let completer = create_default_completer(cfg.completion.clone(), jobs::table());
 
+    // Descriptions put the menu in a single column, column_width only
+    // applies without them
     let menu = ReedlineMenu::EngineCompleter(Box::new(
         ColumnarMenu::default()
             .with_name("completion_menu")
-            .with_column_width(Some(20)),
+            .with_column_width(Some(cfg.completion.column_width))
+            .with_description_text_style(Style::new().fg(theme::active().hint)),
+    ));
+    // Ctrl-R: every entry containing the line, newest first, one per row with
+    // the match underlined. Esc leaves the line as it was.
+    let history_menu = ReedlineMenu::HistoryMenu(Box::new(
+        ColumnarMenu::default()
+            .with_name("history_menu")
+            .with_columns(1)
+            .with_marker(&cfg.prompt.search)
+            .with_match_text_style(Style::new().fg(theme::active().hint).underline()),
     ));
 
-    // [5] Configure keybindings for Emacs mode
-    let mut keybindings = default_emacs_keybindings();
-    keybindings.add_binding(
-        KeyModifiers::CONTROL,
-        KeyCode::Char('c'),
-        ReedlineEvent::Edit(vec![EditCommand::Clear]),
-    );
-    keybindings.add_binding(
-        KeyModifiers::NONE,
-        KeyCode::Tab,
-        ReedlineEvent::UntilFound(vec![
-            ReedlineEvent::Menu("completion_menu".into()),
-            ReedlineEvent::MenuNext,
-        ]),
-    );
-    keybindings.add_binding(
-        KeyModifiers::SHIFT,
-        KeyCode::BackTab,
-        ReedlineEvent::UntilFound(vec![
-            ReedlineEvent::Menu("completion_menu".into()),
-            ReedlineEvent::MenuPrevious,
-        ]),
-    );
+    // [5] Start in the configured edit mode, with the config's keys on top of shesh's
+    let mut vim_mode = builtins::get_vim_mode();
 
     // [6] Build the line editor
     let mut editor = Reedline::create()
         .with_history(history)
         .with_completer(completer)
         .with_menu(menu)
-        .with_hinter(Box::new(
-            DefaultHinter::default()
-                .with_style(
-                    Style::new()
-                        .underline()
-                        .italic()
-                        .fg(Color::Rgb(120, 120, 120)),
-                )
-                .with_min_chars(1),
-        ))
-        .with_edit_mode(Box::new(Emacs::new(keybindings)));
+        .with_menu(history_menu)
+        .with_hinter(Box::new(HistoryHinter::default()))
+        .with_validator(Box::new(LineValidator))
+        .with_edit_mode(keys::edit_mode(vim_mode));
+    // reedline redraws the submitted prompt in place, wrapped lines included
+    if cfg.prompt.transient && interactive {
+        editor = editor.with_transient_prompt(Box::new(TransientPrompt));
+    }
 
     unsafe {
         libc::signal(libc::SIGINT, libc::SIG_IGN);
         libc::signal(libc::SIGQUIT, libc::SIG_IGN);
     }
+    jobs::init_job_control();
+    term::save();
 
     // [7] Main REPL loop
+    doctor::startup_done(started.elapsed());
+    direnv::enable();
+    dirs::enable();
     loop {
-        match editor.read_line(&prompt) {
-            Ok(Signal::Success(buf)) if !buf.trim().is_empty() => {
-                config::append_to_history(&buf);
+        // Ends the last line's output before job notices are printed
+        integration::at_prompt(shell::last_status());
+        jobs::notify_finished();
+        git::forget();
+        prompt::forget();
+        title::at_prompt();
+        // Lines other sessions entered since the last prompt
+        if let Err(e) = history::sync() {
+            output::fail(&format!("Failed to read the history file: {e}"));
+        }
 
-                if buf.trim() == "24! vim_keys" {
-                    let enabled = builtins::toggle_vim_mode();
-                    println!("Vim keys {}", if enabled { "enabled" } else { "disabled" });
+        // `24! vim_keys` flips the mode, from the prompt, startup or a sourced file
+        if builtins::get_vim_mode() != vim_mode {
+            vim_mode = !vim_mode;
+            editor = editor.with_edit_mode(keys::edit_mode(vim_mode));
+        }
 
-                    editor = editor.with_edit_mode(if enabled {
-                        Box::new(Vi::default())
-                    } else {
-                        Box::new(Emacs::new(default_emacs_keybindings()))
-                    });
+        match editor.read_line(&prompt) {
+            Ok(Signal::Success(buf)) if !buf.trim().is_empty() => {
+                // `!!`, `^old^new` and friends; the expanded line is shown, run
+                // and kept
+                let buf = match history::expand(&buf) {
+                    Ok(Some(expanded)) => {
+                        println!("{expanded}");
+                        history::replace_last(&buf, Some(&expanded));
+                        expanded
+                    }
+                    Ok(None) => buf,
+                    Err(e) => {
+                        history::replace_last(&buf, None);
+                        output::error(&e);
+                        shell::set_last_status(1);
+                        continue;
+                    }
+                };
+                // Written before running so other shells see a long job, unless
+                // failed lines or typos are left out and the status has to be
+                // known first
+                if !history::waits_for_status()
+                    && let Err(e) = history::sync()
+                {
+                    output::fail(&format!("Failed to write to history file: {e}"));
                 }
 
+                title::running(&buf);
+                integration::running();
+                let start = Instant::now();
                 if let Err(e) = shell::exec(&buf) {
-                    eprintln!("{e}");
+                    output::error(&e.to_string());
+                }
+                term::recover();
+                // Builtins finish at once, only other commands get a duration
+                let first = buf.split_whitespace().next().unwrap_or_default();
+                prompt::set_took((!builtins::is_builtin(first)).then(|| start.elapsed()));
+                let not_found = shell::last_status() == 127
+                    && buf
+                        .split([';', '|', '&'])
+                        .filter_map(|segment| segment.split_whitespace().next())
+                        .any(|cmd| !builtins::is_known_command(cmd));
+                if let Err(e) = history::command_done(shell::last_status(), not_found) {
+                    output::fail(&format!("Failed to write to history file: {e}"));
+                }
+
+                stats::record(&buf, shell::last_status());
+                if shell::last_status() != 0 {
+                    for hint in hints::for_failed_line(&buf, shell::last_status()) {
+                        eprintln!("{hint}");
+                    }
+                }
+            }
+            Ok(Signal::CtrlD) => {
+                if jobs::confirm_exit() {
+                    shell::shutdown(shell::last_status());
+                }
+            }
+            Ok(Signal::Success(_)) => {
+                prompt::set_took(None);
+                continue;
+            }
+            // The line stays on screen and out of history, as in bash
+            Ok(Signal::CtrlC) => {
+                if let Some(mark) = hinter::drawn_line().and_then(|line| prompt::abort_mark(&line))
+                {
+                    print!("{mark}");
+                    let _ = io::stdout().flush();
                 }
+                shell::set_last_status(130);
+                prompt::set_took(None);
             }
-            Ok(Signal::CtrlD) => break,
-            Ok(Signal::Success(_)) => continue,
             _ => eprintln!("Reedline error"),
         }
     }
@@ -0,0 +1,63 @@
+use nu_ansi_term::Style;
+use std::env;
+
+/// Whether what shesh writes to stderr may carry color and `[!]` marks:
+/// stderr is a terminal and NO_COLOR is unset or empty. A script whose
+/// stderr goes to a log gets plain `shesh: ...` lines.
+pub fn styled() -> bool {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    !no_color && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1
+}
+
+/// `text` in `style` when stderr is styled, as it is otherwise.
+pub fn paint(style: impl Into<Style>, text: &str) -> String {
+    if styled() {
+        style.into().paint(text).to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+// `[!] msg` at a terminal, `shesh: msg` anywhere else
+fn marked(mark: &str, msg: &str, styled: bool) -> String {
+    if styled {
+        format!("{mark} {msg}")
+    } else {
+        format!("shesh: {msg}")
+    }
+}
+
+/// Something shesh did on its own, like loading a directory's env file.
+pub fn info(msg: &str) {
+    eprintln!("{}", marked("[i]", msg, styled()));
+}
+
+/// Something shesh carries on from: a bad setting, a skipped file.
+pub fn warn(msg: &str) {
+    eprintln!("{}", marked("[!]", msg, styled()));
+}
+
+/// Something shesh failed to do itself, like writing the history file.
+pub fn fail(msg: &str) {
+    eprintln!("{}", marked("[X]", msg, styled()));
+}
+
+/// A command line's error, in the theme's error color.
+pub fn error(msg: &str) {
+    eprintln!("{}", paint(crate::theme::active().error, msg));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plain_output() {
+        assert_eq!(marked("[!]", "bad key", true), "[!] bad key");
+        assert_eq!(marked("[X]", "no history", false), "shesh: no history");
+        // Test output isn't a terminal
+        if !styled() {
+            assert_eq!(paint(nu_ansi_term::Color::Red, "e"), "e");
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use std::{env, fs};
+use crate::process_exec::capture_output;
+use reedline::{ValidationResult, Validator};
+use std::{env, fmt, fs, io};
 
 // AST (Abstract Syntax Tree) representation of commands
 #[derive(Debug, Clone)]
@@ -27,6 +29,7 @@ pub enum Operator {
     Pipe,                   // | (pipe)
     Seq,                    // ; (sequential)
     Background,             // & (background process)
+    QuietBackground,        // &! (background process with output discarded)
 }
 
 static OPERATORS: &[(&str, Operator)] = &[
@@ -41,9 +44,101 @@ static OPERATORS: &[(&str, Operator)] = &[
     ("&&", Operator::And),
     ("||", Operator::Or),
     ("|", Operator::Pipe),
+    ("&!", Operator::QuietBackground),
     ("&", Operator::Background),
 ];
 
+// The token of a whole-word `"$*"`, which joins the parameters into one
+// word. Arguments can't hold NUL, so nothing typed looks like it, and a
+// bare `$*` stays one word per parameter.
+const JOINED_PARAMS: &str = "\0$*";
+
+// How a token was written, for showing or re-parsing it
+fn written(arg: &str) -> &str {
+    if arg == JOINED_PARAMS { "\"$*\"" } else { arg }
+}
+
+// Renders a parsed command back to shell syntax (used for job listings)
+impl fmt::Display for ParsedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParsedCommand::Single(args) => {
+                let args: Vec<&str> = args.iter().map(|arg| written(arg)).collect();
+                write!(f, "{}", args.join(" "))
+            }
+            ParsedCommand::BinaryOp(left, op, right) => {
+                let op_str = OPERATORS
+                    .iter()
+                    .find(|(_, o)| o == op)
+                    .map_or("?", |(s, _)| s);
+                match right.as_ref() {
+                    ParsedCommand::Single(args) if args.is_empty() => write!(f, "{left} {op_str}"),
+                    _ => write!(f, "{left} {op_str} {right}"),
+                }
+            }
+        }
+    }
+}
+
+// How loosely an operator binds; the loosest one splits the line first
+fn precedence(op: Operator) -> u8 {
+    match op {
+        Operator::Seq | Operator::Background | Operator::QuietBackground => 0,
+        Operator::And | Operator::Or => 1,
+        Operator::Pipe => 2,
+        Operator::Redirect(_) => 3,
+    }
+}
+
+impl ParsedCommand {
+    /// The command as a line that parses back to it, for a subshell to run.
+    /// Each word is double-quoted, so it stays one word and its expansions
+    /// happen there as they would have here.
+    pub fn source(&self) -> String {
+        match self {
+            // `$*` is left bare, as quoting it would join the parameters
+            ParsedCommand::Single(args) => args
+                .iter()
+                .map(|arg| match arg.as_str() {
+                    "$*" | JOINED_PARAMS => written(arg).to_string(),
+                    _ => quote_word(arg),
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+            ParsedCommand::BinaryOp(left, op, right) => {
+                let op_str = OPERATORS
+                    .iter()
+                    .find(|(_, o)| o == op)
+                    .map_or("?", |(s, _)| s);
+                format!("{} {op_str} {}", left.source(), right.source())
+            }
+        }
+    }
+}
+
+// `"` and `\` are escaped, a `$(...)` is kept as it is: inside one the
+// tokenizer takes everything verbatim
+fn quote_word(word: &str) -> String {
+    let mut quoted = String::from('"');
+    let mut i = 0;
+    while let Some(c) = word[i..].chars().next() {
+        if word[i..].starts_with("$(")
+            && let Some(end) = substitution_end(word, i)
+        {
+            quoted.push_str(&word[i..=end]);
+            i = end + 1;
+            continue;
+        }
+        if matches!(c, '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+        i += c.len_utf8();
+    }
+    quoted.push('"');
+    quoted
+}
+
 // Main parsing function - entry point
 pub fn parse_syntax(input: &str) -> ParsedCommand {
     // If the input is a single operator from OPERATORS
@@ -51,38 +146,84 @@ pub fn parse_syntax(input: &str) -> ParsedCommand {
         return ParsedCommand::Single(vec![]); // Empty list
     }
 
-    // The rest remains the same
-    OPERATORS
-        .iter()
-        .find_map(|(op_str, op_enum)| {
-            find_outside_quotes(input, op_str).map(|index| {
-                let (left, right_with_op) = input.split_at(index);
-                let right = &right_with_op[op_str.len()..];
-                ParsedCommand::BinaryOp(
-                    Box::new(parse_syntax(left)),
-                    *op_enum,
-                    Box::new(parse_syntax(right)),
-                )
-            })
-        })
-        .unwrap_or_else(|| ParsedCommand::Single(tokenize(input)))
-}
-
-// Finds operator occurrences outside quoted strings
-fn find_outside_quotes(input: &str, target: &str) -> Option<usize> {
+    // && and || group to the left, so they split at their last occurrence;
+    // everything else splits at the first
+    let ops = find_operators(input);
+    let split = (0..=3).find_map(|level| {
+        let mut at_level = ops.iter().filter(|(_, _, op)| precedence(*op) == level);
+        if level == 1 {
+            at_level.next_back()
+        } else {
+            at_level.next()
+        }
+    });
+
+    match split {
+        Some(&(index, len, op)) => ParsedCommand::BinaryOp(
+            Box::new(parse_syntax(&input[..index])),
+            op,
+            Box::new(parse_syntax(&input[index + len..])),
+        ),
+        None => ParsedCommand::Single(tokenize(input)),
+    }
+}
+
+// Operators outside quoted strings and `$(...)` groups, as (byte index,
+// length, operator); the longest operator wins at each position
+fn find_operators(input: &str) -> Vec<(usize, usize, Operator)> {
+    let mut found = Vec::new();
     let mut in_quotes = None;
-    let first_char = target.chars().next()?;
-    let mut chars = input.char_indices();
+    let mut skip_to = 0;
 
-    while let Some((i, c)) = chars.next() {
+    for (i, c) in input.char_indices() {
+        if i < skip_to {
+            continue;
+        }
         match c {
-            '"' | '\'' => {
-                if in_quotes.take() != Some(c) {
-                    in_quotes = Some(c);
+            // `\&` and `\;` are the characters, not operators
+            '\\' if in_quotes != Some('\'') => {
+                skip_to = input[i + 1..]
+                    .chars()
+                    .next()
+                    .map_or(i + 1, |n| i + 1 + n.len_utf8());
+            }
+            '$' if in_quotes != Some('\'') && input[i..].starts_with("$(") => {
+                skip_to = substitution_end(input, i).map_or(input.len(), |end| end + 1);
+            }
+            '"' | '\'' => in_quotes = if in_quotes == Some(c) { None } else { Some(c) },
+            _ if in_quotes.is_none() => {
+                if let Some((op_str, op)) =
+                    OPERATORS.iter().find(|(s, _)| input[i..].starts_with(s))
+                {
+                    found.push((i, op_str.len(), *op));
+                    skip_to = i + op_str.len();
                 }
             }
-            _ if in_quotes.is_none() && c == first_char && input[i..].starts_with(target) => {
-                return Some(i);
+            _ => {}
+        }
+    }
+    found
+}
+
+// Byte index of the `)` closing the `$(` that starts at `start`
+pub fn substitution_end(input: &str, start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut chars = input[start..].char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if quote != Some('\'') => {
+                chars.next();
+            }
+            '"' | '\'' if quote.is_none() => quote = Some(c),
+            _ if quote == Some(c) => quote = None,
+            '(' if quote.is_none() => depth += 1,
+            ')' if quote.is_none() => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(start + i);
+                }
             }
             _ => {}
         }
@@ -90,32 +231,107 @@ fn find_outside_quotes(input: &str, target: &str) -> Option<usize> {
     None
 }
 
+/// Whether `input` is a whole command: no quote or `$(` left open and no
+/// `\` at the very end. Scripts and piped input join lines until it is, and
+/// Enter at the prompt starts a new line until it is.
+pub fn is_complete(input: &str) -> bool {
+    // Open quotes and `$(`s, innermost last
+    let mut open: Vec<char> = Vec::new();
+    let mut chars = input.chars().peekable();
+    let mut word_start = true;
+    while let Some(c) = chars.next() {
+        match (open.last(), c) {
+            (Some('\''), '\'') => {
+                open.pop();
+            }
+            (Some('\''), _) => {}
+            (_, '\\') if chars.next().is_none() => return false,
+            (_, '$') if chars.peek() == Some(&'(') => {
+                chars.next();
+                open.push('(');
+            }
+            (Some('"'), '"') => {
+                open.pop();
+            }
+            (Some('"'), _) => {}
+            (_, '\'' | '"') => open.push(c),
+            (Some('('), '(') => open.push('('),
+            (Some('('), ')') => {
+                open.pop();
+            }
+            // A comment runs to the end of its line, quotes and all
+            (_, '#') if word_start => while chars.next_if(|&n| n != '\n').is_some() {},
+            _ => {}
+        }
+        word_start = c.is_whitespace() || matches!(c, ';' | '&' | '|' | '(');
+    }
+    open.is_empty()
+}
+
+/// Keeps the prompt's line open while `is_complete` says it isn't done.
+pub struct LineValidator;
+
+impl Validator for LineValidator {
+    fn validate(&self, line: &str) -> ValidationResult {
+        if is_complete(line) {
+            ValidationResult::Complete
+        } else {
+            ValidationResult::Incomplete
+        }
+    }
+}
+
 // Splits command into tokens while respecting quotes
 fn tokenize(input: &str) -> Vec<String> {
     let mut tokens = Vec::new();
     let mut current = String::new();
-    let mut chars = input.chars().peekable();
+    let mut chars = input.char_indices().peekable();
     let mut in_single = false;
     let mut in_double = false;
     let mut found_comment = false;
 
-    while let Some(c) = chars.next() {
+    while let Some((i, c)) = chars.next() {
         if found_comment {
             continue; // Ignore everything after #
         }
 
         match c {
-            '\\' => {
-                if let Some(next_char) = chars.next() {
-                    current.push(next_char);
+            // A `\` at the end of a line joins it to the next
+            '\\' if !in_single && chars.peek().is_some_and(|&(_, n)| n == '\n') => {
+                chars.next();
+            }
+            // Backslashes are literal in single quotes, and in double quotes
+            // they only escape characters that are special there
+            '\\' if !in_single => match chars.peek() {
+                Some(&(_, next)) if !in_double || matches!(next, '"' | '\\' | '$' | '`') => {
+                    current.push(next);
+                    chars.next();
                 }
+                _ => current.push(c),
+            },
+            // Command substitution is kept verbatim and expanded in process_tokens
+            '$' if !in_single && chars.peek().is_some_and(|&(_, n)| n == '(') => {
+                let end = substitution_end(input, i).unwrap_or(input.len() - 1);
+                current.push_str(&input[i..=end]);
+                while chars.next_if(|&(j, _)| j <= end).is_some() {}
+            }
+            '"' if !in_single
+                && !in_double
+                && current.is_empty()
+                && input[i..]
+                    .strip_prefix("\"$*\"")
+                    .is_some_and(|after| after.is_empty() || after.starts_with([' ', '\n'])) =>
+            {
+                current.push_str(JOINED_PARAMS);
+                chars.nth(2);
             }
             '"' if !in_single => in_double = !in_double,
             '\'' if !in_double => in_single = !in_single,
-            '#' if !in_single && !in_double => {
+            // Only a word starting with # begins a comment (`$#`, `a#b` don't)
+            '#' if !in_single && !in_double && current.is_empty() => {
                 found_comment = true;
             }
-            ' ' if !in_single && !in_double => {
+            ' ' | '\n' if !in_single && !in_double => {
                 if !current.is_empty() {
                     tokens.push(current.clone());
                     current.clear();
@@ -132,41 +348,114 @@ fn tokenize(input: &str) -> Vec<String> {
     tokens
 }
 
+// Replaces every `$(...)` in a token with the output of the command inside
+fn expand_substitutions(part: &str) -> String {
+    let mut result = String::new();
+    let mut rest = part;
+
+    while let Some(start) = rest.find("$(") {
+        let Some(end) = substitution_end(rest, start) else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        match capture_output(&rest[start + 2..end]) {
+            Ok(bytes) => result.push_str(&substitution_text(bytes)),
+            Err(e) => eprintln!("shesh: $({}): {e}", &rest[start + 2..end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+// Captured output is kept as bytes until it is spliced into a token: only
+// trailing newlines are stripped, NUL bytes can't be part of an argument and
+// are dropped, and invalid UTF-8 is replaced at this last step
+pub fn substitution_text(mut bytes: Vec<u8>) -> String {
+    while bytes.last() == Some(&b'\n') {
+        bytes.pop();
+    }
+    bytes.retain(|&b| b != 0);
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
 // Processes tokens by expanding variables and wildcards
-pub fn process_tokens(cmd: ParsedCommand) -> Vec<String> {
+pub fn process_tokens(cmd: ParsedCommand) -> io::Result<Vec<String>> {
     match cmd {
         ParsedCommand::Single(parts) => {
             let mut result = Vec::with_capacity(parts.len());
             for part in parts {
                 match part {
-                    _ if part.starts_with('$') => {
-                        result.push(env::var(&part[1..]).unwrap_or_default());
+                    _ if part.contains("$(") => result.push(expand_substitutions(&part)),
+                    _ if part == "$?" => result.push(crate::shell::last_status().to_string()),
+                    _ if part == "$!" => result.push(
+                        crate::jobs::last_background()
+                            .map(|pid| pid.to_string())
+                            .unwrap_or_default(),
+                    ),
+                    _ if part == "$#" => result.push(crate::shell::positional_count().to_string()),
+                    // One word per parameter, spaces and all
+                    _ if part == "$@" || part == "$*" => {
+                        result.extend(crate::shell::positional_all());
+                    }
+                    // One word, the parameters joined by the first IFS character
+                    _ if part == JOINED_PARAMS => {
+                        let ifs = env::var("IFS").unwrap_or_else(|_| " ".to_string());
+                        let separator: String = ifs.chars().take(1).collect();
+                        result.push(crate::shell::positional_all().join(&separator));
+                    }
+                    // `$1`..`$9` take one digit, `$10` is `$1` then `0`
+                    _ if part.len() > 1
+                        && part.starts_with('$')
+                        && part.as_bytes()[1].is_ascii_digit() =>
+                    {
+                        let n = (part.as_bytes()[1] - b'0') as usize;
+                        result.push(positional(n)? + &part[2..]);
+                    }
+                    _ if part.starts_with("${") && part.ends_with('}') => {
+                        let name = &part[2..part.len() - 1];
+                        match name.parse::<usize>() {
+                            Ok(n) => result.push(positional(n)?),
+                            Err(_) => match env::var(name) {
+                                Ok(value) => result.push(value),
+                                Err(_) if crate::shell::options().nounset => {
+                                    return Err(unbound(&format!("${name}")));
+                                }
+                                Err(_) => result.push(String::new()),
+                            },
+                        }
                     }
+                    _ if part.starts_with('$') => match env::var(&part[1..]) {
+                        Ok(value) => result.push(value),
+                        Err(_) if part.len() > 1 && crate::shell::options().nounset => {
+                            return Err(unbound(&part));
+                        }
+                        Err(_) => result.push(String::new()),
+                    },
                     _ if part.contains('*') => {
                         // Handle directory/* pattern
                         if let Some(slash_pos) = part.rfind('/') {
                             let (dir, pattern) = part.split_at(slash_pos + 1);
-                            if pattern == "*" {
-                                if let Ok(entries) = fs::read_dir(dir) {
-                                    for entry in entries.flatten() {
-                                        let filename =
-                                            entry.file_name().to_string_lossy().into_owned();
-                                        result.push(format!("{dir}{filename}"));
-                                    }
-                                    continue;
-                                }
-                            }
-                        }
-                        // Handle simple * in current directory
-                        else if part == "*" {
-                            if let Ok(entries) = fs::read_dir(".") {
+                            if pattern == "*"
+                                && let Ok(entries) = fs::read_dir(dir)
+                            {
                                 for entry in entries.flatten() {
                                     let filename = entry.file_name().to_string_lossy().into_owned();
-                                    result.push(filename);
+                                    result.push(format!("{dir}{filename}"));
                                 }
                                 continue;
                             }
                         }
+                        // Handle simple * in current directory
+                        else if part == "*"
+                            && let Ok(entries) = fs::read_dir(".")
+                        {
+                            for entry in entries.flatten() {
+                                let filename = entry.file_name().to_string_lossy().into_owned();
+                                result.push(filename);
+                            }
+                            continue;
+                        }
                         // If we get here, pass the original pattern
                         result.push(part);
                     }
@@ -179,11 +468,10 @@ pub fn process_tokens(cmd: ParsedCommand) -> Vec<String> {
                     }
                     _ if part.contains('{') && part.contains('}') => {
                         if let Some((start, end)) = part.find('{').zip(part.find('}')) {
-                            let expanded = part[start + 1..end].split(',').flat_map(|opt| {
+                            for opt in part[start + 1..end].split(',') {
                                 let new = format!("{}{}{}", &part[..start], opt, &part[end + 1..]);
-                                process_tokens(ParsedCommand::Single(vec![new]))
-                            });
-                            result.extend(expanded);
+                                result.extend(process_tokens(ParsedCommand::Single(vec![new]))?);
+                            }
                             continue;
                         }
                         result.push(part);
@@ -191,8 +479,211 @@ pub fn process_tokens(cmd: ParsedCommand) -> Vec<String> {
                     _ => result.push(part),
                 }
             }
-            result
+            Ok(result)
         }
-        _ => vec!["[complex command not handled yet]".into()],
+        _ => Ok(vec!["[complex command not handled yet]".into()]),
+    }
+}
+
+// `$n`, or `${n}` past 9
+fn positional(n: usize) -> io::Result<String> {
+    match crate::shell::positional(n) {
+        Some(value) => Ok(value),
+        None if crate::shell::options().nounset => Err(unbound(&format!("${n}"))),
+        None => Ok(String::new()),
+    }
+}
+
+// `set -u`: an unset variable fails the command instead of expanding to
+// nothing
+fn unbound(part: &str) -> io::Error {
+    crate::shell::set_last_status(1);
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: unbound variable", &part[1..]),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shell::exec;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_capture_is_byte_faithful() {
+        let bytes = capture_output(r"printf '\377\000\376'").unwrap();
+        assert_eq!(bytes, b"\xff\x00\xfe");
+
+        // Redirection to a file never goes through a String
+        let dir = TempDir::new("parse-redirect");
+        let out = dir.join("out");
+        exec(&format!(r"printf '\377\000\376' > {}", out.display())).unwrap();
+        assert_eq!(fs::read(&out).unwrap(), b"\xff\x00\xfe");
+    }
+
+    #[test]
+    fn test_substitution_into_file() {
+        assert_eq!(substitution_text(b"a b\n\n".to_vec()), "a b");
+        assert_eq!(substitution_text(b"\nkeep\n".to_vec()), "\nkeep");
+
+        // Invalid UTF-8 only becomes U+FFFD where it is spliced into the command
+        let dir = TempDir::new("parse-subst");
+        let out = dir.join("out");
+        exec(&format!(
+            r"printf %s $(printf '\377\000\376\n\n') > {}",
+            out.display()
+        ))
+        .unwrap();
+        assert_eq!(fs::read(&out).unwrap(), "\u{FFFD}\u{FFFD}".as_bytes());
+    }
+
+    #[test]
+    fn test_pipe_redirects() {
+        let dir = TempDir::new("parse-pipe-redirect");
+        let (input, out) = (dir.join("in"), dir.join("out"));
+        exec(&format!(r"printf 'b\na\n' | sort > {}", out.display())).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "a\nb\n");
+
+        fs::write(&input, "d\nc\n").unwrap();
+        exec(&format!(
+            "sort < {} 2> /dev/null | head -n 1 >> {}",
+            input.display(),
+            out.display()
+        ))
+        .unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "a\nb\nc\n");
+
+        // The stage after one writing to a file reads nothing
+        exec(&format!("echo x > {} | wc -c > {0}.n", out.display())).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "x\n");
+        assert_eq!(fs::read_to_string(dir.join("out.n")).unwrap().trim(), "0");
+    }
+
+    // Every operator with its operands in brackets
+    fn tree(cmd: &ParsedCommand) -> String {
+        match cmd {
+            ParsedCommand::Single(args) => args.join(" "),
+            ParsedCommand::BinaryOp(left, op, right) => {
+                let op = OPERATORS
+                    .iter()
+                    .find(|(_, o)| o == op)
+                    .map_or("?", |(s, _)| s);
+                format!("({} {op} {})", tree(left), tree(right))
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_trees() {
+        let parse = |line: &str| tree(&parse_syntax(line));
+        // Trees that were already right when the first operator in the
+        // table split the line
+        assert_eq!(parse("a; b; c"), "(a ; (b ; c))");
+        assert_eq!(parse("a | b | c"), "(a | (b | c))");
+        assert_eq!(parse("make && make test; ls"), "((make && make test) ; ls)");
+        assert_eq!(parse("ls > out"), "(ls > out)");
+        assert_eq!(parse("sleep 1 & echo hi"), "(sleep 1 & echo hi)");
+
+        // A redirect split first and took the rest of the line as its file:
+        // (read x < (in ; echo $?))
+        assert_eq!(parse("read x < in; echo $?"), "((read x < in) ; echo $?)");
+        // So did the redirect after a pipe: ((a | b) > out)
+        assert_eq!(parse("a | b > out"), "(a | (b > out))");
+        // && split before ||, at its first occurrence: (a && (b || c))
+        assert_eq!(parse("a && b || c"), "((a && b) || c)");
+        // and chains grouped to the right: (a && (b && c))
+        assert_eq!(parse("a && b && c"), "((a && b) && c)");
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        let render = |line: &str| match parse_syntax(line) {
+            ParsedCommand::BinaryOp(_, op, _) => op,
+            ParsedCommand::Single(_) => panic!("{line} should split"),
+        };
+        assert_eq!(render("read x < in; echo $?"), Operator::Seq);
+        assert_eq!(render("a && b || c"), Operator::Or);
+        assert_eq!(render("a | b > out"), Operator::Pipe);
+        assert_eq!(render("sleep 1 & echo hi"), Operator::Background);
+        assert_eq!(render("a &! b"), Operator::QuietBackground);
+        assert_eq!(
+            render("a 2>> err"),
+            Operator::Redirect(RedirectType::StderrAppend)
+        );
+
+        // Left-associative: the left side of || is the whole && chain
+        let ParsedCommand::BinaryOp(left, _, _) = parse_syntax("a && b || c") else {
+            unreachable!()
+        };
+        assert_eq!(left.to_string(), "a && b");
+
+        // Escaped operator characters are part of a word
+        let ParsedCommand::Single(tokens) = parse_syntax("ls a\\&b\\;c") else {
+            panic!("escaped & and ; should not split")
+        };
+        assert_eq!(tokens, ["ls", "a&b;c"]);
+    }
+
+    #[test]
+    fn test_source_parses_back() {
+        for line in [
+            "echo 'a  b' \"c|d\" x\\ y > out.txt",
+            "sleep 1 && echo \"$(echo \"a;b\")\" | wc -c",
+            "grep -v \\\" f 2>&1 || printf 'a\\\\b'",
+            "a && b || c; d &",
+            "printf '[%s]' \"$*\" $* \"$@\"",
+        ] {
+            let parsed = parse_syntax(line);
+            let source = parsed.source();
+            assert_eq!(
+                format!("{:?}", parse_syntax(&source)),
+                format!("{parsed:?}"),
+                "{line} became {source}"
+            );
+        }
+        assert_eq!(
+            parse_syntax("echo 'a b' c").source(),
+            "\"echo\" \"a b\" \"c\""
+        );
+        // Only a whole quoted word joins the parameters
+        let ParsedCommand::Single(tokens) = parse_syntax("echo \"$*\" $* \"$*x\"") else {
+            unreachable!()
+        };
+        assert_eq!(tokens, ["echo", JOINED_PARAMS, "$*", "$*x"]);
+    }
+
+    #[test]
+    fn test_is_complete() {
+        for done in [
+            "echo 'a \"b' \"c 'd\"",
+            "echo \"$(echo \"x)\")\"",
+            "echo \\\\",
+            "echo it # isn't",
+            "echo a#'b'",
+        ] {
+            assert!(is_complete(done), "{done}");
+        }
+        for open in [
+            "echo 'a",
+            "echo \"a\nb",
+            "echo $(ls",
+            "echo a \\",
+            "echo a#'b",
+        ] {
+            assert!(!is_complete(open), "{open}");
+        }
+    }
+
+    #[test]
+    fn test_substitution_tokens() {
+        let ParsedCommand::Single(args) = parse_syntax("echo $(echo 'a | b') c") else {
+            panic!("substitution must not split on the pipe inside it");
+        };
+        assert_eq!(args, vec!["echo", "$(echo 'a | b')", "c"]);
+        assert_eq!(
+            process_tokens(ParsedCommand::Single(args)).unwrap(),
+            vec!["echo", "a | b", "c"]
+        );
     }
 }
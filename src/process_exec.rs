@@ -1,20 +1,30 @@
 use std::{
-    ffi::CString,
     fs::{File, OpenOptions},
-    io,
-    os::fd::{AsFd, AsRawFd, FromRawFd, IntoRawFd},
-    process::{Command, Stdio, exit},
-    ptr,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    parse::{Operator, ParsedCommand, RedirectType},
-    shell::run,
-};
-use libc::{
-    SIG_DFL, SIG_IGN, SIGINT, SIGQUIT, SIGTTIN, SIGTTOU, STDIN_FILENO, STDOUT_FILENO, close, dup2,
-    fork, pipe, setsid, signal, waitpid,
+    builtins::is_builtin,
+    hash,
+    jobs::{self, JobState, Waited},
+    parse::{Operator, ParsedCommand, RedirectType, process_tokens},
+    shell::{run, set_last_status},
 };
+use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO, close, dup, dup2, waitpid};
+
+// Set by `quiet_background = true`, sends output of every `&` job to /dev/null
+static QUIET_BACKGROUND: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet_background(on: bool) {
+    QUIET_BACKGROUND.store(on, Ordering::Relaxed);
+}
 
 pub fn handle_redirect(
     left_cmd: ParsedCommand,
@@ -23,7 +33,7 @@ pub fn handle_redirect(
 ) -> io::Result<()> {
     // Extract filename from right command
     let filename = match right_cmd {
-        ParsedCommand::Single(args) => args.join(" "),
+        ParsedCommand::Single(args) => process_tokens(ParsedCommand::Single(args))?.join(" "),
         _ => {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -38,13 +48,24 @@ pub fn handle_redirect(
         ));
     }
 
+    // Builtins run inside the shell, so they get the redirection on its own fds
+    if let ParsedCommand::Single(args) = &left_cmd
+        && args.first().is_some_and(|cmd| is_builtin(cmd))
+    {
+        return redirect_builtin(left_cmd, redirect_type, &filename);
+    }
+
     // Build the base command
     let mut cmd = match left_cmd {
         ParsedCommand::Single(args) => {
+            let args = process_tokens(ParsedCommand::Single(args))?;
             if args.is_empty() {
                 return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty command"));
             }
-            let mut cmd = Command::new(&args[0]);
+            crate::shell::trace(&args);
+            let mut cmd =
+                Command::new(hash::lookup(&args[0]).unwrap_or_else(|| args[0].clone().into()));
+            cmd.arg0(&args[0]);
             if args.len() > 1 {
                 cmd.args(&args[1..]);
             }
@@ -59,75 +80,108 @@ pub fn handle_redirect(
     };
 
     // Handle each redirection type
+    let file = open_target(redirect_type, &filename)?;
     match redirect_type {
-        RedirectType::Stdout => {
-            let file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(filename)?;
-            cmd.stdout(file).stderr(Stdio::inherit());
-        }
-        RedirectType::StdoutAppend => {
-            let file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(filename)?;
+        RedirectType::Stdout | RedirectType::StdoutAppend => {
             cmd.stdout(file).stderr(Stdio::inherit());
         }
-        RedirectType::Stderr => {
-            let file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(filename)?;
+        RedirectType::Stderr | RedirectType::StderrAppend => {
             cmd.stderr(file).stdout(Stdio::inherit());
         }
-        RedirectType::StderrAppend => {
-            let file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(filename)?;
-            cmd.stderr(file).stdout(Stdio::inherit());
-        }
-        RedirectType::Both => {
-            let file = File::create(filename)?;
-            let fd = file.into_raw_fd();
-            unsafe {
-                cmd.stdout(Stdio::from_raw_fd(fd))
-                    .stderr(Stdio::from_raw_fd(libc::dup(fd)));
-            }
-        }
-        RedirectType::BothAppend => {
-            let file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(filename)?;
-            let fd = file.into_raw_fd();
-            unsafe {
-                cmd.stdout(Stdio::from_raw_fd(fd))
-                    .stderr(Stdio::from_raw_fd(libc::dup(fd)));
-            }
+        RedirectType::Both | RedirectType::BothAppend => {
+            cmd.stderr(file.try_clone()?).stdout(file);
         }
         RedirectType::Stdin => {
-            let file = File::open(filename)?;
             cmd.stdin(file);
         }
     }
 
-    // Execute the command with appropriate error handling
-    let status = cmd.status()?;
-    if !status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Command failed with status: {}", status),
-        ));
+    // Run it as its own foreground job, like any other external command
+    let line = cmd_line(&cmd);
+    unsafe {
+        cmd.pre_exec(|| {
+            jobs::setup_child(0);
+            Ok(())
+        });
     }
+    set_last_status(1);
+    let child = cmd.spawn()?;
+    let pid = child.id() as i32;
+    jobs::set_child_group(pid, pid);
+    let waited = jobs::wait_foreground(pid, &[pid], &line, None);
+    set_last_status(waited.exit_code());
+    check_status(waited)
+}
 
-    Ok(())
+fn open_target(redirect_type: RedirectType, filename: &str) -> io::Result<File> {
+    match redirect_type {
+        RedirectType::Stdout | RedirectType::Stderr | RedirectType::Both => File::create(filename),
+        RedirectType::StdoutAppend | RedirectType::StderrAppend | RedirectType::BothAppend => {
+            OpenOptions::new().create(true).append(true).open(filename)
+        }
+        RedirectType::Stdin => File::open(filename),
+    }
+}
+
+// Points the shell's own stdin/stdout/stderr at the file while a builtin runs
+fn redirect_builtin(
+    cmd: ParsedCommand,
+    redirect_type: RedirectType,
+    filename: &str,
+) -> io::Result<()> {
+    let file = open_target(redirect_type, filename)?;
+    let targets: &[i32] = match redirect_type {
+        RedirectType::Stdout | RedirectType::StdoutAppend => &[STDOUT_FILENO],
+        RedirectType::Stderr | RedirectType::StderrAppend => &[STDERR_FILENO],
+        RedirectType::Both | RedirectType::BothAppend => &[STDOUT_FILENO, STDERR_FILENO],
+        RedirectType::Stdin => &[STDIN_FILENO],
+    };
+
+    io::stdout().flush()?;
+    let saved: Vec<(i32, i32)> = targets
+        .iter()
+        .map(|&fd| unsafe {
+            let copy = dup(fd);
+            dup2(file.as_raw_fd(), fd);
+            (fd, copy)
+        })
+        .collect();
+
+    let result = run(cmd);
+
+    let _ = io::stdout().flush();
+    for (fd, copy) in saved {
+        unsafe {
+            dup2(copy, fd);
+            close(copy);
+        }
+    }
+    result
+}
+
+fn cmd_line(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|s| s.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Failures worth reporting; a stop or a Ctrl-C/kill of the job is not one
+fn check_status(waited: Waited) -> io::Result<()> {
+    let Waited::Finished(status) = waited else {
+        return Ok(());
+    };
+    if status == 0 {
+        return Ok(());
+    }
+    if libc::WIFSIGNALED(status) && matches!(libc::WTERMSIG(status), libc::SIGINT | libc::SIGTERM) {
+        return Ok(());
+    }
+    Err(io::Error::other(format!(
+        "Command failed with status {}",
+        jobs::exit_code(status)
+    )))
 }
 
 // // Helper function to build Command from ParsedCommand
@@ -162,93 +216,148 @@ pub fn run_pipe(commands: Vec<ParsedCommand>) -> io::Result<()> {
             "Pipe requires at least 2 commands",
         ));
     }
+    let line = commands
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(" | ");
 
-    let mut prev_read = None;
-    let mut child_pids = Vec::new();
-
-    for (i, cmd) in commands.iter().enumerate() {
-        let is_last = i == commands.len() - 1;
-        let mut fds = [0; 2];
+    // Expand every stage up front so substitutions run before any stage starts
+    let stages = commands
+        .into_iter()
+        .map(pipe_stage)
+        .collect::<io::Result<Vec<_>>>()?;
 
-        if !is_last && unsafe { pipe(fds.as_mut_ptr()) } == -1 {
-            return Err(io::Error::last_os_error());
+    // Each stage is spawned through Command, which forks and execs without
+    // running any of this shell's code in the child
+    let mut child_pids = Vec::new();
+    let mut pgid = 0;
+    let mut next_stdin = None;
+    let mut last_failed = false;
+    for (i, (args, redirects)) in stages.iter().enumerate() {
+        let is_last = i == stages.len() - 1;
+        // Resolved here so the lookups land in the shell's hash table
+        let mut cmd =
+            Command::new(hash::lookup(&args[0]).unwrap_or_else(|| args[0].clone().into()));
+        cmd.arg0(&args[0]).args(&args[1..]);
+        if let Some(stdin) = next_stdin.take() {
+            cmd.stdin(stdin);
+        }
+        if !is_last {
+            cmd.stdout(Stdio::piped());
+        }
+        // Every stage joins the first stage's group
+        unsafe {
+            cmd.pre_exec(move || {
+                jobs::setup_child(pgid);
+                Ok(())
+            });
         }
 
-        match unsafe { fork() } {
-            0 => {
-                // Child process
-                if let Some(fd) = prev_read {
-                    unsafe {
-                        dup2(fd, STDIN_FILENO);
-                        close(fd);
-                    }
-                }
-
-                if !is_last {
-                    unsafe {
-                        dup2(fds[1], STDOUT_FILENO);
-                        close(fds[1]);
-                        close(fds[0]);
-                    }
-                }
-
-                if let ParsedCommand::Single(args) = cmd {
-                    let cmd = CString::new(args[0].clone())?;
-                    let args: Vec<CString> = args[1..]
-                        .iter()
-                        .map(|s| CString::new(s.as_str()))
-                        .collect::<Result<_, _>>()?;
-
-                    let argv: Vec<*const libc::c_char> = std::iter::once(cmd.as_ptr())
-                        .chain(args.iter().map(|a| a.as_ptr()))
-                        .chain(std::iter::once(ptr::null()))
-                        .collect();
-
-                    unsafe {
-                        libc::execvp(cmd.as_ptr(), argv.as_ptr());
-                        eprintln!(
-                            "Failed to execute {:?}: {}",
-                            args[0],
-                            io::Error::last_os_error()
-                        );
-                    }
-                }
-                exit(1);
-            }
-            pid if pid > 0 => {
-                // Parent
-                if let Some(fd) = prev_read {
-                    unsafe {
-                        close(fd);
-                    }
-                }
-                if !is_last {
-                    unsafe {
-                        close(fds[1]);
-                    }
-                    prev_read = Some(fds[0]);
+        let spawned = match redirect_stage(&mut cmd, redirects) {
+            Ok(()) => cmd.spawn().map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => format!("'{}' command not found.", args[0]),
+                _ => format!("{}: {e}", args[0]),
+            }),
+            Err(e) => Err(e),
+        };
+        match spawned {
+            Ok(mut child) => {
+                let pid = child.id() as i32;
+                if pgid == 0 {
+                    pgid = pid;
                 }
+                jobs::set_child_group(pid, pgid);
+                // A stage whose stdout went to a file leaves the next one nothing
+                next_stdin = Some(child.stdout.take().map_or_else(Stdio::null, Stdio::from));
                 child_pids.push(pid);
             }
-            _ => return Err(io::Error::last_os_error()),
+            // The other stages still run; the next one reads nothing
+            Err(e) => {
+                crate::output::error(&format!("shesh: {e}"));
+                next_stdin = Some(Stdio::null());
+                last_failed = is_last;
+            }
         }
     }
 
-    // Wait for all children
-    let mut status = 0;
-    for pid in child_pids {
-        unsafe {
-            waitpid(pid, &mut status, 0);
+    // Wait for all children (or until the pipeline is stopped); a last stage
+    // that never started decides the status, as it would have
+    if child_pids.is_empty() {
+        set_last_status(127);
+        return Ok(());
+    }
+    let waited = jobs::wait_foreground(pgid, &child_pids, &line, None);
+    if last_failed {
+        set_last_status(127);
+        return Ok(());
+    }
+    set_last_status(waited.exit_code());
+    check_status(waited)
+}
+
+// A pipeline stage expanded: a simple command and the redirects written after
+// it, in order
+type PipeStage = (Vec<String>, Vec<(RedirectType, String)>);
+
+fn pipe_stage(cmd: ParsedCommand) -> io::Result<PipeStage> {
+    let (args, redirects) = redirected(cmd)?;
+    if args.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty command"));
+    }
+    crate::shell::trace(&args);
+    Ok((args, redirects))
+}
+
+// Redirects split at the first one, so in `a < in 2> err` the file and the
+// redirect after it are both on the right: (a < (in 2> err))
+fn redirected(cmd: ParsedCommand) -> io::Result<PipeStage> {
+    match cmd {
+        ParsedCommand::Single(args) => {
+            Ok((process_tokens(ParsedCommand::Single(args))?, Vec::new()))
+        }
+        ParsedCommand::BinaryOp(left, Operator::Redirect(redirect_type), right) => {
+            let (args, mut redirects) = redirected(*left)?;
+            let (file, more) = redirected(*right)?;
+            if file.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Missing filename for redirection",
+                ));
+            }
+            redirects.push((redirect_type, file.join(" ")));
+            redirects.extend(more);
+            Ok((args, redirects))
         }
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{other}' can't be a pipeline stage"),
+        )),
     }
+}
 
-    if status != 0 {
-        Err(io::Error::other(format!(
-            "Command failed with status {status}"
-        )))
-    } else {
-        Ok(())
+// Points a stage's fds at its files, over the pipe ends; a later redirect of
+// the same fd wins, as in sh
+fn redirect_stage(cmd: &mut Command, redirects: &[(RedirectType, String)]) -> Result<(), String> {
+    for (redirect_type, filename) in redirects {
+        let file = open_target(*redirect_type, filename).map_err(|e| format!("{filename}: {e}"))?;
+        match redirect_type {
+            RedirectType::Stdout | RedirectType::StdoutAppend => {
+                cmd.stdout(file);
+            }
+            RedirectType::Stderr | RedirectType::StderrAppend => {
+                cmd.stderr(file);
+            }
+            RedirectType::Both | RedirectType::BothAppend => {
+                let copy = file.try_clone().map_err(|e| format!("{filename}: {e}"))?;
+                cmd.stdout(file).stderr(copy);
+            }
+            RedirectType::Stdin => {
+                cmd.stdin(file);
+            }
+        }
     }
+    Ok(())
 }
 
 /// Flatten nested pipe commands
@@ -266,42 +375,177 @@ pub fn flatten_pipes(commands: Vec<ParsedCommand>) -> Vec<ParsedCommand> {
         .collect()
 }
 
-pub fn run_background(command: ParsedCommand) -> io::Result<()> {
-    let pid = unsafe { fork() };
-    match pid {
-        0 => {
-            // Child process
-            unsafe {
-                setsid();
-            }
+// Set by main: this process is the shesh binary, so a subshell is a fresh
+// `shesh --subshell` rather than a fork of this one, whose other threads may
+// hold locks the forked copy would wait on forever
+static REEXEC: AtomicBool = AtomicBool::new(false);
 
-            // Reset signal handlers
-            unsafe {
-                signal(SIGINT, SIG_DFL);
-                signal(SIGQUIT, SIG_DFL);
-                signal(SIGTTOU, SIG_IGN);
-                signal(SIGTTIN, SIG_IGN);
-            }
+pub fn enable_reexec() {
+    REEXEC.store(true, Ordering::Relaxed);
+}
 
-            // Redirect standard I/O
-            let null = std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open("/dev/null")?;
+// A subshell running `line` with this shell's status, options, aliases and
+// positional parameters. Unit tests have no shesh binary to start, and
+// their lines are plain sh.
+fn subshell(line: &str) -> Command {
+    if !REEXEC.load(Ordering::Relaxed) {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(line);
+        return cmd;
+    }
+    let mut cmd = Command::new("/proc/self/exe");
+    cmd.arg0("shesh")
+        .arg("--subshell")
+        .arg(crate::shell::subshell_state())
+        .arg("-c")
+        .arg(line)
+        .arg(crate::shell::positional(0).unwrap_or_default())
+        .args(crate::shell::positional_all());
+    cmd
+}
 
+pub fn run_background(command: ParsedCommand, quiet: bool) -> io::Result<()> {
+    // A new process group in the same session so fg can hand it the
+    // terminal later; output stays on the terminal unless asked otherwise
+    let mut child = subshell(&command.source());
+    child.stdin(Stdio::null());
+    if quiet || QUIET_BACKGROUND.load(Ordering::Relaxed) {
+        child.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+    unsafe {
+        child.pre_exec(|| {
+            jobs::setup_child(0);
+            Ok(())
+        });
+    }
+    let pid = child.spawn()?.id() as i32;
+    jobs::set_child_group(pid, pid);
+    let id = jobs::add(pid, &[pid], &command.to_string(), JobState::Running);
+    jobs::set_last_background(pid);
+    println!("[{id}] {pid}");
+    set_last_status(0);
+    Ok(())
+}
+
+/// Runs `cmd` in a subshell and returns everything it wrote to stdout.
+///
+/// Output is returned as raw bytes; callers decide how to turn it into text.
+pub fn capture_output(cmd: &str) -> io::Result<Vec<u8>> {
+    let (pid, mut pipe, _waiting) = spawn_capture(cmd, false, &[])?;
+    let mut output = Vec::new();
+    let read = pipe.read_to_end(&mut output);
+
+    let mut status = 0;
+    unsafe {
+        waitpid(pid, &mut status, 0);
+    }
+    read.map(|_| output)
+}
+
+/// Like capture_output, for commands run while drawing the prompt: stdin and
+/// stderr are /dev/null, and a command still running after `timeout` is
+/// killed along with its children and reported as TimedOut. Its exit status
+/// is ignored, as `$(...)` ignores it.
+pub fn capture_output_within(cmd: &str, timeout: Duration) -> io::Result<Vec<u8>> {
+    capture_timed(cmd, &[], timeout).map(|(output, _)| output)
+}
+
+/// capture_output_within with variables set for the command only, for the
+/// programs that stand in for shesh's own prompt or completions: one that
+/// exits non-zero has failed, whatever it printed.
+pub fn capture_output_with(
+    cmd: &str,
+    vars: &[(&str, String)],
+    timeout: Duration,
+) -> io::Result<Vec<u8>> {
+    let (output, status) = capture_timed(cmd, vars, timeout)?;
+    match jobs::exit_code(status) {
+        0 => Ok(output),
+        code => Err(io::Error::other(format!("exited with status {code}"))),
+    }
+}
+
+// The output of a detached subshell and its wait status
+fn capture_timed(
+    cmd: &str,
+    vars: &[(&str, String)],
+    timeout: Duration,
+) -> io::Result<(Vec<u8>, i32)> {
+    let (pid, mut pipe, _waiting) = spawn_capture(cmd, true, vars)?;
+    let deadline = Instant::now() + timeout;
+    let mut output = Vec::new();
+    let mut buf = [0u8; 4096];
+    let result = loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let mut poll_fd = libc::pollfd {
+            fd: pipe.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, left.as_millis() as i32) };
+        // A SIGCHLD from a finishing job cuts the wait short; it goes on
+        // with the time that's left
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
             unsafe {
-                dup2(null.as_raw_fd(), 0);
-                dup2(null.as_raw_fd(), 1);
-                dup2(null.as_fd().as_raw_fd(), 2);
+                libc::kill(-pid, libc::SIGKILL);
             }
-
-            let _ = run(command);
-            std::process::exit(0);
+            break Err(e);
         }
-        pid if pid > 0 => {
-            println!("[{pid}] Running in background");
-            Ok(())
+        if ready == 0 {
+            unsafe {
+                libc::kill(-pid, libc::SIGKILL);
+            }
+            break Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        }
+        match pipe.read(&mut buf) {
+            Ok(0) => break Ok(output),
+            Ok(n) => output.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(e),
         }
-        _ => Err(io::Error::last_os_error()),
+    };
+
+    let mut status = 0;
+    unsafe {
+        waitpid(pid, &mut status, 0);
+    }
+    result.map(|output| (output, status))
+}
+
+// Starts a subshell running `cmd` with its stdout on the returned pipe and
+// `vars` in its environment; the caller waits for it while holding the guard. Unless detached it stays in the shell's group;
+// a detached one gets a process group of its own, so it can be killed as a
+// whole, and nothing from the terminal.
+fn spawn_capture(
+    cmd: &str,
+    detached: bool,
+    vars: &[(&str, String)],
+) -> io::Result<(i32, File, jobs::WaitGuard)> {
+    let mut child = subshell(cmd);
+    child
+        .envs(vars.iter().map(|(name, value)| (name, value)))
+        .stdout(Stdio::piped());
+    if detached {
+        child
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0);
+    }
+    unsafe {
+        child.pre_exec(|| {
+            jobs::default_signals();
+            Ok(())
+        });
     }
+    let (mut child, waiting) = jobs::spawn_waited(&mut child)?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    Ok((
+        child.id() as i32,
+        File::from(OwnedFd::from(stdout)),
+        waiting,
+    ))
 }
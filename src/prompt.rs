@@ -1,80 +1,826 @@
-use reedline::{Prompt, PromptEditMode, PromptHistorySearch, PromptViMode};
-use std::env;
+use nu_ansi_term::Color;
+use reedline::{
+    Prompt, PromptEditMode, PromptHistorySearch, PromptHistorySearchStatus, PromptViMode,
+};
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// What the left prompt shows.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PromptStyle {
+    Path,           // shortened path, `~/p/shesh>`
+    Full,           // whole path, `~/projects/shesh>`
+    Minimal,        // `$ `
+    TwoLine,        // the path style's line, then `❯ ` on the next
+    Custom(String), // `prompt = "..."` from the config, expanded by `expand`
+}
+
+impl PromptStyle {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "path" => Some(Self::Path),
+            "full" => Some(Self::Full),
+            "minimal" => Some(Self::Minimal),
+            "two-line" => Some(Self::TwoLine),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Path => "path",
+            Self::Full => "full",
+            Self::Minimal => "minimal",
+            Self::TwoLine => "two-line",
+            Self::Custom(_) => "custom",
+        }
+    }
+}
+
+// Shared between the prompt and `24! prompt`, so a switch shows up on the
+// very next prompt
+static STYLE: OnceLock<Mutex<PromptStyle>> = OnceLock::new();
+
+fn style_cell() -> &'static Mutex<PromptStyle> {
+    STYLE.get_or_init(|| Mutex::new(PromptStyle::Path))
+}
+
+pub fn style() -> PromptStyle {
+    style_cell().lock().unwrap().clone()
+}
+
+pub fn set_style(style: PromptStyle) {
+    *style_cell().lock().unwrap() = style;
+}
+
+/// How the path style shortens the directories before the last keep_full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shorten {
+    Full,   // `~/projects/shesh`
+    Last,   // `…/shesh`, only the last keep_full directories
+    Letter, // `~/p/shesh`
+    Unique, // `~/pr/shesh`, as much as tells it from its siblings
+}
+
+impl Shorten {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "full" => Some(Self::Full),
+            "last" => Some(Self::Last),
+            "letter" => Some(Self::Letter),
+            "unique" => Some(Self::Unique),
+            _ => None,
+        }
+    }
+}
+
+/// The config's `[prompt]` section.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptConfig {
+    pub format: Option<String>,
+    pub style: Option<String>,
+    // None follows the theme's path color
+    pub color: Option<Color>,
+    pub separator: String,
+    pub suffix: String,
+    // How the path style cuts directories down
+    pub shorten: Shorten,
+    // Trailing directories always shown whole, or the only ones shown
+    pub keep_full: usize,
+    pub home_tilde: bool,
+    // Shown at the right edge; empty for none
+    pub right: String,
+    // Narrower terminals leave the right prompt out
+    pub right_min_width: usize,
+    // The repository's branch after the path, and whether to mark changes
+    pub git: bool,
+    pub git_dirty: bool,
+    // After a failed command the suffix turns red, optionally with the code
+    pub status: bool,
+    pub status_code: bool,
+    // `took 2m14s` after a command that ran at least this long; None for never
+    pub took_min: Option<Duration>,
+    pub took_right: bool,
+    // `user@host` in front over SSH or as root
+    pub user_host: bool,
+    // Which of INDICATORS to look for
+    pub indicators: Vec<String>,
+    // Collapse a prompt to `❯ ` once its line has run
+    pub transient: bool,
+    // In front of the line while searching history
+    pub search: String,
+    // `$(...)` in a prompt is given up on after this, and its output reused
+    // for command_cache
+    pub command_timeout: Duration,
+    pub command_cache: Duration,
+    // Programs printing the whole left or right prompt, like `starship
+    // prompt`; the built-in one is drawn when they fail or take longer than
+    // external_timeout
+    pub command: Option<String>,
+    pub right_command: Option<String>,
+    pub external_timeout: Duration,
+}
+
+impl Default for PromptConfig {
+    fn default() -> Self {
+        Self {
+            format: None,
+            style: None,
+            color: None,
+            separator: "/".into(),
+            suffix: "> ".into(),
+            shorten: Shorten::Letter,
+            keep_full: 1,
+            home_tilde: true,
+            right: String::new(),
+            right_min_width: 0,
+            git: true,
+            git_dirty: true,
+            status: true,
+            status_code: true,
+            took_min: Some(Duration::from_secs(3)),
+            took_right: false,
+            user_host: true,
+            indicators: INDICATORS.iter().map(|name| name.to_string()).collect(),
+            transient: false,
+            search: "search: ".into(),
+            command_timeout: Duration::from_millis(100),
+            command_cache: Duration::from_secs(5),
+            command: None,
+            right_command: None,
+            external_timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Environments and toolchains the path and full styles can show before
+/// the path: a Python virtualenv, a conda env, and the project's .nvmrc or
+/// rust-toolchain file.
+pub const INDICATORS: &[&str] = &["venv", "conda", "node", "rust"];
+
+// `(venv)`, `(node 18)`: environment variables, or a file in `cwd` itself
+// so looking costs a single stat
+fn indicator(name: &str, cwd: &Path, var: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+    let basename = |value: String| {
+        let trimmed = value.trim_end_matches('/');
+        trimmed.rsplit('/').next().unwrap_or(trimmed).to_string()
+    };
+    let first_line = |file: &str| {
+        let content = fs::read_to_string(cwd.join(file)).ok()?;
+        Some(content.lines().next()?.trim().to_string()).filter(|line| !line.is_empty())
+    };
+    let shown = match name {
+        "venv" => basename(var("VIRTUAL_ENV")?),
+        "conda" => basename(var("CONDA_DEFAULT_ENV")?),
+        "node" => format!("node {}", first_line(".nvmrc")?.trim_start_matches('v')),
+        "rust" => {
+            let channel = match fs::read_to_string(cwd.join("rust-toolchain.toml")) {
+                Ok(toml) => toml.lines().find_map(|line| {
+                    let (key, value) = line.split_once('=')?;
+                    (key.trim() == "channel").then(|| value.trim().trim_matches('"').to_string())
+                })?,
+                Err(_) => first_line("rust-toolchain")?,
+            };
+            format!("rust {channel}")
+        }
+        _ => return None,
+    };
+    Some(format!("({shown}) "))
+}
+
+fn indicators(config: &PromptConfig, cwd: &Path) -> String {
+    let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+    config
+        .indicators
+        .iter()
+        .filter_map(|name| indicator(name, cwd, &var))
+        .collect()
+}
 
 pub struct PromptSystem {
-    custom_prompt: Option<String>,
+    config: PromptConfig,
 }
 
 impl PromptSystem {
-    pub fn new(custom_prompt: Option<String>) -> Self {
-        Self { custom_prompt }
+    // A custom format from the config replaces the current style, and a
+    // saved `24! prompt` style wins over both
+    pub fn new(config: PromptConfig) -> Self {
+        if let Some(format) = &config.format {
+            set_style(PromptStyle::Custom(format.clone()));
+        }
+        if let Some(name) = &config.style {
+            match PromptStyle::parse(name) {
+                Some(style) => set_style(style),
+                None => crate::output::warn(&format!("Unknown prompt style '{name}'")),
+            }
+        }
+        Self { config }
     }
 }
 
-impl Prompt for PromptSystem {
-    fn render_prompt_left(&self) -> std::borrow::Cow<'static, str> {
-        if let Some(prompt) = &self.custom_prompt {
-            return std::borrow::Cow::Owned(crate::utils::expand_env_vars(prompt));
+// The shortest start of `name` no other entry of `dir` begins with, like
+// fish abbreviates directories; hidden ones keep their dot
+fn unique_prefix(name: &str, dir: &Path) -> String {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return name.to_string();
+    };
+    let others: Vec<String> = entries
+        .flatten()
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|other| other != name)
+        .collect();
+    let chars: Vec<char> = name.chars().collect();
+    let min = if name.starts_with('.') { 2 } else { 1 };
+    (min..chars.len())
+        .map(|n| chars[..n].iter().collect::<String>())
+        .find(|prefix| {
+            !others
+                .iter()
+                .any(|other| other.starts_with(prefix.as_str()))
+        })
+        .unwrap_or(name.to_string())
+}
+
+// `cwd` as the prompt shows it: `~` for home, directories shortened as
+// configured and joined with the separator
+fn display_path(cwd: &str, home: &str, config: &PromptConfig, shorten: Shorten) -> String {
+    let under_home = !home.is_empty()
+        && cwd
+            .strip_prefix(home)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+    let path = if config.home_tilde && under_home {
+        format!("~{}", &cwd[home.len()..])
+    } else {
+        cwd.to_string()
+    };
+    let root = if path.starts_with('/') {
+        config.separator.as_str()
+    } else {
+        ""
+    };
+
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let keep_from = segments.len().saturating_sub(config.keep_full);
+    if shorten == Shorten::Last && keep_from > 0 {
+        let sep = &config.separator;
+        return format!("…{sep}{}", segments[keep_from..].join(sep));
+    }
+
+    // Where each segment's siblings are, for the unique abbreviation
+    let mut parent = PathBuf::from(if path.starts_with('~') { home } else { "/" });
+    let mut shown = Vec::with_capacity(segments.len());
+    for (i, seg) in segments.iter().enumerate() {
+        let whole = i >= keep_from || *seg == "~";
+        shown.push(match shorten {
+            _ if whole => seg.to_string(),
+            Shorten::Full | Shorten::Last => seg.to_string(),
+            // Hidden directories keep their dot
+            Shorten::Letter => {
+                let take = if seg.starts_with('.') { 2 } else { 1 };
+                seg.chars().take(take).collect()
+            }
+            Shorten::Unique => unique_prefix(seg, &parent),
+        });
+        if *seg != "~" {
+            parent.push(seg);
+        }
+    }
+    format!("{root}{}", shown.join(&config.separator))
+}
+
+// What the % placeholders stand for, gathered once per prompt
+struct PromptEnv {
+    user: String,
+    host: String,
+    cwd: String,
+    home: String,
+    time: String,
+    jobs: usize,
+    status: i32,
+}
+
+impl PromptEnv {
+    fn current() -> Self {
+        Self {
+            user: user_name(),
+            host: host_name(),
+            cwd: env::current_dir()
+                .map(|p| p.display().to_string())
+                .unwrap_or("no path".into()),
+            home: env::var("HOME").unwrap_or_default(),
+            time: clock(),
+            jobs: crate::jobs::list()
+                .iter()
+                .filter(|job| !matches!(job.state, crate::jobs::JobState::Done(_)))
+                .count(),
+            status: crate::shell::last_status(),
         }
+    }
+}
 
-        let path = env::current_dir()
-            .ok()
-            .map(|p| p.display().to_string())
-            .unwrap_or("no path".into());
+// $USER, or the password database's name for our uid
+fn user_name() -> String {
+    if let Ok(user) = env::var("USER") {
+        return user;
+    }
+    let pw = unsafe { libc::getpwuid(libc::getuid()) };
+    if pw.is_null() {
+        return String::new();
+    }
+    unsafe { std::ffi::CStr::from_ptr((*pw).pw_name) }
+        .to_string_lossy()
+        .into_owned()
+}
 
-        let homedir = env::var("HOME").unwrap_or_default();
-        let new_path = path.replace(&homedir, "~");
+/// The machine's whole host name, empty if it can't be read.
+pub fn full_host_name() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return String::new();
+    }
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
 
-        let segments: Vec<&str> = new_path.split('/').filter(|s| !s.is_empty()).collect();
-        let len = segments.len();
+// Up to the first dot, like bash's \h
+fn host_name() -> String {
+    full_host_name()
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
 
-        let base_prompt = if segments.is_empty() {
-            if new_path.starts_with('/') {
-                "/> "
-            } else {
-                "> "
+// %u user, %h host, %d shortened and %D whole working directory, %t (or %T)
+// the time, %j running and stopped jobs, %? the last exit status and %% a
+// percent sign. %{...%} is copied as it is, for escape sequences. Anything
+// else after `%` is kept as it is.
+fn expand_placeholders(format: &str, env: &PromptEnv, config: &PromptConfig) -> String {
+    let mut result = String::with_capacity(format.len());
+    let mut rest = format;
+    while let Some(i) = rest.find('%') {
+        result.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        let Some(c) = after.chars().next() else {
+            result.push('%');
+            return result;
+        };
+        match c {
+            'u' => result.push_str(&env.user),
+            'h' => result.push_str(&env.host),
+            'd' => result.push_str(&display_path(&env.cwd, &env.home, config, config.shorten)),
+            'D' => result.push_str(&display_path(&env.cwd, &env.home, config, Shorten::Full)),
+            't' | 'T' => result.push_str(&env.time),
+            'j' => result.push_str(&env.jobs.to_string()),
+            '?' => result.push_str(&env.status.to_string()),
+            '%' => result.push('%'),
+            '{' => match after.find("%}") {
+                Some(end) => {
+                    result.push_str(&after[1..end]);
+                    rest = &after[end + 2..];
+                    continue;
+                }
+                None => result.push_str("%{"),
+            },
+            other => {
+                result.push('%');
+                result.push(other);
             }
-            .to_string()
-        } else {
-            let start = if new_path.starts_with('/') { "/" } else { "" };
-            let shortened = segments
-                .iter()
-                .enumerate()
-                .fold(String::new(), |mut acc, (i, seg)| {
-                    if i > 0 {
-                        acc.push('/');
+        }
+        rest = &after[c.len_utf8()..];
+    }
+    result.push_str(rest);
+    result
+}
+
+// Local wall-clock time, HH:MM:SS
+fn clock() -> String {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+    }
+    format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+// Output of each `$(...)` in a prompt and when it ran
+static SUBSTITUTIONS: OnceLock<Mutex<HashMap<String, (Instant, String)>>> = OnceLock::new();
+
+// The output of `cmd`, run again once the cached one is older than
+// command_cache; empty when it fails or takes too long
+fn substitute(cmd: &str, config: &PromptConfig) -> String {
+    let cache = SUBSTITUTIONS.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some((ran, output)) = cache.lock().unwrap().get(cmd)
+        && ran.elapsed() < config.command_cache
+    {
+        return output.clone();
+    }
+    let output = crate::process_exec::capture_output_within(cmd, config.command_timeout)
+        .map(crate::parse::substitution_text)
+        .unwrap_or_default();
+    cache
+        .lock()
+        .unwrap()
+        .insert(cmd.to_string(), (Instant::now(), output.clone()));
+    output
+}
+
+// What `command` and `right_command` printed for the prompt being drawn,
+// None for a failure; a repaint while typing doesn't run them again
+static EXTERNAL: Mutex<Vec<(String, Option<String>)>> = Mutex::new(Vec::new());
+
+// Called before each prompt, so the programs see the new state
+pub fn forget() {
+    EXTERNAL.lock().unwrap().clear();
+}
+
+// The output of a prompt program, as it is. It runs in the shell's
+// directory with STATUS, CMD_DURATION (in milliseconds) and JOBS set, so
+// `starship prompt --status=$STATUS` works. None when it printed nothing,
+// exited non-zero or ran past external_timeout.
+fn external(cmd: &str, config: &PromptConfig) -> Option<String> {
+    let mut cache = EXTERNAL.lock().unwrap();
+    if let Some((_, output)) = cache.iter().find(|(ran, _)| ran == cmd) {
+        return output.clone();
+    }
+    let env = PromptEnv::current();
+    let took = took_cell()
+        .lock()
+        .unwrap()
+        .map_or(0, |took| took.as_millis());
+    let vars = [
+        ("STATUS", env.status.to_string()),
+        ("CMD_DURATION", took.to_string()),
+        ("JOBS", env.jobs.to_string()),
+    ];
+    let output = crate::process_exec::capture_output_with(cmd, &vars, config.external_timeout)
+        .ok()
+        .map(|out| String::from_utf8_lossy(&out).into_owned())
+        .filter(|out| !out.trim().is_empty());
+    cache.push((cmd.to_string(), output.clone()));
+    output
+}
+
+// $VARS and % placeholders
+fn expand_text(text: &str, config: &PromptConfig) -> String {
+    let text = crate::utils::expand_env_vars(text);
+    if !text.contains('%') {
+        return text;
+    }
+    expand_placeholders(&text, &PromptEnv::current(), config)
+}
+
+/// A prompt format with its $VARS, % placeholders and `$(...)` filled in;
+/// what a command prints is shown as it is. A `\n` that reached us
+/// unescaped, from a single-quoted or shesh.24 prompt, starts a new line too.
+pub fn expand(format: &str, config: &PromptConfig) -> String {
+    let format = format.replace("\\n", "\n");
+    let mut result = String::new();
+    let mut rest = format.as_str();
+    while let Some(start) = rest.find("$(") {
+        let Some(end) = crate::parse::substitution_end(rest, start) else {
+            break;
+        };
+        result.push_str(&expand_text(&rest[..start], config));
+        result.push_str(&substitute(&rest[start + 2..end], config));
+        rest = &rest[end + 1..];
+    }
+    result.push_str(&expand_text(rest, config));
+    result
+}
+
+// Columns of the terminal on stdout, None when it is not one
+fn terminal_width() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    (ok && size.ws_col > 0).then_some(size.ws_col as usize)
+}
+
+pub fn render_right(config: &PromptConfig) -> String {
+    if let Some(output) = config
+        .right_command
+        .as_deref()
+        .and_then(|cmd| external(cmd, config))
+    {
+        return output;
+    }
+    let took = if config.took_right {
+        took_segment(config)
+    } else {
+        String::new()
+    };
+    if (config.right.is_empty() && took.is_empty())
+        || terminal_width().is_some_and(|width| width < config.right_min_width)
+    {
+        return String::new();
+    }
+    format!("{}{took}", expand(&config.right, config))
+        .trim_start()
+        .to_string()
+}
+
+// `✗2`, or `✗INT` for a command killed by a signal
+fn status_mark(status: i32) -> String {
+    match crate::jobs::signal_name(status) {
+        Some(signal) => format!("✗{signal}"),
+        None => format!("✗{status}"),
+    }
+}
+
+// `user@host ` in front of any prompt over SSH or as root, red for root,
+// so it is clear which machine and account a command is going to
+fn user_host_segment(config: &PromptConfig, ssh: bool, root: bool) -> String {
+    if !config.user_host || !(ssh || root) {
+        return String::new();
+    }
+    let color = if root {
+        crate::theme::active().error
+    } else {
+        config.color.unwrap_or(crate::theme::active().path)
+    };
+    format!(
+        "{} ",
+        color.paint(format!("{}@{}", user_name(), host_name()))
+    )
+}
+
+fn over_ssh() -> bool {
+    ["SSH_CONNECTION", "SSH_TTY"]
+        .iter()
+        .any(|var| env::var_os(var).is_some_and(|v| !v.is_empty()))
+}
+
+pub fn render(style: &PromptStyle, config: &PromptConfig) -> String {
+    if let Some(output) = config
+        .command
+        .as_deref()
+        .and_then(|cmd| external(cmd, config))
+    {
+        return output;
+    }
+    let root = unsafe { libc::geteuid() } == 0;
+    format!(
+        "{}{}",
+        user_host_segment(config, over_ssh(), root),
+        render_style(style, config)
+    )
+}
+
+fn render_style(style: &PromptStyle, config: &PromptConfig) -> String {
+    let shorten = match style {
+        PromptStyle::Custom(prompt) => return expand(prompt, config),
+        PromptStyle::Minimal => return "$ ".to_string(),
+        PromptStyle::Full => Shorten::Full,
+        PromptStyle::Path | PromptStyle::TwoLine => config.shorten,
+    };
+    let cwd = env::current_dir().ok();
+    let path = cwd
+        .as_ref()
+        .map(|p| p.display().to_string())
+        .unwrap_or("no path".into());
+    let home = env::var("HOME").unwrap_or_default();
+    let shown = display_path(&path, &home, config, shorten);
+    let indicators = cwd
+        .as_deref()
+        .map(|dir| indicators(config, dir))
+        .unwrap_or_default();
+    let git = cwd
+        .filter(|_| config.git)
+        .and_then(|dir| crate::git::info(&dir, config.git_dirty))
+        .map(|info| format!(" {}", info.segment()))
+        .unwrap_or_default();
+    let took = if config.took_right {
+        String::new()
+    } else {
+        took_segment(config)
+    };
+    // Two lines: everything so far on the first, only `❯ ` where you type
+    let (newline, suffix) = match style {
+        PromptStyle::TwoLine => ("\x1b[0m\n", "❯ "),
+        _ => ("", config.suffix.as_str()),
+    };
+    let theme = crate::theme::active();
+    let color = config.color.unwrap_or(theme.path).prefix().to_string();
+    let status = crate::shell::last_status();
+    if !config.status || status == 0 {
+        return format!("{indicators}{color}{shown}{git}{took}{newline}{color}{suffix}\x1b[0m");
+    }
+    let mark = if config.status_code {
+        format!("{} ", status_mark(status))
+    } else {
+        String::new()
+    };
+    format!(
+        "{indicators}{color}{shown}{git}{took}{newline}{}{suffix}{mark}\x1b[0m",
+        theme.error.prefix()
+    )
+}
+
+// How long the last command line ran, None after a builtin or an empty line
+static TOOK: OnceLock<Mutex<Option<Duration>>> = OnceLock::new();
+
+fn took_cell() -> &'static Mutex<Option<Duration>> {
+    TOOK.get_or_init(|| Mutex::new(None))
+}
+
+pub fn set_took(took: Option<Duration>) {
+    *took_cell().lock().unwrap() = took;
+}
+
+// `850ms`, `4.2s`, `42s`, `2m14s`, `1h3m`
+fn format_took(took: Duration) -> String {
+    let secs = took.as_secs();
+    match secs {
+        0 => format!("{}ms", took.as_millis()),
+        1..10 => format!("{:.1}s", took.as_secs_f64()),
+        10..60 => format!("{secs}s"),
+        60..3600 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+// ` took 2m14s` when the last command was slow enough to mention
+fn took_segment(config: &PromptConfig) -> String {
+    let took = *took_cell().lock().unwrap();
+    match (took, config.took_min) {
+        (Some(took), Some(min)) if took >= min => format!(" took {}", format_took(took)),
+        _ => String::new(),
+    }
+}
+
+/// `24! prompt [path|full|minimal|two-line] [--save] | preview FORMAT`
+pub fn handle_prompt(args: &[&str]) -> io::Result<()> {
+    match args {
+        [] => {
+            println!("Prompt style: {}", style().name());
+            println!("Available: path, full, minimal, two-line");
+            Ok(())
+        }
+        ["preview", format @ ..] if !format.is_empty() => {
+            let preview = render(
+                &PromptStyle::Custom(format.join(" ")),
+                &PromptConfig::default(),
+            );
+            println!("{preview}");
+            Ok(())
+        }
+        [name] | [name, "--save"] => {
+            let new = PromptStyle::parse(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("24! prompt: unknown style '{name}' (path, full, minimal, two-line)"),
+                )
+            })?;
+            set_style(new);
+            if args.len() == 2 {
+                crate::config::set_option(
+                    "prompt",
+                    "style",
+                    crate::config::Value::Str(name.to_string()),
+                )?;
+            }
+            Ok(())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: 24! prompt [path|full|minimal|two-line] [--save] | preview format",
+        )),
+    }
+}
+
+// Columns the prompt and its vi indicator take on the row the line starts
+// on, as last drawn
+static LEFT_WIDTH: AtomicUsize = AtomicUsize::new(0);
+static INDICATOR_WIDTH: AtomicUsize = AtomicUsize::new(0);
+
+// Characters on the last row of `text`, skipping escape sequences
+fn visible_width(text: &str) -> usize {
+    let last = text.rsplit('\n').next().unwrap_or_default();
+    let mut width = 0;
+    let mut chars = last.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI runs to a final byte, OSC to BEL or ST
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
                     }
-                    if i == len - 1 {
-                        acc.push_str(seg);
-                    } else if seg.starts_with('.') {
-                        acc.push_str(&seg[..2]);
-                    } else {
-                        acc.push(seg.chars().next().unwrap_or(' '));
+                }
+                Some(']') => {
+                    for c in chars.by_ref() {
+                        if c == '\x07' || c == '\\' {
+                            break;
+                        }
                     }
-                    acc
-                });
-            format!("\x1b[32m{start}{shortened}>\x1b[0m ")
-        };
+                }
+                _ => {}
+            },
+            c if !c.is_control() => width += 1,
+            _ => {}
+        }
+    }
+    width
+}
 
-        std::borrow::Cow::Owned(base_prompt)
+/// What Ctrl-C prints to leave an aborted line on screen: reedline has
+/// already moved to the row below it, so this steps back up to the end of
+/// `line` after the prompt, clears the grey hint and any menu under it, and
+/// writes `^C`. None when that end can't be found from the widths alone.
+pub fn abort_mark(line: &str) -> Option<String> {
+    if line.contains('\n') {
+        return None;
+    }
+    let end = LEFT_WIDTH.load(Ordering::Relaxed)
+        + INDICATOR_WIDTH.load(Ordering::Relaxed)
+        + line.chars().count();
+    let column = end % terminal_width()?;
+    // A line filling its last row leaves the cursor past the edge
+    if column == 0 && end > 0 {
+        return None;
+    }
+    Some(format!("\x1b[A\x1b[{}G\x1b[J^C\r\n", column + 1))
+}
+
+impl Prompt for PromptSystem {
+    fn render_prompt_left(&self) -> std::borrow::Cow<'static, str> {
+        let left = render(&style(), &self.config);
+        LEFT_WIDTH.store(visible_width(&left), Ordering::Relaxed);
+        std::borrow::Cow::Owned(crate::integration::prompt_start() + &left)
     }
 
     fn render_prompt_right(&self) -> std::borrow::Cow<'static, str> {
-        std::borrow::Cow::Borrowed("")
+        std::borrow::Cow::Owned(render_right(&self.config))
     }
 
     fn render_prompt_indicator(&self, edit_mode: PromptEditMode) -> std::borrow::Cow<'static, str> {
-        match edit_mode {
+        let indicator = match edit_mode {
             PromptEditMode::Vi(PromptViMode::Normal) => {
                 print!("\x1b[0 q"); // Reset cursor to default shape
-                std::borrow::Cow::Borrowed("\x1b[33m[N]\x1b[0m ")
+                let color = crate::theme::active().vi_normal;
+                std::borrow::Cow::Owned(format!("{} ", color.paint("[N]")))
             }
             PromptEditMode::Vi(PromptViMode::Insert) => {
                 print!("\x1b[6 q"); // Vertical cursor shape (|) for Insert mode
-                std::borrow::Cow::Borrowed("\x1b[32m[I]\x1b[0m ")
+                let color = crate::theme::active().vi_insert;
+                std::borrow::Cow::Owned(format!("{} ", color.paint("[I]")))
             }
             _ => std::borrow::Cow::Borrowed(""), // No cursor shape change
-        }
+        };
+        INDICATOR_WIDTH.store(visible_width(&indicator), Ordering::Relaxed);
+        std::borrow::Cow::Owned(indicator.into_owned() + &crate::integration::prompt_end())
+    }
+
+    fn render_prompt_multiline_indicator(&self) -> std::borrow::Cow<'static, str> {
+        std::borrow::Cow::Borrowed("::: ")
+    }
+
+    fn render_prompt_history_search_indicator(
+        &self,
+        history_search: PromptHistorySearch,
+    ) -> std::borrow::Cow<'static, str> {
+        std::borrow::Cow::Owned(search_indicator(&self.config.search, &history_search))
+    }
+}
+
+// `search: 'git' ` before the match reedline's incremental search found,
+// `failing search: 'gti' ` once nothing matches
+fn search_indicator(search: &str, history_search: &PromptHistorySearch) -> String {
+    let failing = match history_search.status {
+        PromptHistorySearchStatus::Failing => "failing ",
+        PromptHistorySearchStatus::Passing => "",
+    };
+    format!("{failing}{search}'{}' ", history_search.term)
+}
+
+/// What a prompt is redrawn as after its line is submitted, so the
+/// scrollback shows `❯ command` instead of every segment.
+pub struct TransientPrompt;
+
+impl Prompt for TransientPrompt {
+    fn render_prompt_left(&self) -> std::borrow::Cow<'static, str> {
+        std::borrow::Cow::Owned(crate::integration::prompt_start() + "❯ ")
+    }
+
+    fn render_prompt_right(&self) -> std::borrow::Cow<'static, str> {
+        std::borrow::Cow::Borrowed("")
+    }
+
+    fn render_prompt_indicator(
+        &self,
+        _edit_mode: PromptEditMode,
+    ) -> std::borrow::Cow<'static, str> {
+        std::borrow::Cow::Owned(crate::integration::prompt_end())
     }
 
     fn render_prompt_multiline_indicator(&self) -> std::borrow::Cow<'static, str> {
@@ -85,6 +831,257 @@ impl Prompt for PromptSystem {
         &self,
         _history_search: PromptHistorySearch,
     ) -> std::borrow::Cow<'static, str> {
-        std::borrow::Cow::Borrowed("⭠ ")
+        std::borrow::Cow::Borrowed("")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_styles() {
+        let config = PromptConfig {
+            user_host: false,
+            ..PromptConfig::default()
+        };
+        assert_eq!(render(&PromptStyle::Minimal, &config), "$ ");
+        assert_eq!(render(&PromptStyle::Custom("x> ".into()), &config), "x> ");
+        assert_eq!(PromptStyle::parse("full"), Some(PromptStyle::Full));
+        assert_eq!(PromptStyle::parse("fancy"), None);
+        assert_eq!(PromptStyle::parse("two-line"), Some(PromptStyle::TwoLine));
+
+        let two_line = render(&PromptStyle::TwoLine, &config);
+        let (info, input) = two_line.split_once('\n').unwrap();
+        assert!(!info.is_empty());
+        assert!(input.contains("❯ ") && !input.contains('/'), "{input}");
+        assert_eq!(
+            render(&PromptStyle::Custom("top\\nx> ".into()), &config),
+            "top\nx> "
+        );
+    }
+
+    #[test]
+    fn test_command_substitution() {
+        let config = PromptConfig::default();
+        assert_eq!(expand("[$(echo 50%d)] %%", &config), "[50%d] %");
+        assert_eq!(expand("<$(exit 3)>", &config), "<>");
+
+        let slow = PromptConfig {
+            command_timeout: Duration::from_millis(50),
+            ..PromptConfig::default()
+        };
+        let start = Instant::now();
+        assert_eq!(expand("$(sleep 5; echo late)", &slow), "");
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_format_took() {
+        assert_eq!(format_took(Duration::from_millis(850)), "850ms");
+        assert_eq!(format_took(Duration::from_millis(4230)), "4.2s");
+        assert_eq!(format_took(Duration::from_secs(42)), "42s");
+        assert_eq!(format_took(Duration::from_secs(134)), "2m14s");
+        assert_eq!(format_took(Duration::from_secs(3780)), "1h3m");
+
+        let config = PromptConfig::default();
+        set_took(Some(Duration::from_secs(1)));
+        assert_eq!(took_segment(&config), "");
+        set_took(Some(Duration::from_secs(5)));
+        assert_eq!(took_segment(&config), " took 5.0s");
+        set_took(None);
+        assert_eq!(took_segment(&config), "");
+    }
+
+    #[test]
+    fn test_indicators() {
+        let dir = TempDir::new("indicators");
+        let var = |name: &str| match name {
+            "VIRTUAL_ENV" => Some("/home/me/project/.venv/".to_string()),
+            _ => None,
+        };
+        assert_eq!(indicator("venv", &dir, &var).as_deref(), Some("(.venv) "));
+        assert_eq!(indicator("conda", &dir, &var), None);
+        assert_eq!(indicator("node", &dir, &var), None);
+
+        fs::write(dir.join(".nvmrc"), "v18.2.0\n").unwrap();
+        assert_eq!(
+            indicator("node", &dir, &var).as_deref(),
+            Some("(node 18.2.0) ")
+        );
+        fs::write(dir.join("rust-toolchain"), "stable\n").unwrap();
+        assert_eq!(
+            indicator("rust", &dir, &var).as_deref(),
+            Some("(rust stable) ")
+        );
+        fs::write(
+            dir.join("rust-toolchain.toml"),
+            "[toolchain]\nchannel = \"nightly\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            indicator("rust", &dir, &var).as_deref(),
+            Some("(rust nightly) ")
+        );
+    }
+
+    #[test]
+    fn test_search_indicator() {
+        let mut search = PromptHistorySearch {
+            status: PromptHistorySearchStatus::Passing,
+            term: "git".into(),
+        };
+        assert_eq!(search_indicator("search: ", &search), "search: 'git' ");
+        search.status = PromptHistorySearchStatus::Failing;
+        assert_eq!(search_indicator("? ", &search), "failing ? 'git' ");
+    }
+
+    #[test]
+    fn test_user_host_segment() {
+        let config = PromptConfig::default();
+        assert_eq!(user_host_segment(&config, false, false), "");
+        let ssh = user_host_segment(&config, true, false);
+        assert!(ssh.contains(&format!("{}@{}", user_name(), host_name())));
+        let root = user_host_segment(&config, false, true);
+        assert!(root.starts_with(&crate::theme::active().error.prefix().to_string()));
+
+        let off = PromptConfig {
+            user_host: false,
+            ..PromptConfig::default()
+        };
+        assert_eq!(user_host_segment(&off, true, true), "");
+    }
+
+    #[test]
+    fn test_visible_width() {
+        assert_eq!(visible_width("~/src> "), 7);
+        assert_eq!(
+            visible_width(&Color::Green.bold().paint("❯ ").to_string()),
+            2
+        );
+        assert_eq!(visible_width("~/p/shesh on main\n❯ "), 2);
+        assert_eq!(visible_width("\x1b]0;title\x07$ "), 2);
+    }
+
+    #[test]
+    fn test_status_mark() {
+        assert_eq!(status_mark(2), "✗2");
+        assert_eq!(status_mark(130), "✗INT");
+        assert_eq!(status_mark(137), "✗KILL");
+        assert_eq!(status_mark(200), "✗200");
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let env = PromptEnv {
+            user: "me".into(),
+            host: "box".into(),
+            cwd: "/home/me/projects/shesh".into(),
+            home: "/home/me".into(),
+            time: "09:05:00".into(),
+            jobs: 2,
+            status: 127,
+        };
+        let config = PromptConfig::default();
+        let expand = |format| expand_placeholders(format, &env, &config);
+        assert_eq!(expand("%u@%h:%d%% "), "me@box:~/p/shesh% ");
+        assert_eq!(
+            expand("%D [%j] %t/%T %?"),
+            "~/projects/shesh [2] 09:05:00/09:05:00 127"
+        );
+        assert_eq!(expand("%{\x1b[1m%}%u%{\x1b[0m%}"), "\x1b[1mme\x1b[0m");
+        // Unknown or unfinished placeholders stay as they are
+        assert_eq!(expand("%x %é %{ 100%"), "%x %é %{ 100%");
+        assert_eq!(clock().len(), 8);
+
+        // The right prompt is off unless configured
+        assert_eq!(render_right(&PromptConfig::default()), "");
+        let hidden = PromptConfig {
+            right: "%T".into(),
+            right_min_width: usize::MAX,
+            ..PromptConfig::default()
+        };
+        if terminal_width().is_some() {
+            assert_eq!(render_right(&hidden), "");
+        }
+    }
+
+    #[test]
+    fn test_display_path() {
+        let home = "/home/me";
+        let path = |cwd, config: &PromptConfig| display_path(cwd, home, config, config.shorten);
+        // Character-wise, so multibyte and one-letter hidden names are fine
+        assert_eq!(
+            path("/home/me/.x/ünï/.élan/z", &PromptConfig::default()),
+            "~/.x/ü/.é/z"
+        );
+        let default = PromptConfig::default();
+        assert_eq!(
+            path("/home/me/projects/.config/shesh", &default),
+            "~/p/.c/shesh"
+        );
+        assert_eq!(path("/usr/local/bin", &default), "/u/l/bin");
+        assert_eq!(path("/home/me", &default), "~");
+        assert_eq!(path("/", &default), "/");
+        // Only a whole directory name counts as home
+        assert_eq!(path("/home/mead/x", &default), "/h/m/x");
+
+        let custom = PromptConfig {
+            separator: " > ".into(),
+            keep_full: 2,
+            home_tilde: false,
+            ..PromptConfig::default()
+        };
+        assert_eq!(
+            path("/home/me/projects/shesh", &custom),
+            " > h > m > projects > shesh"
+        );
+
+        let whole = PromptConfig {
+            shorten: Shorten::Full,
+            ..PromptConfig::default()
+        };
+        assert_eq!(path("/home/me/projects/shesh", &whole), "~/projects/shesh");
+        let last = PromptConfig {
+            shorten: Shorten::Last,
+            keep_full: 2,
+            ..PromptConfig::default()
+        };
+        assert_eq!(
+            path("/etc/nginx/sites/available", &last),
+            "…/sites/available"
+        );
+        assert_eq!(path("/home/me/shesh", &last), "~/shesh");
+    }
+
+    #[test]
+    fn test_unique_prefix() {
+        let dir = TempDir::new("unique");
+        for name in ["projects", "private", "music", ".config", ".cache", "src/x"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        assert_eq!(unique_prefix("projects", &dir), "pro");
+        assert_eq!(unique_prefix("music", &dir), "m");
+        assert_eq!(unique_prefix(".config", &dir), ".co");
+        assert_eq!(unique_prefix("gone", &dir.join("missing")), "gone");
+
+        let config = PromptConfig {
+            shorten: Shorten::Unique,
+            home_tilde: false,
+            ..PromptConfig::default()
+        };
+        let cwd = dir.join("projects/x");
+        let shown = display_path(&cwd.display().to_string(), "", &config, config.shorten);
+        assert!(shown.ends_with("/pro/x"), "{shown}");
+    }
+
+    #[test]
+    fn test_external_prompt() {
+        let config = PromptConfig::default();
+        assert_eq!(external("echo '> '", &config).as_deref(), Some("> \n"));
+        // A failing program's output is not a prompt
+        assert_eq!(external("echo 'error: no config'; exit 2", &config), None);
+        assert_eq!(external("printf ''", &config), None);
     }
 }
@@ -0,0 +1,160 @@
+use crate::process_exec::capture_output_within;
+use std::{env, time::Duration};
+
+// Git answers from its own files in a few milliseconds; a repository on a
+// slow mount still can't hold the Tab up for longer than this
+const GIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A candidate with what the menu says about it.
+pub type Candidate = (String, String);
+
+// Arguments for one command, computed at each Tab from the words typed after
+// the command (not the one being completed). None leaves the word to the
+// usual completion.
+type Provider = fn(args: &[&str]) -> Option<Vec<Candidate>>;
+
+// Built-in providers by command; a tool that needs more than its `--help`
+// gets an entry here
+const PROVIDERS: &[(&str, Provider)] = &[("git", git)];
+
+/// What the built-in provider for `cmd` offers, if there is one and it
+/// applies to this position.
+pub fn complete(cmd: &str, args: &[&str]) -> Option<Vec<Candidate>> {
+    let (_, provider) = PROVIDERS.iter().find(|(name, _)| *name == cmd)?;
+    provider(args)
+}
+
+// The output of a git command, None when it fails or takes too long
+fn run_git(args: &str) -> Option<String> {
+    let output = capture_output_within(&format!("git {args}"), GIT_TIMEOUT).ok()?;
+    Some(String::from_utf8_lossy(&output).into_owned())
+}
+
+// The subcommand and the words after it; `-C dir` and `-c key=value` take a
+// value of their own
+fn subcommand<'a>(args: &[&'a str]) -> Option<(&'a str, Vec<&'a str>)> {
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        match *arg {
+            "-C" | "-c" => i += 2,
+            arg if arg.starts_with('-') => i += 1,
+            sub => return Some((sub, args[i + 1..].to_vec())),
+        }
+    }
+    None
+}
+
+fn git(args: &[&str]) -> Option<Vec<Candidate>> {
+    let (sub, rest) = subcommand(args)?;
+    // Past `--` everything is a path
+    if rest.contains(&"--") {
+        return None;
+    }
+    crate::git::git_dir(&env::current_dir().ok()?)?;
+    let positional = rest.iter().filter(|arg| !arg.starts_with('-')).count();
+    let candidates = match sub {
+        "checkout" | "switch" | "merge" | "rebase" | "branch" | "log" | "cherry-pick" => refs(
+            &run_git("for-each-ref --format='%(refname)' refs/heads refs/remotes refs/tags")?,
+        ),
+        "add" | "restore" => changed_files(&run_git("status --short")?),
+        "push" | "pull" | "fetch" if positional == 0 => run_git("remote")?
+            .lines()
+            .map(|remote| (remote.to_string(), "remote".to_string()))
+            .collect(),
+        "push" | "pull" => refs(&run_git("for-each-ref --format='%(refname)' refs/heads")?),
+        _ => return None,
+    };
+    (!candidates.is_empty()).then_some(candidates)
+}
+
+// Full ref names, one per line, as short names with their kind
+fn refs(output: &str) -> Vec<Candidate> {
+    output
+        .lines()
+        .filter(|line| !line.ends_with("/HEAD"))
+        .filter_map(|line| {
+            let kinds = [
+                ("refs/heads/", "branch"),
+                ("refs/remotes/", "remote branch"),
+                ("refs/tags/", "tag"),
+            ];
+            kinds.iter().find_map(|(prefix, kind)| {
+                Some((line.strip_prefix(prefix)?.to_string(), kind.to_string()))
+            })
+        })
+        .collect()
+}
+
+// `git status --short` lines, `XY path` or `XY old -> new`, relative to the
+// current directory. Quoted names (unusual characters) are left to file
+// completion.
+fn changed_files(output: &str) -> Vec<Candidate> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (status, path) = (line.get(..2)?, line.get(3..)?);
+            let path = path.rsplit(" -> ").next().unwrap_or(path);
+            if path.starts_with('"') {
+                return None;
+            }
+            let kind = match status.trim() {
+                "??" => "untracked",
+                s if s.contains('D') => "deleted",
+                s if s.contains('R') => "renamed",
+                s if s.contains('A') => "added",
+                s if s.contains('U') => "conflict",
+                _ => "modified",
+            };
+            Some((path.to_string(), kind.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_git_provider() {
+        assert_eq!(
+            subcommand(&["-C", "repo", "--no-pager", "push", "origin"]),
+            Some(("push", vec!["origin"]))
+        );
+        assert_eq!(subcommand(&["--version"]), None);
+
+        let refs = refs(
+            "refs/heads/main\nrefs/heads/feature/x\nrefs/remotes/origin/HEAD\n\
+             refs/remotes/origin/main\nrefs/tags/v1.0\n",
+        );
+        assert_eq!(
+            refs,
+            [
+                ("main".into(), "branch".into()),
+                ("feature/x".into(), "branch".into()),
+                ("origin/main".into(), "remote branch".into()),
+                ("v1.0".into(), "tag".into()),
+            ]
+        );
+
+        let files = changed_files(
+            " M src/main.rs\n?? notes.txt\nR  old.rs -> new.rs\nA  lib.rs\n\
+             ?? \"odd\\tname\"\n",
+        );
+        let kinds: Vec<(&str, &str)> = files
+            .iter()
+            .map(|(path, kind)| (path.as_str(), kind.as_str()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("src/main.rs", "modified"),
+                ("notes.txt", "untracked"),
+                ("new.rs", "renamed"),
+                ("lib.rs", "added"),
+            ]
+        );
+
+        assert_eq!(complete("hg", &["checkout"]), None);
+        assert_eq!(git(&["checkout", "--", "x"]), None);
+    }
+}
@@ -1,12 +1,212 @@
 use crate::{
-    builtins::{
-        cd, execute_external, expand_aliases, handle_24_command, handle_alias, handle_export_cmd,
-        help,
-    },
+    builtins::{execute_external, expand_aliases, find_builtin},
+    history, jobs,
     parse::{Operator, ParsedCommand, parse_syntax, process_tokens},
     process_exec::{flatten_pipes, handle_redirect, run_background, run_pipe},
 };
-use std::io;
+use std::{
+    io,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicI32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+// Exit status of the last command, what `$?` expands to
+static LAST_STATUS: AtomicI32 = AtomicI32::new(0);
+
+pub fn last_status() -> i32 {
+    LAST_STATUS.load(Ordering::Relaxed)
+}
+
+pub fn set_last_status(code: i32) {
+    LAST_STATUS.store(code, Ordering::Relaxed);
+}
+
+/// `set -e`, `set -u` and `set -x`, off until a script or the prompt turns
+/// them on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    // A failing command stops the script or startup file it's in
+    pub errexit: bool,
+    // Expanding an unset variable is an error
+    pub nounset: bool,
+    // Each command is printed to stderr after expansion
+    pub xtrace: bool,
+}
+
+static OPTIONS: Mutex<Options> = Mutex::new(Options {
+    errexit: false,
+    nounset: false,
+    xtrace: false,
+});
+
+pub fn options() -> Options {
+    *OPTIONS.lock().unwrap()
+}
+
+/// Names for `set -o`, with their state.
+pub fn settings() -> [(&'static str, bool); 3] {
+    let options = options();
+    [
+        ("errexit", options.errexit),
+        ("nounset", options.nounset),
+        ("xtrace", options.xtrace),
+    ]
+}
+
+/// `set -o name` or `set -e`-style letters; false for an unknown option.
+pub fn set_option(name: &str, on: bool) -> bool {
+    let mut options = OPTIONS.lock().unwrap();
+    let option = match name {
+        "errexit" | "e" => &mut options.errexit,
+        "nounset" | "u" => &mut options.nounset,
+        "xtrace" | "x" => &mut options.xtrace,
+        _ => return false,
+    };
+    *option = on;
+    true
+}
+
+// The last status came from the left side of `&&`, which `set -e` lets fail
+static TESTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether `set -e` should stop at the last command: it failed outside the
+/// left side of `&&` or `||`.
+pub fn errexit_failed() -> bool {
+    options().errexit && last_status() != 0 && !TESTED.load(Ordering::Relaxed)
+}
+
+/// `set -x`: `+ cmd args` on stderr.
+pub fn trace(args: &[String]) {
+    if options().xtrace {
+        eprintln!("{}", traced(args));
+    }
+}
+
+// Arguments are quoted when they wouldn't come back as one word
+fn traced(args: &[String]) -> String {
+    let quoted: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            if !arg.is_empty()
+                && !arg.contains(|c: char| c.is_whitespace() || "'\"$;|&<>".contains(c))
+            {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect();
+    format!("+ {}", quoted.join(" "))
+}
+
+// $1..$n, set while a sourced file runs
+static POSITIONAL: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+
+fn positional_args() -> &'static Mutex<Vec<String>> {
+    POSITIONAL.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+// $0: the script's path when shesh runs one, otherwise the shell's name
+static SCRIPT_NAME: OnceLock<String> = OnceLock::new();
+
+pub fn set_script_name(name: String) {
+    let _ = SCRIPT_NAME.set(name);
+}
+
+// `$n`; $0 is always set
+pub fn positional(n: usize) -> Option<String> {
+    let Some(i) = n.checked_sub(1) else {
+        return Some(
+            SCRIPT_NAME
+                .get()
+                .map_or("shesh", String::as_str)
+                .to_string(),
+        );
+    };
+    positional_args().lock().unwrap().get(i).cloned()
+}
+
+pub fn positional_count() -> usize {
+    positional_args().lock().unwrap().len()
+}
+
+// $1..$n, what `$@` and `$*` expand to
+pub fn positional_all() -> Vec<String> {
+    positional_args().lock().unwrap().clone()
+}
+
+// `shift n`: $n+1 becomes $1; false when there are fewer than n
+pub fn shift_positional(n: usize) -> bool {
+    let mut args = positional_args().lock().unwrap();
+    if n > args.len() {
+        return false;
+    }
+    args.drain(..n);
+    true
+}
+
+// Runs `f` with `args` as $1..$n, then puts the previous ones back
+pub fn with_positional<T>(args: Vec<String>, f: impl FnOnce() -> T) -> T {
+    let saved = std::mem::replace(&mut *positional_args().lock().unwrap(), args);
+    let result = f();
+    *positional_args().lock().unwrap() = saved;
+    result
+}
+
+/// What `shesh --subshell STATE` takes over from this shell besides the
+/// environment: `$?`, the `set` options and the aliases, one per line.
+pub fn subshell_state() -> String {
+    format_state(last_status(), options(), &crate::builtins::alias_list())
+}
+
+fn format_state(status: i32, options: Options, aliases: &[(String, String)]) -> String {
+    let letters = [
+        (options.errexit, 'e'),
+        (options.nounset, 'u'),
+        (options.xtrace, 'x'),
+    ];
+    let mut state = format!("{status}\n");
+    state.extend(
+        letters
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, letter)| letter),
+    );
+    state.push('\n');
+    // An alias spanning lines can't be told from the next one, and stays
+    // behind
+    for (name, value) in aliases.iter().filter(|(_, value)| !value.contains('\n')) {
+        state.push_str(&format!("{name}={value}\n"));
+    }
+    state
+}
+
+/// In a subshell: applies the state its parent passed.
+pub fn restore_subshell_state(state: &str) {
+    let mut lines = state.lines();
+    if let Some(status) = lines.next().and_then(|line| line.parse().ok()) {
+        set_last_status(status);
+    }
+    for letter in lines.next().unwrap_or_default().chars() {
+        set_option(&letter.to_string(), true);
+    }
+    for (name, value) in lines.filter_map(|line| line.split_once('=')) {
+        crate::builtins::set_alias(name, value);
+    }
+}
+
+/// The one way out of the shell, for `exit` and Ctrl-D alike: writes
+/// pending history, hangs up the remaining jobs and exits with `code`.
+pub fn shutdown(code: i32) -> ! {
+    if let Err(e) = history::sync() {
+        crate::output::fail(&format!("Failed to write to history file: {e}"));
+    }
+    jobs::hang_up();
+    std::process::exit(code)
+}
 
 // Main execution entry point
 pub fn exec(cmd: &str) -> io::Result<()> {
@@ -19,33 +219,85 @@ pub fn exec(cmd: &str) -> io::Result<()> {
     run(command)
 }
 
+// Removes a leading `time` keyword from the pipeline's first command. Only
+// pipes and redirects are looked through, so `time a && b` times just `a`.
+fn strip_time(cmd: &mut ParsedCommand) -> bool {
+    match cmd {
+        ParsedCommand::Single(args) if args.first().is_some_and(|a| a == "time") => {
+            args.remove(0);
+            true
+        }
+        ParsedCommand::BinaryOp(left, Operator::Pipe | Operator::Redirect(_), _) => {
+            strip_time(left)
+        }
+        _ => false,
+    }
+}
+
+// User and system CPU time of all waited-for children so far
+fn children_cpu() -> (Duration, Duration) {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage);
+    }
+    let to_duration = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    (to_duration(usage.ru_utime), to_duration(usage.ru_stime))
+}
+
+fn format_time(d: Duration) -> String {
+    let secs = d.as_secs_f64();
+    format!("{}m{:.3}s", (secs / 60.0) as u64, secs % 60.0)
+}
+
+// Runs `cmd` and reports real/user/sys times on stderr like bash; the
+// command's own exit status is left as the status of the whole thing
+fn run_timed(cmd: ParsedCommand) -> io::Result<()> {
+    let (user_before, sys_before) = children_cpu();
+    let start = Instant::now();
+    let result = run(cmd);
+    let real = start.elapsed();
+    let (user_after, sys_after) = children_cpu();
+
+    eprintln!();
+    eprintln!("real\t{}", format_time(real));
+    eprintln!(
+        "user\t{}",
+        format_time(user_after.saturating_sub(user_before))
+    );
+    eprintln!("sys\t{}", format_time(sys_after.saturating_sub(sys_before)));
+    result
+}
+
 // Executes commands based on their parsed structure
-pub fn run(cmd: ParsedCommand) -> io::Result<()> {
+pub fn run(mut cmd: ParsedCommand) -> io::Result<()> {
+    TESTED.store(false, Ordering::Relaxed);
+    if strip_time(&mut cmd) {
+        return run_timed(cmd);
+    }
+
     match cmd {
         ParsedCommand::Single(args) => {
             if args.is_empty() {
                 return Ok(());
             }
 
-            let str_args: Vec<String> = process_tokens(ParsedCommand::Single(args));
-            let cmd = str_args[0].as_str();
+            let str_args: Vec<String> = process_tokens(ParsedCommand::Single(args))?;
+            let Some(cmd) = str_args.first() else {
+                return Ok(());
+            };
+            trace(&str_args);
             let rest: Vec<&str> = str_args[1..].iter().map(|s| s.as_str()).collect();
 
-            match cmd {
-                "24!" => handle_24_command(&rest),
-                "alias" => handle_alias(&str_args[1..].join(" ")),
-                "cd" => cd(&rest),
-                "exit" => std::process::exit(0),
-                "export" => {
-                    let rest_str: Vec<String> = rest.iter().map(|&s| s.to_string()).collect();
-                    handle_export_cmd(&rest_str)
-                }
-                "help" => {
-                    println!("{}", help());
-                    Ok(())
-                }
-                _ => execute_external(cmd, &rest),
+            // External commands record their own exit status
+            let Some(builtin) = find_builtin(cmd) else {
+                return execute_external(cmd, &rest);
+            };
+            let result = (builtin.run)(&rest);
+            if builtin.sets_status {
+                return result;
             }
+            set_last_status(if result.is_ok() { 0 } else { 1 });
+            result
         }
 
         // Compound commands with operators (e.g., "cmd1 && cmd2")
@@ -54,22 +306,33 @@ pub fn run(cmd: ParsedCommand) -> io::Result<()> {
                 // Sequential execution (;)
                 Operator::Seq => {
                     // Execute left command, then right regardless of result
-                    run(*left)?;
+                    if let Err(e) = run(*left) {
+                        eprintln!("{e}");
+                    }
+                    // `set -e` stops the line here too, the caller stops the file
+                    if errexit_failed() {
+                        return Ok(());
+                    }
                     run(*right)
                 }
                 // Logical AND (&&)
                 Operator::And => {
-                    // Only execute right if left succeeds
-                    if run(*left).is_ok() {
+                    // Only execute right if left exits with status 0
+                    let left = run(*left);
+                    if left.is_ok() && last_status() == 0 {
                         run(*right)
                     } else {
-                        Ok(())
+                        TESTED.store(true, Ordering::Relaxed);
+                        left
                     }
                 }
                 // Logical OR (||)
                 Operator::Or => {
                     // Only execute right if left fails
-                    if run(*left).is_err() {
+                    if let Err(e) = run(*left) {
+                        eprintln!("{e}");
+                    }
+                    if last_status() != 0 {
                         run(*right)
                     } else {
                         Ok(())
@@ -79,9 +342,63 @@ pub fn run(cmd: ParsedCommand) -> io::Result<()> {
                     let commands = flatten_pipes(vec![*left, *right]);
                     run_pipe(commands)
                 }
-                Operator::Background => run_background(*left),
+                // Start the left side as a job, then go on with the rest of the line
+                Operator::Background => {
+                    run_background(*left, false)?;
+                    run(*right)
+                }
+                Operator::QuietBackground => {
+                    run_background(*left, true)?;
+                    run(*right)
+                }
                 Operator::Redirect(redirect_type) => handle_redirect(*left, redirect_type, *right),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_time_keeps_status() {
+        let mut piped = parse_syntax("time echo a | wc -l > /dev/null");
+        assert!(strip_time(&mut piped));
+        assert_eq!(piped.to_string(), "echo a | wc -l > /dev/null");
+
+        let mut chained = parse_syntax("true && time false");
+        assert!(!strip_time(&mut chained));
+
+        // The timed command's status decides what runs next
+        let dir = TempDir::new("time");
+        let out = dir.join("out");
+        exec(&format!("time false || echo failed > {}", out.display())).unwrap();
+        exec(&format!("time true || echo wrong >> {}", out.display())).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "failed\n");
+    }
+
+    #[test]
+    fn test_set_options() {
+        let args = ["echo", "a b", "", "it's", "$HOME"].map(String::from);
+        assert_eq!(traced(&args), r#"+ echo 'a b' '' 'it'\''s' '$HOME'"#);
+        assert!(!set_option("noclobber", true));
+        assert!(!options().errexit);
+    }
+
+    #[test]
+    fn test_subshell_state() {
+        let options = Options {
+            errexit: true,
+            nounset: false,
+            xtrace: true,
+        };
+        let aliases = [
+            ("ll".to_string(), "ls -la".to_string()),
+            ("two".to_string(), "echo a\necho b".to_string()),
+        ];
+        assert_eq!(format_state(3, options, &aliases), "3\nex\nll=ls -la\n");
+        assert_eq!(format_state(0, Options::default(), &[]), "0\n\n");
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{
+    config::{Value, read_table},
+    process_exec::capture_output_within,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+// A `dynamic` command still running after this gives no candidates
+const DYNAMIC_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Completions for one command, written by hand in
+/// `~/.config/shesh/completions/*.toml`:
+///
+/// ```toml
+/// command = "mytool"
+/// subcommands = ["build", "deploy"]
+/// flags = ["--verbose", "--target=TRIPLE"]  # a hint after `=`
+/// dynamic = "mytool list-projects"          # more first arguments
+///
+/// [arguments]
+/// deploy = "mytool list-envs"               # what follows `deploy`
+///
+/// [descriptions]
+/// build = "Compile the project"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Spec {
+    pub command: String,
+    pub subcommands: Vec<String>,
+    // `--target=`, with "TRIPLE" as its description unless one is given
+    pub flags: Vec<String>,
+    pub descriptions: HashMap<String, String>,
+    // Commands whose output, one candidate per line, is read at each Tab
+    pub dynamic: Option<String>,
+    pub arguments: HashMap<String, String>,
+}
+
+impl Spec {
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut spec = Spec::default();
+        let mut hints = HashMap::new();
+        for (section, key, value) in read_table(content)? {
+            match (section.as_str(), key.as_str(), value) {
+                ("", "command", Value::Str(s)) => spec.command = s,
+                ("", "subcommands", Value::List(items)) => spec.subcommands = items,
+                ("", "flags", Value::List(items)) => {
+                    for flag in items {
+                        match flag.split_once('=') {
+                            Some((name, hint)) => {
+                                let name = format!("{name}=");
+                                if !hint.is_empty() {
+                                    hints.insert(name.clone(), hint.to_string());
+                                }
+                                spec.flags.push(name);
+                            }
+                            None => spec.flags.push(flag),
+                        }
+                    }
+                }
+                ("", "dynamic", Value::Str(s)) => spec.dynamic = Some(s),
+                ("arguments", sub, Value::Str(s)) => {
+                    spec.arguments.insert(sub.to_string(), s);
+                }
+                ("descriptions", name, Value::Str(s)) => {
+                    spec.descriptions.insert(name.to_string(), s);
+                }
+                (section, key, _) => {
+                    let name = if section.is_empty() {
+                        key.to_string()
+                    } else {
+                        format!("{section}.{key}")
+                    };
+                    return Err(format!("{name}: not a completion setting"));
+                }
+            }
+        }
+        if spec.command.is_empty() {
+            return Err("no `command = \"...\"` line".into());
+        }
+        for (flag, hint) in hints {
+            spec.descriptions.entry(flag).or_insert(hint);
+        }
+        Ok(spec)
+    }
+}
+
+static SPECS: OnceLock<Mutex<HashMap<String, Spec>>> = OnceLock::new();
+
+fn specs() -> &'static Mutex<HashMap<String, Spec>> {
+    SPECS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn spec_dir() -> PathBuf {
+    crate::config::get_config().join("shesh/completions")
+}
+
+// Every `*.toml` in `dir`, by command; a later file for the same command
+// replaces an earlier one. Problems name the file.
+fn read_specs(dir: &Path) -> (HashMap<String, Spec>, Vec<String>) {
+    let mut found = HashMap::new();
+    let mut problems = Vec::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return (found, problems);
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    paths.sort();
+    for path in paths {
+        let parsed = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| Spec::parse(&content));
+        match parsed {
+            Ok(spec) => {
+                found.insert(spec.command.clone(), spec);
+            }
+            Err(e) => problems.push(format!("{}: {e}", path.display())),
+        }
+    }
+    (found, problems)
+}
+
+/// Reads the spec files again, at startup and on `24! completions refresh`;
+/// returns how many commands they cover.
+pub fn load() -> usize {
+    let (found, problems) = read_specs(&spec_dir());
+    for problem in problems {
+        crate::output::warn(&problem);
+    }
+    let count = found.len();
+    *specs().lock().unwrap() = found;
+    count
+}
+
+pub fn get(cmd: &str) -> Option<Spec> {
+    specs().lock().unwrap().get(cmd).cloned()
+}
+
+/// The lines a `dynamic` or `[arguments]` command prints.
+pub fn run_dynamic(cmd: &str) -> Vec<String> {
+    let Ok(output) = capture_output_within(cmd, DYNAMIC_TIMEOUT) else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_read_specs() {
+        let dir = TempDir::new("specs");
+        fs::write(
+            dir.join("mytool.toml"),
+            r#"command = "mytool"
+subcommands = ["build", "deploy"]
+flags = ["--verbose", "--target=TRIPLE", "--color="]
+dynamic = "echo one"
+
+[arguments]
+deploy = "printf 'staging\nprod\n'"
+
+[descriptions]
+build = "Compile it"
+"#,
+        )
+        .unwrap();
+        fs::write(dir.join("broken.toml"), "subcommands = [\"x\"]\n").unwrap();
+        fs::write(dir.join("notes.txt"), "ignored").unwrap();
+
+        let (found, problems) = read_specs(&dir);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("broken.toml"));
+        let spec = &found["mytool"];
+        assert_eq!(spec.subcommands, ["build", "deploy"]);
+        assert_eq!(spec.flags, ["--verbose", "--target=", "--color="]);
+        assert_eq!(spec.descriptions["--target="], "TRIPLE");
+        assert_eq!(spec.descriptions["build"], "Compile it");
+        assert!(!spec.descriptions.contains_key("--color="));
+        assert_eq!(run_dynamic(&spec.arguments["deploy"]), ["staging", "prod"]);
+
+        assert!(Spec::parse("command = \"x\"\nextra = 1\n").is_err());
+    }
+}
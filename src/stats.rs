@@ -0,0 +1,137 @@
+use crate::history;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Lines run in this session and, per command, how many of them failed
+#[derive(Default)]
+struct Session {
+    lines: usize,
+    failed: usize,
+    failures: HashMap<String, usize>,
+}
+
+static SESSION: OnceLock<Mutex<Session>> = OnceLock::new();
+
+fn session() -> &'static Mutex<Session> {
+    SESSION.get_or_init(|| Mutex::new(Session::default()))
+}
+
+// First word of every command on the line, `a | b; c` gives a, b, c
+fn commands(line: &str) -> impl Iterator<Item = &str> {
+    line.split([';', '|', '&'])
+        .filter_map(|segment| segment.split_whitespace().next())
+}
+
+// Called by the REPL after each line with the status it left behind
+pub fn record(line: &str, status: i32) {
+    let mut session = session().lock().unwrap();
+    session.lines += 1;
+    if status != 0 {
+        session.failed += 1;
+        if let Some(cmd) = commands(line).last() {
+            *session.failures.entry(cmd.to_string()).or_default() += 1;
+        }
+    }
+}
+
+// Highest counts first, ties by name so the output is stable
+fn top(counts: HashMap<String, usize>, n: usize) -> Vec<(String, usize)> {
+    let mut sorted: Vec<_> = counts.into_iter().collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    sorted.truncate(n);
+    sorted
+}
+
+/// `24! stats [--since AGE] [N]`: the N most used commands in the history
+/// file, or in the entries of the last AGE (`7d`, `12h`), and the failures
+/// of this session.
+pub fn run(args: &[&str]) -> io::Result<()> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: 24! stats [--since age] [N]",
+        )
+    };
+    let mut limit = 10;
+    let mut since = None;
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--since" => {
+                let age = args
+                    .next()
+                    .and_then(|age| crate::completions::parse_ttl(age).flatten())
+                    .ok_or_else(usage)?;
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                since = Some(now.saturating_sub(age.as_secs()));
+            }
+            n => limit = n.parse().map_err(|_| usage())?,
+        }
+    }
+
+    // Counting streams the file, so put this session's lines in it first
+    history::sync()?;
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut total = 0;
+    // Entries from before times were recorded can't be placed, --since
+    // leaves them out
+    match history::for_each_timed(&history::file_path(), |time, entry| {
+        if since.is_some_and(|since| time.is_none_or(|time| time < since)) {
+            return;
+        }
+        for cmd in commands(entry) {
+            *counts.entry(cmd.to_string()).or_default() += 1;
+            total += 1;
+        }
+    }) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    println!("Top {limit} commands ({total} in history):");
+    for (cmd, count) in top(counts, limit) {
+        println!("  {count:>6}  {cmd}");
+    }
+
+    let session = session().lock().unwrap();
+    println!(
+        "This session: {} lines, {} failed",
+        session.lines, session.failed
+    );
+    if !session.failures.is_empty() {
+        println!("Most failures this session:");
+        for (cmd, count) in top(session.failures.clone(), limit) {
+            println!("  {count:>6}  {cmd}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_top_commands() {
+        let line = "cat a | grep b && ls; ls &";
+        assert_eq!(
+            commands(line).collect::<Vec<_>>(),
+            vec!["cat", "grep", "ls", "ls"]
+        );
+
+        let mut counts = HashMap::new();
+        for cmd in commands(line) {
+            *counts.entry(cmd.to_string()).or_default() += 1;
+        }
+        assert_eq!(
+            top(counts, 2),
+            vec![("ls".to_string(), 2), ("cat".to_string(), 1)]
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+};
+
+// The terminal's settings while the shell waits at its prompt: taken at
+// startup and after every command that leaves the terminal usable, so an
+// `stty` the user ran sticks
+static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+// SGR reset, cursor shown, and ASCII as G0 and as the active character set:
+// what a program killed halfway through drawing may have left otherwise
+const RESET_SEQUENCE: &str = "\x1b[0m\x1b[?25h\x1b(B\x0f";
+
+fn current() -> Option<libc::termios> {
+    let mut settings: libc::termios = unsafe { std::mem::zeroed() };
+    (unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut settings) } == 0).then_some(settings)
+}
+
+/// Remembers the terminal's settings; called once the shell owns the
+/// terminal, before the first prompt.
+pub fn save() {
+    *SAVED.lock().unwrap() = current();
+}
+
+// Raw mode, no echo, or output without newline translation: typing would be
+// invisible or the prompt drawn as a staircase
+fn is_broken(settings: &libc::termios) -> bool {
+    settings.c_lflag & libc::ICANON == 0
+        || settings.c_lflag & libc::ECHO == 0
+        || settings.c_oflag & libc::OPOST == 0
+}
+
+fn put_back(saved: &libc::termios) {
+    unsafe {
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, saved);
+    }
+}
+
+fn reset_display() {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(RESET_SEQUENCE.as_bytes());
+    let _ = stdout.flush();
+}
+
+/// After a foreground command: a terminal it left raw or silent, as a
+/// crashed curses program or a killed ssh does, gets the saved settings
+/// back; otherwise its settings become the saved ones. Colors, the cursor
+/// and the character set are reset either way.
+pub fn recover() {
+    let mut saved = SAVED.lock().unwrap();
+    let Some(now) = current() else {
+        return;
+    };
+    if !is_broken(&now) {
+        *saved = Some(now);
+    } else if let Some(good) = &*saved {
+        put_back(good);
+    }
+    reset_display();
+}
+
+/// `24! reset-term`: the saved settings and the reset sequence, whatever
+/// state the terminal is in.
+pub fn reset() -> io::Result<()> {
+    let saved = SAVED.lock().unwrap();
+    let good = saved
+        .as_ref()
+        .ok_or_else(|| io::Error::other("reset-term: stdin is not a terminal"))?;
+    put_back(good);
+    reset_display();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_broken_terminal() {
+        let mut settings: libc::termios = unsafe { std::mem::zeroed() };
+        settings.c_lflag = libc::ICANON | libc::ECHO | libc::ISIG;
+        settings.c_oflag = libc::OPOST | libc::ONLCR;
+        assert!(!is_broken(&settings));
+        // What cfmakeraw clears
+        let mut raw = settings;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        assert!(is_broken(&raw));
+        // `stty -echo` left behind by a password prompt
+        settings.c_lflag &= !libc::ECHO;
+        assert!(is_broken(&settings));
+    }
+}
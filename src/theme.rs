@@ -0,0 +1,201 @@
+use crate::config::Value;
+use nu_ansi_term::Color;
+use std::{
+    io,
+    sync::{Mutex, OnceLock},
+};
+
+/// Colors for the prompt, the vi mode indicators, hints and error messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    pub name: String,
+    pub path: Color,
+    pub vi_normal: Color,
+    pub vi_insert: Color,
+    pub hint: Color,
+    pub error: Color,
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        Self {
+            name: "dark".into(),
+            path: Color::Green,
+            vi_normal: Color::Yellow,
+            vi_insert: Color::Green,
+            hint: Color::Rgb(120, 120, 120),
+            error: Color::Red,
+        }
+    }
+
+    pub fn light() -> Self {
+        Self {
+            name: "light".into(),
+            path: Color::Blue,
+            vi_normal: Color::Purple,
+            vi_insert: Color::Blue,
+            hint: Color::Rgb(100, 100, 100),
+            error: Color::Red,
+        }
+    }
+
+    // A custom theme starts out as the dark one
+    pub fn custom(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            ..Self::dark()
+        }
+    }
+
+    /// Sets `field` from a config line; false when the field or color is unknown.
+    pub fn set_field(&mut self, field: &str, value: &str) -> bool {
+        let Some(color) = parse_color(value) else {
+            return false;
+        };
+        match field {
+            "path" => self.path = color,
+            "vi_normal" => self.vi_normal = color,
+            "vi_insert" => self.vi_insert = color,
+            "hint" => self.hint = color,
+            "error" => self.error = color,
+            _ => return false,
+        }
+        true
+    }
+}
+
+// `green`, `#88c0d0` or a 256-color number
+pub fn parse_color(value: &str) -> Option<Color> {
+    let value = value.trim().trim_matches('"').to_ascii_lowercase();
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+    }
+    if let Ok(n) = value.parse::<u8>() {
+        return Some(Color::Fixed(n));
+    }
+    Some(match value.as_str() {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "purple" | "magenta" => Color::Purple,
+        "cyan" => Color::Cyan,
+        "white" => Color::White,
+        "gray" | "grey" => Color::DarkGray,
+        _ => return None,
+    })
+}
+
+// Themes from the config file, next to the built-in ones
+static CUSTOM: OnceLock<Mutex<Vec<Theme>>> = OnceLock::new();
+static ACTIVE: OnceLock<Mutex<Theme>> = OnceLock::new();
+
+fn custom() -> &'static Mutex<Vec<Theme>> {
+    CUSTOM.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+fn active_theme() -> &'static Mutex<Theme> {
+    ACTIVE.get_or_init(|| Mutex::new(Theme::dark()))
+}
+
+pub fn active() -> Theme {
+    active_theme().lock().unwrap().clone()
+}
+
+fn available() -> Vec<Theme> {
+    let mut themes = vec![Theme::dark(), Theme::light()];
+    for theme in custom().lock().unwrap().iter() {
+        themes.retain(|t| t.name != theme.name);
+        themes.push(theme.clone());
+    }
+    themes
+}
+
+fn find(name: &str) -> Option<Theme> {
+    available().into_iter().find(|t| t.name == name)
+}
+
+// Called at startup with what the config file defines and selects
+pub fn init(themes: Vec<Theme>, selected: Option<&str>) {
+    *custom().lock().unwrap() = themes;
+    if let Some(name) = selected {
+        match find(name) {
+            Some(theme) => activate(theme),
+            None => crate::output::warn(&format!("Unknown theme '{name}', using dark")),
+        }
+    }
+}
+
+fn activate(theme: Theme) {
+    *active_theme().lock().unwrap() = theme;
+}
+
+fn show() {
+    let theme = active();
+    println!("Active theme: {}", theme.name);
+    for (field, color) in [
+        ("path", theme.path),
+        ("vi_normal", theme.vi_normal),
+        ("vi_insert", theme.vi_insert),
+        ("hint", theme.hint),
+        ("error", theme.error),
+    ] {
+        println!("  {}  {color:?}", color.paint(format!("{field:<9}")));
+    }
+    let names: Vec<String> = available().into_iter().map(|t| t.name).collect();
+    println!("Available: {}", names.join(", "));
+}
+
+/// `24! theme [show | NAME]`: switching applies from the next prompt and is
+/// saved to the config file.
+pub fn handle_theme(args: &[&str]) -> io::Result<()> {
+    match args {
+        [] | ["show"] => {
+            show();
+            Ok(())
+        }
+        [name] => {
+            let theme = find(name).ok_or_else(|| {
+                let names: Vec<String> = available().into_iter().map(|t| t.name).collect();
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("24! theme: unknown theme '{name}' ({})", names.join(", ")),
+                )
+            })?;
+            activate(theme);
+            crate::config::set_option("prompt", "theme", Value::Str(name.to_string()))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: 24! theme [show | name]",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("green"), Some(Color::Green));
+        assert_eq!(
+            parse_color("\"#88C0D0\""),
+            Some(Color::Rgb(0x88, 0xc0, 0xd0))
+        );
+        assert_eq!(parse_color("208"), Some(Color::Fixed(208)));
+        assert_eq!(parse_color("#12345"), None);
+        assert_eq!(parse_color("chartreuse"), None);
+
+        let mut theme = Theme::custom("mine");
+        assert!(theme.set_field("path", "cyan"));
+        assert!(!theme.set_field("prompt", "cyan"));
+        assert_eq!(theme.path, Color::Cyan);
+        assert_eq!(theme.error, Theme::dark().error);
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+// Set from `[shell] title`; "auto" turns it on for graphical terminals
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// `Some(on)` from the config, None to decide from $TERM.
+pub fn configure(setting: Option<bool>) {
+    let on = setting.unwrap_or_else(|| looks_graphical(&env::var("TERM").unwrap_or_default()));
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+/// Terminal emulators in a window; the Linux console, serial lines and dumb
+/// terminals have no title to set, and no use for other OSC sequences.
+pub fn looks_graphical(term: &str) -> bool {
+    const PREFIXES: &[&str] = &[
+        "xterm",
+        "rxvt",
+        "alacritty",
+        "kitty",
+        "foot",
+        "wezterm",
+        "st-",
+        "konsole",
+        "gnome",
+        "vte",
+        "tmux",
+        "screen",
+        "iterm",
+        "contour",
+        "ghostty",
+    ];
+    PREFIXES.iter().any(|prefix| term.starts_with(prefix))
+}
+
+// OSC 0 sets the window and icon title. Inside tmux or screen it names the
+// pane; the same sequence wrapped in DCS passthrough reaches the terminal
+// around them too.
+fn sequence(title: &str, multiplexer: Option<&str>) -> String {
+    let title: String = title.chars().filter(|c| !c.is_control()).collect();
+    let osc = format!("\x1b]0;{title}\x07");
+    match multiplexer {
+        Some("tmux") => format!("{osc}\x1bPtmux;\x1b{osc}\x1b\\"),
+        Some("screen") => format!("{osc}\x1bP{osc}\x1b\\"),
+        _ => osc,
+    }
+}
+
+fn multiplexer() -> Option<&'static str> {
+    if env::var_os("TMUX").is_some() {
+        Some("tmux")
+    } else if env::var_os("STY").is_some() {
+        Some("screen")
+    } else {
+        None
+    }
+}
+
+// The controlling terminal, opened once: a command's stdout may be
+// redirected, the title still has to reach the terminal
+static TTY: OnceLock<Option<Mutex<File>>> = OnceLock::new();
+
+fn set(title: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let tty = TTY.get_or_init(|| {
+        OpenOptions::new()
+            .write(true)
+            .open("/dev/tty")
+            .ok()
+            .map(Mutex::new)
+    });
+    if let Some(tty) = tty {
+        let mut tty = tty.lock().unwrap();
+        let _ = tty.write_all(sequence(title, multiplexer()).as_bytes());
+        let _ = tty.flush();
+    }
+}
+
+/// `shesh: ~/src/project`, before each prompt.
+pub fn at_prompt() {
+    let cwd = env::current_dir()
+        .map(|p| p.display().to_string())
+        .unwrap_or_default();
+    let home = env::var("HOME").unwrap_or_default();
+    let shown = match cwd.strip_prefix(&home) {
+        Some(rest) if !home.is_empty() && (rest.is_empty() || rest.starts_with('/')) => {
+            format!("~{rest}")
+        }
+        _ => cwd,
+    };
+    set(&format!("shesh: {shown}"));
+}
+
+/// The command's name while a foreground line runs; the next prompt puts
+/// the directory back.
+pub fn running(line: &str) {
+    if let Some(name) = line.split_whitespace().next() {
+        set(name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_title_sequences() {
+        assert!(looks_graphical("xterm-256color"));
+        assert!(looks_graphical("tmux-256color"));
+        assert!(!looks_graphical("linux"));
+        assert!(!looks_graphical("dumb"));
+        assert!(!looks_graphical(""));
+
+        assert_eq!(sequence("shesh: ~", None), "\x1b]0;shesh: ~\x07");
+        // A title can't end the sequence early
+        assert_eq!(sequence("a\x07b\nc", None), "\x1b]0;abc\x07");
+        assert_eq!(
+            sequence("vim", Some("tmux")),
+            "\x1b]0;vim\x07\x1bPtmux;\x1b\x1b]0;vim\x07\x1b\\"
+        );
+    }
+}
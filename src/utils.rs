@@ -1,26 +1,250 @@
-use std::{env, path::PathBuf};
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Numbers the temp files of atomic_write_with, so threads replacing the same
+// file at once each write their own
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
 
 pub fn expand_tilde(path: &str) -> PathBuf {
-    if let Some(stripped) = path.strip_prefix('~') {
-        if let Ok(home) = env::var("HOME") {
-            if stripped.is_empty() {
-                return PathBuf::from(home);
-            } else if let Some(rest) = stripped.strip_prefix('/') {
-                return PathBuf::from(home).join(rest);
-            }
+    if let Some(stripped) = path.strip_prefix('~')
+        && let Ok(home) = env::var("HOME")
+    {
+        if stripped.is_empty() {
+            return PathBuf::from(home);
+        } else if let Some(rest) = stripped.strip_prefix('/') {
+            return PathBuf::from(home).join(rest);
         }
     }
     PathBuf::from(path)
 }
 
+// `$NAME` and `${NAME}`, where a name is the longest run of letters, digits
+// and `_`. Unset variables expand to nothing; a `$` that starts no name stays.
 pub fn expand_env_vars(input: &str) -> String {
-    let mut result = input.to_string();
-    for (key, value) in env::vars() {
-        result = result.replace(&format!("${key}"), &value);
+    let mut result = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(i) = rest.find('$') {
+        result.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        let (name, skip) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let len = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..len], len)
+            }
+        };
+        if name.is_empty() {
+            result.push('$');
+        } else {
+            result.push_str(&env::var(name).unwrap_or_default());
+        }
+        rest = &after[skip..];
+    }
+    result.push_str(rest);
+    result
+}
+
+// Path of the running shesh binary, resolved through /proc/self/exe
+pub fn self_exe() -> Option<PathBuf> {
+    fs::canonicalize("/proc/self/exe")
+        .or_else(|_| env::current_exe())
+        .ok()
+}
+
+// A regular file with at least one execute bit set
+pub fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+// Every executable named `cmd` on PATH, in PATH order
+pub fn find_all_in_path(cmd: &str) -> Vec<PathBuf> {
+    if cmd.contains('/') {
+        let path = expand_tilde(cmd);
+        return if is_executable(&path) {
+            vec![path]
+        } else {
+            Vec::new()
+        };
+    }
+    let Some(path_var) = env::var_os("PATH") else {
+        return Vec::new();
+    };
+    env::split_paths(&path_var)
+        .map(|dir| dir.join(cmd))
+        .filter(|path| is_executable(path))
+        .collect()
+}
+
+// First match for `cmd` on PATH (or the path itself when it contains a slash)
+pub fn find_in_path(cmd: &str) -> Option<PathBuf> {
+    find_all_in_path(cmd).into_iter().next()
+}
+
+// True when any command in `line` would launch shesh itself
+pub fn resolves_to_self(line: &str) -> bool {
+    let Some(me) = self_exe() else {
+        return false;
+    };
+    line.split([';', '|', '&'])
+        .filter_map(|segment| segment.split_whitespace().next())
+        .filter_map(|cmd| fs::canonicalize(find_in_path(cmd.trim_matches(['"', '\'']))?).ok())
+        .any(|path| path == me)
+}
+
+// Bumps SHESH_DEPTH for this shell and its children, refusing to go past `limit`
+pub fn enter_shell_depth(limit: u32) -> io::Result<u32> {
+    let depth = env::var("SHESH_DEPTH")
+        .ok()
+        .and_then(|d| d.parse::<u32>().ok())
+        .unwrap_or(0)
+        + 1;
+
+    if depth > limit {
+        return Err(io::Error::other(format!(
+            "shesh: refusing to start, nesting depth {depth} exceeds max_depth {limit} (check aliases and startup commands that launch shesh)"
+        )));
+    }
+
+    unsafe {
+        env::set_var("SHESH_DEPTH", depth.to_string());
+    }
+    Ok(depth)
+}
+
+/// Whole-string match with `*` (any run of characters) and `?` (one character).
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Where the last `*` was, and how much text it has swallowed so far
+    let mut star: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        match p.get(pi) {
+            Some('*') => {
+                star = Some((pi, ti));
+                pi += 1;
+            }
+            Some(&c) if c == '?' || c == t[ti] => {
+                pi += 1;
+                ti += 1;
+            }
+            _ => match star {
+                Some((sp, st)) => {
+                    pi = sp + 1;
+                    ti = st + 1;
+                    star = Some((sp, st + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Replaces `path` with `bytes` so readers only ever see the old or the new file.
+pub fn atomic_write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    atomic_write_with(path, |w| w.write_all(bytes))
+}
+
+/// Streams new contents for `path` into a temp file in the same directory,
+/// fsyncs it, keeps the old file's permissions and renames it into place.
+pub fn atomic_write_with<T>(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<T>,
+) -> io::Result<T> {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "atomic_write needs a file path",
+        )
+    })?;
+    let tmp_path = dir.join(format!(
+        ".{}.tmp.{}.{}",
+        name.to_string_lossy(),
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    // Never one already there, which may be someone else's or a symlink
+    let tmp = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)?;
+    let result = (|| {
+        let mut writer = BufWriter::new(tmp);
+        let value = write(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        if let Ok(meta) = fs::metadata(path) {
+            file.set_permissions(meta.permissions())?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        // Persist the rename itself
+        File::open(dir)?.sync_all()?;
+        Ok(value)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
     }
     result
 }
 
+/// A test's scratch directory, `shesh-<name>-<pid>` under the temp dir. It
+/// starts empty and is removed with its contents when dropped, so a failed
+/// assert doesn't leave it behind.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("shesh-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -33,4 +257,63 @@ mod test {
             PathBuf::from(format!("/home/{}/Documents/projects", user_name))
         );
     }
+
+    #[test]
+    fn test_expand_env_vars() {
+        let home = env::var("HOME").unwrap();
+        assert_eq!(expand_env_vars("$HOME/bin"), format!("{home}/bin"));
+        assert_eq!(expand_env_vars("${HOME}x"), format!("{home}x"));
+        // A longer name is not a variable plus text
+        assert_eq!(expand_env_vars("$HOMESHESH_UNSET_X"), "");
+        assert_eq!(expand_env_vars("cost: 5$ ${"), "cost: 5$ ${");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("export TOKEN=*", "export TOKEN=abc123"));
+        assert!(glob_match("*secret*", "curl -H secret-header"));
+        assert!(glob_match("l?", "ls"));
+        assert!(!glob_match("l?", "lsd"));
+        assert!(!glob_match("export TOKEN=*", "echo export TOKEN=x"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_atomic_write() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("atomic");
+        let path = dir.join("file");
+        atomic_write(&path, b"first").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        atomic_write(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // A failed write leaves the old contents and no temp file behind
+        let failed = atomic_write_with(&path, |w| {
+            w.write_all(b"partial")?;
+            Err::<(), _>(io::Error::other("crash"))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Threads replacing it at once don't trip over each other's temp file
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || atomic_write(&path, format!("writer {i}").as_bytes()))
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+        assert!(fs::read_to_string(&path).unwrap().starts_with("writer "));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
 }
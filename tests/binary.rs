@@ -0,0 +1,35 @@
+// These run the built shesh, which unit tests can't: there, subshells fall
+// back to `sh -c` and pipelines never meet shesh's own threads.
+use std::{path::PathBuf, process::Command};
+
+// `shesh -c line` with a home of its own, its stdout and exit code
+fn shesh(name: &str, line: &str) -> (String, i32) {
+    let home = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::create_dir_all(&home).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_shesh"))
+        .args(["-c", line])
+        .env("HOME", &home)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    (stdout, output.status.code().unwrap_or(-1))
+}
+
+#[test]
+fn test_subshell_is_shesh() {
+    // Only shesh knows the alias, so sh -c would fail here
+    let (out, status) = shesh("subshell", "alias greet='echo hi'; echo $(greet)");
+    assert_eq!((out.as_str(), status), ("hi\n", 0));
+}
+
+#[test]
+fn test_pipeline() {
+    let (out, status) = shesh("pipeline", "printf 'b\\na\\nc\\n' | sort | head -n 2");
+    assert_eq!((out.as_str(), status), ("a\nb\n", 0));
+
+    // The last stage decides the status, even when it never starts
+    let (out, status) = shesh("pipeline", "echo x | shesh-no-such-command");
+    assert_eq!((out.as_str(), status), ("", 127));
+    let (_, status) = shesh("pipeline", "false | true");
+    assert_eq!(status, 0);
+}